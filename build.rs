@@ -0,0 +1,186 @@
+/* Compiles the game database in db/ into a sorted table the emulator can binary search */
+//Two kinds of files are understood, and entries for the same ROM from either get merged together:
+//  NES 2.0 XML (nes20db.xml), which has the correct board, mirroring, region and expansion device for each dump
+//  Logiqx XML DATs (No-Intro style, headerless), which have the title, publisher and year
+//Both are keyed on the CRC32/SHA-1 of PRG ROM followed by CHR ROM, without the INES header or trainer.
+//Set NES_GAME_DB to a directory to build from somewhere other than db/
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+struct Entry {
+    sha1: Option<String>,
+    mapper: Option<u16>,
+    submapper: Option<u8>,
+    mirroring: Option<&'static str>,
+    region: Option<&'static str>,
+    battery: Option<bool>,
+    expansion_device: Option<u8>,
+    title: Option<String>,
+    publisher: Option<String>,
+    year: Option<u16>,
+}
+
+impl Entry {
+    //Anything already known wins, so the order files are read in only matters for conflicting entries
+    fn merge(&mut self, other: Entry) {
+        self.sha1 = self.sha1.take().or(other.sha1);
+        self.mapper = self.mapper.or(other.mapper);
+        self.submapper = self.submapper.or(other.submapper);
+        self.mirroring = self.mirroring.or(other.mirroring);
+        self.region = self.region.or(other.region);
+        self.battery = self.battery.or(other.battery);
+        self.expansion_device = self.expansion_device.or(other.expansion_device);
+        self.title = self.title.take().or(other.title);
+        self.publisher = self.publisher.take().or(other.publisher);
+        self.year = self.year.or(other.year);
+    }
+}
+
+fn parse_hex_u32(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim(), 16).ok()
+}
+
+fn child<'a, 'input>(node: roxmltree::Node<'a, 'input>, name: &str) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+//https://forums.nesdev.org/viewtopic.php?t=19940 for the format
+fn read_nes20db(root: roxmltree::Node, games: &mut BTreeMap<u32, Entry>) {
+    for game in root.children().filter(|n| n.has_tag_name("game")) {
+        let rom = match child(game, "rom") {
+            Some(rom) => rom,
+            None => continue,
+        };
+        let crc32 = match rom.attribute("crc32").and_then(parse_hex_u32) {
+            Some(crc32) => crc32,
+            None => continue,
+        };
+        let mut entry = Entry {
+            sha1: rom.attribute("sha1").map(|s| s.to_ascii_lowercase()),
+            ..Entry::default()
+        };
+        //The comment in front of each game is the file name it was dumped as
+        entry.title = game
+            .children()
+            .find(|n| n.is_comment())
+            .and_then(|n| n.text())
+            .map(|path| {
+                let name = path.trim().rsplit(['\\', '/']).next().unwrap_or("");
+                name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(name).to_string()
+            })
+            .filter(|name| !name.is_empty());
+        if let Some(pcb) = child(game, "pcb") {
+            entry.mapper = pcb.attribute("mapper").and_then(|s| s.parse().ok());
+            entry.submapper = pcb.attribute("submapper").and_then(|s| s.parse().ok());
+            entry.mirroring = match pcb.attribute("mirroring") {
+                Some("H") => Some("Horizontal"),
+                Some("V") => Some("Vertical"),
+                Some("4") => Some("FourScreen"),
+                _ => None,
+            };
+            entry.battery = pcb.attribute("battery").map(|s| s == "1");
+        }
+        if let Some(console) = child(game, "console") {
+            entry.region = match console.attribute("region") {
+                Some("0") => Some("Ntsc"),
+                Some("1") => Some("Pal"),
+                Some("2") => Some("Auto"),
+                Some("3") => Some("Dendy"),
+                _ => None,
+            };
+        }
+        if let Some(expansion) = child(game, "expansion") {
+            entry.expansion_device = expansion.attribute("type").and_then(|s| s.parse().ok());
+        }
+        games.entry(crc32).or_default().merge(entry);
+    }
+}
+
+//http://www.logiqx.com/DatFAQs/ for the format
+fn read_logiqx_dat(root: roxmltree::Node, games: &mut BTreeMap<u32, Entry>) {
+    for game in root.children().filter(|n| n.has_tag_name("game") || n.has_tag_name("machine")) {
+        let text = |name: &str| child(game, name).and_then(|n| n.text()).map(|s| s.trim().to_string());
+        for rom in game.children().filter(|n| n.has_tag_name("rom")) {
+            let crc32 = match rom.attribute("crc").and_then(parse_hex_u32) {
+                Some(crc32) => crc32,
+                None => continue,
+            };
+            let mut entry = Entry {
+                sha1: rom.attribute("sha1").map(|s| s.to_ascii_lowercase()),
+                title: game.attribute("name").map(|s| s.to_string()),
+                publisher: text("manufacturer"),
+                year: text("year").and_then(|s| s.parse().ok()),
+                ..Entry::default()
+            };
+            //DAT names are proper titles, prefer them over the file names in nes20db.xml comments
+            entry.merge(games.remove(&crc32).unwrap_or_default());
+            games.insert(crc32, entry);
+        }
+    }
+}
+
+fn write_str(out: &mut String, value: &Option<String>) {
+    match value {
+        Some(s) => write!(out, "Some({:?})", s).unwrap(),
+        None => out.push_str("None"),
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=NES_GAME_DB");
+    let db_dir = env::var_os("NES_GAME_DB")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("db"));
+    println!("cargo:rerun-if-changed={}", db_dir.display());
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&db_dir)
+        .map(|dir| dir.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    paths.retain(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("xml") | Some("dat")));
+    paths.sort();
+
+    let mut games = BTreeMap::new();
+    for path in paths {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let xml = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let doc = roxmltree::Document::parse(&xml).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        let root = doc.root_element();
+        match root.tag_name().name() {
+            "nes20db" => read_nes20db(root, &mut games),
+            "datafile" => read_logiqx_dat(root, &mut games),
+            other => panic!("{}: don't know what a <{}> database is", path.display(), other),
+        }
+    }
+
+    //BTreeMap keeps it sorted by CRC32 for the binary search in gamedb.rs
+    let mut out = String::from("pub static GAME_DB: &[GameDbEntry] = &[\n");
+    for (crc32, entry) in &games {
+        write!(out, "    GameDbEntry {{ crc32: 0x{:08x}, sha1: ", crc32).unwrap();
+        match entry.sha1.as_deref().and_then(|s| hex::decode(s).ok()).filter(|b| b.len() == 20) {
+            Some(sha1) => write!(out, "Some({:?})", sha1).unwrap(),
+            None => out.push_str("None"),
+        }
+        write!(out, ", mapper: {:?}, submapper: {:?}", entry.mapper, entry.submapper).unwrap();
+        match entry.mirroring {
+            Some(m) => write!(out, ", mirror_table: Some(MirrorTable::{})", m).unwrap(),
+            None => out.push_str(", mirror_table: None"),
+        }
+        match entry.region {
+            Some(r) => write!(out, ", timing: Some(Region::{})", r).unwrap(),
+            None => out.push_str(", timing: None"),
+        }
+        write!(out, ", has_battery: {:?}, expansion_device: {:?}, title: ", entry.battery, entry.expansion_device).unwrap();
+        write_str(&mut out, &entry.title);
+        out.push_str(", publisher: ");
+        write_str(&mut out, &entry.publisher);
+        writeln!(out, ", year: {:?} }},", entry.year).unwrap();
+    }
+    out.push_str("];\n");
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("game_db.rs");
+    fs::write(out_path, out).unwrap();
+}
@@ -8,7 +8,9 @@ async function main() {
     );
     const {
       WasmEmulator,
-      KeyEvent,
+      InputDeviceKind,
+      RunStatus,
+      PixelFormat,
     } = await import(
       "/pkg/nes.js"
     );
@@ -17,118 +19,172 @@ async function main() {
     const NUM_OF_COLORS = 3
     const emu = new WasmEmulator();
     emu.reset();
-    const rustBuf = new Uint8Array(memory.buffer);
-    const fbBasePtr = emu.get_fb_ptr();
-      //Do some drawing, I have not figured out how to make this faster, there might be a way but I am not 
-      //Familiar enough with canvas to get there.
-      //This takes the fb from waaaay down in the PPU and draws it :D
+    //The core hands over the picture as RGBA, already laid out like ImageData, so the canvas reads it straight out
+    //of wasm memory. The view only has to be made again if the pointer moves, wasm memory grows or the NTSC filter
+    //changes the width. The canvas is stretched to the same size on screen either way
+    emu.set_pixel_format(PixelFormat.Rgba8888);
+    let outputImage = null;
     function draw() {
+      const ptr = emu.get_output_ptr();
+      const width = emu.get_output_width();
+      if (
+        outputImage == null ||
+        outputImage.data.buffer !== memory.buffer ||
+        outputImage.data.byteOffset != ptr ||
+        outputImage.width != width
+      ) {
+        const pixels = new Uint8ClampedArray(memory.buffer, ptr, emu.get_output_size());
+        outputImage = new ImageData(pixels, width, SCREEN_HEIGHT);
+      }
       const canvas = document.getElementById("fb");
-      const ctx = canvas.getContext("2d");
-      const imageData = ctx.getImageData(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT);
-      for (let j = 0; j < SCREEN_HEIGHT; j++) {
-        for (let i = 0; i < SCREEN_WIDTH; i++) {
-          const imageDataPtr = j * (SCREEN_WIDTH * 4) + i * 4;
-          const rustDataPtr =
-            fbBasePtr + j * (SCREEN_WIDTH * NUM_OF_COLORS) + i * NUM_OF_COLORS;
-          imageData.data[imageDataPtr + 0] = rustBuf[rustDataPtr + 0]; // red
-          imageData.data[imageDataPtr + 1] = rustBuf[rustDataPtr + 1]; // green
-          imageData.data[imageDataPtr + 2] = rustBuf[rustDataPtr + 2]; // blue
-          imageData.data[imageDataPtr + 3] = 255; //alpha
-        }
+      if (canvas.width != width) {
+        canvas.width = width;
       }
-      ctx.putImageData(imageData, 0, 0);
+      const ctx = canvas.getContext("2d");
+      ctx.putImageData(outputImage, 0, 0);
     }
   
     
-    const emulateFps = 60;
-    const emulateInterval = 1000.0 / emulateFps;
+    //The APU hands over samples every line, the audio callback drains them at whatever rate the browser asks for.
+    //Browsers won't start audio before the page is interacted with, so this is resumed when a ROM is loaded.
+    const AUDIO_BUFFER_SIZE = 2048;
+    const audioCtx = new (window.AudioContext || window.webkitAudioContext)();
+    emu.set_audio_sample_rate(audioCtx.sampleRate);
+    let audioQueue = [];
+    const audioNode = audioCtx.createScriptProcessor(AUDIO_BUFFER_SIZE, 0, 1);
+    audioNode.onaudioprocess = e => {
+      const out = e.outputBuffer.getChannelData(0);
+      let i = 0;
+      while (i < out.length && audioQueue.length > 0) {
+        const chunk = audioQueue[0];
+        const n = Math.min(out.length - i, chunk.length);
+        out.set(chunk.subarray(0, n), i);
+        i += n;
+        if (n == chunk.length) {
+          audioQueue.shift();
+        } else {
+          audioQueue[0] = chunk.subarray(n);
+        }
+      }
+      out.fill(0, i);
+    };
+    audioNode.connect(audioCtx.destination);
+
     let isEmulateEnable = false;
+    //Held down with Backspace, the game runs backwards instead
+    let isRewinding = false;
+    const REWIND_FRAMES_PER_STEP = 2;
   
-   
-    function emulate_loop() {
+    //Stopped from the Pause menu, at a breakpoint or on a JAM. Frame advance still works
+    let isPaused = false;
+    let lastTime = performance.now();
+
+    //The core keeps time itself, each call just says how long it's been. It works out how much of a frame that is
+    //from the region's clock, so PAL/Dendy come out at 50Hz and NTSC at 60.1Hz whatever the monitor runs at
+    function emulate_loop(now) {
       //fun fact: performance.now() is a lot better to use in this context than messing with date stuff.
-      const start = performance.now()
-      if (isEmulateEnable) {
-        emu.step_line();
+      const elapsed = now - lastTime;
+      lastTime = now;
+      if (isEmulateEnable && isRewinding) {
+        emu.rewind(REWIND_FRAMES_PER_STEP);
+        draw();
+      } else if (isEmulateEnable && !isPaused) {
+        const status = emu.run_for(elapsed);
+        queue_audio();
+        if (status != RunStatus.TimeUp) {
+          draw();
+        }
+        if (status == RunStatus.Breakpoint || status == RunStatus.Jam) {
+          app.stopped(status);
+        }
       }
-      const elapsed = (performance.now() - start);
-      const diffTime = emulateInterval - elapsed;
-    
-      const sleepTime = diffTime < 0 ? 0 : diffTime;
-      setTimeout(emulate_loop, sleepTime);
+      requestAnimationFrame(emulate_loop);
     }
-    
-    function draw_loop() {
-      if (isEmulateEnable) {
-        draw();
+
+    function queue_audio() {
+      const samples = emu.take_audio_samples();
+      //Don't let the queue grow forever if the tab falls behind
+      if (samples.length > 0 && audioQueue.length < 32) {
+        audioQueue.push(samples);
       }
-      requestAnimationFrame(draw_loop);
     }
-    emulate_loop();
-    draw_loop();
+    requestAnimationFrame(emulate_loop);
   
+    //Keyboard layout for each player, key to [player, button bit]. Bits go A, B, Select, Start, Up, Down, Left, Right
+    const KEY_MAP = {
+      "j": [0, 0x01], "k": [0, 0x02], "u": [0, 0x04], "i": [0, 0x08],
+      "w": [0, 0x10], "s": [0, 0x20], "a": [0, 0x40], "d": [0, 0x80],
+      ".": [1, 0x01], ",": [1, 0x02], "n": [1, 0x04], "m": [1, 0x08],
+      "ArrowUp": [1, 0x10], "ArrowDown": [1, 0x20], "ArrowLeft": [1, 0x40], "ArrowRight": [1, 0x80],
+    };
+    //Same again for buttons held with autofire
+    const TURBO_KEY_MAP = {
+      "o": [0, 0x01], "p": [0, 0x02],
+      "l": [1, 0x01], ";": [1, 0x02],
+    };
+    //Power Pad/Family Trainer buttons 1-12 along the number row
+    const POWER_PAD_KEYS = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "0", "-", "="];
+    //What each player is holding down right now
+    const buttons = [0, 0, 0, 0];
+    const turboButtons = [0, 0, 0, 0];
+    let powerPadButtons = 0;
     function release_key(key) {
-      if (isEmulateEnable) {
-        switch (key) {
-          case "j":
-            emu.update_key(KeyEvent.ReleaseA);
-            break;
-          case "k":
-            emu.update_key(KeyEvent.ReleaseB);
-            break;
-          case "u":
-            emu.update_key(KeyEvent.ReleaseSelect);
-            break;
-          case "i":
-            emu.update_key(KeyEvent.ReleaseStart);
-            break;
-          case "w":
-            emu.update_key(KeyEvent.ReleaseUp);
-            break;
-          case "s":
-            emu.update_key(KeyEvent.ReleaseDown);
-            break;
-          case "a":
-            emu.update_key(KeyEvent.ReleaseLeft);
-            break;
-          case "d":
-            emu.update_key(KeyEvent.ReleaseRight);
-            break;
+      if (key == "Backspace" && isRewinding) {
+        isRewinding = false;
+        //The replay put back whatever was held back then, carry on with what's held now
+        for (let player = 0; player < buttons.length; player++) {
+          emu.set_buttons(player, buttons[player]);
+          emu.set_turbo_buttons(player, turboButtons[player]);
         }
       }
+      const mapping = KEY_MAP[key];
+      if (isEmulateEnable && mapping) {
+        const [player, bit] = mapping;
+        buttons[player] &= ~bit;
+        emu.set_buttons(player, buttons[player]);
+      }
+      const turboMapping = TURBO_KEY_MAP[key];
+      if (isEmulateEnable && turboMapping) {
+        const [player, bit] = turboMapping;
+        turboButtons[player] &= ~bit;
+        emu.set_turbo_buttons(player, turboButtons[player]);
+      }
+      const powerPadButton = POWER_PAD_KEYS.indexOf(key);
+      if (isEmulateEnable && powerPadButton >= 0) {
+        powerPadButtons &= ~(1 << powerPadButton);
+        emu.set_power_pad(powerPadButtons);
+      }
     }
     function press_key(key) {
-      if (isEmulateEnable) {
-        switch (key) {
-          case "j":
-            emu.update_key(KeyEvent.PressA);
-            break;
-          case "k":
-            emu.update_key(KeyEvent.PressB);
-            break;
-          case "u":
-            emu.update_key(KeyEvent.PressSelect);
-            break;
-          case "i":
-            emu.update_key(KeyEvent.PressStart);
-            break;
-          case "w":
-            emu.update_key(KeyEvent.PressUp);
-            break;
-          case "s":
-            emu.update_key(KeyEvent.PressDown);
-            break;
-          case "a":
-            emu.update_key(KeyEvent.PressLeft);
-            break;
-          case "d":
-            emu.update_key(KeyEvent.PressRight);
-            break;
-        }
+      if (key == "Backspace") {
+        isRewinding = true;
+      }
+      const mapping = KEY_MAP[key];
+      if (isEmulateEnable && mapping) {
+        const [player, bit] = mapping;
+        buttons[player] |= bit;
+        emu.set_buttons(player, buttons[player]);
+      }
+      const turboMapping = TURBO_KEY_MAP[key];
+      if (isEmulateEnable && turboMapping) {
+        const [player, bit] = turboMapping;
+        turboButtons[player] |= bit;
+        emu.set_turbo_buttons(player, turboButtons[player]);
+      }
+      const powerPadButton = POWER_PAD_KEYS.indexOf(key);
+      if (isEmulateEnable && powerPadButton >= 0) {
+        powerPadButtons |= 1 << powerPadButton;
+        emu.set_power_pad(powerPadButtons);
       }
     }
   
+    //Romhack/translation patch to apply to the next ROM loaded, if one was picked
+    let patchBuf = null;
+    //Name of the loaded ROM, movies keep it in their header
+    let romFileName = "";
+    //Archive holding several ROMs, kept while the player picks one
+    let archiveBuf = null;
+
     ELEMENT.locale("en", ELEMENT.lang.en);
     const app = new Vue({
       el: "#app",
@@ -137,37 +193,397 @@ async function main() {
         loadRomVisible: false,
         keyconfigVisible: false,
         gamepadVisible: false,
+        patternTableVisible: false,
+        archiveEntryVisible: false,
+        archiveEntries: [],
+        fdsVisible: false,
+        inputDeviceVisible: false,
+        movieVisible: false,
+        videoVisible: false,
+        //Which palette the picture is colored with, and the TV knobs for the generated one
+        video: {
+          palette: "Built in",
+          paletteFile: null,
+          hue: 0,
+          saturation: 1,
+          contrast: 1,
+          brightness: 0,
+          gamma: 2.2,
+          //Composite video filter, the NTSC palette's TV knobs apply to it too
+          ntsc: false,
+          artifacts: 0,
+          fringing: 0,
+          bleed: 0,
+          mergeFields: true,
+        },
+        videoKnobs: [
+          { name: "hue", label: "Hue", min: -45, max: 45, step: 1 },
+          { name: "saturation", label: "Saturation", min: 0, max: 2, step: 0.05 },
+          { name: "contrast", label: "Contrast", min: 0.5, max: 1.5, step: 0.05 },
+          { name: "brightness", label: "Brightness", min: -0.5, max: 0.5, step: 0.05 },
+          { name: "gamma", label: "Gamma", min: 1.6, max: 2.8, step: 0.05 },
+        ],
+        ntscKnobs: [
+          { name: "artifacts", label: "Artifacts" },
+          { name: "fringing", label: "Fringing" },
+          { name: "bleed", label: "Color bleed" },
+        ],
+        paused: false,
+        //1 is full speed, fast forward above and slow motion below
+        speed: 1,
+        speeds: [0.25, 0.5, 1, 2, 4],
+        //Input movie state, refreshed while the Movie dialog is open
+        movie: {
+          recording: false,
+          playing: false,
+          frame: 0,
+          length: 0,
+          rerecords: 0,
+        },
+        //What's plugged into the two front ports and the Famicom expansion port
+        inputDevices: ["Auto", "Auto", "Auto"],
+        inputDeviceKinds: ["Auto", "Pad", "Zapper", "ArkanoidPaddle", "PowerPad", "SnesMouse"],
+        //Autofire presses for turboOnFrames out of every turboPeriod frames
+        turboPeriod: 4,
+        turboOnFrames: 2,
+        //Frames to run ahead of the picture to cut input lag, 0 is off
+        runAheadFrames: 0,
+        runAheadSecondInstance: false,
+        //Sides of the loaded disk image, sideCount is 0 for anything that isn't an FDS game
+        fds: {
+          sideCount: 0,
+          side: undefined,
+        },
+        //Filled in when an NSF is loaded, there's no picture so this is what gets shown instead
+        nsf: {
+          visible: false,
+          title: "",
+          artist: "",
+          copyright: "",
+          track: 0,
+          trackCount: 0,
+          trackTitle: "",
+          trackLength: "",
+          expansion: "",
+          elapsed: "0:00",
+        },
       },
       methods: {
         romSelect(e) {
           if (e.target.files.length == 0) return;
+          const fileName = e.target.files[0].name;
           const reader = new FileReader();
           reader.onload = file => {
             const arrayBuf = file.target.result;
             const src = new Uint8Array(arrayBuf);
-            sleep(1000);
-            isEmulateEnable = false;
-            
-            if (!emu.load(src)) {
-             
+            //Zip files with several ROMs in them get a picker, everything else loads straight away
+            let entries = [];
+            try {
+              entries = emu.list_archive_roms(src);
+            } catch (err) {
               this.$notify({
-                title: "Load ROM Error"
+                title: "Load ROM Error",
+                message: String(err),
+                type: "error"
               });
               return;
             }
-           
-            const h = this.$createElement;
+            if (entries.length > 1) {
+              archiveBuf = src;
+              this.archiveEntries = entries;
+              this.archiveEntryVisible = true;
+              return;
+            }
+            this.loadRom(src, null, fileName);
+          };
+         
+          reader.readAsArrayBuffer(e.target.files[0]);
+        },
+        archiveEntrySelect(entryName) {
+          this.archiveEntryVisible = false;
+          this.loadRom(archiveBuf, entryName, entryName);
+          archiveBuf = null;
+        },
+        loadRom(src, entryName, fileName) {
+          sleep(1000);
+          isEmulateEnable = false;
+          
+          try {
+            if (entryName !== null && patchBuf) {
+              emu.load_archive_entry_with_patch(src, entryName, patchBuf);
+            } else if (entryName !== null) {
+              emu.load_archive_entry(src, entryName);
+            } else if (patchBuf) {
+              emu.load_with_patch(src, patchBuf);
+            } else {
+              emu.load(src);
+            }
+          } catch (err) {
             this.$notify({
-              title: "Load ROM Success",
-              message: h("i", { style: "color: teal" }, e.target.files[0].name)
+              title: "Load ROM Error",
+              message: String(err),
+              type: "error"
             });
-           
-            emu.reset();
-            isEmulateEnable = true;
-          };
+            return;
+          }
          
+          //Show what the game database knows about the dump, falling back to the file name
+          const h = this.$createElement;
+          const title = emu.get_game_title() || fileName;
+          const details = [emu.get_game_publisher(), emu.get_game_year()]
+            .filter(x => x !== undefined)
+            .join(", ");
+          this.$notify({
+            title: "Load ROM Success",
+            message: h("div", [
+              h("i", { style: "color: teal" }, title),
+              details ? h("div", details) : null
+            ])
+          });
+         
+          emu.reset();
+          romFileName = fileName;
+          audioQueue = [];
+          audioCtx.resume();
+          this.fds.sideCount = emu.get_fds_side_count();
+          this.fds.side = emu.get_fds_side();
+          this.nsf.visible = emu.is_nsf();
+          if (this.nsf.visible) {
+            this.nsf.title = emu.get_nsf_title();
+            this.nsf.artist = emu.get_nsf_artist();
+            this.nsf.copyright = emu.get_nsf_copyright();
+            this.nsf.trackCount = emu.get_nsf_track_count();
+            this.nsf.expansion = emu.get_nsf_expansion_audio().join(", ");
+            this.updateNsfTrack();
+          }
+          isEmulateEnable = true;
+        },
+        formatTime(seconds) {
+          const s = Math.floor(seconds);
+          return Math.floor(s / 60) + ":" + String(s % 60).padStart(2, "0");
+        },
+        updateNsfTrack() {
+          this.nsf.track = emu.get_nsf_track();
+          this.nsf.trackTitle = emu.get_nsf_track_title() || "";
+          const length = emu.get_nsf_track_length_ms();
+          this.nsf.trackLength = length === undefined ? "" : this.formatTime(length / 1000);
+          this.nsf.elapsed = this.formatTime(0);
+        },
+        selectNsfTrack(track) {
+          if (track < 0 || track >= this.nsf.trackCount) return;
+          emu.set_nsf_track(track);
+          audioQueue = [];
+          this.updateNsfTrack();
+        },
+        fdsBiosSelect(e) {
+          if (e.target.files.length == 0) return;
+          const reader = new FileReader();
+          reader.onload = file => {
+            try {
+              emu.set_fds_bios(new Uint8Array(file.target.result));
+            } catch (err) {
+              this.$notify({
+                title: "Load BIOS Error",
+                message: String(err),
+                type: "error"
+              });
+            }
+          };
+          reader.readAsArrayBuffer(e.target.files[0]);
+        },
+        showFds() {
+          this.fds.side = emu.get_fds_side();
+          this.fdsVisible = true;
+        },
+        insertFdsSide(side) {
+          emu.insert_fds_side(side);
+          //The old side comes out first, check back once the new one has gone in
+          this.fds.side = emu.get_fds_side();
+          setTimeout(() => {
+            this.fds.side = emu.get_fds_side();
+          }, 1000);
+        },
+        ejectFdsDisk() {
+          emu.eject_fds_disk();
+          this.fds.side = emu.get_fds_side();
+        },
+        //Saves are an IPS patch against the disk image, small enough to keep next to it
+        saveFdsDisk() {
+          const save = emu.get_fds_save();
+          const link = document.createElement("a");
+          link.href = URL.createObjectURL(new Blob([save], { type: "application/octet-stream" }));
+          link.download = "disk-save.ips";
+          link.click();
+          URL.revokeObjectURL(link.href);
+        },
+        fdsSaveSelect(e) {
+          if (e.target.files.length == 0) return;
+          const reader = new FileReader();
+          reader.onload = file => {
+            try {
+              emu.load_fds_save(new Uint8Array(file.target.result));
+              this.fds.side = emu.get_fds_side();
+            } catch (err) {
+              this.$notify({
+                title: "Load Save Error",
+                message: String(err),
+                type: "error"
+              });
+            }
+          };
           reader.readAsArrayBuffer(e.target.files[0]);
         },
+        patchSelect(e) {
+          if (e.target.files.length == 0) {
+            patchBuf = null;
+            return;
+          }
+          const reader = new FileReader();
+          reader.onload = file => {
+            patchBuf = new Uint8Array(file.target.result);
+          };
+          reader.readAsArrayBuffer(e.target.files[0]);
+        },
+        updateMovieStatus() {
+          this.movie.recording = emu.is_movie_recording();
+          this.movie.playing = emu.is_movie_playing();
+          this.movie.frame = emu.get_movie_frame();
+          this.movie.length = emu.get_movie_length();
+          this.movie.rerecords = emu.get_movie_rerecord_count();
+        },
+        startMovieRecording(fromPowerOn) {
+          emu.start_movie_recording(fromPowerOn, romFileName);
+          this.updateMovieStatus();
+        },
+        stopMovie() {
+          emu.stop_movie();
+          this.updateMovieStatus();
+        },
+        replayMovie() {
+          emu.replay_movie();
+          this.updateMovieStatus();
+        },
+        recordMovieFromHere() {
+          emu.record_movie_from_here();
+          this.updateMovieStatus();
+        },
+        exportMovie() {
+          let text;
+          try {
+            text = emu.export_movie_fm2();
+          } catch (err) {
+            this.$notify({
+              title: "Save Movie Error",
+              message: String(err),
+              type: "error"
+            });
+            return;
+          }
+          const link = document.createElement("a");
+          link.href = URL.createObjectURL(new Blob([text], { type: "text/plain" }));
+          link.download = romFileName.replace(/\.[^.]*$/, "") + ".fm2";
+          link.click();
+          URL.revokeObjectURL(link.href);
+        },
+        movieSelect(e) {
+          if (e.target.files.length == 0) return;
+          const reader = new FileReader();
+          reader.onload = file => {
+            try {
+              emu.load_movie_fm2(file.target.result);
+            } catch (err) {
+              this.$notify({
+                title: "Load Movie Error",
+                message: String(err),
+                type: "error"
+              });
+              return;
+            }
+            this.updateMovieStatus();
+          };
+          reader.readAsText(e.target.files[0]);
+        },
+        togglePause() {
+          this.paused = !this.paused;
+          isPaused = this.paused;
+        },
+        //One frame at a time while paused
+        frameAdvance() {
+          if (!isEmulateEnable) return;
+          const status = emu.run_frame();
+          queue_audio();
+          draw();
+          if (status == RunStatus.Breakpoint || status == RunStatus.Jam) {
+            this.stopped(status);
+          }
+        },
+        setSpeed(speed) {
+          this.speed = speed;
+          emu.set_speed(speed);
+        },
+        //The core stopped on its own, pause so it can be looked at
+        stopped(status) {
+          this.paused = true;
+          isPaused = true;
+          this.$notify({
+            title: status == RunStatus.Jam ? "CPU jammed" : "Breakpoint",
+            message: "PC $" + emu.get_cpu_pc().toString(16).toUpperCase().padStart(4, "0"),
+            type: "warning"
+          });
+        },
+        setPalette() {
+          const v = this.video;
+          if (v.palette == "NTSC") {
+            emu.generate_palette(v.hue, v.saturation, v.contrast, v.brightness, v.gamma);
+          } else if (v.palette == "File" && v.paletteFile) {
+            emu.load_palette(v.paletteFile);
+          } else {
+            emu.reset_palette();
+          }
+          //Show the change even while paused
+          if (isEmulateEnable) {
+            draw();
+          }
+        },
+        paletteSelect(e) {
+          if (e.target.files.length == 0) return;
+          const reader = new FileReader();
+          reader.onload = file => {
+            const data = new Uint8Array(file.target.result);
+            try {
+              emu.load_palette(data);
+            } catch (err) {
+              this.$notify({
+                title: "Load Palette Error",
+                message: String(err),
+                type: "error"
+              });
+              return;
+            }
+            this.video.paletteFile = data;
+            this.video.palette = "File";
+            this.setPalette();
+          };
+          reader.readAsArrayBuffer(e.target.files[0]);
+        },
+        setNtscFilter() {
+          const v = this.video;
+          emu.set_ntsc_filter(v.ntsc, v.artifacts, v.fringing, v.bleed, v.mergeFields);
+          if (isEmulateEnable) {
+            draw();
+          }
+        },
+        setRunAhead() {
+          emu.set_run_ahead(this.runAheadFrames, this.runAheadSecondInstance);
+        },
+        setTurboRate() {
+          emu.set_turbo_rate(this.turboPeriod, this.turboOnFrames);
+        },
+        setInputDevice(port, kind) {
+          this.$set(this.inputDevices, port, kind);
+          emu.set_input_device(port, InputDeviceKind[kind]);
+          this.reset();
+        },
         reset() {
          
           if (isEmulateEnable) {
@@ -181,6 +597,23 @@ async function main() {
             isEmulateEnable = true;
           }
         },
+        //Both pattern tables with the first background palette, straight from CHR ROM or CHR-RAM
+        drawPatternTables() {
+          const size = 128;
+          for (let table = 0; table < 2; table++) {
+            const view = emu.get_pattern_table(table, 0);
+            const canvas = document.getElementById("pattern-table-" + table);
+            const ctx = canvas.getContext("2d");
+            const imageData = ctx.createImageData(size, size);
+            for (let i = 0; i < size * size; i++) {
+              imageData.data[i * 4 + 0] = view[i * NUM_OF_COLORS + 0];
+              imageData.data[i * 4 + 1] = view[i * NUM_OF_COLORS + 1];
+              imageData.data[i * 4 + 2] = view[i * NUM_OF_COLORS + 2];
+              imageData.data[i * 4 + 3] = 255;
+            }
+            ctx.putImageData(imageData, 0, 0);
+          }
+        },
         press_key(key) {
           console.log("press", key);
           press_key(key);
@@ -195,8 +628,73 @@ async function main() {
           release_key(e.key);
         });
         window.addEventListener("keydown", e => {
+          //Player 2's arrows would scroll the page otherwise
+          if (isEmulateEnable && (e.key in KEY_MAP || e.key == "Backspace")) {
+            e.preventDefault();
+          }
           press_key(e.key);
         });
+        //The mouse over the screen stands in for whichever pointing device is plugged in: it aims the Zapper, turns
+        //the Arkanoid knob (the scroll wheel nudges it too) and moves the SNES mouse. The left button is the
+        //trigger/fire/left click and the right one the SNES mouse's right click
+        const screen = document.getElementById("fb");
+        let mouseX = -1;
+        let mouseY = -1;
+        let paddle = 0;
+        let isLeft = false;
+        let isRight = false;
+        const update_mouse = (dx, dy) => {
+          if (isEmulateEnable) {
+            emu.set_zapper(mouseX, mouseY, isLeft);
+            emu.set_arkanoid_paddle(paddle, isLeft);
+            emu.move_snes_mouse(dx, dy, isLeft, isRight);
+          }
+        };
+        screen.addEventListener("mousemove", e => {
+          const rect = screen.getBoundingClientRect();
+          mouseX = Math.floor((e.clientX - rect.left) * SCREEN_WIDTH / rect.width);
+          mouseY = Math.floor((e.clientY - rect.top) * SCREEN_HEIGHT / rect.height);
+          paddle = Math.min(Math.max(Math.floor(mouseX * 255 / (SCREEN_WIDTH - 1)), 0), 255);
+          update_mouse(
+            Math.round(e.movementX * SCREEN_WIDTH / rect.width),
+            Math.round(e.movementY * SCREEN_HEIGHT / rect.height)
+          );
+        });
+        screen.addEventListener("mouseleave", () => {
+          mouseX = -1;
+          mouseY = -1;
+          update_mouse(0, 0);
+        });
+        screen.addEventListener("wheel", e => {
+          e.preventDefault();
+          paddle = Math.min(Math.max(paddle + Math.sign(e.deltaY) * 4, 0), 255);
+          update_mouse(0, 0);
+        });
+        screen.addEventListener("contextmenu", e => e.preventDefault());
+        screen.addEventListener("mousedown", e => {
+          if (e.button == 2) {
+            isRight = true;
+          } else {
+            isLeft = true;
+          }
+          update_mouse(0, 0);
+        });
+        window.addEventListener("mouseup", e => {
+          if (e.button == 2) {
+            isRight = false;
+          } else {
+            isLeft = false;
+          }
+          update_mouse(0, 0);
+        });
+        setInterval(() => {
+          if (isEmulateEnable && this.nsf.visible) {
+            this.nsf.elapsed = this.formatTime(emu.get_nsf_elapsed_seconds());
+          }
+          if (isEmulateEnable && this.movieVisible) {
+            this.updateMovieStatus();
+          }
+        }, 250);
       }
     });
   }
@@ -0,0 +1,670 @@
+/* The 2A03 audio processing unit */
+//https://wiki.nesdev.com/w/index.php/APU
+//Two pulse channels, a triangle, a noise channel, and a delta modulation channel that plays samples straight out of
+//PRG ROM. Everything runs off the CPU clock, with the frame counter ticking the envelopes, sweeps and length counters
+//along at roughly 240Hz. The mixed output gets averaged down to whatever sample rate the browser asked for.
+use super::region::Region;
+use super::rom::Rom;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+//Over a second of audio, if nobody has taken it by now nobody is listening
+pub const AUDIO_BUFFER_MAX_SIZE: usize = 0x10000;
+
+//Register offsets from $4000
+pub const APU_STATUS_OFFSET: usize = 0x15;
+pub const APU_FRAME_COUNTER_OFFSET: usize = 0x17;
+
+//What gets loaded into a length counter, indexed by the top 5 bits of the write
+//https://wiki.nesdev.com/w/index.php/APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16,
+    28, 32, 30,
+];
+//12.5%, 25%, 50% and 25% negated
+const PULSE_DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+//Roughly a 30Hz high pass at 44.1kHz, the real console has a couple of these to keep the output centred
+const HIGH_PASS_FACTOR: f32 = 0.996;
+
+//Both pulses together, n is the sum of their outputs
+pub(crate) fn pulse_mix(n: u8) -> f32 {
+    if n == 0 {
+        0.0
+    } else {
+        95.52 / (8128.0 / f32::from(n) + 100.0)
+    }
+}
+
+//Volume, either constant or a sawtooth that decays from 15
+//https://wiki.nesdev.com/w/index.php/APU_Envelope
+#[derive(Clone, Debug, Default)]
+struct Envelope {
+    is_start: bool,
+    is_loop: bool,
+    is_constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.is_loop = (data & 0x20) == 0x20;
+        self.is_constant = (data & 0x10) == 0x10;
+        self.volume = data & 0x0f;
+    }
+    //Quarter frame
+    fn clock(&mut self) {
+        if self.is_start {
+            self.is_start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.is_loop {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.is_constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+//Silences a channel once a note has played for long enough
+#[derive(Clone, Debug, Default)]
+struct LengthCounter {
+    is_enabled: bool,
+    is_halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.is_enabled {
+            self.value = LENGTH_TABLE[usize::from(index & 0x1f)];
+        }
+    }
+    fn set_enabled(&mut self, is_enabled: bool) {
+        self.is_enabled = is_enabled;
+        if !is_enabled {
+            self.value = 0;
+        }
+    }
+    //Half frame
+    fn clock(&mut self) {
+        if !self.is_halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/APU_Pulse
+//The MMC5 has two more of these, just without the sweep unit
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Pulse {
+    has_sweep: bool,
+    //The two pulse channels negate their sweeps slightly differently
+    is_second: bool,
+    duty: u8,
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    is_sweep_enabled: bool,
+    is_sweep_negate: bool,
+    is_sweep_reload: bool,
+    sweep_period: u8,
+    sweep_shift: u8,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(is_second: bool) -> Self {
+        Self {
+            has_sweep: true,
+            is_second,
+            ..Pulse::default()
+        }
+    }
+    pub(crate) fn without_sweep() -> Self {
+        Pulse::default()
+    }
+    pub(crate) fn write(&mut self, index: usize, data: u8) {
+        match index {
+            0 => {
+                self.duty = data >> 6;
+                self.length.is_halt = (data & 0x20) == 0x20;
+                self.envelope.write(data);
+            }
+            1 if !self.has_sweep => {}
+            1 => {
+                self.is_sweep_enabled = (data & 0x80) == 0x80;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.is_sweep_negate = (data & 0x08) == 0x08;
+                self.sweep_shift = data & 0x07;
+                self.is_sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | u16::from(data),
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | (u16::from(data & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.step = 0;
+                self.envelope.is_start = true;
+            }
+        }
+    }
+    fn sweep_target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.is_sweep_negate {
+            //Pulse 1 uses one's complement, pulse 2 two's complement
+            let carry = if self.is_second { 0 } else { 1 };
+            self.timer_period.saturating_sub(change + carry)
+        } else {
+            self.timer_period + change
+        }
+    }
+    //The sweep unit mutes the channel whenever the period would go out of range, even if it isn't enabled
+    fn is_muted(&self) -> bool {
+        self.has_sweep && (self.timer_period < 8 || self.sweep_target_period() > 0x07ff)
+    }
+    //Every other CPU cycle
+    pub(crate) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    //Half frame
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.is_sweep_enabled && self.sweep_shift > 0 && !self.is_muted() {
+            self.timer_period = self.sweep_target_period();
+        }
+        if self.sweep_divider == 0 || self.is_sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.is_sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+    //Quarter frame
+    pub(crate) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+    //Half frame
+    pub(crate) fn clock_length(&mut self) {
+        self.length.clock();
+    }
+    pub(crate) fn set_enabled(&mut self, is_enabled: bool) {
+        self.length.set_enabled(is_enabled);
+    }
+    pub(crate) fn is_playing(&self) -> bool {
+        self.length.value > 0
+    }
+    pub(crate) fn output(&self) -> u8 {
+        if self.length.value == 0 || self.is_muted() || PULSE_DUTY_TABLE[usize::from(self.duty)][usize::from(self.step)] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/APU_Triangle
+#[derive(Clone, Debug, Default)]
+struct Triangle {
+    //Doubles as the length counter halt flag
+    is_control: bool,
+    is_linear_reload: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+}
+
+impl Triangle {
+    fn write(&mut self, index: usize, data: u8) {
+        match index {
+            0 => {
+                self.is_control = (data & 0x80) == 0x80;
+                self.length.is_halt = self.is_control;
+                self.linear_reload_value = data & 0x7f;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | u16::from(data),
+            3 => {
+                self.timer_period = (self.timer_period & 0x00ff) | (u16::from(data & 0x07) << 8);
+                self.length.load(data >> 3);
+                self.is_linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+    //Every CPU cycle, the triangle runs twice as fast as the pulses
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.value > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1f;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+    //Quarter frame
+    fn clock_linear_counter(&mut self) {
+        if self.is_linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.is_control {
+            self.is_linear_reload = false;
+        }
+    }
+    fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[usize::from(self.step)]
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/APU_Noise
+#[derive(Clone, Debug)]
+struct Noise {
+    //Short mode, taps bit 6 instead of bit 1 for a metallic 93 step loop
+    is_short_mode: bool,
+    period_index: u8,
+    timer: u16,
+    shift_register: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            is_short_mode: false,
+            period_index: 0,
+            timer: 0,
+            //Loaded with 1 at power on, it would never make a sound from 0
+            shift_register: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+}
+
+impl Noise {
+    fn write(&mut self, index: usize, data: u8) {
+        match index {
+            0 => {
+                self.length.is_halt = (data & 0x20) == 0x20;
+                self.envelope.write(data);
+            }
+            2 => {
+                self.is_short_mode = (data & 0x80) == 0x80;
+                self.period_index = data & 0x0f;
+            }
+            3 => {
+                self.length.load(data >> 3);
+                self.envelope.is_start = true;
+            }
+            _ => {}
+        }
+    }
+    //Every CPU cycle, the period table is in CPU cycles
+    fn clock_timer(&mut self, region: Region) {
+        if self.timer == 0 {
+            self.timer = region.noise_period_table()[usize::from(self.period_index)] - 1;
+            let tap = if self.is_short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register & 0x01) ^ ((self.shift_register >> tap) & 0x01);
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.length.value == 0 || (self.shift_register & 0x01) == 0x01 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/APU_DMC
+#[derive(Clone, Debug, Default)]
+struct Dmc {
+    is_irq_enable: bool,
+    is_irq: bool,
+    is_loop: bool,
+    rate_index: u8,
+    timer: u16,
+    output_level: u8,
+    sample_addr: u16,
+    sample_length: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    //Sample byte fetched from the cartridge and waiting to be played
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    is_silence: bool,
+}
+
+impl Dmc {
+    fn write(&mut self, index: usize, data: u8) {
+        match index {
+            0 => {
+                self.is_irq_enable = (data & 0x80) == 0x80;
+                self.is_loop = (data & 0x40) == 0x40;
+                self.rate_index = data & 0x0f;
+                if !self.is_irq_enable {
+                    self.is_irq = false;
+                }
+            }
+            1 => self.output_level = data & 0x7f,
+            2 => self.sample_addr = 0xc000 | (u16::from(data) << 6),
+            _ => self.sample_length = (u16::from(data) << 4) | 0x0001,
+        }
+    }
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+    fn set_enabled(&mut self, is_enabled: bool) {
+        if !is_enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+    //The memory reader, grabs the next sample byte as soon as the buffer is free
+    fn fetch(&mut self, rom: &mut Rom) {
+        if self.sample_buffer.is_some() || self.bytes_remaining == 0 {
+            return;
+        }
+        self.sample_buffer = Some(rom.read_u8(self.current_addr, false));
+        self.current_addr = if self.current_addr == 0xffff {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.is_loop {
+                self.restart();
+            } else if self.is_irq_enable {
+                self.is_irq = true;
+            }
+        }
+    }
+    //Every CPU cycle, the rate table is in CPU cycles
+    fn clock_timer(&mut self, region: Region) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = region.dmc_rate_table()[usize::from(self.rate_index)] - 1;
+        if !self.is_silence {
+            if (self.shift_register & 0x01) == 0x01 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        if self.bits_remaining > 0 {
+            self.bits_remaining -= 1;
+        }
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.is_silence = false;
+                    self.shift_register = data;
+                }
+                None => self.is_silence = true,
+            }
+        }
+    }
+    fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+//Drives the envelopes, sweeps and length counters, and can interrupt the CPU in 4 step mode
+//https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+#[derive(Clone, Debug, Default)]
+struct FrameCounter {
+    is_five_step: bool,
+    is_irq_inhibit: bool,
+    is_irq: bool,
+    cycle: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    //The pulses, and the frame counter's notion of a half cycle, tick every other CPU cycle
+    is_odd_cycle: bool,
+    //The mixer isn't linear, these are the lookup tables from the wiki
+    //https://wiki.nesdev.com/w/index.php/APU_Mixer
+    pulse_table: Vec<f32>,
+    tnd_table: Vec<f32>,
+    //Downsampling to the output rate
+    pub sample_rate: u32,
+    sample_cycle: f64,
+    sample_sum: f32,
+    sample_count: u32,
+    high_pass_in: f32,
+    high_pass_out: f32,
+    //Finished samples, waiting for the browser to take them
+    pub samples: Vec<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self {
+            pulse1: Pulse::new(false),
+            pulse2: Pulse::new(true),
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            frame_counter: FrameCounter::default(),
+            is_odd_cycle: false,
+            pulse_table: (0..31u8).map(pulse_mix).collect(),
+            tnd_table: (0..203)
+                .map(|n| if n == 0 { 0.0 } else { 163.67 / (24329.0 / (n as f32) + 100.0) })
+                .collect(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_cycle: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            high_pass_in: 0.0,
+            high_pass_out: 0.0,
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl Apu {
+    //Memory the mixer tables and waiting samples take up
+    pub fn heap_bytes(&self) -> usize {
+        (self.pulse_table.capacity() + self.tnd_table.capacity() + self.samples.capacity()) * std::mem::size_of::<f32>()
+    }
+    //Back to power on, but keep the output settings
+    pub fn reset(&mut self) {
+        *self = Apu {
+            sample_rate: self.sample_rate,
+            ..Apu::default()
+        };
+    }
+    //$4000-$4017, the caller sorts out which ones actually belong to us
+    pub fn write_register(&mut self, index: usize, data: u8) {
+        match index {
+            0x00..=0x03 => self.pulse1.write(index, data),
+            0x04..=0x07 => self.pulse2.write(index - 0x04, data),
+            0x08..=0x0b => self.triangle.write(index - 0x08, data),
+            0x0c..=0x0f => self.noise.write(index - 0x0c, data),
+            0x10..=0x13 => self.dmc.write(index - 0x10, data),
+            APU_STATUS_OFFSET => {
+                self.pulse1.length.set_enabled((data & 0x01) == 0x01);
+                self.pulse2.length.set_enabled((data & 0x02) == 0x02);
+                self.triangle.length.set_enabled((data & 0x04) == 0x04);
+                self.noise.length.set_enabled((data & 0x08) == 0x08);
+                self.dmc.set_enabled((data & 0x10) == 0x10);
+                self.dmc.is_irq = false;
+            }
+            APU_FRAME_COUNTER_OFFSET => {
+                self.frame_counter.is_five_step = (data & 0x80) == 0x80;
+                self.frame_counter.is_irq_inhibit = (data & 0x40) == 0x40;
+                if self.frame_counter.is_irq_inhibit {
+                    self.frame_counter.is_irq = false;
+                }
+                self.frame_counter.cycle = 0;
+                //5 step mode clocks everything straight away
+                if self.frame_counter.is_five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+    //$4015, reading acknowledges the frame interrupt
+    pub fn read_status(&mut self) -> u8 {
+        let mut data = 0;
+        if self.pulse1.length.value > 0 {
+            data |= 0x01;
+        }
+        if self.pulse2.length.value > 0 {
+            data |= 0x02;
+        }
+        if self.triangle.length.value > 0 {
+            data |= 0x04;
+        }
+        if self.noise.length.value > 0 {
+            data |= 0x08;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            data |= 0x10;
+        }
+        if self.frame_counter.is_irq {
+            data |= 0x40;
+        }
+        if self.dmc.is_irq {
+            data |= 0x80;
+        }
+        self.frame_counter.is_irq = false;
+        data
+    }
+    pub fn is_irq(&self) -> bool {
+        self.frame_counter.is_irq || self.dmc.is_irq
+    }
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+    fn clock_frame_counter(&mut self, region: Region) {
+        let steps = region.frame_counter_steps();
+        self.frame_counter.cycle += 1;
+        let cycle = self.frame_counter.cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if cycle == steps[1] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        } else if !self.frame_counter.is_five_step && cycle == steps[3] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            if !self.frame_counter.is_irq_inhibit {
+                self.frame_counter.is_irq = true;
+            }
+            self.frame_counter.cycle = 0;
+        } else if self.frame_counter.is_five_step && cycle == steps[4] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            self.frame_counter.cycle = 0;
+        }
+    }
+    //The 2A03's own output, somewhere between 0 and 1
+    pub fn mix(&self) -> f32 {
+        let pulse = usize::from(self.pulse1.output() + self.pulse2.output());
+        let tnd = 3 * usize::from(self.triangle.output())
+            + 2 * usize::from(self.noise.output())
+            + usize::from(self.dmc.output());
+        self.pulse_table[pulse] + self.tnd_table[tnd]
+    }
+    //Run for however many cycles the last CPU instruction took, along with any sound chip on the cartridge
+    pub fn step(&mut self, cpu_cyc: usize, region: Region, rom: &mut Rom) {
+        let cycles_per_sample = f64::from(region.cpu_freq()) / f64::from(self.sample_rate);
+        for _ in 0..cpu_cyc {
+            self.clock_frame_counter(region);
+            self.triangle.clock_timer();
+            self.noise.clock_timer(region);
+            self.dmc.clock_timer(region);
+            self.dmc.fetch(rom);
+            if self.is_odd_cycle {
+                self.pulse1.clock_timer();
+                self.pulse2.clock_timer();
+            }
+            self.is_odd_cycle = !self.is_odd_cycle;
+
+            rom.clock_expansion_audio();
+
+            self.sample_sum += self.mix() + rom.expansion_audio_output();
+            self.sample_count += 1;
+            self.sample_cycle += 1.0;
+            if self.sample_cycle >= cycles_per_sample {
+                self.sample_cycle -= cycles_per_sample;
+                let sample = self.sample_sum / (self.sample_count as f32);
+                self.sample_sum = 0.0;
+                self.sample_count = 0;
+                self.push_sample(sample);
+            }
+        }
+    }
+    fn push_sample(&mut self, sample: f32) {
+        self.high_pass_out = HIGH_PASS_FACTOR * self.high_pass_out + sample - self.high_pass_in;
+        self.high_pass_in = sample;
+        if self.samples.len() < AUDIO_BUFFER_MAX_SIZE {
+            self.samples.push(self.high_pass_out);
+        }
+    }
+}
@@ -0,0 +1,561 @@
+/* Pulling ROMs out of .zip and .gz archives */
+//ROM sets are almost always distributed zipped, so rather than making people unzip them first we look inside.
+//Only what ROM archives actually use is supported: zip entries that are stored or deflated, and single file gzip.
+//https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+//https://www.ietf.org/rfc/rfc1952.txt
+use std::borrow::Cow;
+use std::fmt;
+
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, TINFLStatus};
+
+use super::rom::ROM_FILE_MAX_SIZE;
+
+//What we'll consider loading out of an archive
+pub const ROM_EXTENSIONS: &[&str] = &["nes", "fds", "nsf", "nsfe", "unf", "unif"];
+pub const PATCH_EXTENSIONS: &[&str] = &["ips", "ups", "bps"];
+
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP_END_OF_CENTRAL_DIR_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP_LOCAL_HEADER_SIZE: usize = 30;
+const ZIP_CENTRAL_HEADER_SIZE: usize = 46;
+const ZIP_END_OF_CENTRAL_DIR_SIZE: usize = 22;
+const ZIP_MAX_COMMENT_SIZE: usize = 0xffff;
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+const ZIP_FLAG_ENCRYPTED: u16 = 0x0001;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const GZIP_METHOD_DEFLATE: u8 = 8;
+const GZIP_HEADER_SIZE: usize = 10;
+const GZIP_TRAILER_SIZE: usize = 8;
+const GZIP_FLAG_HCRC: u8 = 0x02;
+const GZIP_FLAG_EXTRA: u8 = 0x04;
+const GZIP_FLAG_NAME: u8 = 0x08;
+const GZIP_FLAG_COMMENT: u8 = 0x10;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum ArchiveFormat {
+    Zip,
+    Gzip,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ArchiveError {
+    //Headers pointing off the end of the file and that sort of thing
+    Corrupt(ArchiveFormat),
+    //Zip64, encryption, and compression methods other than deflate
+    Unsupported(ArchiveFormat, &'static str),
+    ChecksumMismatch(String),
+    //Nothing with an extension we can load
+    NoRomInArchive,
+    //No ROM, but patches, most likely picked instead of the ROM they go with
+    OnlyPatches(Vec<String>),
+    //More than one thing we could load and the caller didn't say which
+    MultipleRoms(Vec<String>),
+    EntryNotFound(String),
+    //Unpacks to more than any ROM could be, most likely a zip bomb
+    TooLarge(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Corrupt(format) => write!(f, "{:?} archive is corrupt", format),
+            ArchiveError::Unsupported(format, what) => {
+                write!(f, "{:?} archive uses {}, which is not supported", format, what)
+            }
+            ArchiveError::ChecksumMismatch(name) => {
+                write!(f, "{} failed its CRC check, the archive is damaged", name)
+            }
+            ArchiveError::NoRomInArchive => write!(f, "Archive doesn't contain a ROM"),
+            ArchiveError::OnlyPatches(names) => write!(
+                f,
+                "Archive only contains patches ({}), load the ROM and give it one of these as its patch",
+                names.join(", ")
+            ),
+            ArchiveError::MultipleRoms(names) => write!(
+                f,
+                "Archive contains {} ROMs, pick one: {}",
+                names.len(),
+                names.join(", ")
+            ),
+            ArchiveError::EntryNotFound(name) => write!(f, "{} is not in the archive", name),
+            ArchiveError::TooLarge(name) => write!(
+                f,
+                "{} unpacks to more than the {} KiB the biggest cartridge needs",
+                name,
+                ROM_FILE_MAX_SIZE / 1024
+            ),
+        }
+    }
+}
+
+//One file inside an archive, with where to find its (possibly compressed) bytes
+#[derive(Clone, Debug)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: usize,
+    method: u16,
+    crc32: u32,
+    data_start: usize,
+    data_end: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from(bytes[0]) | (u16::from(bytes[1]) << 8))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(
+        u32::from(bytes[0])
+            | (u32::from(bytes[1]) << 8)
+            | (u32::from(bytes[2]) << 16)
+            | (u32::from(bytes[3]) << 24),
+    )
+}
+
+pub fn detect(data: &[u8]) -> Option<ArchiveFormat> {
+    if read_u32(data, 0) == Some(ZIP_LOCAL_HEADER_SIGNATURE)
+        || read_u32(data, 0) == Some(ZIP_END_OF_CENTRAL_DIR_SIGNATURE)
+    {
+        Some(ArchiveFormat::Zip)
+    } else if data.starts_with(GZIP_MAGIC) {
+        Some(ArchiveFormat::Gzip)
+    } else {
+        None
+    }
+}
+
+//Case insensitive extension check, also used to decide what to show in the picker
+pub fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, ext)) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+//The central directory at the end of a zip is the real list of files, the local headers can't be trusted
+//for sizes when the zip was streamed
+fn zip_entries(data: &[u8]) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    let corrupt = ArchiveError::Corrupt(ArchiveFormat::Zip);
+    if data.len() < ZIP_END_OF_CENTRAL_DIR_SIZE {
+        return Err(corrupt);
+    }
+    //The end of central directory record is followed by a comment of up to 64k, so search backwards for it
+    let search_end = data.len() - ZIP_END_OF_CENTRAL_DIR_SIZE;
+    let search_start = search_end.saturating_sub(ZIP_MAX_COMMENT_SIZE);
+    let eocd = (search_start..=search_end)
+        .rev()
+        .find(|offset| read_u32(data, *offset) == Some(ZIP_END_OF_CENTRAL_DIR_SIGNATURE))
+        .ok_or_else(|| corrupt.clone())?;
+    let num_entries = usize::from(read_u16(data, eocd + 10).ok_or_else(|| corrupt.clone())?);
+    let central_dir_offset = read_u32(data, eocd + 16).ok_or_else(|| corrupt.clone())?;
+    if central_dir_offset == 0xffff_ffff || num_entries == 0xffff {
+        return Err(ArchiveError::Unsupported(ArchiveFormat::Zip, "Zip64"));
+    }
+
+    let mut entries = Vec::with_capacity(num_entries);
+    let mut offset = central_dir_offset as usize;
+    for _ in 0..num_entries {
+        if read_u32(data, offset) != Some(ZIP_CENTRAL_HEADER_SIGNATURE) {
+            return Err(corrupt);
+        }
+        //Offsets come straight out of the file, on wasm32 adding to them can overflow
+        let at = |base: usize, relative: usize| base.checked_add(relative).ok_or_else(|| corrupt.clone());
+        let field = |relative: usize| read_u16(data, at(offset, relative)?).ok_or_else(|| corrupt.clone());
+        let field32 = |relative: usize| read_u32(data, at(offset, relative)?).ok_or_else(|| corrupt.clone());
+        let flags = field(8)?;
+        let method = field(10)?;
+        let crc32 = field32(16)?;
+        let compressed_size = field32(20)? as usize;
+        let size = field32(24)? as usize;
+        let name_len = usize::from(field(28)?);
+        let extra_len = usize::from(field(30)?);
+        let comment_len = usize::from(field(32)?);
+        let local_header_offset = field32(42)? as usize;
+        let name_start = at(offset, ZIP_CENTRAL_HEADER_SIZE)?;
+        let name = data
+            .get(name_start..at(name_start, name_len)?)
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+            .ok_or_else(|| corrupt.clone())?;
+        offset = at(name_start, name_len + extra_len + comment_len)?;
+
+        if (flags & ZIP_FLAG_ENCRYPTED) == ZIP_FLAG_ENCRYPTED {
+            return Err(ArchiveError::Unsupported(ArchiveFormat::Zip, "encryption"));
+        }
+        //Directories
+        if name.ends_with('/') {
+            continue;
+        }
+        //The local header has its own name and extra field lengths which can differ from the central directory's
+        if read_u32(data, local_header_offset) != Some(ZIP_LOCAL_HEADER_SIGNATURE) {
+            return Err(corrupt);
+        }
+        let local_name_len =
+            usize::from(read_u16(data, at(local_header_offset, 26)?).ok_or_else(|| corrupt.clone())?);
+        let local_extra_len =
+            usize::from(read_u16(data, at(local_header_offset, 28)?).ok_or_else(|| corrupt.clone())?);
+        let data_start = at(local_header_offset, ZIP_LOCAL_HEADER_SIZE + local_name_len + local_extra_len)?;
+        let data_end = at(data_start, compressed_size)?;
+        if data_end > data.len() {
+            return Err(corrupt);
+        }
+        entries.push(ArchiveEntry {
+            name,
+            size,
+            method,
+            crc32,
+            data_start,
+            data_end,
+        });
+    }
+    Ok(entries)
+}
+
+//Gzip holds exactly one file, named in the header if we're lucky
+fn gzip_entries(data: &[u8]) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    let corrupt = ArchiveError::Corrupt(ArchiveFormat::Gzip);
+    if data.len() < GZIP_HEADER_SIZE + GZIP_TRAILER_SIZE {
+        return Err(corrupt);
+    }
+    if data[2] != GZIP_METHOD_DEFLATE {
+        return Err(ArchiveError::Unsupported(ArchiveFormat::Gzip, "a compression method other than deflate"));
+    }
+    let flags = data[3];
+    let mut offset = GZIP_HEADER_SIZE;
+    if (flags & GZIP_FLAG_EXTRA) == GZIP_FLAG_EXTRA {
+        offset += 2 + usize::from(read_u16(data, offset).ok_or_else(|| corrupt.clone())?);
+    }
+    //Zero terminated strings
+    let read_string = |offset: &mut usize| -> Result<String, ArchiveError> {
+        let len = data
+            .get(*offset..)
+            .and_then(|rest| rest.iter().position(|b| *b == 0))
+            .ok_or_else(|| corrupt.clone())?;
+        let s = String::from_utf8_lossy(&data[*offset..*offset + len]).into_owned();
+        *offset += len + 1;
+        Ok(s)
+    };
+    let name = if (flags & GZIP_FLAG_NAME) == GZIP_FLAG_NAME {
+        read_string(&mut offset)?
+    } else {
+        //Unnamed, assume it's a plain .nes.gz
+        String::from("rom.nes")
+    };
+    if (flags & GZIP_FLAG_COMMENT) == GZIP_FLAG_COMMENT {
+        read_string(&mut offset)?;
+    }
+    if (flags & GZIP_FLAG_HCRC) == GZIP_FLAG_HCRC {
+        offset += 2;
+    }
+    let data_end = data.len() - GZIP_TRAILER_SIZE;
+    if offset > data_end {
+        return Err(corrupt);
+    }
+    Ok(vec![ArchiveEntry {
+        name,
+        size: read_u32(data, data_end + 4).ok_or_else(|| corrupt.clone())? as usize,
+        method: ZIP_METHOD_DEFLATE,
+        crc32: read_u32(data, data_end).ok_or(corrupt)?,
+        data_start: offset,
+        data_end,
+    }])
+}
+
+//Everything in the archive, whatever it is
+pub fn entries(data: &[u8]) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    match detect(data) {
+        Some(ArchiveFormat::Zip) => zip_entries(data),
+        Some(ArchiveFormat::Gzip) => gzip_entries(data),
+        None => Ok(Vec::new()),
+    }
+}
+
+//Names of the files in the archive with one of the given extensions, for the caller to choose from
+pub fn list(data: &[u8], extensions: &[&str]) -> Result<Vec<String>, ArchiveError> {
+    Ok(entries(data)?
+        .into_iter()
+        .filter(|entry| has_extension(&entry.name, extensions))
+        .map(|entry| entry.name)
+        .collect())
+}
+
+fn decompress(data: &[u8], entry: &ArchiveEntry, format: ArchiveFormat) -> Result<Vec<u8>, ArchiveError> {
+    let compressed = &data[entry.data_start..entry.data_end];
+    let too_large = || ArchiveError::TooLarge(entry.name.clone());
+    let contents = match entry.method {
+        ZIP_METHOD_STORED if compressed.len() > ROM_FILE_MAX_SIZE => return Err(too_large()),
+        ZIP_METHOD_STORED => compressed.to_vec(),
+        //Stops inflating once it's bigger than any ROM rather than filling memory with a zip bomb
+        ZIP_METHOD_DEFLATE => {
+            decompress_to_vec_with_limit(compressed, ROM_FILE_MAX_SIZE).map_err(|status| match status {
+                TINFLStatus::HasMoreOutput => too_large(),
+                _ => ArchiveError::Corrupt(format),
+            })?
+        }
+        _ => return Err(ArchiveError::Unsupported(format, "a compression method other than deflate")),
+    };
+    if crc32fast::hash(&contents) != entry.crc32 {
+        return Err(ArchiveError::ChecksumMismatch(entry.name.clone()));
+    }
+    Ok(contents)
+}
+
+//Pull a file out of an archive. With no name the archive has to contain exactly one file with one of the
+//given extensions. Anything that isn't an archive is handed straight back
+pub fn extract<'a>(
+    data: &'a [u8],
+    name: Option<&str>,
+    extensions: &[&str],
+) -> Result<Cow<'a, [u8]>, ArchiveError> {
+    let format = match detect(data) {
+        Some(format) => format,
+        None => return Ok(Cow::Borrowed(data)),
+    };
+    let entries = entries(data)?;
+    let mut candidates: Vec<ArchiveEntry> = entries
+        .iter()
+        .filter(|entry| has_extension(&entry.name, extensions))
+        .cloned()
+        .collect();
+    let entry = match name {
+        Some(name) => candidates
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| ArchiveError::EntryNotFound(name.to_string()))?,
+        None => match candidates.len() {
+            0 => {
+                let patches: Vec<String> = entries
+                    .into_iter()
+                    .filter(|entry| has_extension(&entry.name, PATCH_EXTENSIONS))
+                    .map(|entry| entry.name)
+                    .collect();
+                return Err(if patches.is_empty() {
+                    ArchiveError::NoRomInArchive
+                } else {
+                    ArchiveError::OnlyPatches(patches)
+                });
+            }
+            1 => candidates.remove(0),
+            _ => {
+                return Err(ArchiveError::MultipleRoms(
+                    candidates.into_iter().map(|entry| entry.name).collect(),
+                ))
+            }
+        },
+    };
+    Ok(Cow::Owned(decompress(data, &entry, format)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec;
+
+    fn push_u16(out: &mut Vec<u8>, value: u16) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    //A zip of (name, method, contents), local headers then the central directory
+    fn zip(files: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central_dir = Vec::new();
+        for (name, method, contents) in files {
+            let compressed = match *method {
+                ZIP_METHOD_DEFLATE => compress_to_vec(contents, 6),
+                _ => contents.to_vec(),
+            };
+            let crc32 = crc32fast::hash(contents);
+            let local_header_offset = out.len() as u32;
+            push_u32(&mut out, ZIP_LOCAL_HEADER_SIGNATURE);
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            push_u16(&mut out, *method);
+            out.extend_from_slice(&[0; 4]);
+            push_u32(&mut out, crc32);
+            push_u32(&mut out, compressed.len() as u32);
+            push_u32(&mut out, contents.len() as u32);
+            push_u16(&mut out, name.len() as u16);
+            push_u16(&mut out, 0);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&compressed);
+
+            push_u32(&mut central_dir, ZIP_CENTRAL_HEADER_SIGNATURE);
+            central_dir.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            push_u16(&mut central_dir, *method);
+            central_dir.extend_from_slice(&[0; 4]);
+            push_u32(&mut central_dir, crc32);
+            push_u32(&mut central_dir, compressed.len() as u32);
+            push_u32(&mut central_dir, contents.len() as u32);
+            push_u16(&mut central_dir, name.len() as u16);
+            central_dir.extend_from_slice(&[0; 12]);
+            push_u32(&mut central_dir, local_header_offset);
+            central_dir.extend_from_slice(name.as_bytes());
+        }
+        let central_dir_offset = out.len() as u32;
+        let central_dir_size = central_dir.len() as u32;
+        out.extend(central_dir);
+        push_u32(&mut out, ZIP_END_OF_CENTRAL_DIR_SIGNATURE);
+        out.extend_from_slice(&[0; 4]);
+        push_u16(&mut out, files.len() as u16);
+        push_u16(&mut out, files.len() as u16);
+        push_u32(&mut out, central_dir_size);
+        push_u32(&mut out, central_dir_offset);
+        push_u16(&mut out, 0);
+        out
+    }
+
+    fn gzip(name: Option<&str>, contents: &[u8]) -> Vec<u8> {
+        let flags = if name.is_some() { GZIP_FLAG_NAME } else { 0 };
+        let mut out = vec![0x1f, 0x8b, GZIP_METHOD_DEFLATE, flags, 0, 0, 0, 0, 0, 0xff];
+        if let Some(name) = name {
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+        }
+        out.extend(compress_to_vec(contents, 6));
+        push_u32(&mut out, crc32fast::hash(contents));
+        push_u32(&mut out, contents.len() as u32);
+        out
+    }
+
+    //Where the first central directory header starts
+    fn central_dir(zip: &[u8]) -> usize {
+        let eocd = zip.len() - ZIP_END_OF_CENTRAL_DIR_SIZE;
+        read_u32(zip, eocd + 16).unwrap() as usize
+    }
+
+    fn rom() -> Vec<u8> {
+        (0..0x6000).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn not_an_archive_is_passed_through() {
+        let rom = rom();
+        assert_eq!(detect(&rom), None);
+        assert!(matches!(extract(&rom, None, ROM_EXTENSIONS), Ok(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn zip_extracts_stored_and_deflated() {
+        let rom = rom();
+        for method in [ZIP_METHOD_STORED, ZIP_METHOD_DEFLATE] {
+            let data = zip(&[("readme.txt", method, b"hi"), ("Game (U).NES", method, &rom)]);
+            assert_eq!(detect(&data), Some(ArchiveFormat::Zip));
+            assert_eq!(list(&data, ROM_EXTENSIONS), Ok(vec![String::from("Game (U).NES")]));
+            assert_eq!(extract(&data, None, ROM_EXTENSIONS).unwrap().as_ref(), &rom[..]);
+        }
+    }
+
+    #[test]
+    fn zip_picks_by_name() {
+        let rom = rom();
+        let data = zip(&[("a.nes", ZIP_METHOD_STORED, b"a"), ("b.nes", ZIP_METHOD_DEFLATE, &rom)]);
+        assert_eq!(
+            extract(&data, None, ROM_EXTENSIONS),
+            Err(ArchiveError::MultipleRoms(vec![String::from("a.nes"), String::from("b.nes")]))
+        );
+        assert_eq!(extract(&data, Some("b.nes"), ROM_EXTENSIONS).unwrap().as_ref(), &rom[..]);
+        assert_eq!(
+            extract(&data, Some("c.nes"), ROM_EXTENSIONS),
+            Err(ArchiveError::EntryNotFound(String::from("c.nes")))
+        );
+    }
+
+    #[test]
+    fn zip_with_only_a_patch() {
+        let data = zip(&[("hack.ips", ZIP_METHOD_STORED, b"PATCHEOF")]);
+        assert_eq!(
+            extract(&data, None, ROM_EXTENSIONS),
+            Err(ArchiveError::OnlyPatches(vec![String::from("hack.ips")]))
+        );
+        assert_eq!(extract(&data, None, PATCH_EXTENSIONS).unwrap().as_ref(), b"PATCHEOF");
+        let data = zip(&[("readme.txt", ZIP_METHOD_STORED, b"hi")]);
+        assert_eq!(extract(&data, None, ROM_EXTENSIONS), Err(ArchiveError::NoRomInArchive));
+    }
+
+    #[test]
+    fn zip_checksum_mismatch() {
+        let mut data = zip(&[("game.nes", ZIP_METHOD_STORED, &rom())]);
+        data[ZIP_LOCAL_HEADER_SIZE + "game.nes".len()] ^= 0xff;
+        assert_eq!(
+            extract(&data, None, ROM_EXTENSIONS),
+            Err(ArchiveError::ChecksumMismatch(String::from("game.nes")))
+        );
+    }
+
+    #[test]
+    fn zip_truncated() {
+        let data = zip(&[("game.nes", ZIP_METHOD_STORED, &rom())]);
+        let corrupt = Err(ArchiveError::Corrupt(ArchiveFormat::Zip));
+        assert_eq!(extract(&data[..ZIP_LOCAL_HEADER_SIZE], None, ROM_EXTENSIONS), corrupt);
+        //Cut out the middle of the data, so everything after it points too far
+        let cut = [&data[..0x100], &data[0x200..]].concat();
+        assert_eq!(extract(&cut, None, ROM_EXTENSIONS), corrupt);
+    }
+
+    #[test]
+    fn zip_offsets_off_the_end() {
+        let data = zip(&[("game.nes", ZIP_METHOD_STORED, &rom())]);
+        let corrupt = Err(ArchiveError::Corrupt(ArchiveFormat::Zip));
+        let central_dir = central_dir(&data);
+        //Local header offset, compressed size and central directory offset all as far out as they go
+        for offset in [central_dir + 42, central_dir + 20, data.len() - 6] {
+            let mut data = data.clone();
+            data[offset..offset + 4].copy_from_slice(&[0xfe, 0xff, 0xff, 0xff]);
+            assert_eq!(extract(&data, None, ROM_EXTENSIONS), corrupt);
+        }
+        //And a name longer than what's left
+        let mut data = data;
+        data[central_dir + 28..central_dir + 30].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(extract(&data, None, ROM_EXTENSIONS), corrupt);
+    }
+
+    #[test]
+    fn zip_bigger_than_any_rom() {
+        let bomb = vec![0; ROM_FILE_MAX_SIZE + 1];
+        for method in [ZIP_METHOD_STORED, ZIP_METHOD_DEFLATE] {
+            let data = zip(&[("bomb.nes", method, &bomb)]);
+            assert_eq!(
+                extract(&data, None, ROM_EXTENSIONS),
+                Err(ArchiveError::TooLarge(String::from("bomb.nes")))
+            );
+        }
+    }
+
+    #[test]
+    fn gzip_extracts() {
+        let rom = rom();
+        let data = gzip(Some("game.fds"), &rom);
+        assert_eq!(detect(&data), Some(ArchiveFormat::Gzip));
+        assert_eq!(list(&data, ROM_EXTENSIONS), Ok(vec![String::from("game.fds")]));
+        assert_eq!(extract(&data, None, ROM_EXTENSIONS).unwrap().as_ref(), &rom[..]);
+        //No name in the header, taken to be a .nes
+        let data = gzip(None, &rom);
+        assert_eq!(extract(&data, None, ROM_EXTENSIONS).unwrap().as_ref(), &rom[..]);
+    }
+
+    #[test]
+    fn gzip_truncated() {
+        let data = gzip(Some("game.nes"), &rom());
+        let corrupt = Err(ArchiveError::Corrupt(ArchiveFormat::Gzip));
+        assert_eq!(extract(&data[..GZIP_HEADER_SIZE], None, ROM_EXTENSIONS), corrupt);
+        //The name runs off the end without its terminating zero
+        assert_eq!(extract(&data[..GZIP_HEADER_SIZE + 12], None, ROM_EXTENSIONS), corrupt);
+        assert_eq!(extract(&data[..data.len() / 2], None, ROM_EXTENSIONS), corrupt);
+    }
+
+    #[test]
+    fn gzip_bigger_than_any_rom() {
+        let data = gzip(Some("bomb.nes"), &vec![0; ROM_FILE_MAX_SIZE + 1]);
+        assert_eq!(
+            extract(&data, None, ROM_EXTENSIONS),
+            Err(ArchiveError::TooLarge(String::from("bomb.nes")))
+        );
+    }
+}
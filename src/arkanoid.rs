@@ -0,0 +1,69 @@
+/* Arkanoid "Vaus" paddle */
+//https://wiki.nesdev.com/w/index.php/Arkanoid_controller
+//A knob on a potentiometer and a fire button. Strobing latches the knob's position as an 8 bit value, which is then
+//shifted out MSB first and inverted. The NES version sends it on D4 with the button on D3 of its port, the Famicom
+//version sits on the expansion port with the button on D1 of $4016 and the value on D1 of $4017.
+
+//The potentiometer never reaches either end of the byte, games calibrate against roughly this range
+const ARKANOID_DIAL_MIN: u8 = 0x62;
+const ARKANOID_DIAL_MAX: u8 = 0xf2;
+
+#[derive(Clone, Debug)]
+pub struct ArkanoidPaddle {
+    dial: u8,
+    is_button: bool,
+    shift_reg: u8,
+    strobe_enable: bool,
+}
+
+impl Default for ArkanoidPaddle {
+    fn default() -> Self {
+        Self {
+            dial: ARKANOID_DIAL_MIN,
+            is_button: false,
+            shift_reg: 0,
+            strobe_enable: false,
+        }
+    }
+}
+
+impl ArkanoidPaddle {
+    pub fn reset(&mut self) {
+        self.shift_reg = 0;
+        self.strobe_enable = false;
+    }
+    //How far the knob is turned, 0 all the way left to 255 all the way right
+    pub fn set_position(&mut self, position: u8) {
+        let range = u16::from(ARKANOID_DIAL_MAX - ARKANOID_DIAL_MIN);
+        self.dial = ARKANOID_DIAL_MIN + (u16::from(position) * range / 0xff) as u8;
+    }
+    pub fn set_button(&mut self, is_pressed: bool) {
+        self.is_button = is_pressed;
+    }
+    //The knob as the game reads it, movies keep this rather than the position it was set from
+    pub fn dial(&self) -> u8 {
+        self.dial
+    }
+    pub fn set_dial(&mut self, dial: u8) {
+        self.dial = dial;
+    }
+    pub fn is_button(&self) -> bool {
+        self.is_button
+    }
+    pub fn write_strobe(&mut self, is_enable: bool) {
+        self.strobe_enable = is_enable;
+        if is_enable {
+            self.shift_reg = !self.dial;
+        }
+    }
+    pub fn read_button(&self) -> u8 {
+        u8::from(self.is_button)
+    }
+    pub fn read_dial(&mut self) -> u8 {
+        let data = self.shift_reg >> 7;
+        if !self.strobe_enable {
+            self.shift_reg <<= 1;
+        }
+        data
+    }
+}
@@ -43,7 +43,6 @@ extern "C" {
 use super::system::*;
 use super::instruction::*;
 
-pub const CPU_FREQ: u32 = 1790000;
 pub const NMI_READ_LOWER: u16 = 0xfffa;
 pub const NMI_READ_UPPER: u16 = 0xfffb;
 pub const RESET_READ_LOWER: u16 = 0xfffc;
@@ -60,6 +59,7 @@ pub struct Cpu{
     pub a  : u8, //Accumulator
     pub s : u16, //Stack Pointer
     pub p : u8, //Status Register
+    pub is_jammed: bool, //Hit a JAM opcode, nothing but a reset gets it going again
 
 }
 
@@ -82,6 +82,7 @@ impl Cpu{
         self.pc = 0;
         self.s = 0x01fd;
         self.p = 0x34;
+        self.is_jammed = false;
     }
     pub fn regstat(&self, reg:u8) -> u8{
         match reg {
@@ -113,6 +114,7 @@ impl Cpu{
             a : 0,
             s : 0,
             p : 0,
+            is_jammed: false,
 
         }
     }
@@ -217,6 +219,12 @@ impl Cpu {
     //The 6502 has 4 interrupts, NMI, RESET, IRQ, and FLAG
     //They are pretty self-explanatory
     pub fn interrupt(&mut self, system: &mut System, irq : Interrupt){
+        if self.is_jammed {
+            if irq != Interrupt::RESET {
+                return;
+            }
+            self.is_jammed = false;
+        }
         let is_nested = self.read_interrupt_flag();
         if is_nested && (irq == Interrupt::IRQ) || (irq == Interrupt::BRK) {
             return;
@@ -411,6 +419,10 @@ impl Cpu {
     //The meat of the CPU, this function is an OO abomination but without costly abstraction, this is really the easiest way
     //I do not have time to explain every operation here. Or any of them. Look them up. It's neat.
     pub fn step(&mut self, system : &mut System) -> u8{
+        //The rest of the console carries on while the CPU is stuck
+        if self.is_jammed {
+            return 1;
+        }
         let inst_pc = self.pc;
         let inst_code = self.fetch8(system);
         
@@ -1201,6 +1213,11 @@ impl Cpu {
             Opcode::NOP =>{
                 2
             },
+            Opcode::JAM => {
+                self.pc = inst_pc;
+                self.is_jammed = true;
+                2
+            },
 
         }
     }
@@ -0,0 +1,619 @@
+/* Sound chips on the cartridge */
+//https://wiki.nesdev.com/w/index.php/Expansion_audio
+//The Famicom passes audio through the cartridge, so Japanese carts could bring their own sound hardware. Everything
+//here is clocked off the CPU clock and mixed in with the 2A03 by the APU. Levels are relative to Apu::mix, where a
+//single full volume 2A03 pulse comes out at about 0.149. The FDS lives in fds.rs and VRC7's FM synth in vrc7.rs.
+use super::apu::{pulse_mix, Pulse};
+use super::fds::FdsAudio;
+use super::nsf::{
+    NSF_EXPANSION_FDS, NSF_EXPANSION_MMC5, NSF_EXPANSION_N163, NSF_EXPANSION_SUNSOFT5B, NSF_EXPANSION_VRC6,
+    NSF_EXPANSION_VRC7,
+};
+use super::vrc7::Vrc7Audio;
+
+//Each step of VRC6 volume, a full volume VRC6 pulse is about 1.5 times a 2A03 one
+const VRC6_AUDIO_LEVEL: f32 = 0.015;
+//A single 5B channel at full volume
+const SUNSOFT5B_AUDIO_LEVEL: f32 = 0.535;
+//Each step of N163 sample times volume. One channel on its own at full volume is about 3 times a 2A03 pulse
+const N163_AUDIO_LEVEL: f32 = 0.002;
+//The MMC5 PCM DAC at full scale is about as loud as the DMC at full scale
+const MMC5_PCM_LEVEL: f32 = 0.561 / 255.0;
+
+//https://wiki.nesdev.com/w/index.php/VRC6_audio
+#[derive(Clone, Debug, Default)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    //Mode bit, a constant volume instead of a pulse
+    is_ignore_duty: bool,
+    period: u16,
+    is_enabled: bool,
+    timer: u16,
+    //Counts down from 15, the output is high while it's at or below duty
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, index: usize, data: u8) {
+        match index {
+            0 => {
+                self.is_ignore_duty = (data & 0x80) == 0x80;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0f;
+            }
+            1 => self.period = (self.period & 0x0f00) | u16::from(data),
+            _ => {
+                self.period = (self.period & 0x00ff) | (u16::from(data & 0x0f) << 8);
+                self.is_enabled = (data & 0x80) == 0x80;
+                if !self.is_enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.is_enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0f;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.is_enabled && (self.is_ignore_duty || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    is_enabled: bool,
+    timer: u16,
+    //The accumulator takes the rate every other clock and resets on the 14th
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, index: usize, data: u8) {
+        match index {
+            0 => self.rate = data & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | u16::from(data),
+            _ => {
+                self.period = (self.period & 0x00ff) | (u16::from(data & 0x0f) << 8);
+                self.is_enabled = (data & 0x80) == 0x80;
+                if !self.is_enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.is_enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step.is_multiple_of(2) {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.is_enabled {
+            self.accumulator >> 3
+        } else {
+            0
+        }
+    }
+}
+
+//Two pulses with 8 duty cycles and a sawtooth, used by Konami's mappers 24 and 26
+#[derive(Clone, Debug, Default)]
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    //$9003, stops every channel or speeds them all up by 16 or 256
+    is_halt: bool,
+    shift: u8,
+}
+
+impl Vrc6Audio {
+    //$9000-$B002 as VRC6a (mapper 24) lays them out, mapper 26 has to swap A0 and A1 before calling this
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        let index = usize::from(addr & 0x0003);
+        match addr & 0xf003 {
+            0x9003 => {
+                self.is_halt = (data & 0x01) == 0x01;
+                self.shift = if (data & 0x04) == 0x04 {
+                    8
+                } else if (data & 0x02) == 0x02 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000..=0x9002 => self.pulse1.write(index, data),
+            0xa000..=0xa002 => self.pulse2.write(index, data),
+            0xb000..=0xb002 => self.saw.write(index, data),
+            _ => {}
+        }
+    }
+    pub fn clock(&mut self) {
+        if self.is_halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+    pub fn output(&self) -> f32 {
+        f32::from(self.pulse1.output() + self.pulse2.output() + self.saw.output()) * VRC6_AUDIO_LEVEL
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+//A YM2149 (AY-3-8910 clone) inside the FME-7: three square waves, a noise generator and a hardware envelope
+const SUNSOFT5B_NUM_OF_CHANNELS: usize = 3;
+//Everything in the chip ticks at the CPU clock divided by 16
+const SUNSOFT5B_CLOCK_DIVIDER: u8 = 16;
+//32 levels, 1.5dB apart
+const SUNSOFT5B_NUM_OF_LEVELS: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct Sunsoft5bAudio {
+    register: u8,
+    divider: u8,
+    tone_period: [u16; SUNSOFT5B_NUM_OF_CHANNELS],
+    tone_counter: [u16; SUNSOFT5B_NUM_OF_CHANNELS],
+    tone_output: [bool; SUNSOFT5B_NUM_OF_CHANNELS],
+    noise_period: u8,
+    noise_counter: u16,
+    //17 bit LFSR
+    noise_shift: u32,
+    //R7, tone and noise disables. Set means off
+    mixer: u8,
+    //Bit 4 hands the channel over to the envelope
+    volume: [u8; SUNSOFT5B_NUM_OF_CHANNELS],
+    envelope_period: u16,
+    envelope_counter: u16,
+    envelope_shape: u8,
+    envelope_step: u8,
+    is_envelope_attack: bool,
+    is_envelope_holding: bool,
+    level_table: Vec<f32>,
+}
+
+impl Default for Sunsoft5bAudio {
+    fn default() -> Self {
+        Self {
+            register: 0,
+            divider: 0,
+            tone_period: [0; SUNSOFT5B_NUM_OF_CHANNELS],
+            tone_counter: [0; SUNSOFT5B_NUM_OF_CHANNELS],
+            tone_output: [false; SUNSOFT5B_NUM_OF_CHANNELS],
+            noise_period: 0,
+            noise_counter: 0,
+            noise_shift: 1,
+            mixer: 0,
+            volume: [0; SUNSOFT5B_NUM_OF_CHANNELS],
+            envelope_period: 0,
+            envelope_counter: 0,
+            envelope_shape: 0,
+            envelope_step: 0,
+            is_envelope_attack: false,
+            is_envelope_holding: true,
+            level_table: (0..SUNSOFT5B_NUM_OF_LEVELS)
+                .map(|level| match level {
+                    0 => 0.0,
+                    _ => 10f32.powf(-1.5 * ((SUNSOFT5B_NUM_OF_LEVELS - 1 - level) as f32) / 20.0),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Sunsoft5bAudio {
+    pub fn heap_bytes(&self) -> usize {
+        self.level_table.capacity() * std::mem::size_of::<f32>()
+    }
+    //$C000 picks the register, $E000 writes it
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr & 0xe000 {
+            0xc000 => self.register = data & 0x0f,
+            0xe000 => self.write_data(data),
+            _ => {}
+        }
+    }
+    fn write_data(&mut self, data: u8) {
+        let register = usize::from(self.register);
+        match register {
+            0x00..=0x05 => {
+                let channel = register / 2;
+                self.tone_period[channel] = if register % 2 == 0 {
+                    (self.tone_period[channel] & 0x0f00) | u16::from(data)
+                } else {
+                    (self.tone_period[channel] & 0x00ff) | (u16::from(data & 0x0f) << 8)
+                };
+            }
+            0x06 => self.noise_period = data & 0x1f,
+            0x07 => self.mixer = data,
+            0x08..=0x0a => self.volume[register - 0x08] = data & 0x1f,
+            0x0b => self.envelope_period = (self.envelope_period & 0xff00) | u16::from(data),
+            0x0c => self.envelope_period = (self.envelope_period & 0x00ff) | (u16::from(data) << 8),
+            0x0d => {
+                self.envelope_shape = data & 0x0f;
+                self.envelope_step = 0;
+                self.envelope_counter = 0;
+                self.is_envelope_attack = (data & 0x04) == 0x04;
+                self.is_envelope_holding = false;
+            }
+            //The I/O ports, nothing is wired to them
+            _ => {}
+        }
+    }
+    fn envelope_level(&self) -> usize {
+        if self.is_envelope_attack {
+            usize::from(self.envelope_step)
+        } else {
+            SUNSOFT5B_NUM_OF_LEVELS - 1 - usize::from(self.envelope_step)
+        }
+    }
+    //One pass through the 32 steps is done, what happens next depends on the shape
+    //https://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio#Envelope
+    fn end_envelope_cycle(&mut self) {
+        let is_continue = (self.envelope_shape & 0x08) == 0x08;
+        let is_alternate = (self.envelope_shape & 0x02) == 0x02;
+        let is_hold = (self.envelope_shape & 0x01) == 0x01;
+        if !is_continue {
+            //Shapes 0-7 drop to silence and stay there
+            self.is_envelope_attack = false;
+            self.is_envelope_holding = true;
+        } else if is_hold {
+            if is_alternate {
+                self.is_envelope_attack = !self.is_envelope_attack;
+            }
+            self.is_envelope_holding = true;
+        } else {
+            if is_alternate {
+                self.is_envelope_attack = !self.is_envelope_attack;
+            }
+            self.envelope_step = 0;
+            return;
+        }
+        self.envelope_step = (SUNSOFT5B_NUM_OF_LEVELS - 1) as u8;
+    }
+    fn tick(&mut self) {
+        for channel in 0..SUNSOFT5B_NUM_OF_CHANNELS {
+            self.tone_counter[channel] += 1;
+            if self.tone_counter[channel] >= self.tone_period[channel].max(1) {
+                self.tone_counter[channel] = 0;
+                self.tone_output[channel] = !self.tone_output[channel];
+            }
+        }
+        self.noise_counter += 1;
+        if self.noise_counter >= 2 * u16::from(self.noise_period.max(1)) {
+            self.noise_counter = 0;
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 0x01;
+            self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+        }
+        if !self.is_envelope_holding {
+            self.envelope_counter += 1;
+            if self.envelope_counter >= self.envelope_period.max(1) {
+                self.envelope_counter = 0;
+                if usize::from(self.envelope_step) == SUNSOFT5B_NUM_OF_LEVELS - 1 {
+                    self.end_envelope_cycle();
+                } else {
+                    self.envelope_step += 1;
+                }
+            }
+        }
+    }
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider == SUNSOFT5B_CLOCK_DIVIDER {
+            self.divider = 0;
+            self.tick();
+        }
+    }
+    pub fn output(&self) -> f32 {
+        let is_noise = (self.noise_shift & 0x01) == 0x01;
+        let mut output = 0.0;
+        for channel in 0..SUNSOFT5B_NUM_OF_CHANNELS {
+            let is_tone_off = (self.mixer >> channel) & 0x01 == 0x01;
+            let is_noise_off = (self.mixer >> (channel + 3)) & 0x01 == 0x01;
+            if !((self.tone_output[channel] || is_tone_off) && (is_noise || is_noise_off)) {
+                continue;
+            }
+            let volume = self.volume[channel];
+            let level = if (volume & 0x10) == 0x10 {
+                self.envelope_level()
+            } else if volume == 0 {
+                0
+            } else {
+                //The 4 bit volumes sit on every other envelope level
+                usize::from(volume) * 2 + 1
+            };
+            output += self.level_table[level];
+        }
+        output * SUNSOFT5B_AUDIO_LEVEL
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/Namco_163_audio
+//Up to 8 wavetable channels with their samples and registers all in 128 bytes of internal RAM. It only has the one
+//DAC, so the active channels take turns at it, one every 15 CPU cycles
+pub const N163_RAM_SIZE: usize = 0x80;
+const N163_NUM_OF_CHANNELS: usize = 8;
+const N163_CYCLES_PER_CHANNEL: u8 = 15;
+//Channel 7's registers, the ones further down belong to the lower channels
+const N163_CHANNEL_REG_BASE: usize = 0x40;
+const N163_CHANNEL_REG_SIZE: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct Namco163Audio {
+    ram: [u8; N163_RAM_SIZE],
+    //$F800, bit 7 moves it along after every access
+    addr: u8,
+    is_auto_increment: bool,
+    cycle: u8,
+    channel: usize,
+    outputs: [i32; N163_NUM_OF_CHANNELS],
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Self {
+            ram: [0; N163_RAM_SIZE],
+            addr: 0,
+            is_auto_increment: false,
+            cycle: 0,
+            channel: N163_NUM_OF_CHANNELS - 1,
+            outputs: [0; N163_NUM_OF_CHANNELS],
+        }
+    }
+}
+
+impl Namco163Audio {
+    pub fn write_addr(&mut self, data: u8) {
+        self.addr = data & 0x7f;
+        self.is_auto_increment = (data & 0x80) == 0x80;
+    }
+    fn next_addr(&mut self) {
+        if self.is_auto_increment {
+            self.addr = (self.addr + 1) & 0x7f;
+        }
+    }
+    //$4800
+    pub fn read_data(&mut self, is_nondestructive: bool) -> u8 {
+        let data = self.ram[usize::from(self.addr)];
+        if !is_nondestructive {
+            self.next_addr();
+        }
+        data
+    }
+    pub fn write_data(&mut self, data: u8) {
+        self.ram[usize::from(self.addr)] = data;
+        self.next_addr();
+    }
+    //Some games keep save data in here too
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+    fn num_of_channels(&self) -> usize {
+        usize::from((self.ram[N163_RAM_SIZE - 1] >> 4) & 0x07) + 1
+    }
+    fn update_channel(&mut self, channel: usize) {
+        let base = N163_CHANNEL_REG_BASE + channel * N163_CHANNEL_REG_SIZE;
+        let reg = |offset: usize| u32::from(self.ram[base + offset]);
+        let frequency = reg(0) | (reg(2) << 8) | ((reg(4) & 0x03) << 16);
+        let phase = reg(1) | (reg(3) << 8) | (reg(5) << 16);
+        let length = (256 - (reg(4) & 0xfc)) << 16;
+        let offset = reg(6);
+        let volume = (reg(7) & 0x0f) as i32;
+
+        let phase = (phase + frequency) % length;
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        //4 bit samples, low nibble first
+        let sample_index = (((phase >> 16) + offset) & 0xff) as usize;
+        let sample = (self.ram[sample_index / 2] >> ((sample_index & 0x01) * 4)) & 0x0f;
+        self.outputs[channel] = (i32::from(sample) - 8) * volume;
+    }
+    pub fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < N163_CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.cycle = 0;
+        self.update_channel(self.channel);
+        //Channels go 7, 6, 5... down to however many are on
+        let lowest_channel = N163_NUM_OF_CHANNELS - self.num_of_channels();
+        self.channel = if self.channel <= lowest_channel {
+            N163_NUM_OF_CHANNELS - 1
+        } else {
+            self.channel - 1
+        };
+    }
+    //The channels are heard one at a time, quickly enough that it averages out
+    pub fn output(&self) -> f32 {
+        let num_of_channels = self.num_of_channels();
+        let sum: i32 = self.outputs[N163_NUM_OF_CHANNELS - num_of_channels..].iter().sum();
+        (sum as f32) / (num_of_channels as f32) * N163_AUDIO_LEVEL
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/MMC5_audio
+//Two more 2A03 pulses minus the sweep, and an 8 bit PCM DAC
+//The MMC5 has its own frame counter, clocking envelopes and length counters together at 240Hz
+const MMC5_FRAME_CYCLES: usize = 7457;
+
+#[derive(Clone, Debug)]
+pub struct Mmc5Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    frame_cycle: usize,
+    is_odd_cycle: bool,
+    pcm: u8,
+    //$5010, the DAC picks up whatever the CPU reads from $8000-$BFFF instead of $5011 writes
+    is_pcm_read_mode: bool,
+    is_pcm_irq_enabled: bool,
+    is_pcm_irq: bool,
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Self {
+            pulse1: Pulse::without_sweep(),
+            pulse2: Pulse::without_sweep(),
+            frame_cycle: 0,
+            is_odd_cycle: false,
+            pcm: 0,
+            is_pcm_read_mode: false,
+            is_pcm_irq_enabled: false,
+            is_pcm_irq: false,
+        }
+    }
+}
+
+impl Mmc5Audio {
+    //$5000-$5015
+    pub fn read_register(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 => {
+                let data = if self.is_pcm_irq { 0x80 } else { 0x00 } | u8::from(self.is_pcm_read_mode);
+                self.is_pcm_irq = false;
+                Some(data)
+            }
+            0x5015 => Some(u8::from(self.pulse1.is_playing()) | (u8::from(self.pulse2.is_playing()) << 1)),
+            _ => None,
+        }
+    }
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write(usize::from(addr - 0x5000), data),
+            0x5004..=0x5007 => self.pulse2.write(usize::from(addr - 0x5004), data),
+            0x5010 => {
+                self.is_pcm_read_mode = (data & 0x01) == 0x01;
+                self.is_pcm_irq_enabled = (data & 0x80) == 0x80;
+            }
+            //A 0 is ignored rather than played
+            0x5011 if !self.is_pcm_read_mode && data != 0 => self.pcm = data,
+            0x5015 => {
+                self.pulse1.set_enabled((data & 0x01) == 0x01);
+                self.pulse2.set_enabled((data & 0x02) == 0x02);
+            }
+            _ => {}
+        }
+    }
+    //In read mode the mapper hands over every read from $8000-$BFFF. A 0 doesn't play, it raises the IRQ instead
+    pub fn read_pcm(&mut self, data: u8) {
+        if !self.is_pcm_read_mode {
+            return;
+        }
+        if data == 0 {
+            self.is_pcm_irq = self.is_pcm_irq_enabled;
+        } else {
+            self.pcm = data;
+        }
+    }
+    pub fn is_irq(&self) -> bool {
+        self.is_pcm_irq
+    }
+    pub fn clock(&mut self) {
+        self.frame_cycle += 1;
+        if self.frame_cycle >= MMC5_FRAME_CYCLES {
+            self.frame_cycle = 0;
+            self.pulse1.clock_envelope();
+            self.pulse2.clock_envelope();
+            self.pulse1.clock_length();
+            self.pulse2.clock_length();
+        }
+        if self.is_odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.is_odd_cycle = !self.is_odd_cycle;
+    }
+    pub fn output(&self) -> f32 {
+        pulse_mix(self.pulse1.output() + self.pulse2.output()) + f32::from(self.pcm) * MMC5_PCM_LEVEL
+    }
+}
+
+//Whichever chips an NSF asked for, all playing at once
+#[derive(Clone, Debug, Default)]
+pub struct ExpansionAudio {
+    pub vrc6: Option<Vrc6Audio>,
+    //Boxed, the FM synth carries a lot of state
+    pub vrc7: Option<Box<Vrc7Audio>>,
+    pub fds: Option<FdsAudio>,
+    pub mmc5: Option<Mmc5Audio>,
+    pub n163: Option<Namco163Audio>,
+    pub sunsoft5b: Option<Sunsoft5bAudio>,
+}
+
+impl ExpansionAudio {
+    pub fn heap_bytes(&self) -> usize {
+        let vrc7 = self.vrc7.as_ref().map_or(0, |_| std::mem::size_of::<Vrc7Audio>());
+        vrc7 + self.sunsoft5b.as_ref().map_or(0, Sunsoft5bAudio::heap_bytes)
+    }
+    //From an NSF header's expansion audio byte
+    pub fn from_nsf_flags(flags: u8) -> Self {
+        let has = |flag: u8| (flags & flag) == flag;
+        Self {
+            vrc6: Some(Vrc6Audio::default()).filter(|_| has(NSF_EXPANSION_VRC6)),
+            vrc7: Some(Box::default()).filter(|_| has(NSF_EXPANSION_VRC7)),
+            fds: Some(FdsAudio::default()).filter(|_| has(NSF_EXPANSION_FDS)),
+            mmc5: Some(Mmc5Audio::default()).filter(|_| has(NSF_EXPANSION_MMC5)),
+            n163: Some(Namco163Audio::default()).filter(|_| has(NSF_EXPANSION_N163)),
+            sunsoft5b: Some(Sunsoft5bAudio::default()).filter(|_| has(NSF_EXPANSION_SUNSOFT5B)),
+        }
+    }
+    pub fn clock(&mut self) {
+        if let Some(vrc6) = &mut self.vrc6 {
+            vrc6.clock();
+        }
+        if let Some(vrc7) = &mut self.vrc7 {
+            vrc7.clock();
+        }
+        if let Some(fds) = &mut self.fds {
+            fds.clock();
+        }
+        if let Some(mmc5) = &mut self.mmc5 {
+            mmc5.clock();
+        }
+        if let Some(n163) = &mut self.n163 {
+            n163.clock();
+        }
+        if let Some(sunsoft5b) = &mut self.sunsoft5b {
+            sunsoft5b.clock();
+        }
+    }
+    pub fn output(&self) -> f32 {
+        self.vrc6.as_ref().map_or(0.0, |vrc6| vrc6.output())
+            + self.vrc7.as_ref().map_or(0.0, |vrc7| vrc7.output())
+            + self.fds.as_ref().map_or(0.0, |fds| fds.output())
+            + self.mmc5.as_ref().map_or(0.0, |mmc5| mmc5.output())
+            + self.n163.as_ref().map_or(0.0, |n163| n163.output())
+            + self.sunsoft5b.as_ref().map_or(0.0, |sunsoft5b| sunsoft5b.output())
+    }
+}
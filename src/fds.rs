@@ -0,0 +1,980 @@
+/* Famicom Disk System */
+//https://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+//The FDS is a RAM adapter that plugs into the cartridge slot, with a disk drive hanging off of it. The adapter has 32k
+//of PRG-RAM where a cartridge would have ROM, 8k of CHR-RAM, the BIOS at $E000, a timer IRQ and a wavetable sound
+//channel. The BIOS reads games off of the disk a byte at a time, the drive raising an IRQ as each one goes by.
+//.fds files only keep the data blocks, so the gaps and CRCs the drive would see between them get put back on load.
+use std::fmt;
+
+use super::patch::{self, PatchError};
+use super::rom::MirrorTable;
+
+//NES 2.0 sets this one aside for the FDS
+pub const FDS_MAPPER: u16 = 20;
+pub const FDS_SIDE_SIZE: usize = 65500;
+pub const FWNES_HEADER_SIZE: usize = 16;
+pub const FDS_BIOS_SIZE: usize = 0x2000;
+pub const FDS_PRG_RAM_SIZE: usize = 0x8000;
+pub const FDS_CHR_RAM_SIZE: usize = 0x2000;
+
+const FDS_PRG_RAM_BASE_ADDR: u16 = 0x6000;
+const FDS_BIOS_BASE_ADDR: u16 = 0xe000;
+
+//Every side starts with the disk info block, which starts with this
+const FDS_DISK_INFO_MAGIC: &[u8] = b"\x01*NINTENDO-HVC*";
+const FWNES_MAGIC: &[u8] = b"FDS\x1a";
+
+//Block types, the first byte of each block
+const FDS_DISK_INFO_BLOCK: u8 = 1;
+const FDS_FILE_AMOUNT_BLOCK: u8 = 2;
+const FDS_FILE_HEADER_BLOCK: u8 = 3;
+const FDS_FILE_DATA_BLOCK: u8 = 4;
+//Where the file header keeps the size of the data block that follows it
+const FDS_FILE_SIZE_OFFSET: usize = 13;
+
+//28300 bits of nothing before the first block, 976 between blocks
+const FDS_LEAD_IN_GAP_SIZE: usize = 28300 / 8;
+const FDS_BLOCK_GAP_SIZE: usize = 976 / 8;
+//The drive syncs up on the first 1 bit after a gap
+const FDS_BLOCK_START_MARK: u8 = 0x80;
+const FDS_CRC_SIZE: usize = 2;
+
+//The disk moves at about 96.4kbit/s, so a byte goes by every 149 CPU cycles
+const FDS_CYCLES_PER_BYTE: usize = 149;
+//From the motor starting to the head reaching the start of the disk
+const FDS_SPIN_UP_CYCLES: usize = 50000;
+//Swapping sides goes through a moment with no disk at all, otherwise the BIOS never notices. About half a second
+const FDS_DISK_SWAP_CYCLES: usize = 900_000;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum FdsError {
+    //The fwNES header promises more sides than the file has
+    Truncated { expected: usize, actual: usize },
+    NoSides,
+    //A side that doesn't start with the disk info block
+    BadSide(usize),
+    //Disk images are no use without the BIOS to boot them, and we can't ship Nintendo's
+    MissingBios,
+    BadBiosSize(usize),
+    //A save that doesn't apply to the image that's loaded
+    BadSave(PatchError),
+    //A save that applies but makes an image of a different size, so it was made for some other disk
+    SaveSizeMismatch { expected: usize, actual: usize },
+}
+
+impl From<PatchError> for FdsError {
+    fn from(err: PatchError) -> Self {
+        FdsError::BadSave(err)
+    }
+}
+
+impl fmt::Display for FdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdsError::Truncated { expected, actual } => write!(
+                f,
+                "FDS image is truncated, expected {} bytes but got {}",
+                expected, actual
+            ),
+            FdsError::NoSides => write!(f, "FDS image has no disk sides"),
+            FdsError::BadSide(side) => write!(f, "FDS disk side {} has no disk info block", side + 1),
+            FdsError::MissingBios => write!(f, "FDS games need the disksys.rom BIOS, load it first"),
+            FdsError::BadBiosSize(bytes) => write!(
+                f,
+                "FDS BIOS is {} bytes but should be {}",
+                bytes, FDS_BIOS_SIZE
+            ),
+            FdsError::BadSave(err) => write!(f, "FDS save doesn't fit this disk: {}", err),
+            FdsError::SaveSizeMismatch { expected, actual } => write!(
+                f,
+                "FDS save is for a {} byte disk image but this one is {} bytes",
+                actual, expected
+            ),
+        }
+    }
+}
+
+pub fn is_fds(binary: &[u8]) -> bool {
+    binary.starts_with(FWNES_MAGIC) || binary.starts_with(FDS_DISK_INFO_MAGIC)
+}
+
+//A .fds file, with or without the fwNES header
+#[derive(Clone, Debug, Default)]
+pub struct FdsImage {
+    pub has_header: bool,
+    //FDS_SIDE_SIZE bytes each
+    pub sides: Vec<Vec<u8>>,
+    //The file as it was loaded, saves are a diff against this
+    pub original: Vec<u8>,
+}
+
+impl FdsImage {
+    pub fn parse(binary: &[u8]) -> Result<FdsImage, FdsError> {
+        let has_header = binary.starts_with(FWNES_MAGIC);
+        let (data, side_count) = if has_header {
+            let side_count = usize::from(*binary.get(4).unwrap_or(&0));
+            (binary.get(FWNES_HEADER_SIZE..).unwrap_or(&[]), side_count)
+        } else {
+            //Headerless images are just the sides back to back
+            (binary, binary.len() / FDS_SIDE_SIZE)
+        };
+        if side_count == 0 && has_header {
+            return Err(FdsError::NoSides);
+        }
+        if side_count == 0 || data.len() < side_count * FDS_SIDE_SIZE {
+            let header_size = if has_header { FWNES_HEADER_SIZE } else { 0 };
+            return Err(FdsError::Truncated {
+                expected: header_size + side_count.max(1) * FDS_SIDE_SIZE,
+                actual: binary.len(),
+            });
+        }
+        let sides = data
+            .chunks_exact(FDS_SIDE_SIZE)
+            .take(side_count)
+            .map(|side| side.to_vec())
+            .collect::<Vec<_>>();
+        if let Some(side) = sides.iter().position(|side| !side.starts_with(FDS_DISK_INFO_MAGIC)) {
+            return Err(FdsError::BadSide(side));
+        }
+        let image = FdsImage {
+            has_header,
+            sides,
+            original: binary.to_vec(),
+        };
+        //disk_image and load_save cut every side back out of the original by its offset
+        assert!(image.side_offset(image.sides.len()) <= image.original.len());
+        Ok(image)
+    }
+    fn side_offset(&self, side: usize) -> usize {
+        let header_size = if self.has_header { FWNES_HEADER_SIZE } else { 0 };
+        header_size + side * FDS_SIDE_SIZE
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/FDS_disk_format
+fn block_size(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        FDS_DISK_INFO_BLOCK => Some(56),
+        FDS_FILE_AMOUNT_BLOCK => Some(2),
+        FDS_FILE_HEADER_BLOCK => Some(16),
+        FDS_FILE_DATA_BLOCK => Some(1 + file_size),
+        //Anything else is the unused end of the disk
+        _ => None,
+    }
+}
+
+fn file_size(file_header: &[u8]) -> usize {
+    usize::from(file_header[FDS_FILE_SIZE_OFFSET]) | (usize::from(file_header[FDS_FILE_SIZE_OFFSET + 1]) << 8)
+}
+
+//CRC-16 the drive appends to each block, the start mark included
+fn update_crc(mut crc: u16, data: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = (crc & 0x01) == 0x01;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if (data >> bit) & 0x01 == 0x01 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+//Lay a side out the way it passes under the head: a long gap, then each block behind a start mark and followed by
+//its CRC and another gap. Whatever space the side had left over stays blank for the game to save into
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut disk = vec![0; FDS_LEAD_IN_GAP_SIZE];
+    let mut offset = 0;
+    let mut file_size_next = 0;
+    while let Some(block) = side
+        .get(offset)
+        .and_then(|block_type| block_size(*block_type, file_size_next))
+        .and_then(|size| side.get(offset..offset + size))
+    {
+        if block[0] == FDS_FILE_HEADER_BLOCK {
+            file_size_next = file_size(block);
+        }
+        disk.push(FDS_BLOCK_START_MARK);
+        disk.extend_from_slice(block);
+        //Same as the drive works it out when writing, two zeros pushed through at the end
+        let crc = disk[disk.len() - block.len() - 1..]
+            .iter()
+            .chain(&[0, 0])
+            .fold(0, |crc, data| update_crc(crc, *data));
+        disk.push(crc as u8);
+        disk.push((crc >> 8) as u8);
+        disk.resize(disk.len() + FDS_BLOCK_GAP_SIZE, 0);
+        offset += block.len();
+    }
+    disk.resize(disk.len() + (FDS_SIDE_SIZE - offset), 0);
+    disk
+}
+
+//The other way, pull the blocks back out from between the gaps. Written over the side as it was loaded so anything
+//past the last block is left how it was
+fn remove_gaps(disk: &[u8], side: &mut [u8]) {
+    let mut position = 0;
+    let mut offset = 0;
+    let mut file_size_next = 0;
+    while let Some(start) = disk[position..].iter().position(|data| *data == FDS_BLOCK_START_MARK) {
+        position += start + 1;
+        let block = match disk
+            .get(position)
+            .and_then(|block_type| block_size(*block_type, file_size_next))
+            .and_then(|size| disk.get(position..position + size))
+        {
+            Some(block) if offset + block.len() <= side.len() => block,
+            _ => break,
+        };
+        if block[0] == FDS_FILE_HEADER_BLOCK {
+            file_size_next = file_size(block);
+        }
+        side[offset..offset + block.len()].copy_from_slice(block);
+        offset += block.len();
+        position = (position + block.len() + FDS_CRC_SIZE).min(disk.len());
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/FDS_audio
+//One 64 step wavetable voice, with a volume envelope and a second wavetable bending the pitch
+//Modulation table entries, 4 resets the counter instead of adding to it
+const FDS_MOD_ADJUST: [i32; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const FDS_MOD_RESET: u8 = 4;
+//$4089 master volume, 2/2, 2/3, 2/4 and 2/5 scaled so that 1152 is full
+const FDS_MASTER_VOLUME_TABLE: [u32; 4] = [36, 24, 17, 14];
+const FDS_DEFAULT_ENVELOPE_SPEED: u8 = 0xe8;
+const FDS_WAVE_TABLE_SIZE: usize = 64;
+const FDS_MAX_GAIN: u8 = 32;
+//A full volume FDS wave is about 2.4 times as loud as a full volume 2A03 pulse, which the APU mixes to ~0.149
+const FDS_AUDIO_LEVEL: f32 = 2.4 * 0.149 / 63.0;
+
+//Shared by the volume and modulation units, $4080-$4083 and $4084-$4087
+#[derive(Clone, Debug, Default)]
+struct FdsEnvelope {
+    is_disabled: bool,
+    is_increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: u32,
+    frequency: u16,
+}
+
+impl FdsEnvelope {
+    fn write_control(&mut self, data: u8) {
+        self.is_disabled = (data & 0x80) == 0x80;
+        self.is_increase = (data & 0x40) == 0x40;
+        self.speed = data & 0x3f;
+        if self.is_disabled {
+            self.gain = data & 0x3f;
+        }
+    }
+    fn write_frequency_low(&mut self, data: u8) {
+        self.frequency = (self.frequency & 0x0f00) | u16::from(data);
+    }
+    fn write_frequency_high(&mut self, data: u8) {
+        self.frequency = (self.frequency & 0x00ff) | (u16::from(data & 0x0f) << 8);
+    }
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (u32::from(self.speed) + 1) * u32::from(master_speed);
+    }
+    //True when the gain changed
+    fn clock(&mut self, master_speed: u8) -> bool {
+        if self.is_disabled || master_speed == 0 {
+            return false;
+        }
+        if self.timer > 1 {
+            self.timer -= 1;
+            return false;
+        }
+        self.reset_timer(master_speed);
+        if self.is_increase && self.gain < FDS_MAX_GAIN {
+            self.gain += 1;
+        } else if !self.is_increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FdsAudio {
+    wave_table: [u8; FDS_WAVE_TABLE_SIZE],
+    mod_table: [u8; FDS_WAVE_TABLE_SIZE],
+    volume: FdsEnvelope,
+    modulator: FdsEnvelope,
+    master_speed: u8,
+    master_volume: usize,
+    //$4089 bit 7, the CPU can write the wavetable and the voice holds still
+    is_wave_write: bool,
+    is_wave_halted: bool,
+    is_envelope_halted: bool,
+    //$4087 bit 7, also what lets the CPU write the modulation table
+    is_mod_halted: bool,
+    wave_accumulator: u16,
+    wave_position: usize,
+    mod_accumulator: u16,
+    mod_position: usize,
+    //7 bit signed
+    mod_counter: i32,
+    //How much the modulator bends the pitch right now
+    mod_pitch: i32,
+    output: u8,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self {
+            wave_table: [0; FDS_WAVE_TABLE_SIZE],
+            mod_table: [0; FDS_WAVE_TABLE_SIZE],
+            volume: FdsEnvelope::default(),
+            modulator: FdsEnvelope::default(),
+            master_speed: FDS_DEFAULT_ENVELOPE_SPEED,
+            master_volume: 0,
+            is_wave_write: false,
+            is_wave_halted: true,
+            is_envelope_halted: false,
+            is_mod_halted: true,
+            wave_accumulator: 0,
+            wave_position: 0,
+            mod_accumulator: 0,
+            mod_position: 0,
+            mod_counter: 0,
+            mod_pitch: 0,
+            output: 0,
+        }
+    }
+}
+
+impl FdsAudio {
+    //$4040-$4097
+    pub fn read_register(&self, addr: u16) -> u8 {
+        //Only the low 6 bits are driven, the rest is open bus
+        match addr {
+            0x4040..=0x407f => self.wave_table[usize::from(addr - 0x4040)] | 0x40,
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.modulator.gain | 0x40,
+            _ => (addr >> 8) as u8,
+        }
+    }
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407f if self.is_wave_write => self.wave_table[usize::from(addr - 0x4040)] = data & 0x3f,
+            0x4080 => self.volume.write_control(data),
+            0x4082 => self.volume.write_frequency_low(data),
+            0x4083 => {
+                self.volume.write_frequency_high(data);
+                self.is_envelope_halted = (data & 0x40) == 0x40;
+                self.is_wave_halted = (data & 0x80) == 0x80;
+                if self.is_wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+                if self.is_envelope_halted {
+                    self.volume.reset_timer(self.master_speed);
+                    self.modulator.reset_timer(self.master_speed);
+                }
+            }
+            0x4084 => self.modulator.write_control(data),
+            0x4085 => {
+                self.set_mod_counter(i32::from(data & 0x7f));
+                self.update_mod_pitch();
+            }
+            0x4086 => self.modulator.write_frequency_low(data),
+            0x4087 => {
+                self.modulator.write_frequency_high(data);
+                self.is_mod_halted = (data & 0x80) == 0x80;
+                if self.is_mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            //Each write fills two steps of the 64 step table
+            0x4088 if self.is_mod_halted => {
+                self.mod_table[self.mod_position] = data & 0x07;
+                self.mod_table[(self.mod_position + 1) % FDS_WAVE_TABLE_SIZE] = data & 0x07;
+                self.mod_position = (self.mod_position + 2) % FDS_WAVE_TABLE_SIZE;
+            }
+            0x4089 => {
+                self.master_volume = usize::from(data & 0x03);
+                self.is_wave_write = (data & 0x80) == 0x80;
+            }
+            0x408a => self.master_speed = data,
+            _ => {}
+        }
+    }
+    fn set_mod_counter(&mut self, value: i32) {
+        self.mod_counter = ((value + 64) & 0x7f) - 64;
+    }
+    //The modulator's effect on the pitch, done the same odd way the hardware rounds it
+    //https://wiki.nesdev.com/w/index.php/FDS_audio#Frequency_calculation
+    fn update_mod_pitch(&mut self) {
+        let mut temp = self.mod_counter * i32::from(self.modulator.gain);
+        let remainder = temp & 0x0f;
+        temp >>= 4;
+        if remainder > 0 && (temp & 0x80) == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= i32::from(self.volume.frequency);
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_pitch = temp;
+    }
+    //True when the counter moved
+    fn clock_modulator(&mut self) -> bool {
+        if self.is_mod_halted || self.modulator.frequency == 0 {
+            return false;
+        }
+        let (accumulator, is_overflow) = self.mod_accumulator.overflowing_add(self.modulator.frequency);
+        self.mod_accumulator = accumulator;
+        if !is_overflow {
+            return false;
+        }
+        let adjust = self.mod_table[self.mod_position];
+        let counter = if adjust == FDS_MOD_RESET {
+            0
+        } else {
+            self.mod_counter + FDS_MOD_ADJUST[usize::from(adjust)]
+        };
+        self.set_mod_counter(counter);
+        self.mod_position = (self.mod_position + 1) % FDS_WAVE_TABLE_SIZE;
+        true
+    }
+    //Every CPU cycle
+    pub fn clock(&mut self) {
+        if !self.is_wave_halted && !self.is_envelope_halted {
+            self.volume.clock(self.master_speed);
+            if self.modulator.clock(self.master_speed) {
+                self.update_mod_pitch();
+            }
+        }
+        if self.clock_modulator() {
+            self.update_mod_pitch();
+        }
+        //The voice holds its last level while the wavetable is being written
+        if self.is_wave_write {
+            return;
+        }
+        if !self.is_wave_halted {
+            let pitch = i32::from(self.volume.frequency) + self.mod_pitch;
+            if pitch > 0 {
+                let (accumulator, is_overflow) = self.wave_accumulator.overflowing_add(pitch as u16);
+                self.wave_accumulator = accumulator;
+                if is_overflow {
+                    self.wave_position = (self.wave_position + 1) % FDS_WAVE_TABLE_SIZE;
+                }
+            }
+        }
+        let level = u32::from(self.volume.gain.min(FDS_MAX_GAIN)) * FDS_MASTER_VOLUME_TABLE[self.master_volume];
+        self.output = (u32::from(self.wave_table[self.wave_position]) * level / 1152) as u8;
+    }
+    //On the same scale as Apu::mix
+    pub fn output(&self) -> f32 {
+        f32::from(self.output) * FDS_AUDIO_LEVEL
+    }
+}
+
+//The RAM adapter and the drive, mapped in where the cartridge would be
+#[derive(Clone, Debug)]
+pub struct Fds {
+    image: FdsImage,
+    //Each side with its gaps put back, what the drive actually reads and writes
+    disks: Vec<Vec<u8>>,
+    //None when the drive is empty
+    side: Option<usize>,
+    //Side waiting to go in once the swap delay is up
+    next_side: Option<usize>,
+    swap_cycles: usize,
+    prg_ram: Vec<u8>,
+    pub mirror_table: MirrorTable,
+    pub audio: FdsAudio,
+
+    //$4023
+    is_disk_enabled: bool,
+    is_sound_enabled: bool,
+
+    //Timer IRQ, $4020-$4022
+    timer_reload: u16,
+    timer_counter: u16,
+    is_timer_repeat: bool,
+    is_timer_enabled: bool,
+    is_timer_irq: bool,
+
+    //$4025
+    is_motor_on: bool,
+    is_transfer_reset: bool,
+    is_read_mode: bool,
+    is_crc_control: bool,
+    is_drive_ready: bool,
+    is_disk_irq_enabled: bool,
+
+    //Drive state
+    is_disk_irq: bool,
+    is_transfer_complete: bool,
+    is_end_of_head: bool,
+    is_scanning: bool,
+    is_gap_ended: bool,
+    was_crc_control: bool,
+    read_data: u8,
+    write_data: u8,
+    crc: u16,
+    position: usize,
+    delay: usize,
+}
+
+impl Fds {
+    //Power on with side A in the drive
+    pub fn new(image: FdsImage) -> Self {
+        let disks = image.sides.iter().map(|side| add_gaps(side)).collect();
+        Self {
+            image,
+            disks,
+            side: Some(0),
+            next_side: None,
+            swap_cycles: 0,
+            prg_ram: vec![0; FDS_PRG_RAM_SIZE],
+            mirror_table: MirrorTable::Horizontal,
+            audio: FdsAudio::default(),
+            is_disk_enabled: false,
+            is_sound_enabled: false,
+            timer_reload: 0,
+            timer_counter: 0,
+            is_timer_repeat: false,
+            is_timer_enabled: false,
+            is_timer_irq: false,
+            is_motor_on: false,
+            is_transfer_reset: false,
+            is_read_mode: true,
+            is_crc_control: false,
+            is_drive_ready: false,
+            is_disk_irq_enabled: false,
+            is_disk_irq: false,
+            is_transfer_complete: false,
+            is_end_of_head: true,
+            is_scanning: false,
+            is_gap_ended: false,
+            was_crc_control: false,
+            read_data: 0,
+            write_data: 0,
+            crc: 0,
+            position: 0,
+            delay: 0,
+        }
+    }
+    pub fn side_count(&self) -> usize {
+        self.disks.len()
+    }
+    //The side in the drive, None while it's empty or in the middle of a swap
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+    pub fn eject(&mut self) {
+        self.side = None;
+        self.next_side = None;
+        self.swap_cycles = 0;
+    }
+    //Put a side in. If there's already one in there it comes out first, and the new one goes in a moment later
+    pub fn insert(&mut self, side: usize) {
+        if side >= self.side_count() {
+            return;
+        }
+        if self.side.is_some() || self.swap_cycles > 0 {
+            self.side = None;
+            self.next_side = Some(side);
+            self.swap_cycles = FDS_DISK_SWAP_CYCLES;
+        } else {
+            self.side = Some(side);
+        }
+    }
+    //The .fds file as it is now, with whatever the game has saved to it
+    pub fn disk_image(&self) -> Vec<u8> {
+        let mut image = self.image.original.clone();
+        for (side, disk) in self.disks.iter().enumerate() {
+            let offset = self.image.side_offset(side);
+            remove_gaps(disk, &mut image[offset..offset + FDS_SIDE_SIZE]);
+        }
+        image
+    }
+    //PRG RAM then every disk side, for snapshots
+    pub fn ram_mut(&mut self) -> Vec<&mut Vec<u8>> {
+        std::iter::once(&mut self.prg_ram).chain(self.disks.iter_mut()).collect()
+    }
+    //The image as it was loaded never changes, so snapshots kept in bulk leave it out like they do the ROM
+    pub fn take_image(&mut self) -> FdsImage {
+        std::mem::take(&mut self.image)
+    }
+    pub fn put_image(&mut self, image: FdsImage) {
+        self.image = image;
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        let sides = self.image.sides.iter().chain(&self.disks);
+        std::mem::size_of::<Self>()
+            + sides.map(|side| side.capacity()).sum::<usize>()
+            + (self.image.sides.capacity() + self.disks.capacity()) * std::mem::size_of::<Vec<u8>>()
+            + self.image.original.capacity()
+            + self.prg_ram.capacity()
+    }
+    //Saves are kept as an IPS patch against the image that was loaded, so they're small and don't need the
+    //original dump redistributed
+    pub fn save(&self) -> Vec<u8> {
+        patch::create_ips(&self.image.original, &self.disk_image())
+    }
+    pub fn load_save(&mut self, save: &[u8]) -> Result<(), FdsError> {
+        let image = patch::apply_ips(&self.image.original, save)?;
+        if image.len() != self.image.original.len() {
+            return Err(FdsError::SaveSizeMismatch {
+                expected: self.image.original.len(),
+                actual: image.len(),
+            });
+        }
+        for (side, disk) in self.disks.iter_mut().enumerate() {
+            let offset = self.image.side_offset(side);
+            *disk = add_gaps(&image[offset..offset + FDS_SIDE_SIZE]);
+        }
+        Ok(())
+    }
+    pub fn is_irq(&self) -> bool {
+        self.is_timer_irq || self.is_disk_irq
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&mut self, addr: u16, bios: &[u8], is_nondestructive: bool) -> u8 {
+        match addr {
+            0x4030 if self.is_disk_enabled => {
+                let mut data = 0;
+                if self.is_timer_irq {
+                    data |= 0x01;
+                }
+                if self.is_transfer_complete {
+                    data |= 0x02;
+                }
+                //Bit 4 would be a CRC error, the disks we build never have one
+                if self.is_end_of_head {
+                    data |= 0x40;
+                }
+                if !is_nondestructive {
+                    self.is_transfer_complete = false;
+                    self.is_timer_irq = false;
+                    self.is_disk_irq = false;
+                }
+                data
+            }
+            0x4031 if self.is_disk_enabled => {
+                if !is_nondestructive {
+                    self.is_transfer_complete = false;
+                    self.is_disk_irq = false;
+                }
+                self.read_data
+            }
+            0x4032 if self.is_disk_enabled => {
+                let mut data = 0x40;
+                if self.side.is_none() {
+                    //No disk, so not ready and not writable either
+                    data |= 0x07;
+                } else if !self.is_scanning {
+                    data |= 0x02;
+                }
+                data
+            }
+            //Expansion port, with the battery good bit set
+            0x4033 if self.is_disk_enabled => 0x80,
+            0x4040..=0x407f | 0x4090 | 0x4092 if self.is_sound_enabled => self.audio.read_register(addr),
+            FDS_PRG_RAM_BASE_ADDR..=0xdfff => self.prg_ram[usize::from(addr - FDS_PRG_RAM_BASE_ADDR)],
+            FDS_BIOS_BASE_ADDR..=0xffff => bios[usize::from(addr - FDS_BIOS_BASE_ADDR) % bios.len()],
+            _ => (addr >> 8) as u8,
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xff00) | u16::from(data),
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00ff) | (u16::from(data) << 8),
+            0x4022 => {
+                self.is_timer_repeat = (data & 0x01) == 0x01;
+                self.is_timer_enabled = (data & 0x02) == 0x02 && self.is_disk_enabled;
+                if self.is_timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.is_timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.is_disk_enabled = (data & 0x01) == 0x01;
+                self.is_sound_enabled = (data & 0x02) == 0x02;
+                if !self.is_disk_enabled {
+                    self.is_timer_enabled = false;
+                    self.is_timer_irq = false;
+                    self.is_disk_irq = false;
+                }
+            }
+            0x4024 if self.is_disk_enabled => {
+                self.write_data = data;
+                self.is_transfer_complete = false;
+                self.is_disk_irq = false;
+            }
+            0x4025 if self.is_disk_enabled => {
+                self.is_motor_on = (data & 0x01) == 0x01;
+                self.is_transfer_reset = (data & 0x02) == 0x02;
+                self.is_read_mode = (data & 0x04) == 0x04;
+                self.mirror_table = if (data & 0x08) == 0x08 {
+                    MirrorTable::Horizontal
+                } else {
+                    MirrorTable::Vertical
+                };
+                self.is_crc_control = (data & 0x10) == 0x10;
+                self.is_drive_ready = (data & 0x40) == 0x40;
+                self.is_disk_irq_enabled = (data & 0x80) == 0x80;
+                self.is_disk_irq = false;
+            }
+            0x4040..=0x408a if self.is_sound_enabled => self.audio.write_register(addr, data),
+            FDS_PRG_RAM_BASE_ADDR..=0xdfff => self.prg_ram[usize::from(addr - FDS_PRG_RAM_BASE_ADDR)] = data,
+            //$E000-$FFFF is the BIOS ROM
+            _ => {}
+        }
+    }
+    //Run the timer and the drive for however many cycles the last CPU instruction took
+    pub fn step(&mut self, cpu_cyc: usize) {
+        for _ in 0..cpu_cyc {
+            self.clock_timer();
+            self.clock_drive();
+        }
+    }
+    fn clock_timer(&mut self) {
+        if !self.is_timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.is_timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.is_timer_repeat {
+                self.is_timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+    fn clock_drive(&mut self) {
+        if self.swap_cycles > 0 {
+            self.swap_cycles -= 1;
+            if self.swap_cycles == 0 {
+                self.side = self.next_side.take();
+            }
+        }
+        let side = match self.side {
+            Some(side) if self.is_motor_on => side,
+            _ => {
+                self.is_end_of_head = true;
+                self.is_scanning = false;
+                return;
+            }
+        };
+        if self.is_transfer_reset && !self.is_scanning {
+            return;
+        }
+        //Head goes back to the start, then the disk has to come up to speed
+        if self.is_end_of_head {
+            self.delay = FDS_SPIN_UP_CYCLES;
+            self.is_end_of_head = false;
+            self.position = 0;
+            self.is_gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.is_scanning = true;
+        let mut is_irq = self.is_disk_irq_enabled;
+        if self.is_read_mode {
+            let data = self.disks[side][self.position];
+            if !self.was_crc_control {
+                self.crc = update_crc(self.crc, data);
+            }
+            if !self.is_drive_ready {
+                self.is_gap_ended = false;
+                self.crc = 0;
+            } else if data != 0 && !self.is_gap_ended {
+                //That's the start mark, the byte after it is the first one the BIOS gets to see
+                self.is_gap_ended = true;
+                is_irq = false;
+            }
+            if self.is_gap_ended {
+                self.is_transfer_complete = true;
+                self.read_data = data;
+                if is_irq {
+                    self.is_disk_irq = true;
+                }
+            }
+        } else {
+            let mut data = 0;
+            if !self.is_crc_control {
+                self.is_transfer_complete = true;
+                data = self.write_data;
+                if is_irq {
+                    self.is_disk_irq = true;
+                }
+            }
+            if !self.is_drive_ready {
+                data = 0;
+            }
+            if !self.is_crc_control {
+                self.crc = update_crc(self.crc, data);
+            } else {
+                if !self.was_crc_control {
+                    self.crc = update_crc(update_crc(self.crc, 0), 0);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            self.disks[side][self.position] = data;
+            self.is_gap_ended = false;
+        }
+        self.was_crc_control = self.is_crc_control;
+
+        self.position += 1;
+        if self.position >= self.disks[side].len() {
+            self.is_motor_on = false;
+        } else {
+            self.delay = FDS_CYCLES_PER_BYTE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::{Rom, RomError};
+
+    //Bytes of the file on the test disk
+    const FILE_DATA: &[u8] = &[0xde, 0xad, 0xbe, 0xef];
+
+    //A side with the disk info block and one file on it
+    fn side() -> Vec<u8> {
+        let mut side = FDS_DISK_INFO_MAGIC.to_vec();
+        side.resize(56, 0);
+        side.extend_from_slice(&[FDS_FILE_AMOUNT_BLOCK, 1]);
+        let mut file_header = vec![FDS_FILE_HEADER_BLOCK; 16];
+        file_header[FDS_FILE_SIZE_OFFSET..FDS_FILE_SIZE_OFFSET + 2].copy_from_slice(&[FILE_DATA.len() as u8, 0]);
+        side.extend(file_header);
+        side.push(FDS_FILE_DATA_BLOCK);
+        side.extend_from_slice(FILE_DATA);
+        side.resize(FDS_SIDE_SIZE, 0);
+        side
+    }
+
+    fn fwnes(side_count: u8) -> Vec<u8> {
+        let mut binary = FWNES_MAGIC.to_vec();
+        binary.push(side_count);
+        binary.resize(FWNES_HEADER_SIZE, 0);
+        for _ in 0..side_count {
+            binary.extend(side());
+        }
+        binary
+    }
+
+    #[test]
+    fn parses_with_and_without_header() {
+        let binary = fwnes(2);
+        assert!(is_fds(&binary));
+        let image = FdsImage::parse(&binary).unwrap();
+        assert!(image.has_header);
+        assert_eq!(image.sides, vec![side(), side()]);
+        assert_eq!(image.side_offset(1), FWNES_HEADER_SIZE + FDS_SIDE_SIZE);
+
+        let binary = [side(), side(), side()].concat();
+        assert!(is_fds(&binary));
+        let image = FdsImage::parse(&binary).unwrap();
+        assert!(!image.has_header);
+        assert_eq!(image.sides.len(), 3);
+    }
+
+    #[test]
+    fn truncated() {
+        let binary = fwnes(2);
+        assert_eq!(
+            FdsImage::parse(&binary[..binary.len() - 1]).err(),
+            Some(FdsError::Truncated {
+                expected: binary.len(),
+                actual: binary.len() - 1
+            })
+        );
+        assert!(matches!(FdsImage::parse(&side()[..100]), Err(FdsError::Truncated { .. })));
+        assert!(matches!(FdsImage::parse(&fwnes(1)[..8]), Err(FdsError::Truncated { .. })));
+        assert_eq!(FdsImage::parse(&fwnes(0)).err(), Some(FdsError::NoSides));
+    }
+
+    #[test]
+    fn oversized() {
+        //Sides past what the header says are ignored, as is anything on the end that isn't a whole side
+        let mut binary = fwnes(2);
+        binary.extend(side());
+        assert_eq!(FdsImage::parse(&binary).unwrap().sides.len(), 2);
+        let binary = [side(), vec![0; 100]].concat();
+        assert_eq!(FdsImage::parse(&binary).unwrap().sides.len(), 1);
+        let mut rom = Rom::default();
+        rom.set_fds_bios(&[0; FDS_BIOS_SIZE]).unwrap();
+        let binary = side().repeat(40);
+        assert!(matches!(rom.load_bin(&binary), Err(RomError::TooLarge(_))));
+    }
+
+    #[test]
+    fn rejects_a_side_without_disk_info() {
+        let mut binary = fwnes(2);
+        binary[FWNES_HEADER_SIZE + FDS_SIDE_SIZE] = 0;
+        assert_eq!(FdsImage::parse(&binary).err(), Some(FdsError::BadSide(1)));
+    }
+
+    #[test]
+    fn gaps_come_back_out() {
+        let fds = Fds::new(FdsImage::parse(&fwnes(2)).unwrap());
+        assert_eq!(fds.disk_image(), fwnes(2));
+        //Nothing written, so nothing to save
+        assert_eq!(fds.save(), b"PATCHEOF");
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut fds = Fds::new(FdsImage::parse(&fwnes(2)).unwrap());
+        //Side B's file, as it sits on the disk
+        let disk = &mut fds.ram_mut()[2];
+        let file = disk.windows(FILE_DATA.len()).position(|data| data == FILE_DATA).unwrap();
+        disk[file] = 0x42;
+        let save = fds.save();
+
+        let mut fds = Fds::new(FdsImage::parse(&fwnes(2)).unwrap());
+        fds.load_save(&save).unwrap();
+        let mut expected = fwnes(2);
+        let side_b = FWNES_HEADER_SIZE + FDS_SIDE_SIZE;
+        let file = side_b + expected[side_b..].windows(FILE_DATA.len()).position(|data| data == FILE_DATA).unwrap();
+        expected[file] = 0x42;
+        assert_eq!(fds.disk_image(), expected);
+    }
+
+    #[test]
+    fn rejects_saves_for_other_disks() {
+        let mut fds = Fds::new(FdsImage::parse(&fwnes(1)).unwrap());
+        let other = fwnes(2);
+        let mut grown = other.clone();
+        grown[FWNES_HEADER_SIZE + FDS_SIDE_SIZE - 1] = 0x42;
+        let save = patch::create_ips(&fwnes(1), &grown);
+        assert_eq!(
+            fds.load_save(&save),
+            Err(FdsError::SaveSizeMismatch {
+                expected: fwnes(1).len(),
+                actual: other.len()
+            })
+        );
+        assert_eq!(
+            fds.load_save(b"PATCH\x00\x00"),
+            Err(FdsError::BadSave(PatchError::Truncated(patch::PatchFormat::Ips)))
+        );
+    }
+}
@@ -0,0 +1,137 @@
+/* Sunsoft FME-7 and 5A/5B */
+//https://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+//A command register at $8000 picks one of 16 internal registers and $A000 writes it: eight 1k CHR banks, four 8k
+//PRG banks (the one at $6000 can be RAM instead), mirroring and a 16 bit IRQ counter that counts down every CPU cycle.
+//The 5B is the same mapper with a sound chip bolted on, listening at $C000/$E000.
+use super::expansion_audio::Sunsoft5bAudio;
+use super::rom::{MirrorTable, NameTableSource};
+
+pub const FME7_MAPPER: u16 = 69;
+pub const FME7_PRG_ROM_MAX_SIZE: usize = 0x8_0000;
+pub const FME7_CHR_ROM_MAX_SIZE: usize = 0x4_0000;
+const FME7_PRG_BANK_SIZE: usize = 0x2000;
+const FME7_CHR_BANK_SIZE: usize = 0x0400;
+const FME7_PRG_RAM_SIZE: usize = 0x2000;
+const FME7_NUM_OF_CHR_BANKS: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct Fme7 {
+    command: u8,
+    chr_banks: [u8; FME7_NUM_OF_CHR_BANKS],
+    //Command 8: bit 6 puts RAM at $6000 instead of ROM, bit 7 enables it
+    prg_bank_6000: u8,
+    //$8000, $A000, $C000. $E000 is always the last bank
+    prg_banks: [u8; 3],
+    prg_ram: Vec<u8>,
+    //Vertical, horizontal, one screen A, one screen B
+    mirroring: u8,
+    is_irq_enabled: bool,
+    is_irq_counter_enabled: bool,
+    irq_counter: u16,
+    is_irq_pending: bool,
+    //Only the 5B has it, but nothing on a plain FME-7 board would write there
+    pub audio: Sunsoft5bAudio,
+}
+
+impl Default for Fme7 {
+    fn default() -> Self {
+        Self {
+            command: 0,
+            chr_banks: [0; FME7_NUM_OF_CHR_BANKS],
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            prg_ram: vec![0; FME7_PRG_RAM_SIZE],
+            mirroring: 0,
+            is_irq_enabled: false,
+            is_irq_counter_enabled: false,
+            irq_counter: 0,
+            is_irq_pending: false,
+            audio: Sunsoft5bAudio::default(),
+        }
+    }
+}
+
+impl Fme7 {
+    //PRG RAM, for snapshots
+    pub fn ram_mut(&mut self) -> [&mut Vec<u8>; 1] {
+        [&mut self.prg_ram]
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.prg_ram.capacity() + self.audio.heap_bytes()
+    }
+    fn prg_rom_index(bank: usize, addr: u16, prg_rom: &[u8]) -> usize {
+        (bank * FME7_PRG_BANK_SIZE + usize::from(addr & 0x1fff)) % prg_rom.len()
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&self, addr: u16, prg_rom: &[u8]) -> u8 {
+        match addr {
+            0x6000..=0x7fff => match self.prg_bank_6000 & 0xc0 {
+                0xc0 => self.prg_ram[usize::from(addr & 0x1fff)],
+                //RAM selected but disabled is open bus
+                0x40 => (addr >> 8) as u8,
+                _ => prg_rom[Self::prg_rom_index(usize::from(self.prg_bank_6000 & 0x3f), addr, prg_rom)],
+            },
+            0x8000..=0xdfff => {
+                let bank = self.prg_banks[usize::from((addr - 0x8000) >> 13)] & 0x3f;
+                prg_rom[Self::prg_rom_index(usize::from(bank), addr, prg_rom)]
+            }
+            0xe000..=0xffff => {
+                let last_bank = (prg_rom.len() / FME7_PRG_BANK_SIZE).saturating_sub(1);
+                prg_rom[Self::prg_rom_index(last_bank, addr, prg_rom)]
+            }
+            _ => (addr >> 8) as u8,
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if (self.prg_bank_6000 & 0xc0) == 0xc0 => {
+                self.prg_ram[usize::from(addr & 0x1fff)] = data;
+            }
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_parameter(data),
+            0xc000..=0xffff => self.audio.write_register(addr, data),
+            _ => {}
+        }
+    }
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[usize::from(self.command)] = data,
+            0x8 => self.prg_bank_6000 = data,
+            0x9..=0xb => self.prg_banks[usize::from(self.command - 0x9)] = data,
+            0xc => self.mirroring = data & 0x03,
+            //Any write acknowledges the IRQ
+            0xd => {
+                self.is_irq_enabled = (data & 0x01) == 0x01;
+                self.is_irq_counter_enabled = (data & 0x80) == 0x80;
+                self.is_irq_pending = false;
+            }
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | u16::from(data),
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | (u16::from(data) << 8),
+        }
+    }
+    pub fn chr_index(&self, addr: u16) -> usize {
+        usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]) * FME7_CHR_BANK_SIZE + usize::from(addr & 0x03ff)
+    }
+    pub fn name_table_source(&self) -> NameTableSource {
+        match self.mirroring {
+            0 => NameTableSource::Mirror(MirrorTable::Vertical),
+            1 => NameTableSource::Mirror(MirrorTable::Horizontal),
+            page => NameTableSource::Vram(usize::from(page - 2)),
+        }
+    }
+    //The counter goes off when it wraps from 0 to $FFFF
+    pub fn step(&mut self, cpu_cyc: usize) {
+        if !self.is_irq_counter_enabled {
+            return;
+        }
+        let (counter, is_wrapped) = self.irq_counter.overflowing_sub(cpu_cyc as u16);
+        self.irq_counter = counter;
+        if is_wrapped && self.is_irq_enabled {
+            self.is_irq_pending = true;
+        }
+    }
+    pub fn is_irq(&self) -> bool {
+        self.is_irq_pending
+    }
+}
@@ -0,0 +1,87 @@
+/* NES Four Score and the Famicom Hori 4 player adapter */
+//https://wiki.nesdev.com/w/index.php/Four_player_adapters
+//Both turn two controller ports into a 24 bit report: 8 buttons for one player, 8 for another, then a signature byte
+//games check to see whether the adapter is plugged in at all. The Four Score answers on D0 of $4016/$4017, the Hori
+//sits on the Famicom expansion port and answers on D1 with the signatures swapped.
+use super::pad::Pad;
+use wasm_bindgen::prelude::*;
+
+//After the report the Four Score pulls the line high, like an official controller does after 8 reads
+const FOUR_PLAYER_REPORT_BITS: u8 = 24;
+//Written the way games read them, first bit into the top of the byte
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0x10, 0x20];
+const HORI_SIGNATURES: [u8; 2] = [0x20, 0x10];
+
+#[wasm_bindgen]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum FourPlayerMode {
+    //Four Score if the cartridge header asks for one, plain controllers otherwise
+    #[default]
+    Auto,
+    //Players 1 and 2 on the front ports, 3 and 4 on the Famicom expansion port with no signature
+    Off,
+    FourScore,
+    Hori,
+}
+
+impl FourPlayerMode {
+    //NES 2.0 expansion device 2 is the Four Score
+    //https://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+    pub fn resolve(self, expansion_device: u8) -> FourPlayerMode {
+        match (self, expansion_device) {
+            (FourPlayerMode::Auto, 0x02) => FourPlayerMode::FourScore,
+            (FourPlayerMode::Auto, _) => FourPlayerMode::Off,
+            (mode, _) => mode,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FourPlayerAdapter {
+    pub mode: FourPlayerMode,
+    //One shift position per port
+    read_index: [u8; 2],
+    strobe_enable: bool,
+}
+
+impl Default for FourPlayerAdapter {
+    fn default() -> Self {
+        Self {
+            mode: FourPlayerMode::Off,
+            read_index: [0; 2],
+            strobe_enable: false,
+        }
+    }
+}
+
+impl FourPlayerAdapter {
+    pub fn reset(&mut self) {
+        self.read_index = [0; 2];
+        self.strobe_enable = false;
+    }
+    pub fn write_strobe(&mut self, is_enable: bool) {
+        self.strobe_enable = is_enable;
+        if is_enable {
+            self.read_index = [0; 2];
+        }
+    }
+    //Next bit of the report for $4016 (port 0) or $4017 (port 1), first is the player on that port and second the
+    //one on the adapter's extra socket behind it
+    pub fn read_out(&mut self, port: usize, first: &Pad, second: &Pad) -> u8 {
+        let signature = match self.mode {
+            FourPlayerMode::Hori => HORI_SIGNATURES[port],
+            _ => FOUR_SCORE_SIGNATURES[port],
+        };
+        let index = self.read_index[port];
+        let data = match index {
+            0..=7 => first.buttons() >> index,
+            8..=15 => second.buttons() >> (index - 8),
+            16..=23 => signature >> (23 - index),
+            _ => 0x01,
+        } & 0x01;
+        if !self.strobe_enable && index < FOUR_PLAYER_REPORT_BITS {
+            self.read_index[port] = index + 1;
+        }
+        data
+    }
+}
@@ -0,0 +1,181 @@
+/* Framebuffer output formats */
+//The PPU draws palette indexes, this looks them up in the palette and lays the colors out however the host wants them.
+//Rgba8888 is byte for byte what a canvas ImageData holds, so js can wrap the output buffer in a Uint8ClampedArray
+//and hand it straight to putImageData with nothing copied on its side.
+use super::ntsc::{NtscFilter, NTSC_OUTPUT_WIDTH};
+use super::palette::Palette;
+use super::ppu::{VISIBLE_SCREEN_HEIGHT, VISIBLE_SCREEN_WIDTH};
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+//9 bit palette indexes, the 6 bit color with the 3 emphasis bits above it
+pub type FrameBuffer = [[u16; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT];
+
+#[wasm_bindgen]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum PixelFormat {
+    //R, G, B bytes
+    Rgb888,
+    //R, G, B, A bytes with A always 255, the layout of ImageData
+    #[default]
+    Rgba8888,
+    //16 bits, 5 red 6 green 5 blue from the top, in native byte order like a u16 texture expects
+    Rgb565,
+    //32 bits 0xAARRGGBB in native byte order, what SDL and Direct3D call ARGB8888. B, G, R, A bytes on little endian
+    Argb8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Argb8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+    //Bytes in a row with nothing between rows
+    pub fn min_pitch(self) -> usize {
+        self.bytes_per_pixel() * VISIBLE_SCREEN_WIDTH
+    }
+    pub fn write_pixel(self, out: &mut [u8], r: u8, g: u8, b: u8) {
+        match self {
+            PixelFormat::Rgb888 => out.copy_from_slice(&[r, g, b]),
+            PixelFormat::Rgba8888 => out.copy_from_slice(&[r, g, b, 0xff]),
+            PixelFormat::Rgb565 => {
+                let pixel = (u16::from(r >> 3) << 11) | (u16::from(g >> 2) << 5) | u16::from(b >> 3);
+                out.copy_from_slice(&pixel.to_ne_bytes());
+            }
+            PixelFormat::Argb8888 => {
+                let pixel = 0xff00_0000 | (u32::from(r) << 16) | (u32::from(g) << 8) | u32::from(b);
+                out.copy_from_slice(&pixel.to_ne_bytes());
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FramebufferError {
+    //Pitch that was asked for, and the least a row needs
+    PitchTooSmall(usize, usize),
+    //Length of the buffer, and the least the picture needs at that pitch
+    BufferTooSmall(usize, usize),
+}
+
+impl fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FramebufferError::PitchTooSmall(pitch, min) => {
+                write!(f, "Pitch of {} bytes is less than a {} byte row", pitch, min)
+            }
+            FramebufferError::BufferTooSmall(len, min) => {
+                write!(f, "Buffer of {} bytes is less than the {} bytes the picture needs", len, min)
+            }
+        }
+    }
+}
+
+//Writes the picture into out with each row starting pitch bytes after the last, for hosts drawing into a locked
+//texture or a window surface whose rows are padded. The padding between rows is left alone
+pub fn render(
+    fb: &FrameBuffer,
+    palette: &Palette,
+    format: PixelFormat,
+    out: &mut [u8],
+    pitch: usize,
+) -> Result<(), FramebufferError> {
+    let row_bytes = format.min_pitch();
+    if pitch < row_bytes {
+        return Err(FramebufferError::PitchTooSmall(pitch, row_bytes));
+    }
+    let needed = pitch * (VISIBLE_SCREEN_HEIGHT - 1) + row_bytes;
+    if out.len() < needed {
+        return Err(FramebufferError::BufferTooSmall(out.len(), needed));
+    }
+    let bytes_per_pixel = format.bytes_per_pixel();
+    for (row, out_row) in fb.iter().zip(out.chunks_mut(pitch)) {
+        for (&index, out_pixel) in row.iter().zip(out_row[..row_bytes].chunks_exact_mut(bytes_per_pixel)) {
+            let color = palette.color(index);
+            format.write_pixel(out_pixel, color.0, color.1, color.2);
+        }
+    }
+    Ok(())
+}
+
+//The buffer js draws from. It's brought up to date when asked for rather than on every frame, run-ahead and rewind
+//run frames nobody sees
+#[derive(Default)]
+pub struct FrameOutput {
+    pub format: PixelFormat,
+    pixels: Vec<u8>,
+    //A frame has been drawn since pixels was last filled in
+    is_stale: bool,
+    //Decode the picture as composite video rather than through the palette, it comes out wider
+    ntsc: Option<NtscFilter>,
+}
+
+impl FrameOutput {
+    pub fn set_format(&mut self, format: PixelFormat) {
+        self.format = format;
+        self.is_stale = true;
+    }
+    pub fn set_ntsc(&mut self, ntsc: Option<NtscFilter>) {
+        self.ntsc = ntsc;
+        self.is_stale = true;
+    }
+    pub fn ntsc(&self) -> Option<&NtscFilter> {
+        self.ntsc.as_ref()
+    }
+    pub fn invalidate(&mut self) {
+        self.is_stale = true;
+    }
+    //A new frame has started, which also moves the composite color cycle on
+    pub fn next_frame(&mut self) {
+        if let Some(ntsc) = &mut self.ntsc {
+            ntsc.next_frame();
+        }
+        self.is_stale = true;
+    }
+    pub fn width(&self) -> usize {
+        if self.ntsc.is_some() {
+            NTSC_OUTPUT_WIDTH
+        } else {
+            VISIBLE_SCREEN_WIDTH
+        }
+    }
+    pub fn size(&self) -> usize {
+        self.width() * self.format.bytes_per_pixel() * VISIBLE_SCREEN_HEIGHT
+    }
+    //Fills in the picture if it's out of date and hands back the buffer. The buffer only moves when the format or
+    //the filter change to one that needs more room
+    pub fn update(&mut self, fb: &FrameBuffer, palette: &Palette) -> &[u8] {
+        let len = self.size();
+        if self.pixels.len() != len {
+            self.pixels.resize(len, 0);
+            self.is_stale = true;
+        }
+        if self.is_stale {
+            let pitch = self.width() * self.format.bytes_per_pixel();
+            //Can't fail, the buffer was just sized for it
+            let _ = match &mut self.ntsc {
+                Some(ntsc) => ntsc.render(fb, self.format, &mut self.pixels, pitch),
+                None => render(fb, palette, self.format, &mut self.pixels, pitch),
+            };
+            self.is_stale = false;
+        }
+        &self.pixels
+    }
+    //Same picture into a buffer of the caller's, through the filter if it's on
+    pub fn render_into(
+        &mut self,
+        fb: &FrameBuffer,
+        palette: &Palette,
+        format: PixelFormat,
+        out: &mut [u8],
+        pitch: usize,
+    ) -> Result<(), FramebufferError> {
+        match &mut self.ntsc {
+            Some(ntsc) => ntsc.render(fb, format, out, pitch),
+            None => render(fb, palette, format, out, pitch),
+        }
+    }
+}
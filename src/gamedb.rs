@@ -0,0 +1,144 @@
+/* Built in game database, for dumps with wrong or missing header info */
+//The table itself is generated by build.rs from whatever NES 2.0 XML and DAT files are in db/
+//Lookups are by CRC32 of PRG ROM followed by CHR ROM, confirmed with SHA-1 when the database has one
+use sha1_smol::Sha1;
+
+use super::header::CartridgeInfo;
+use super::region::Region;
+use super::rom::MirrorTable;
+
+#[derive(Clone, Debug)]
+pub struct GameDbEntry {
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    //Everything below is None when the database doesn't know, in which case the header is trusted
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirror_table: Option<MirrorTable>,
+    pub timing: Option<Region>,
+    pub has_battery: Option<bool>,
+    pub expansion_device: Option<u8>,
+    pub title: Option<&'static str>,
+    pub publisher: Option<&'static str>,
+    pub year: Option<u16>,
+}
+
+impl GameDbEntry {
+    //Overwrite whatever the header got wrong with what the database knows
+    pub fn apply(&self, info: &mut CartridgeInfo) {
+        if let Some(mapper) = self.mapper {
+            info.mapper = mapper;
+        }
+        if let Some(submapper) = self.submapper {
+            info.submapper = submapper;
+        }
+        if let Some(mirror_table) = self.mirror_table {
+            info.mirror_table = mirror_table;
+        }
+        if let Some(timing) = self.timing {
+            info.timing = timing;
+        }
+        if let Some(has_battery) = self.has_battery {
+            //INES puts battery RAM in the PRG RAM count, move it over so save data is kept in the right place
+            if has_battery && info.prg_nvram_bytes == 0 {
+                info.prg_nvram_bytes = info.prg_ram_bytes;
+                info.prg_ram_bytes = 0;
+            } else if !has_battery && info.prg_ram_bytes == 0 {
+                info.prg_ram_bytes = info.prg_nvram_bytes;
+                info.prg_nvram_bytes = 0;
+            }
+            info.has_battery = has_battery;
+        }
+        if let Some(expansion_device) = self.expansion_device {
+            info.expansion_device = expansion_device;
+        }
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/game_db.rs"));
+
+//Look up a dump by its PRG and CHR contents
+pub fn lookup(prg_rom: &[u8], chr_rom: &[u8]) -> Option<&'static GameDbEntry> {
+    lookup_in(GAME_DB, prg_rom, chr_rom)
+}
+
+//Same again in any table sorted by CRC32
+fn lookup_in<'a>(table: &'a [GameDbEntry], prg_rom: &[u8], chr_rom: &[u8]) -> Option<&'a GameDbEntry> {
+    if table.is_empty() {
+        return None;
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(prg_rom);
+    hasher.update(chr_rom);
+    let crc32 = hasher.finalize();
+
+    let index = table.binary_search_by_key(&crc32, |entry| entry.crc32).ok()?;
+    let entry = &table[index];
+    //CRC32 collisions are rare but not unheard of across a whole game library
+    if let Some(sha1) = entry.sha1 {
+        let mut hasher = Sha1::new();
+        hasher.update(prg_rom);
+        hasher.update(chr_rom);
+        if hasher.digest().bytes() != sha1 {
+            return None;
+        }
+    }
+    Some(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::nrom;
+
+    //The test cartridge as the database has it: vertical mirroring, PAL and a battery, where its header says
+    //horizontal, NTSC and none
+    fn entry(prg_rom: &[u8], chr_rom: &[u8]) -> GameDbEntry {
+        let mut hasher = Sha1::new();
+        hasher.update(prg_rom);
+        hasher.update(chr_rom);
+        GameDbEntry {
+            crc32: crc32fast::hash(&[prg_rom, chr_rom].concat()),
+            sha1: Some(hasher.digest().bytes()),
+            mapper: Some(0),
+            submapper: Some(0),
+            mirror_table: Some(MirrorTable::Vertical),
+            timing: Some(Region::Pal),
+            has_battery: Some(true),
+            expansion_device: None,
+            title: Some("Test Cartridge"),
+            publisher: None,
+            year: None,
+        }
+    }
+
+    #[test]
+    fn fixes_a_bad_header() {
+        let binary = nrom();
+        let mut info = CartridgeInfo::parse(&binary).unwrap();
+        let (prg_rom, chr_rom) = binary[16..].split_at(0x8000);
+        let table = [entry(prg_rom, chr_rom)];
+        assert!(matches!(info.mirror_table, MirrorTable::Horizontal));
+        lookup_in(&table, prg_rom, chr_rom).unwrap().apply(&mut info);
+        assert!(matches!(info.mirror_table, MirrorTable::Vertical));
+        assert_eq!(info.timing, Region::Pal);
+        assert!(info.has_battery);
+        assert_eq!(info.prg_nvram_bytes, 0x2000);
+    }
+
+    #[test]
+    fn needs_the_sha1_to_match_too() {
+        let binary = nrom();
+        let (prg_rom, chr_rom) = binary[16..].split_at(0x8000);
+        let mut table = [entry(prg_rom, chr_rom)];
+        table[0].sha1 = Some([0; 20]);
+        assert!(lookup_in(&table, prg_rom, chr_rom).is_none());
+        assert!(lookup_in(&[], prg_rom, chr_rom).is_none());
+    }
+
+    #[test]
+    fn shipped_table_is_sorted() {
+        assert!(!GAME_DB.is_empty());
+        assert!(GAME_DB.windows(2).all(|pair| pair[0].crc32 < pair[1].crc32));
+    }
+}
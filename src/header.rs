@@ -0,0 +1,392 @@
+/* INES and NES 2.0 header parsing */
+//https://wiki.nesdev.com/w/index.php/INES
+//https://wiki.nesdev.com/w/index.php/NES_2.0
+//The header is 16 bytes in front of every .nes file. There are three flavours in the wild: archaic INES from before anyone
+//agreed on bytes 7-15, INES 1.0, and NES 2.0 which finally gives exact memory sizes and timing. We turn all of them into
+//one CartridgeInfo so the rest of the emulator doesn't need to care which one it got.
+use std::fmt;
+
+use super::rom::MirrorTable;
+use super::region::Region;
+
+pub const INES_HEADER_SIZE: usize = 16;
+pub const INES_TRAINER_SIZE: usize = 0x0200;
+pub const PRG_ROM_BANK_SIZE: usize = 0x4000;
+pub const CHR_ROM_BANK_SIZE: usize = 0x2000;
+pub const INES_PRG_RAM_BANK_SIZE: usize = 0x2000;
+//What INES 1.0 carts get when they don't say otherwise
+pub const INES_DEFAULT_CHR_RAM_SIZE: usize = 0x2000;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum HeaderFormat {
+    //Bytes 7-15 are garbage (usually "DiskDude!"), only the lower mapper nibble is trustworthy
+    ArchaicINes,
+    INes,
+    Nes2,
+    //Not an INES header at all, see unif.rs
+    Unif,
+    //A music rip rather than a cartridge, see nsf.rs
+    Nsf,
+    //A disk image for the Famicom Disk System, see fds.rs
+    Fds,
+}
+
+//https://wiki.nesdev.com/w/index.php/NES_2.0#Console_Type
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    //Famiclones with extra opcodes, the VT chips and so on, see byte 13
+    Extended(u8),
+}
+
+#[derive(Clone, Debug)]
+pub struct CartridgeInfo {
+    pub format: HeaderFormat,
+    //12 bits on NES 2.0, 8 on INES and 4 on archaic headers
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirror_table: MirrorTable,
+    //Battery backed memory of some sort, the NVRAM sizes below say how much
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub prg_rom_bytes: usize,
+    pub chr_rom_bytes: usize,
+    pub prg_ram_bytes: usize,
+    pub prg_nvram_bytes: usize,
+    pub chr_ram_bytes: usize,
+    pub chr_nvram_bytes: usize,
+    //UNIF's VROR, the CHR ROM is really the initial contents of CHR-RAM and the game writes over it
+    pub is_chr_rom_writable: bool,
+    //Auto when the header doesn't say or the game runs on any system
+    pub timing: Region,
+    pub console_type: ConsoleType,
+    //Byte 13, PPU and hardware type on Vs. System, the extended console type otherwise
+    pub vs_ppu_type: u8,
+    pub vs_hardware_type: u8,
+    pub misc_roms: u8,
+    //https://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+    pub expansion_device: u8,
+    //Only UNIF and NSF files carry the game's name
+    pub title: Option<String>,
+}
+
+impl Default for CartridgeInfo {
+    fn default() -> Self {
+        Self {
+            format: HeaderFormat::INes,
+            mapper: 0,
+            submapper: 0,
+            mirror_table: MirrorTable::Unknown,
+            has_battery: false,
+            has_trainer: false,
+            prg_rom_bytes: 0,
+            chr_rom_bytes: 0,
+            prg_ram_bytes: 0,
+            prg_nvram_bytes: 0,
+            chr_ram_bytes: 0,
+            chr_nvram_bytes: 0,
+            is_chr_rom_writable: false,
+            timing: Region::Auto,
+            console_type: ConsoleType::Nes,
+            vs_ppu_type: 0,
+            vs_hardware_type: 0,
+            misc_roms: 0,
+            expansion_device: 0,
+            title: None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum HeaderError {
+    //The first 4 bytes weren't N E S and the line break
+    BadMagic,
+    //Not even 16 bytes to read a header out of
+    TruncatedHeader(usize),
+    //Every cartridge has program code, a size of 0 means the header is broken
+    NoPrgRom,
+    //The NES 2.0 exponent-multiplier form can describe sizes no real cartridge (or address space) has
+    PrgRomTooLarge,
+    ChrRomTooLarge,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::BadMagic => write!(f, "not an INES file, the header doesn't start with NES<EOF>"),
+            HeaderError::TruncatedHeader(len) => write!(
+                f,
+                "file is only {} bytes, too short for a {} byte INES header",
+                len, INES_HEADER_SIZE
+            ),
+            HeaderError::NoPrgRom => write!(f, "header says there is no PRG ROM"),
+            HeaderError::PrgRomTooLarge => write!(f, "header PRG ROM size is impossibly large"),
+            HeaderError::ChrRomTooLarge => write!(f, "header CHR ROM size is impossibly large"),
+        }
+    }
+}
+
+//Anything past this is a corrupt header, the largest real dumps are a few MiB
+const ROM_SIZE_LIMIT: usize = 0x4000_0000;
+
+//NES 2.0 ROM sizes, either a 12 bit bank count or, when the upper nibble is all ones, 2^E * (MM*2+1) bytes
+fn nes2_rom_size(lsb: u8, msb: u8, bank_size: usize) -> Option<usize> {
+    if msb == 0x0f {
+        let exponent = u32::from(lsb >> 2);
+        let multiplier = usize::from(lsb & 0x03) * 2 + 1;
+        1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .filter(|size| *size <= ROM_SIZE_LIMIT)
+    } else {
+        Some(((usize::from(msb) << 8) | usize::from(lsb)) * bank_size)
+    }
+}
+
+//NES 2.0 RAM sizes are a shift count, 0 means none at all
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64usize << shift
+    }
+}
+
+impl CartridgeInfo {
+    //Takes the whole image, not just the header, since telling NES 2.0 apart from a garbage filled header
+    //means checking whether the sizes it claims actually fit in the file
+    pub fn parse(binary: &[u8]) -> Result<CartridgeInfo, HeaderError> {
+        if binary.len() < INES_HEADER_SIZE {
+            return Err(HeaderError::TruncatedHeader(binary.len()));
+        }
+        if binary[0..4] != [0x4e, 0x45, 0x53, 0x1a] {
+            return Err(HeaderError::BadMagic);
+        }
+        let flags6 = binary[6];
+        let flags7 = binary[7];
+
+        let mut info = CartridgeInfo {
+            has_trainer: (flags6 & 0x04) == 0x04,
+            has_battery: (flags6 & 0x02) == 0x02,
+            mirror_table: if (flags6 & 0x08) == 0x08 {
+                MirrorTable::FourScreen
+            } else if (flags6 & 0x01) == 0x01 {
+                MirrorTable::Vertical
+            } else {
+                MirrorTable::Horizontal
+            },
+            ..CartridgeInfo::default()
+        };
+        let trainer_bytes = if info.has_trainer { INES_TRAINER_SIZE } else { 0 };
+
+        //https://wiki.nesdev.com/w/index.php/NES_2.0#Identification
+        let nes2_prg_rom_bytes = nes2_rom_size(binary[4], binary[9] & 0x0f, PRG_ROM_BANK_SIZE);
+        let nes2_chr_rom_bytes = nes2_rom_size(binary[5], binary[9] >> 4, CHR_ROM_BANK_SIZE);
+        let is_nes2_sized = match (nes2_prg_rom_bytes, nes2_chr_rom_bytes) {
+            (Some(prg), Some(chr)) => INES_HEADER_SIZE + trainer_bytes + prg + chr <= binary.len(),
+            _ => false,
+        };
+        info.format = match flags7 & 0x0c {
+            0x08 if is_nes2_sized => HeaderFormat::Nes2,
+            0x00 if binary[12..16].iter().all(|b| *b == 0) => HeaderFormat::INes,
+            _ => HeaderFormat::ArchaicINes,
+        };
+
+        match info.format {
+            HeaderFormat::Nes2 => {
+                info.mapper = u16::from(flags6 >> 4)
+                    | u16::from(flags7 & 0xf0)
+                    | (u16::from(binary[8] & 0x0f) << 8);
+                info.submapper = binary[8] >> 4;
+                info.prg_rom_bytes = nes2_prg_rom_bytes.ok_or(HeaderError::PrgRomTooLarge)?;
+                info.chr_rom_bytes = nes2_chr_rom_bytes.ok_or(HeaderError::ChrRomTooLarge)?;
+                info.prg_ram_bytes = nes2_ram_size(binary[10] & 0x0f);
+                info.prg_nvram_bytes = nes2_ram_size(binary[10] >> 4);
+                info.chr_ram_bytes = nes2_ram_size(binary[11] & 0x0f);
+                info.chr_nvram_bytes = nes2_ram_size(binary[11] >> 4);
+                info.timing = match binary[12] & 0x03 {
+                    0 => Region::Ntsc,
+                    1 => Region::Pal,
+                    3 => Region::Dendy,
+                    _ => Region::Auto, // multiple-region
+                };
+                info.console_type = match flags7 & 0x03 {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem,
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Extended(binary[13] & 0x0f),
+                };
+                if info.console_type == ConsoleType::VsSystem {
+                    info.vs_ppu_type = binary[13] & 0x0f;
+                    info.vs_hardware_type = binary[13] >> 4;
+                }
+                info.misc_roms = binary[14] & 0x03;
+                info.expansion_device = binary[15] & 0x3f;
+            }
+            _ => {
+                let is_archaic = info.format == HeaderFormat::ArchaicINes;
+                info.mapper = if is_archaic {
+                    u16::from(flags6 >> 4)
+                } else {
+                    u16::from(flags6 >> 4) | u16::from(flags7 & 0xf0)
+                };
+                info.prg_rom_bytes = usize::from(binary[4]) * PRG_ROM_BANK_SIZE;
+                info.chr_rom_bytes = usize::from(binary[5]) * CHR_ROM_BANK_SIZE;
+                //INES only has a rarely filled in PRG RAM count, 0 meaning the traditional 8k
+                let prg_ram_bank = if is_archaic { 0 } else { usize::from(binary[8]) };
+                let prg_ram_bytes = prg_ram_bank.max(1) * INES_PRG_RAM_BANK_SIZE;
+                if info.has_battery {
+                    info.prg_nvram_bytes = prg_ram_bytes;
+                } else {
+                    info.prg_ram_bytes = prg_ram_bytes;
+                }
+                if info.chr_rom_bytes == 0 {
+                    info.chr_ram_bytes = INES_DEFAULT_CHR_RAM_SIZE;
+                }
+                if !is_archaic {
+                    info.timing = if (binary[9] & 0x01) == 0x01 {
+                        Region::Pal
+                    } else {
+                        Region::Auto
+                    };
+                    info.console_type = if (flags7 & 0x01) == 0x01 {
+                        ConsoleType::VsSystem
+                    } else if (flags7 & 0x02) == 0x02 {
+                        ConsoleType::Playchoice10
+                    } else {
+                        ConsoleType::Nes
+                    };
+                }
+            }
+        }
+        if info.prg_rom_bytes == 0 {
+            return Err(HeaderError::NoPrgRom);
+        }
+        Ok(info)
+    }
+    //Where things live in the file
+    pub fn trainer_offset(&self) -> usize {
+        INES_HEADER_SIZE
+    }
+    pub fn prg_rom_offset(&self) -> usize {
+        INES_HEADER_SIZE + if self.has_trainer { INES_TRAINER_SIZE } else { 0 }
+    }
+    pub fn chr_rom_offset(&self) -> usize {
+        self.prg_rom_offset() + self.prg_rom_bytes
+    }
+    //Total bytes the header says the file should have, not counting misc ROMs tacked on the end
+    pub fn image_size(&self) -> usize {
+        self.chr_rom_offset() + self.chr_rom_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Header bytes 4-15 followed by enough zeros for the ROM it describes, or fewer
+    fn image(flags: [u8; 12], body_bytes: usize) -> Vec<u8> {
+        let mut binary = vec![0x4e, 0x45, 0x53, 0x1a];
+        binary.extend_from_slice(&flags);
+        binary.resize(INES_HEADER_SIZE + body_bytes, 0);
+        binary
+    }
+
+    #[test]
+    fn ines() {
+        let binary = image([2, 1, 0x43, 0x10, 0, 1, 0, 0, 0, 0, 0, 0], 0x8000 + 0x2000);
+        let info = CartridgeInfo::parse(&binary).unwrap();
+        assert_eq!(info.format, HeaderFormat::INes);
+        assert_eq!(info.mapper, 0x14);
+        assert!(matches!(info.mirror_table, MirrorTable::Vertical));
+        assert!(info.has_battery);
+        assert_eq!(info.prg_rom_bytes, 0x8000);
+        assert_eq!(info.chr_rom_bytes, 0x2000);
+        assert_eq!(info.prg_ram_bytes, 0);
+        assert_eq!(info.prg_nvram_bytes, INES_PRG_RAM_BANK_SIZE);
+        assert_eq!(info.chr_ram_bytes, 0);
+        assert_eq!(info.timing, Region::Pal);
+        assert_eq!(info.image_size(), binary.len());
+    }
+
+    #[test]
+    fn ines_trainer_and_chr_ram() {
+        let binary = image([1, 0, 0x0c, 0, 2, 0, 0, 0, 0, 0, 0, 0], INES_TRAINER_SIZE + 0x4000);
+        let info = CartridgeInfo::parse(&binary).unwrap();
+        assert!(info.has_trainer);
+        assert!(matches!(info.mirror_table, MirrorTable::FourScreen));
+        assert_eq!(info.prg_ram_bytes, 2 * INES_PRG_RAM_BANK_SIZE);
+        assert_eq!(info.chr_ram_bytes, INES_DEFAULT_CHR_RAM_SIZE);
+        assert_eq!(info.prg_rom_offset(), INES_HEADER_SIZE + INES_TRAINER_SIZE);
+        assert_eq!(info.image_size(), binary.len());
+    }
+
+    #[test]
+    fn archaic_ines_ignores_garbage() {
+        let mut flags = [1, 1, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        flags[3..].copy_from_slice(b"DiskDude!");
+        let info = CartridgeInfo::parse(&image(flags, 0x6000)).unwrap();
+        assert_eq!(info.format, HeaderFormat::ArchaicINes);
+        assert_eq!(info.mapper, 1);
+        assert_eq!(info.timing, Region::Auto);
+        assert_eq!(info.console_type, ConsoleType::Nes);
+        assert_eq!(info.prg_ram_bytes, INES_PRG_RAM_BANK_SIZE);
+    }
+
+    #[test]
+    fn nes2() {
+        let binary = image([2, 0, 0x51, 0x29, 0x31, 0x00, 0x97, 0x07, 0x03, 0x21, 0x01, 0x2a], 0x8000);
+        let info = CartridgeInfo::parse(&binary).unwrap();
+        assert_eq!(info.format, HeaderFormat::Nes2);
+        assert_eq!(info.mapper, 0x125);
+        assert_eq!(info.submapper, 3);
+        assert_eq!(info.prg_rom_bytes, 0x8000);
+        assert_eq!(info.chr_rom_bytes, 0);
+        assert_eq!(info.prg_ram_bytes, 64 << 7);
+        assert_eq!(info.prg_nvram_bytes, 64 << 9);
+        assert_eq!(info.chr_ram_bytes, 64 << 7);
+        assert_eq!(info.chr_nvram_bytes, 0);
+        assert_eq!(info.timing, Region::Dendy);
+        assert_eq!(info.console_type, ConsoleType::VsSystem);
+        assert_eq!(info.vs_ppu_type, 1);
+        assert_eq!(info.vs_hardware_type, 2);
+        assert_eq!(info.misc_roms, 1);
+        assert_eq!(info.expansion_device, 0x2a);
+    }
+
+    #[test]
+    fn nes2_exponent_sizes() {
+        //2^14 * 3 bytes of PRG ROM
+        let binary = image([(14 << 2) | 1, 0, 0, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0], 0xc000);
+        let info = CartridgeInfo::parse(&binary).unwrap();
+        assert_eq!(info.format, HeaderFormat::Nes2);
+        assert_eq!(info.prg_rom_bytes, 0xc000);
+        assert_eq!(nes2_rom_size(0xff, 0x0f, PRG_ROM_BANK_SIZE), None);
+    }
+
+    #[test]
+    fn nes2_sizes_that_dont_fit_are_archaic() {
+        //A NES 2.0 marker but far more ROM than the file has, more likely garbage than a NES 2.0 header
+        let binary = image([2, 1, 0, 0x08, 0, 0x10, 0, 0, 0, 0, 0, 0], 0xa000);
+        assert_eq!(CartridgeInfo::parse(&binary).unwrap().format, HeaderFormat::ArchaicINes);
+        //Or an exponent too big to be a size at all
+        let binary = image([0xff, 1, 0, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0], 0xa000);
+        assert_eq!(CartridgeInfo::parse(&binary).unwrap().format, HeaderFormat::ArchaicINes);
+    }
+
+    #[test]
+    fn bad_headers() {
+        let binary = image([2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0);
+        assert_eq!(
+            CartridgeInfo::parse(&binary[..INES_HEADER_SIZE - 1]).err(),
+            Some(HeaderError::TruncatedHeader(INES_HEADER_SIZE - 1))
+        );
+        let mut bad_magic = binary.clone();
+        bad_magic[3] = 0;
+        assert_eq!(CartridgeInfo::parse(&bad_magic).err(), Some(HeaderError::BadMagic));
+        let no_prg_rom = image([0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0x2000);
+        assert_eq!(CartridgeInfo::parse(&no_prg_rom).err(), Some(HeaderError::NoPrgRom));
+    }
+}
@@ -0,0 +1,167 @@
+/* Controller port and expansion port devices */
+//https://wiki.nesdev.com/w/index.php/Input_devices
+//Everything plugged into the console reads back through $4016/$4017. A write to $4016 strobes every device at once,
+//then each read returns whichever data lines the devices on that port drive: D0 for controllers, D3/D4 for the
+//Zapper and friends, D1-D4 for the Famicom expansion port.
+use super::arkanoid::ArkanoidPaddle;
+use super::pad::Pad;
+use super::power_pad::PowerPad;
+use super::snes_mouse::SnesMouse;
+use super::zapper::Zapper;
+use wasm_bindgen::prelude::*;
+
+//Port numbers for set_input_device, the expansion port comes after the two front ports
+pub const NUM_OF_INPUT_PORTS: usize = 3;
+pub const EXPANSION_PORT: usize = 2;
+
+#[wasm_bindgen]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum InputDeviceKind {
+    //Whatever the cartridge's NES 2.0 header says belongs in the port, controllers otherwise
+    #[default]
+    Auto,
+    //Standard controllers, or players 3 and 4 on the expansion port
+    Pad,
+    Zapper,
+    ArkanoidPaddle,
+    //Family Trainer on the expansion port
+    PowerPad,
+    //Front ports only
+    SnesMouse,
+}
+
+impl InputDeviceKind {
+    //https://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+    pub fn resolve(self, port: usize, expansion_device: u8) -> InputDeviceKind {
+        if self != InputDeviceKind::Auto {
+            return self;
+        }
+        match (port, expansion_device) {
+            (1, 0x08) => InputDeviceKind::Zapper,
+            (1, 0x0b) | (1, 0x0c) => InputDeviceKind::PowerPad,
+            (1, 0x0f) => InputDeviceKind::ArkanoidPaddle,
+            (EXPANSION_PORT, 0x0d) | (EXPANSION_PORT, 0x0e) => InputDeviceKind::PowerPad,
+            (EXPANSION_PORT, 0x10) => InputDeviceKind::ArkanoidPaddle,
+            _ => InputDeviceKind::Pad,
+        }
+    }
+}
+
+//What's in one of the front ports
+#[derive(Clone, Debug)]
+pub enum InputDevice {
+    Pad(Pad),
+    Zapper(Zapper),
+    ArkanoidPaddle(ArkanoidPaddle),
+    PowerPad(PowerPad),
+    SnesMouse(SnesMouse),
+}
+
+impl InputDevice {
+    pub fn new(kind: InputDeviceKind) -> Self {
+        match kind {
+            InputDeviceKind::Zapper => InputDevice::Zapper(Zapper::default()),
+            InputDeviceKind::ArkanoidPaddle => InputDevice::ArkanoidPaddle(ArkanoidPaddle::default()),
+            InputDeviceKind::PowerPad => InputDevice::PowerPad(PowerPad::default()),
+            InputDeviceKind::SnesMouse => InputDevice::SnesMouse(SnesMouse::default()),
+            InputDeviceKind::Auto | InputDeviceKind::Pad => InputDevice::Pad(Pad::default()),
+        }
+    }
+    pub fn reset(&mut self) {
+        match self {
+            InputDevice::Pad(pad) => pad.reset(),
+            InputDevice::Zapper(zapper) => zapper.reset(),
+            InputDevice::ArkanoidPaddle(paddle) => paddle.reset(),
+            InputDevice::PowerPad(power_pad) => power_pad.reset(),
+            InputDevice::SnesMouse(mouse) => mouse.reset(),
+        }
+    }
+    pub fn write_strobe(&mut self, data: u8) {
+        let is_enable = (data & 0x01) == 0x01;
+        match self {
+            InputDevice::Pad(pad) => pad.write_strobe(is_enable),
+            InputDevice::Zapper(_) => {}
+            InputDevice::ArkanoidPaddle(paddle) => paddle.write_strobe(is_enable),
+            InputDevice::PowerPad(power_pad) => power_pad.write_strobe(data),
+            InputDevice::SnesMouse(mouse) => mouse.write_strobe(is_enable),
+        }
+    }
+    pub fn read_out(&mut self) -> u8 {
+        match self {
+            InputDevice::Pad(pad) => pad.read_out(),
+            InputDevice::Zapper(zapper) => zapper.read_out(),
+            InputDevice::ArkanoidPaddle(paddle) => (paddle.read_dial() << 4) | (paddle.read_button() << 3),
+            InputDevice::PowerPad(power_pad) => power_pad.read_out(),
+            InputDevice::SnesMouse(mouse) => mouse.read_out(),
+        }
+    }
+    pub fn pad(&self) -> Option<&Pad> {
+        match self {
+            InputDevice::Pad(pad) => Some(pad),
+            _ => None,
+        }
+    }
+}
+
+//What's in the Famicom expansion port
+#[derive(Clone, Debug)]
+pub enum ExpansionDevice {
+    //Players 3 and 4, also the extra sockets on a Four Score or Hori adapter
+    Pads(Pad, Pad),
+    //The Famicom Zapper answers on $4017 like the NES one
+    Zapper(Zapper),
+    ArkanoidPaddle(ArkanoidPaddle),
+    FamilyTrainer(PowerPad),
+}
+
+impl ExpansionDevice {
+    pub fn new(kind: InputDeviceKind) -> Self {
+        match kind {
+            InputDeviceKind::Zapper => ExpansionDevice::Zapper(Zapper::default()),
+            InputDeviceKind::ArkanoidPaddle => ExpansionDevice::ArkanoidPaddle(ArkanoidPaddle::default()),
+            InputDeviceKind::PowerPad => ExpansionDevice::FamilyTrainer(PowerPad::default()),
+            _ => ExpansionDevice::Pads(Pad::default(), Pad::default()),
+        }
+    }
+    pub fn reset(&mut self) {
+        match self {
+            ExpansionDevice::Pads(pad3, pad4) => {
+                pad3.reset();
+                pad4.reset();
+            }
+            ExpansionDevice::Zapper(zapper) => zapper.reset(),
+            ExpansionDevice::ArkanoidPaddle(paddle) => paddle.reset(),
+            ExpansionDevice::FamilyTrainer(mat) => mat.reset(),
+        }
+    }
+    pub fn write_strobe(&mut self, data: u8) {
+        let is_enable = (data & 0x01) == 0x01;
+        match self {
+            ExpansionDevice::Pads(pad3, pad4) => {
+                pad3.write_strobe(is_enable);
+                pad4.write_strobe(is_enable);
+            }
+            ExpansionDevice::Zapper(_) => {}
+            ExpansionDevice::ArkanoidPaddle(paddle) => paddle.write_strobe(is_enable),
+            ExpansionDevice::FamilyTrainer(mat) => mat.write_strobe(data),
+        }
+    }
+    //Port 0 for $4016 and 1 for $4017
+    pub fn read_out(&mut self, port: usize) -> u8 {
+        match (self, port) {
+            (ExpansionDevice::Pads(pad3, _), 0) => pad3.read_out() << 1,
+            (ExpansionDevice::Pads(_, pad4), _) => pad4.read_out() << 1,
+            (ExpansionDevice::Zapper(zapper), 1) => zapper.read_out(),
+            (ExpansionDevice::ArkanoidPaddle(paddle), 0) => paddle.read_button() << 1,
+            (ExpansionDevice::ArkanoidPaddle(paddle), _) => paddle.read_dial() << 1,
+            (ExpansionDevice::FamilyTrainer(mat), 1) => mat.read_matrix(),
+            _ => 0,
+        }
+    }
+    pub fn pads(&self) -> Option<(&Pad, &Pad)> {
+        match self {
+            ExpansionDevice::Pads(pad3, pad4) => Some((pad3, pad4)),
+            _ => None,
+        }
+    }
+}
@@ -107,6 +107,9 @@ pub enum Opcode{
     SRE,
     SKB,
     IGN,
+    //Locks the CPU up until it's reset
+    //https://wiki.nesdev.com/w/index.php/CPU_unofficial_opcodes
+    JAM,
 }
 //u16 is address, u8 is cycles
 #[derive(Clone, Copy)]
@@ -442,6 +445,10 @@ impl Instruction {
             0x74 => Instruction(Opcode::IGN, AddressingMode::ZeroPageX),
             0xd4 => Instruction(Opcode::IGN, AddressingMode::ZeroPageX),
             0xf4 => Instruction(Opcode::IGN, AddressingMode::ZeroPageX),
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                Instruction(Opcode::JAM, AddressingMode::Implied)
+            },
+
             //This is not how you handle an unhandled opcode, you don't handle an unhandled opcode. This was an early workaround
             _ =>  {
                 //log("Unimplemented opcode:");
@@ -0,0 +1,360 @@
+/* MMC5 */
+//https://wiki.nesdev.com/w/index.php/MMC5
+//Nintendo's biggest mapper, used by Castlevania III and a pile of Koei games. On top of fine grained PRG and CHR
+//banking it has 1k of its own RAM (ExRAM) that can act as a third nametable or as a per tile attribute table, a fill
+//mode nametable, a vertical split screen, a multiplier, a scanline IRQ and two extra pulse channels.
+//The real chip works out what the PPU is doing by watching its address bus, our PPU just tells us, see VideoFetch
+use super::expansion_audio::Mmc5Audio;
+use super::rom::{NameTableSource, VideoFetch};
+
+pub const MMC5_MAPPER: u16 = 5;
+pub const MMC5_PRG_ROM_MAX_SIZE: usize = 0x10_0000;
+pub const MMC5_CHR_ROM_MAX_SIZE: usize = 0x10_0000;
+//The biggest board has 64k, the smaller ones just never bank past what they have
+const MMC5_PRG_RAM_SIZE: usize = 0x1_0000;
+const MMC5_PRG_BANK_SIZE: usize = 0x2000;
+const MMC5_EXRAM_SIZE: usize = 0x400;
+const MMC5_EXRAM_BASE_ADDR: u16 = 0x5c00;
+//Where the attribute table starts in a nametable, and in ExRAM when it's used for the split
+const ATTRIBUTE_OFFSET: usize = 0x3c0;
+//The split's vertical scroll wraps at the bottom of the screen like a nametable does
+const SPLIT_SCROLL_HEIGHT: u8 = 240;
+
+//$5104, what ExRAM is being used for
+const EXRAM_MODE_EXTENDED_ATTRIBUTE: u8 = 1;
+const EXRAM_MODE_CPU_RAM: u8 = 2;
+const EXRAM_MODE_READ_ONLY: u8 = 3;
+
+//Two bits of palette repeated for all four quadrants, so it doesn't matter which one the PPU picks out
+fn repeat_attribute(palette: u8) -> u8 {
+    (palette & 0x03) * 0x55
+}
+
+#[derive(Clone, Debug)]
+pub struct Mmc5 {
+    //$5100, 32k/16k+16k/16k+8k+8k/8k x4
+    prg_mode: u8,
+    //$5101, 8k/4k/2k/1k
+    chr_mode: u8,
+    //$5102/$5103 have to be 2 and 1 for PRG RAM to take writes
+    prg_ram_protect: [u8; 2],
+    //$5104
+    exram_mode: u8,
+    //$5105, two bits per nametable: CIRAM page 0, page 1, ExRAM, fill
+    name_table_select: u8,
+    //$5106/$5107
+    fill_tile: u8,
+    fill_attribute: u8,
+    //$5113-$5117, bit 7 picks ROM over RAM
+    prg_banks: [u8; 5],
+    //$5120-$5127 are the A set used by sprites, $5128-$512B the B set used by the background in 8x16 sprite mode
+    chr_banks: [u16; 12],
+    //$5130, the top 2 bits of every CHR bank
+    chr_upper: u8,
+    is_last_chr_write_b: bool,
+    //$5200-$5202
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    //Where the split is vertically on the current line
+    split_y: u8,
+    //$5203/$5204
+    irq_target: u8,
+    is_irq_enabled: bool,
+    is_irq_pending: bool,
+    is_in_frame: bool,
+    scanline: u8,
+    //$5205/$5206
+    multiplier: [u8; 2],
+    exram: Vec<u8>,
+    prg_ram: Vec<u8>,
+    pub audio: Mmc5Audio,
+    //Watched on the CPU bus, $2000 and $2001
+    is_large_sprites: bool,
+    is_rendering: bool,
+    fetch: VideoFetch,
+}
+
+impl Default for Mmc5 {
+    fn default() -> Self {
+        Self {
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            name_table_select: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            //Powers up with the last bank everywhere, so the vectors are there
+            prg_banks: [0, 0xff, 0xff, 0xff, 0xff],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            is_last_chr_write_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            split_y: 0,
+            irq_target: 0,
+            is_irq_enabled: false,
+            is_irq_pending: false,
+            is_in_frame: false,
+            scanline: 0,
+            multiplier: [0xff; 2],
+            exram: vec![0; MMC5_EXRAM_SIZE],
+            prg_ram: vec![0; MMC5_PRG_RAM_SIZE],
+            audio: Mmc5Audio::default(),
+            is_large_sprites: false,
+            is_rendering: false,
+            fetch: VideoFetch::Cpu,
+        }
+    }
+}
+
+//Where a CPU address in $6000-$FFFF ends up
+enum PrgTarget {
+    Rom(usize),
+    Ram(usize),
+}
+
+impl Mmc5 {
+    fn prg_target(&self, addr: u16) -> PrgTarget {
+        if addr < 0x8000 {
+            let bank = usize::from(self.prg_banks[0] & 0x07);
+            return PrgTarget::Ram(bank * MMC5_PRG_BANK_SIZE + usize::from(addr & 0x1fff));
+        }
+        //Which 8k slot of $8000-$FFFF, and the register and bank size covering it
+        let slot = (addr - 0x8000) >> 13;
+        let (value, slot_mask): (u8, u8) = match (self.prg_mode, slot) {
+            (0, _) => (self.prg_banks[4] | 0x80, 0x03),
+            (1, 0..=1) | (2, 0..=1) => (self.prg_banks[2], 0x01),
+            (1, _) => (self.prg_banks[4] | 0x80, 0x01),
+            (2, 2) | (3, 2) => (self.prg_banks[3], 0x00),
+            (3, 0) => (self.prg_banks[1], 0x00),
+            (3, 1) => (self.prg_banks[2], 0x00),
+            //$5117 is always ROM
+            _ => (self.prg_banks[4] | 0x80, 0x00),
+        };
+        let bank = usize::from((value & 0x7f & !slot_mask) | (slot as u8 & slot_mask));
+        let index = bank * MMC5_PRG_BANK_SIZE + usize::from(addr & 0x1fff);
+        if (value & 0x80) == 0x80 {
+            PrgTarget::Rom(index)
+        } else {
+            PrgTarget::Ram(index & 0xffff)
+        }
+    }
+    fn is_prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x02, 0x01]
+    }
+    //$5000-$5FFF, None for open bus
+    fn read_register(&mut self, addr: u16, is_nondestructive: bool) -> Option<u8> {
+        match addr {
+            0x5000..=0x5015 if is_nondestructive => None,
+            0x5000..=0x5015 => self.audio.read_register(addr),
+            0x5204 => {
+                let data = (u8::from(self.is_irq_pending) << 7) | (u8::from(self.is_in_frame) << 6);
+                if !is_nondestructive {
+                    self.is_irq_pending = false;
+                }
+                Some(data)
+            }
+            0x5205 | 0x5206 => {
+                let product = u16::from(self.multiplier[0]) * u16::from(self.multiplier[1]);
+                Some((product >> ((addr - 0x5205) * 8)) as u8)
+            }
+            //As a nametable or attribute table it's only there for the PPU
+            MMC5_EXRAM_BASE_ADDR..=0x5fff if self.exram_mode >= EXRAM_MODE_CPU_RAM => {
+                Some(self.exram[usize::from(addr - MMC5_EXRAM_BASE_ADDR)])
+            }
+            _ => None,
+        }
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&mut self, addr: u16, prg_rom: &[u8], is_nondestructive: bool) -> u8 {
+        let open_bus = (addr >> 8) as u8;
+        if addr < 0x6000 {
+            return self.read_register(addr, is_nondestructive).unwrap_or(open_bus);
+        }
+        match self.prg_target(addr) {
+            PrgTarget::Rom(index) => {
+                let data = prg_rom[index % prg_rom.len()];
+                if (0x8000..0xc000).contains(&addr) && !is_nondestructive {
+                    self.audio.read_pcm(data);
+                }
+                data
+            }
+            PrgTarget::Ram(index) => self.prg_ram[index],
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5015 => self.audio.write_register(addr, data),
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.prg_ram_protect[0] = data & 0x03,
+            0x5103 => self.prg_ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.name_table_select = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[usize::from(addr - 0x5113)] = data,
+            0x5120..=0x512b => {
+                let index = usize::from(addr - 0x5120);
+                self.chr_banks[index] = u16::from(data) | (u16::from(self.chr_upper) << 8);
+                self.is_last_chr_write_b = index >= 8;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_target = data,
+            0x5204 => self.is_irq_enabled = (data & 0x80) == 0x80,
+            0x5205 | 0x5206 => self.multiplier[usize::from(addr - 0x5205)] = data,
+            //The real thing only takes writes while the PPU is rendering when it's a nametable, and writes 0
+            //otherwise. Nothing relies on that, so they always go through
+            MMC5_EXRAM_BASE_ADDR..=0x5fff if self.exram_mode != EXRAM_MODE_READ_ONLY => {
+                self.exram[usize::from(addr - MMC5_EXRAM_BASE_ADDR)] = data;
+            }
+            0x6000..=0xdfff if self.is_prg_ram_writable() => {
+                if let PrgTarget::Ram(index) = self.prg_target(addr) {
+                    self.prg_ram[index] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+    //The CPU writing PPUCTRL or PPUMASK
+    pub fn snoop_ppu_write(&mut self, index: usize, data: u8) {
+        match index {
+            0 => self.is_large_sprites = (data & 0x20) == 0x20,
+            1 => self.is_rendering = (data & 0x18) != 0,
+            _ => {}
+        }
+    }
+    pub fn set_video_fetch(&mut self, fetch: VideoFetch) {
+        self.fetch = fetch;
+    }
+    //The real chip spots a new scanline from the PPU reading the same nametable byte three times in a row
+    pub fn start_scanline(&mut self, line: u16) {
+        if line >= 240 || !self.is_rendering {
+            self.is_in_frame = false;
+            return;
+        }
+        if self.is_in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_target {
+                self.is_irq_pending = true;
+            }
+            self.split_y = self.split_y.wrapping_add(1);
+            if self.split_y == SPLIT_SCROLL_HEIGHT {
+                self.split_y = 0;
+            }
+        } else {
+            self.is_in_frame = true;
+            self.is_irq_pending = false;
+            self.scanline = 0;
+            self.split_y = self.split_scroll;
+        }
+    }
+    pub fn is_irq(&self) -> bool {
+        (self.is_irq_pending && self.is_irq_enabled) || self.audio.is_irq()
+    }
+    //ExRAM and PRG RAM, for snapshots
+    pub fn ram_mut(&mut self) -> [&mut Vec<u8>; 2] {
+        [&mut self.exram, &mut self.prg_ram]
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.exram.capacity() + self.prg_ram.capacity()
+    }
+    //The background tile being fetched is on the split's side of the screen
+    fn is_split_tile(&self) -> bool {
+        match self.fetch {
+            VideoFetch::Background { tile_x, .. } if (self.split_control & 0x80) == 0x80 => {
+                let split_tile = self.split_control & 0x1f;
+                let is_right = (self.split_control & 0x40) == 0x40;
+                (tile_x >= split_tile) == is_right
+            }
+            _ => false,
+        }
+    }
+    fn is_extended_attribute_fetch(&self) -> bool {
+        self.exram_mode == EXRAM_MODE_EXTENDED_ATTRIBUTE && matches!(self.fetch, VideoFetch::Background { .. })
+    }
+    pub fn name_table_source(&self, addr: u16) -> NameTableSource {
+        let offset = usize::from(addr & 0x3ff);
+        if self.is_split_tile() || (self.is_extended_attribute_fetch() && offset >= ATTRIBUTE_OFFSET) {
+            return NameTableSource::Cartridge;
+        }
+        match (self.name_table_select >> (((addr >> 10) & 0x03) * 2)) & 0x03 {
+            page @ 0..=1 => NameTableSource::Vram(usize::from(page)),
+            _ => NameTableSource::Cartridge,
+        }
+    }
+    //Only called for the nametables name_table_source hands to the cartridge
+    pub fn read_name_table_u8(&self, addr: u16) -> u8 {
+        let offset = usize::from(addr & 0x3ff);
+        let is_attribute = offset >= ATTRIBUTE_OFFSET;
+        match self.fetch {
+            //The split has its own scroll and always uses ExRAM as the nametable
+            VideoFetch::Background { tile_x, .. } if self.is_split_tile() => {
+                let x = usize::from(tile_x & 0x1f);
+                let y = usize::from(self.split_y >> 3);
+                if is_attribute {
+                    let attribute = self.exram[ATTRIBUTE_OFFSET + (y >> 2) * 8 + (x >> 2)];
+                    repeat_attribute(attribute >> (((y & 0x02) << 1) | (x & 0x02)))
+                } else {
+                    self.exram[y * 32 + x]
+                }
+            }
+            //Every tile gets its own palette from ExRAM
+            VideoFetch::Background { name_table_addr, .. } if is_attribute && self.is_extended_attribute_fetch() => {
+                repeat_attribute(self.exram[usize::from(name_table_addr & 0x3ff)] >> 6)
+            }
+            _ => match (self.name_table_select >> (((addr >> 10) & 0x03) * 2)) & 0x03 {
+                2 if self.exram_mode <= EXRAM_MODE_EXTENDED_ATTRIBUTE => self.exram[offset],
+                3 if is_attribute => repeat_attribute(self.fill_attribute),
+                3 => self.fill_tile,
+                _ => 0,
+            },
+        }
+    }
+    pub fn write_name_table_u8(&mut self, addr: u16, data: u8) {
+        let is_exram = (self.name_table_select >> (((addr >> 10) & 0x03) * 2)) & 0x03 == 2;
+        if is_exram && self.exram_mode <= EXRAM_MODE_EXTENDED_ATTRIBUTE {
+            self.exram[usize::from(addr & 0x3ff)] = data;
+        }
+    }
+    //Index into CHR ROM/RAM for a PPU address in $0000-$1FFF
+    pub fn chr_index(&self, addr: u16) -> usize {
+        let addr = usize::from(addr & 0x1fff);
+        if let VideoFetch::Background { name_table_addr, .. } = self.fetch {
+            if self.is_split_tile() {
+                //The split has its own 4k bank and swaps its own fine scroll into the address
+                return usize::from(self.split_bank) * 0x1000 + (addr & 0x0ff8) + usize::from(self.split_y & 0x07);
+            }
+            if self.exram_mode == EXRAM_MODE_EXTENDED_ATTRIBUTE {
+                let exram = self.exram[usize::from(name_table_addr & 0x3ff)];
+                let bank = usize::from(exram & 0x3f) | (usize::from(self.chr_upper) << 6);
+                return bank * 0x1000 + (addr & 0x0fff);
+            }
+        }
+        //Sprites always use A and the background B, but only in 8x16 mode. Otherwise whichever was written last
+        let is_b = match self.fetch {
+            VideoFetch::Sprite if self.is_large_sprites => false,
+            VideoFetch::Background { .. } if self.is_large_sprites => true,
+            _ => self.is_last_chr_write_b,
+        };
+        let (register, bank_size) = match (self.chr_mode, is_b) {
+            (0, false) => (7, 0x2000),
+            (1, false) => ((addr >> 12) * 4 + 3, 0x1000),
+            (2, false) => ((addr >> 11) * 2 + 1, 0x0800),
+            (_, false) => (addr >> 10, 0x0400),
+            //The B set only covers 4k, it shows up in both halves
+            (0, true) => (11, 0x2000),
+            (1, true) => (11, 0x1000),
+            (2, true) => (8 + ((addr >> 11) & 0x01) * 2 + 1, 0x0800),
+            (_, true) => (8 + ((addr >> 10) & 0x03), 0x0400),
+        };
+        usize::from(self.chr_banks[register]) * bank_size + (addr & (bank_size - 1))
+    }
+}
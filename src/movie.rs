@@ -0,0 +1,700 @@
+/* Input movies */
+//http://fceux.com/web/help/fm2.html
+//A movie is the controller state for every frame, so a run can be played back exactly. Input is sampled and replayed
+//as vblank starts, which is where turbo ticks over too and just before games read their controllers in NMI.
+//Movies load and save as FCEUX's FM2 text format, a header of "key value" lines then one "|commands|port0|port1|port2|"
+//line per frame. Every device gets recorded, but FM2 only has room for controllers and Zappers in the front ports, so
+//movies using anything else can be played back here and not exported.
+use super::arkanoid::ArkanoidPaddle;
+use super::four_player::FourPlayerMode;
+use super::input::{ExpansionDevice, InputDevice, InputDeviceKind};
+use super::pad::NUM_OF_PLAYERS;
+use super::region::Region;
+use super::rom::Rom;
+use super::snapshot::Snapshot;
+use super::system::System;
+use super::zapper::Zapper;
+use std::fmt;
+
+//Per frame commands, FCEUX's MOVIECMD_* bits
+pub const MOVIE_COMMAND_SOFT_RESET: u8 = 0x01;
+pub const MOVIE_COMMAND_POWER: u8 = 0x02;
+
+const FM2_VERSION: u32 = 3;
+//FCEUX 2.2.2, the version whose format we write
+const FM2_EMU_VERSION: u32 = 22020;
+//Gamepad buttons in the order FM2 writes them, which is Pad's bit order from the top down
+const FM2_GAMEPAD_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug)]
+pub enum MovieError {
+    //A header value that should be a number isn't
+    BadHeader(String),
+    //Line number of an input line that doesn't parse
+    BadFrame(usize),
+    //Only the text format is supported
+    Binary,
+    //Movies starting from an FCEUX savestate need that savestate, which we can't load
+    Savestate,
+    UnsupportedPort(u32),
+    //FM2 has nowhere to put our snapshot, only movies recorded from power on can be exported
+    NotFromPowerOn,
+    //A device FM2 can't record, in a front port or the expansion port
+    UnsupportedDevice(MoviePort),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::BadHeader(key) => write!(f, "FM2 header {} has a bad value", key),
+            MovieError::BadFrame(line) => write!(f, "FM2 input on line {} doesn't parse", line),
+            MovieError::Binary => write!(f, "Binary FM2 movies are not supported"),
+            MovieError::Savestate => write!(f, "FM2 movies starting from a savestate are not supported"),
+            MovieError::UnsupportedPort(port) => write!(f, "FM2 input device {} is not supported", port),
+            MovieError::NotFromPowerOn => write!(f, "Only movies recorded from power on can be saved as FM2"),
+            MovieError::UnsupportedDevice(port) => write!(f, "FM2 can't record input from {:?}", port),
+        }
+    }
+}
+
+//What a movie has plugged into each port. The first three are FM2's SI_* values, the rest are only ours
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MoviePort {
+    None,
+    Gamepad,
+    Zapper,
+    ArkanoidPaddle,
+    PowerPad,
+    SnesMouse,
+}
+
+impl MoviePort {
+    fn from_fm2(value: u32) -> Result<Self, MovieError> {
+        match value {
+            0 => Ok(MoviePort::None),
+            1 => Ok(MoviePort::Gamepad),
+            2 => Ok(MoviePort::Zapper),
+            _ => Err(MovieError::UnsupportedPort(value)),
+        }
+    }
+    fn to_fm2(self) -> Result<u32, MovieError> {
+        match self {
+            MoviePort::None => Ok(0),
+            MoviePort::Gamepad => Ok(1),
+            MoviePort::Zapper => Ok(2),
+            _ => Err(MovieError::UnsupportedDevice(self)),
+        }
+    }
+    fn from_device(device: &InputDevice) -> Self {
+        match device {
+            InputDevice::Pad(_) => MoviePort::Gamepad,
+            InputDevice::Zapper(_) => MoviePort::Zapper,
+            InputDevice::ArkanoidPaddle(_) => MoviePort::ArkanoidPaddle,
+            InputDevice::PowerPad(_) => MoviePort::PowerPad,
+            InputDevice::SnesMouse(_) => MoviePort::SnesMouse,
+        }
+    }
+    //Players 3 and 4 aren't counted, they're recorded along with the Four Score
+    fn from_expansion(device: &ExpansionDevice) -> Self {
+        match device {
+            ExpansionDevice::Pads(..) => MoviePort::None,
+            ExpansionDevice::Zapper(_) => MoviePort::Zapper,
+            ExpansionDevice::ArkanoidPaddle(_) => MoviePort::ArkanoidPaddle,
+            ExpansionDevice::FamilyTrainer(_) => MoviePort::PowerPad,
+        }
+    }
+    pub fn input_device(self) -> InputDeviceKind {
+        match self {
+            MoviePort::None | MoviePort::Gamepad => InputDeviceKind::Pad,
+            MoviePort::Zapper => InputDeviceKind::Zapper,
+            MoviePort::ArkanoidPaddle => InputDeviceKind::ArkanoidPaddle,
+            MoviePort::PowerPad => InputDeviceKind::PowerPad,
+            MoviePort::SnesMouse => InputDeviceKind::SnesMouse,
+        }
+    }
+}
+
+//One port's input for a frame
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PortInput {
+    None,
+    //Same layout as Pad, A in bit 0
+    Gamepad(u8),
+    //Pointing off screen is -1, -1
+    Zapper { x: i32, y: i32, is_trigger: bool },
+    //The knob as the game reads it
+    ArkanoidPaddle { dial: u8, is_button: bool },
+    //Power Pad or Family Trainer, bit n for button n + 1
+    PowerPad(u16),
+    //Movement that came in as the frame started
+    SnesMouse { dx: i32, dy: i32, is_left: bool, is_right: bool },
+}
+
+impl PortInput {
+    fn capture(device: &InputDevice) -> Self {
+        match device {
+            InputDevice::Pad(pad) => PortInput::Gamepad(pad.buttons()),
+            InputDevice::Zapper(zapper) => Self::capture_zapper(zapper),
+            InputDevice::ArkanoidPaddle(paddle) => Self::capture_paddle(paddle),
+            InputDevice::PowerPad(mat) => PortInput::PowerPad(mat.buttons()),
+            InputDevice::SnesMouse(mouse) => {
+                let (dx, dy) = mouse.frame_motion();
+                let (is_left, is_right) = mouse.buttons();
+                PortInput::SnesMouse {
+                    dx,
+                    dy,
+                    is_left,
+                    is_right,
+                }
+            }
+        }
+    }
+    //Players 3 and 4 go in MovieFrame::extra_buttons instead
+    fn capture_expansion(device: &ExpansionDevice) -> Self {
+        match device {
+            ExpansionDevice::Pads(..) => PortInput::None,
+            ExpansionDevice::Zapper(zapper) => Self::capture_zapper(zapper),
+            ExpansionDevice::ArkanoidPaddle(paddle) => Self::capture_paddle(paddle),
+            ExpansionDevice::FamilyTrainer(mat) => PortInput::PowerPad(mat.buttons()),
+        }
+    }
+    fn capture_zapper(zapper: &Zapper) -> Self {
+        let (x, y) = zapper.aim().map_or((-1, -1), |(x, y)| (x as i32, y as i32));
+        PortInput::Zapper {
+            x,
+            y,
+            is_trigger: zapper.is_trigger(),
+        }
+    }
+    fn capture_paddle(paddle: &ArkanoidPaddle) -> Self {
+        PortInput::ArkanoidPaddle {
+            dial: paddle.dial(),
+            is_button: paddle.is_button(),
+        }
+    }
+    //Buttons go in as held rather than turbo, turbo was already baked into the recording
+    fn apply(self, device: &mut InputDevice) {
+        match (self, device) {
+            (PortInput::Gamepad(buttons), InputDevice::Pad(pad)) => {
+                pad.set_buttons(buttons);
+                pad.set_turbo_buttons(0);
+            }
+            (input, InputDevice::Zapper(zapper)) => input.apply_zapper(zapper),
+            (input, InputDevice::ArkanoidPaddle(paddle)) => input.apply_paddle(paddle),
+            (PortInput::PowerPad(buttons), InputDevice::PowerPad(mat)) => mat.set_buttons(buttons),
+            (
+                PortInput::SnesMouse {
+                    dx,
+                    dy,
+                    is_left,
+                    is_right,
+                },
+                InputDevice::SnesMouse(mouse),
+            ) => {
+                mouse.set_frame_motion(dx, dy);
+                mouse.set_buttons(is_left, is_right);
+            }
+            _ => {}
+        }
+    }
+    fn apply_expansion(self, device: &mut ExpansionDevice) {
+        match (self, device) {
+            (input, ExpansionDevice::Zapper(zapper)) => input.apply_zapper(zapper),
+            (input, ExpansionDevice::ArkanoidPaddle(paddle)) => input.apply_paddle(paddle),
+            (PortInput::PowerPad(buttons), ExpansionDevice::FamilyTrainer(mat)) => mat.set_buttons(buttons),
+            _ => {}
+        }
+    }
+    fn apply_zapper(self, zapper: &mut Zapper) {
+        if let PortInput::Zapper { x, y, is_trigger } = self {
+            zapper.set_aim(x, y);
+            zapper.set_trigger(is_trigger);
+        }
+    }
+    fn apply_paddle(self, paddle: &mut ArkanoidPaddle) {
+        if let PortInput::ArkanoidPaddle { dial, is_button } = self {
+            paddle.set_dial(dial);
+            paddle.set_button(is_button);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub ports: [PortInput; 2],
+    //Players 3 and 4, only used with a Four Score
+    pub extra_buttons: [u8; 2],
+    //Anything else on the Famicom expansion port
+    pub expansion: PortInput,
+}
+
+impl MovieFrame {
+    //What the player is doing on the console right now
+    pub fn capture(system: &System, commands: u8) -> Self {
+        let ports = [PortInput::capture(&system.ports[0]), PortInput::capture(&system.ports[1])];
+        let extra_buttons = match system.expansion.pads() {
+            Some((pad3, pad4)) => [pad3.buttons(), pad4.buttons()],
+            None => [0; 2],
+        };
+        Self {
+            commands,
+            ports,
+            extra_buttons,
+            expansion: PortInput::capture_expansion(&system.expansion),
+        }
+    }
+    //Puts the recorded input back
+    pub fn apply(&self, system: &mut System) {
+        for (input, device) in self.ports.iter().zip(system.ports.iter_mut()) {
+            input.apply(device);
+        }
+        self.expansion.apply_expansion(&mut system.expansion);
+        for (player, &buttons) in self.extra_buttons.iter().enumerate() {
+            system.set_buttons(player + 2, buttons);
+            system.set_turbo_buttons(player + 2, 0);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Movie {
+    pub rerecord_count: u32,
+    pub is_pal: bool,
+    pub rom_filename: String,
+    //"base64:" then the MD5 of the ROM data, see rom_checksum
+    pub rom_checksum: String,
+    pub guid: String,
+    pub is_four_score: bool,
+    pub ports: [MoviePort; 2],
+    //What's on the expansion port other than players 3 and 4
+    pub expansion: MoviePort,
+    pub is_fds: bool,
+    pub comments: Vec<String>,
+    pub subtitles: Vec<String>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Default for Movie {
+    fn default() -> Self {
+        Self {
+            rerecord_count: 0,
+            is_pal: false,
+            rom_filename: String::new(),
+            rom_checksum: String::new(),
+            guid: String::new(),
+            is_four_score: false,
+            ports: [MoviePort::Gamepad; 2],
+            expansion: MoviePort::None,
+            is_fds: false,
+            comments: Vec::new(),
+            subtitles: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+}
+
+fn parse_flag(key: &str, value: &str) -> Result<bool, MovieError> {
+    value
+        .trim()
+        .parse::<u32>()
+        .map(|value| value != 0)
+        .map_err(|_| MovieError::BadHeader(key.to_string()))
+}
+
+fn parse_number(key: &str, value: &str) -> Result<u32, MovieError> {
+    value.trim().parse().map_err(|_| MovieError::BadHeader(key.to_string()))
+}
+
+//Any character other than '.' or ' ' means the button is held
+fn parse_gamepad(field: &str) -> u8 {
+    field
+        .bytes()
+        .take(FM2_GAMEPAD_BUTTONS.len())
+        .enumerate()
+        .filter(|(_, c)| *c != b'.' && *c != b' ')
+        .fold(0, |buttons, (i, _)| buttons | (0x80 >> i))
+}
+
+fn gamepad_to_fm2(buttons: u8) -> String {
+    FM2_GAMEPAD_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| if buttons & (0x80 >> i) != 0 { c as char } else { '.' })
+        .collect()
+}
+
+//"XXX YYY B Q Z", position, mouse buttons, then two FCEUX internals we don't need
+fn parse_zapper(field: &str) -> Option<PortInput> {
+    let mut values = field.split_whitespace().map(|value| value.parse::<i32>());
+    let x = values.next()?.ok()?;
+    let y = values.next()?.ok()?;
+    let buttons = values.next()?.ok()?;
+    Some(PortInput::Zapper {
+        x,
+        y,
+        is_trigger: buttons & 0x01 != 0,
+    })
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (u32::from(b) << (16 - i * 8)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((bits >> (18 - i * 6)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+//FCEUX identifies a ROM by the MD5 of its PRG and CHR data, without the header
+pub fn rom_checksum(rom: &Rom) -> String {
+    let mut context = md5::Context::new();
+    context.consume(&rom.p_rom[..rom.p_rom_bytes.min(rom.p_rom.len())]);
+    context.consume(&rom.c_rom[..rom.c_rom_bytes.min(rom.c_rom.len())]);
+    format!("base64:{}", base64_encode(&context.compute().0))
+}
+
+impl Movie {
+    //A blank movie for whatever the console has plugged in right now
+    pub fn for_system(system: &System, rom_filename: &str) -> Self {
+        Self {
+            is_pal: system.region == Region::Pal,
+            rom_filename: rom_filename.to_string(),
+            rom_checksum: rom_checksum(&system.rom),
+            is_four_score: system.four_player.mode == FourPlayerMode::FourScore,
+            ports: [MoviePort::from_device(&system.ports[0]), MoviePort::from_device(&system.ports[1])],
+            expansion: MoviePort::from_expansion(&system.expansion),
+            is_fds: system.rom.fds().is_some(),
+            ..Self::default()
+        }
+    }
+    fn parse_frame(&self, line: &str) -> Option<MovieFrame> {
+        let fields: Vec<&str> = line.split('|').collect();
+        let commands = fields.get(1)?.trim().parse::<u8>().ok()?;
+        let mut frame = MovieFrame {
+            commands,
+            ports: [PortInput::None; 2],
+            extra_buttons: [0; 2],
+            expansion: PortInput::None,
+        };
+        if self.is_four_score {
+            for player in 0..NUM_OF_PLAYERS {
+                let buttons = parse_gamepad(fields.get(2 + player)?);
+                match player {
+                    0 | 1 => frame.ports[player] = PortInput::Gamepad(buttons),
+                    _ => frame.extra_buttons[player - 2] = buttons,
+                }
+            }
+        } else {
+            for port in 0..2 {
+                let field = fields.get(2 + port)?;
+                frame.ports[port] = match self.ports[port] {
+                    MoviePort::Gamepad => PortInput::Gamepad(parse_gamepad(field)),
+                    MoviePort::Zapper => parse_zapper(field)?,
+                    //Only FM2's devices get this far
+                    _ => PortInput::None,
+                };
+            }
+        }
+        Some(frame)
+    }
+    pub fn parse_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::default();
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                let frame = movie.parse_frame(line).ok_or(MovieError::BadFrame(line_index + 1))?;
+                movie.frames.push(frame);
+                continue;
+            }
+            let (key, value) = match line.find(' ') {
+                Some(split) => (&line[..split], &line[split + 1..]),
+                None => (line, ""),
+            };
+            match key {
+                "rerecordCount" => movie.rerecord_count = parse_number(key, value)?,
+                "palFlag" => movie.is_pal = parse_flag(key, value)?,
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => movie.rom_checksum = value.to_string(),
+                "guid" => movie.guid = value.to_string(),
+                "fourscore" => movie.is_four_score = parse_flag(key, value)?,
+                "port0" => movie.ports[0] = MoviePort::from_fm2(parse_number(key, value)?)?,
+                "port1" => movie.ports[1] = MoviePort::from_fm2(parse_number(key, value)?)?,
+                "FDS" => movie.is_fds = parse_flag(key, value)?,
+                "comment" => movie.comments.push(value.to_string()),
+                "subtitle" => movie.subtitles.push(value.to_string()),
+                "binary" if parse_flag(key, value)? => return Err(MovieError::Binary),
+                "savestate" => return Err(MovieError::Savestate),
+                //version, emuVersion, microphone, port2, NewPPU and anything newer only matter to FCEUX
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+    fn frame_to_fm2(&self, frame: &MovieFrame) -> String {
+        let mut line = format!("|{}|", frame.commands);
+        if self.is_four_score {
+            let buttons = |input: PortInput| match input {
+                PortInput::Gamepad(buttons) => buttons,
+                _ => 0,
+            };
+            for buttons in [buttons(frame.ports[0]), buttons(frame.ports[1])].iter().chain(frame.extra_buttons.iter()) {
+                line.push_str(&gamepad_to_fm2(*buttons));
+                line.push('|');
+            }
+        } else {
+            for (port, input) in self.ports.iter().zip(frame.ports.iter()) {
+                match (port, input) {
+                    (MoviePort::Gamepad, PortInput::Gamepad(buttons)) => line.push_str(&gamepad_to_fm2(*buttons)),
+                    (MoviePort::Gamepad, _) => line.push_str(&gamepad_to_fm2(0)),
+                    (MoviePort::Zapper, PortInput::Zapper { x, y, is_trigger }) => {
+                        line.push_str(&format!("{} {} {} 0 0", x, y, u8::from(*is_trigger)))
+                    }
+                    (MoviePort::Zapper, _) => line.push_str("-1 -1 0 0 0"),
+                    //to_fm2 has already turned away the rest
+                    _ => {}
+                }
+                line.push('|');
+            }
+        }
+        //Expansion port
+        line.push('|');
+        line
+    }
+    pub fn to_fm2(&self) -> Result<String, MovieError> {
+        let port0 = self.ports[0].to_fm2()?;
+        let port1 = self.ports[1].to_fm2()?;
+        if self.expansion != MoviePort::None {
+            return Err(MovieError::UnsupportedDevice(self.expansion));
+        }
+        let input_log: Vec<String> = self.frames.iter().map(|frame| self.frame_to_fm2(frame)).collect();
+        //FCEUX wants a GUID to match savestates to movies, a hash of the input makes one that's stable for a movie
+        let guid = if self.guid.is_empty() {
+            let hash = md5::compute(input_log.concat()).0;
+            let hex: String = hash.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+        } else {
+            self.guid.clone()
+        };
+        let mut text = String::new();
+        text.push_str(&format!("version {}\n", FM2_VERSION));
+        text.push_str(&format!("emuVersion {}\n", FM2_EMU_VERSION));
+        text.push_str(&format!("rerecordCount {}\n", self.rerecord_count));
+        text.push_str(&format!("palFlag {}\n", u8::from(self.is_pal)));
+        text.push_str(&format!("romFilename {}\n", self.rom_filename));
+        text.push_str(&format!("romChecksum {}\n", self.rom_checksum));
+        text.push_str(&format!("guid {}\n", guid));
+        text.push_str(&format!("fourscore {}\n", u8::from(self.is_four_score)));
+        text.push_str("microphone 0\n");
+        text.push_str(&format!("port0 {}\n", port0));
+        text.push_str(&format!("port1 {}\n", port1));
+        text.push_str("port2 0\n");
+        text.push_str(&format!("FDS {}\n", u8::from(self.is_fds)));
+        text.push_str("NewPPU 0\n");
+        for comment in &self.comments {
+            text.push_str(&format!("comment {}\n", comment));
+        }
+        for subtitle in &self.subtitles {
+            text.push_str(&format!("subtitle {}\n", subtitle));
+        }
+        for line in input_log {
+            text.push_str(&line);
+            text.push('\n');
+        }
+        Ok(text)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+    //Played to the end, input is back in the player's hands
+    Finished,
+}
+
+//A movie being recorded or played back
+pub struct MovieSession {
+    pub movie: Movie,
+    pub mode: MovieMode,
+    //Next frame to record or play
+    pub frame: usize,
+    //Where the movie starts when it isn't from power on
+    pub start: Option<Box<Snapshot>>,
+    //Resets asked for while recording, they happen on the next frame boundary so playback can do the same
+    pending_commands: u8,
+}
+
+impl MovieSession {
+    pub fn record(movie: Movie, start: Option<Box<Snapshot>>) -> Self {
+        Self {
+            movie,
+            mode: MovieMode::Recording,
+            frame: 0,
+            start,
+            pending_commands: 0,
+        }
+    }
+    pub fn play(movie: Movie, start: Option<Box<Snapshot>>) -> Self {
+        Self {
+            mode: MovieMode::Playing,
+            ..Self::record(movie, start)
+        }
+    }
+    pub fn request_commands(&mut self, commands: u8) {
+        self.pending_commands |= commands;
+    }
+    //Take over from playback and record from here on, throwing away the rest. TAS tools count these as rerecords
+    pub fn record_from_here(&mut self) {
+        self.movie.frames.truncate(self.frame);
+        self.movie.rerecord_count += 1;
+        self.mode = MovieMode::Recording;
+    }
+    //Go back with the console when it rewinds. While recording that throws away the frames rewound over and counts as
+    //a rerecord
+    pub fn rewind(&mut self, frames: usize) {
+        self.frame = self.frame.saturating_sub(frames);
+        match self.mode {
+            MovieMode::Recording => {
+                self.movie.frames.truncate(self.frame);
+                self.movie.rerecord_count += 1;
+            }
+            MovieMode::Playing | MovieMode::Finished => self.mode = MovieMode::Playing,
+        }
+    }
+    //Called on each frame boundary, returns the reset commands the caller has to carry out
+    pub fn run_frame(&mut self, system: &mut System) -> u8 {
+        let commands = match self.mode {
+            MovieMode::Recording => {
+                let frame = MovieFrame::capture(system, self.pending_commands);
+                self.pending_commands = 0;
+                let commands = frame.commands;
+                self.movie.frames.push(frame);
+                commands
+            }
+            MovieMode::Playing => match self.movie.frames.get(self.frame) {
+                Some(frame) => {
+                    frame.apply(system);
+                    frame.commands
+                }
+                None => {
+                    self.mode = MovieMode::Finished;
+                    return 0;
+                }
+            },
+            MovieMode::Finished => return 0,
+        };
+        self.frame += 1;
+        commands
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Cut down from what FCEUX writes
+    const FM2: &str = "version 3\r\nemuVersion 22020\r\nrerecordCount 12\r\npalFlag 0\r\nromFilename Game\r\n\
+        romChecksum base64:AAAAAAAAAAAAAAAAAAAAAA==\r\nguid 01234567-89AB-CDEF-0123-456789ABCDEF\r\nfourscore 0\r\n\
+        microphone 0\r\nport0 1\r\nport1 2\r\nport2 0\r\nFDS 0\r\nNewPPU 0\r\ncomment author someone\r\n\
+        |0|R......A|-1 -1 0 0 0||\r\n|1|........|128 112 1 0 0||\r\n|0|.LDUTSB.|12 34 0 0 0||\r\n";
+
+    #[test]
+    fn parses_fm2() {
+        let movie = Movie::parse_fm2(FM2).unwrap();
+        assert_eq!(movie.rerecord_count, 12);
+        assert!(!movie.is_pal);
+        assert_eq!(movie.rom_filename, "Game");
+        assert_eq!(movie.rom_checksum, "base64:AAAAAAAAAAAAAAAAAAAAAA==");
+        assert_eq!(movie.guid, "01234567-89AB-CDEF-0123-456789ABCDEF");
+        assert_eq!(movie.ports, [MoviePort::Gamepad, MoviePort::Zapper]);
+        assert_eq!(movie.comments, vec!["author someone"]);
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[0].ports[0], PortInput::Gamepad(0x81));
+        assert_eq!(movie.frames[0].ports[1], PortInput::Zapper { x: -1, y: -1, is_trigger: false });
+        assert_eq!(movie.frames[1].commands, MOVIE_COMMAND_SOFT_RESET);
+        assert_eq!(movie.frames[1].ports[1], PortInput::Zapper { x: 128, y: 112, is_trigger: true });
+        assert_eq!(movie.frames[2].ports[0], PortInput::Gamepad(0x7e));
+    }
+
+    #[test]
+    fn fm2_round_trip() {
+        let movie = Movie::parse_fm2(FM2).unwrap();
+        let text = movie.to_fm2().unwrap();
+        let again = Movie::parse_fm2(&text).unwrap();
+        assert_eq!(again.frames, movie.frames);
+        assert_eq!(again.guid, movie.guid);
+        assert_eq!(again.ports, movie.ports);
+        assert_eq!(again.comments, movie.comments);
+        assert_eq!(again.to_fm2().unwrap(), text);
+    }
+
+    #[test]
+    fn fm2_four_score() {
+        let text = "fourscore 1\n|0|A.......|.B......|..S.....|...T....||\n";
+        let movie = Movie::parse_fm2(text).unwrap();
+        let frame = &movie.frames[0];
+        assert_eq!(frame.ports, [PortInput::Gamepad(0x80), PortInput::Gamepad(0x40)]);
+        assert_eq!(frame.extra_buttons, [0x20, 0x10]);
+        assert!(movie.to_fm2().unwrap().ends_with("|0|R.......|.L......|..D.....|...U....||\n"));
+    }
+
+    #[test]
+    fn fm2_truncated() {
+        //A frame line missing its port fields, counted from the first line
+        let text = "port0 1\nport1 1\n|0|........|........||\n|0|....";
+        assert!(matches!(Movie::parse_fm2(text), Err(MovieError::BadFrame(4))));
+        assert!(matches!(Movie::parse_fm2("port1 2\n|0||12 ||"), Err(MovieError::BadFrame(2))));
+        assert!(matches!(Movie::parse_fm2("rerecordCount\n"), Err(MovieError::BadHeader(_))));
+    }
+
+    #[test]
+    fn fm2_out_of_range() {
+        assert!(matches!(Movie::parse_fm2("|256|........|........||"), Err(MovieError::BadFrame(1))));
+        assert!(matches!(
+            Movie::parse_fm2("rerecordCount 99999999999\n"),
+            Err(MovieError::BadHeader(_))
+        ));
+        assert!(matches!(Movie::parse_fm2("port0 3\n"), Err(MovieError::UnsupportedPort(3))));
+        //Only the eight buttons count, whatever else is on the end
+        let movie = Movie::parse_fm2("|0|.......AXXXX|........||").unwrap();
+        assert_eq!(movie.frames[0].ports[0], PortInput::Gamepad(0x01));
+    }
+
+    #[test]
+    fn fm2_we_cant_load() {
+        assert!(matches!(Movie::parse_fm2("binary 1\n"), Err(MovieError::Binary)));
+        assert!(matches!(Movie::parse_fm2("savestate base64:AAAA\n"), Err(MovieError::Savestate)));
+    }
+
+    #[test]
+    fn fm2_we_cant_save() {
+        let movie = Movie {
+            ports: [MoviePort::Gamepad, MoviePort::ArkanoidPaddle],
+            ..Movie::default()
+        };
+        assert!(matches!(
+            movie.to_fm2(),
+            Err(MovieError::UnsupportedDevice(MoviePort::ArkanoidPaddle))
+        ));
+        let movie = Movie {
+            expansion: MoviePort::PowerPad,
+            ..Movie::default()
+        };
+        assert!(matches!(movie.to_fm2(), Err(MovieError::UnsupportedDevice(MoviePort::PowerPad))));
+    }
+
+    #[test]
+    fn rom_checksum_is_base64() {
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert_eq!(base64_encode(b"M"), "TQ==");
+    }
+}
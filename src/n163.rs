@@ -0,0 +1,211 @@
+/* Namco 163 */
+//https://wiki.nesdev.com/w/index.php/INES_Mapper_019
+//Three 8k PRG banks, eight 1k CHR banks, nametables that can come from CHR ROM as well as the console's VRAM, a 15
+//bit IRQ counter that counts CPU cycles up to $7FFF, and wavetable sound (see Namco163Audio).
+use super::expansion_audio::Namco163Audio;
+use super::rom::NameTableSource;
+
+pub const N163_MAPPER: u16 = 19;
+pub const N163_PRG_ROM_MAX_SIZE: usize = 0x8_0000;
+pub const N163_CHR_ROM_MAX_SIZE: usize = 0x4_0000;
+const N163_PRG_BANK_SIZE: usize = 0x2000;
+const N163_CHR_BANK_SIZE: usize = 0x0400;
+const N163_PRG_RAM_SIZE: usize = 0x2000;
+const N163_NUM_OF_CHR_BANKS: usize = 8;
+const N163_NUM_OF_NAME_TABLES: usize = 4;
+//Nametable banks from here up are the console's VRAM rather than CHR ROM
+const N163_VRAM_BANK: u8 = 0xe0;
+const N163_IRQ_COUNTER_MAX: u16 = 0x7fff;
+
+#[derive(Clone, Debug)]
+pub struct N163 {
+    prg_banks: [u8; 3],
+    //Banks $E0 and up are meant to put VRAM in the pattern tables unless $E800 says otherwise, that isn't emulated
+    //and they're read from CHR ROM like any other bank
+    chr_banks: [u8; N163_NUM_OF_CHR_BANKS],
+    name_table_banks: [u8; N163_NUM_OF_NAME_TABLES],
+    prg_ram: Vec<u8>,
+    irq_counter: u16,
+    is_irq_enabled: bool,
+    is_irq_pending: bool,
+    //$E000 bit 6
+    is_audio_disabled: bool,
+    pub audio: Namco163Audio,
+}
+
+impl Default for N163 {
+    fn default() -> Self {
+        Self {
+            prg_banks: [0; 3],
+            chr_banks: [0; N163_NUM_OF_CHR_BANKS],
+            name_table_banks: [N163_VRAM_BANK; N163_NUM_OF_NAME_TABLES],
+            prg_ram: vec![0; N163_PRG_RAM_SIZE],
+            irq_counter: 0,
+            is_irq_enabled: false,
+            is_irq_pending: false,
+            is_audio_disabled: false,
+            audio: Namco163Audio::default(),
+        }
+    }
+}
+
+impl N163 {
+    //PRG RAM, for snapshots
+    pub fn ram_mut(&mut self) -> [&mut Vec<u8>; 1] {
+        [&mut self.prg_ram]
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.prg_ram.capacity()
+    }
+    fn prg_index(&self, addr: u16, prg_rom: &[u8]) -> usize {
+        let bank = match addr {
+            0x8000..=0xdfff => usize::from(self.prg_banks[usize::from((addr - 0x8000) >> 13)]),
+            _ => (prg_rom.len() / N163_PRG_BANK_SIZE).saturating_sub(1),
+        };
+        (bank * N163_PRG_BANK_SIZE + usize::from(addr & 0x1fff)) % prg_rom.len()
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&mut self, addr: u16, prg_rom: &[u8], is_nondestructive: bool) -> u8 {
+        match addr {
+            0x4800..=0x4fff => self.audio.read_data(is_nondestructive),
+            0x5000..=0x57ff => self.irq_counter as u8,
+            0x5800..=0x5fff => ((self.irq_counter >> 8) as u8) | if self.is_irq_enabled { 0x80 } else { 0x00 },
+            0x6000..=0x7fff => self.prg_ram[usize::from(addr & 0x1fff)],
+            0x8000..=0xffff => prg_rom[self.prg_index(addr, prg_rom)],
+            _ => (addr >> 8) as u8,
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4fff => self.audio.write_data(data),
+            //Writing either half of the counter acknowledges the IRQ
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | u16::from(data);
+                self.is_irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | (u16::from(data & 0x7f) << 8);
+                self.is_irq_enabled = (data & 0x80) == 0x80;
+                self.is_irq_pending = false;
+            }
+            0x6000..=0x7fff => self.prg_ram[usize::from(addr & 0x1fff)] = data,
+            0x8000..=0xbfff => self.chr_banks[usize::from((addr - 0x8000) >> 11)] = data,
+            0xc000..=0xdfff => self.name_table_banks[usize::from((addr - 0xc000) >> 11)] = data,
+            0xe000..=0xe7ff => {
+                self.prg_banks[0] = data & 0x3f;
+                self.is_audio_disabled = (data & 0x40) == 0x40;
+            }
+            0xe800..=0xefff => self.prg_banks[1] = data & 0x3f,
+            0xf000..=0xf7ff => self.prg_banks[2] = data & 0x3f,
+            0xf800..=0xffff => self.audio.write_addr(data),
+            _ => {}
+        }
+    }
+    pub fn chr_index(&self, addr: u16) -> usize {
+        usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]) * N163_CHR_BANK_SIZE + usize::from(addr & 0x03ff)
+    }
+    fn name_table_bank(&self, addr: u16) -> u8 {
+        self.name_table_banks[usize::from((addr >> 10) & 0x03)]
+    }
+    pub fn name_table_source(&self, addr: u16) -> NameTableSource {
+        match self.name_table_bank(addr) {
+            bank if bank >= N163_VRAM_BANK => NameTableSource::Vram(usize::from(bank & 0x01)),
+            _ => NameTableSource::Cartridge,
+        }
+    }
+    //A nametable banked to CHR ROM, writes to it go nowhere
+    pub fn read_name_table_u8(&self, addr: u16, chr_rom: &[u8]) -> u8 {
+        let index = usize::from(self.name_table_bank(addr)) * N163_CHR_BANK_SIZE + usize::from(addr & 0x03ff);
+        chr_rom[index % chr_rom.len()]
+    }
+    pub fn step(&mut self, cpu_cyc: usize) {
+        if !self.is_irq_enabled {
+            return;
+        }
+        //Stops once it gets to the top rather than wrapping
+        self.irq_counter = (self.irq_counter + cpu_cyc as u16).min(N163_IRQ_COUNTER_MAX);
+        if self.irq_counter == N163_IRQ_COUNTER_MAX {
+            self.is_irq_pending = true;
+        }
+    }
+    pub fn is_irq(&self) -> bool {
+        self.is_irq_pending
+    }
+    pub fn clock_audio(&mut self) {
+        if !self.is_audio_disabled {
+            self.audio.clock();
+        }
+    }
+    pub fn audio_output(&self) -> f32 {
+        if self.is_audio_disabled {
+            0.0
+        } else {
+            self.audio.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+    use crate::rom::Rom;
+
+    fn load() -> Rom {
+        let mut rom = Rom::default();
+        rom.load_bin(&ines(N163_MAPPER, 8, 8)).unwrap();
+        rom
+    }
+
+    #[test]
+    fn banks_prg_chr_and_nametables() {
+        let mut rom = load();
+        rom.write_u8(0xe800, 6, false);
+        rom.write_u8(0x8800, 3, false);
+        rom.write_u8(0xc800, 9, false);
+        rom.write_u8(0xd000, 0xe1, false);
+        assert_eq!(rom.read_u8(0xa000, true), 6);
+        assert_eq!(rom.read_u8(0xe000, true), 15);
+        assert_eq!(rom.read_video_u8(0x0400), 3);
+        assert!(matches!(rom.name_table_source(0x2400), NameTableSource::Cartridge));
+        assert_eq!(rom.read_name_table_u8(0x2400), 9);
+        assert!(matches!(rom.name_table_source(0x2800), NameTableSource::Vram(1)));
+    }
+
+    #[test]
+    fn irq_stops_at_the_top() {
+        let mut rom = load();
+        rom.write_u8(0x5000, 0xf0, false);
+        rom.write_u8(0x5800, 0xff, false);
+        rom.step(14);
+        assert!(!rom.is_irq());
+        rom.step(100);
+        assert!(rom.is_irq());
+        assert_eq!(rom.read_u8(0x5000, true), 0xff);
+        rom.write_u8(0x5000, 0, false);
+        assert!(!rom.is_irq());
+    }
+
+    #[test]
+    fn cartridge_plays_its_sound() {
+        let mut rom = load();
+        //A square wave in the first 16 samples for channel 7, the only one on, at full volume
+        rom.write_u8(0xf800, 0x80, false);
+        for _ in 0..8 {
+            rom.write_u8(0x4800, 0xff, false);
+        }
+        let registers = [0x00, 0x00, 0x40, 0x00, 0xe0, 0x00, 0x00, 0x0f];
+        rom.write_u8(0xf800, 0xf8, false);
+        for data in registers {
+            rom.write_u8(0x4800, data, false);
+        }
+        let loudest = (0..1000)
+            .map(|_| {
+                rom.clock_expansion_audio();
+                rom.expansion_audio_output().abs()
+            })
+            .fold(0.0, f32::max);
+        assert!(loudest > 0.0);
+    }
+}
@@ -6,10 +6,48 @@ pub mod rom;
 pub mod cpu;
 pub mod instruction;
 pub mod pad;
+pub mod four_player;
+pub mod zapper;
+pub mod arkanoid;
+pub mod power_pad;
+pub mod snes_mouse;
+pub mod input;
 pub mod ppu;
 pub mod video;
+pub mod region;
+pub mod header;
+pub mod gamedb;
+pub mod patch;
+pub mod archive;
+pub mod unif;
+pub mod apu;
+pub mod nsf;
+pub mod fds;
+pub mod expansion_audio;
+pub mod vrc7;
+pub mod mmc5;
+pub mod vrc;
+pub mod vrc6;
+pub mod n163;
+pub mod fme7;
+pub mod snapshot;
+pub mod movie;
+pub mod rewind;
+pub mod framebuffer;
+pub mod palette;
+pub mod ntsc;
 use crate::cpu::Cpu;
 use crate::system::System;
+use crate::region::Region;
+use crate::four_player::FourPlayerMode;
+use crate::input::{InputDeviceKind, EXPANSION_PORT, NUM_OF_INPUT_PORTS};
+use crate::nsf::NsfPlayer;
+use crate::movie::{Movie, MovieError, MovieFrame, MovieMode, MovieSession, PortInput, MOVIE_COMMAND_POWER, MOVIE_COMMAND_SOFT_RESET};
+use crate::rewind::Rewind;
+use crate::snapshot::Snapshot;
+use crate::framebuffer::{FrameBuffer, FrameOutput, FramebufferError, PixelFormat};
+use crate::palette::{NtscPaletteParams, Palette};
+use crate::ntsc::{NtscFilter, NtscFilterParams};
 
 use crate::ppu::*;
 use crate::cpu::*;
@@ -47,6 +85,24 @@ pub fn get_num_of_colors() -> usize {
     NUM_OF_COLOR
 }
 
+//run_for won't try to catch up on more than this many frames, like after the tab has been in the background. Counted
+//at the current speed, so fast forward isn't held back by it
+const MAX_CATCH_UP_FRAMES: f64 = 4.0;
+
+//Why run_frame or run_for came back
+#[wasm_bindgen]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum RunStatus {
+    //Stopped at the start of vblank with a whole new picture in the framebuffer
+    FrameComplete,
+    //run_for used up its time partway through a frame
+    TimeUp,
+    //About to run the instruction at a breakpoint, running again carries on from there
+    Breakpoint,
+    //The CPU ran into a JAM opcode and is stuck until reset, the rest of the console keeps going
+    Jam,
+}
+
 #[wasm_bindgen]
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum KeyEvent {
@@ -70,19 +126,268 @@ pub enum KeyEvent {
 
 #[wasm_bindgen]
 pub struct WasmEmulator {
-    fb: [[[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
+    fb: FrameBuffer,
+    //Turns the palette indexes in fb into colors
+    palette: Palette,
+    //The TV knobs the palette was generated with, the NTSC filter decodes with them too
+    picture: NtscPaletteParams,
+    //The picture in the format the host draws with
+    output: FrameOutput,
     cpu: Cpu,
     cpu_sys: System,
     ppu: Ppu,
+    //What the user asked for, the resolved region lives on the system bus
+    region: Region,
+    //Same again for the four player adapter
+    four_player: FourPlayerMode,
+    //And for what's plugged into each port, the front two then the expansion port
+    input_devices: [InputDeviceKind; NUM_OF_INPUT_PORTS],
+    //Takes over from the PPU when an NSF is loaded
+    nsf_player: NsfPlayer,
+    //Input movie being recorded or played back, if any
+    movie: Option<MovieSession>,
+    //Frames since the game was loaded, counted as vblank starts
+    frame_count: u64,
+    rewind: Rewind,
+    //Input put back on each frame while replaying after a rewind
+    replay: std::collections::VecDeque<MovieFrame>,
+    //Frames to run ahead of what's shown, hiding that many frames of the game's own input lag
+    run_ahead_frames: u32,
+    //Run ahead on a second copy of the console rather than snapshotting and restoring this one
+    is_run_ahead_second_instance: bool,
+    run_ahead_instance: Option<Box<Snapshot>>,
+    //The second copy is run_ahead_frames ahead of where this console was on run_ahead_frame, holding run_ahead_input.
+    //None means it has to be copied from this console again
+    run_ahead_frame: u64,
+    run_ahead_input: Option<MovieFrame>,
+    //Nothing that happens while running ahead is kept, so movies and rewind leave it alone
+    is_running_ahead: bool,
+    //1.0 for full speed, more to fast forward and less for slow motion
+    speed: f64,
+    //Master clock cycles run_for still owes, carried between calls so the frame rate comes out exact
+    master_cycle_debt: f64,
+    //CPU addresses to stop at
+    breakpoints: Vec<u16>,
+    //Stopped at a breakpoint, the next run steps over it rather than stopping again straight away
+    is_at_breakpoint: bool,
 }
 
 impl Default for WasmEmulator {
     fn default() -> Self {
         Self {
-            fb: [[[0; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
+            fb: [[0; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
+            palette: Palette::default(),
+            picture: NtscPaletteParams::default(),
+            output: FrameOutput::default(),
             cpu: Cpu::new(),
             cpu_sys: System::default(),
             ppu: Ppu::default(),
+            region: Region::Auto,
+            four_player: FourPlayerMode::Auto,
+            input_devices: [InputDeviceKind::Auto; NUM_OF_INPUT_PORTS],
+            nsf_player: NsfPlayer::default(),
+            movie: None,
+            frame_count: 0,
+            rewind: Rewind::default(),
+            replay: std::collections::VecDeque::new(),
+            run_ahead_frames: 0,
+            is_run_ahead_second_instance: false,
+            run_ahead_instance: None,
+            run_ahead_frame: 0,
+            run_ahead_input: None,
+            is_running_ahead: false,
+            speed: 1.0,
+            master_cycle_debt: 0.0,
+            breakpoints: Vec::new(),
+            is_at_breakpoint: false,
+        }
+    }
+}
+
+impl WasmEmulator {
+    //Everything the load functions share: unpack, patch, then hand it to the cartridge
+    fn load_image(&mut self, binary: &[u8], entry_name: Option<&str>, patch: Option<&[u8]>) -> Result<(), JsValue> {
+        let image = archive::extract(binary, entry_name, archive::ROM_EXTENSIONS)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        let image = match patch {
+            Some(patch) => {
+                let patch = archive::extract(patch, None, archive::PATCH_EXTENSIONS)
+                    .map_err(|err| JsValue::from_str(&err.to_string()))?;
+                std::borrow::Cow::Owned(
+                    patch::apply(&image, &patch).map_err(|err| JsValue::from_str(&err.to_string()))?,
+                )
+            }
+            None => image,
+        };
+        self.cpu_sys
+            .rom
+            .load_bin(&image)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
+        //A movie is only any good for the game it was made with
+        self.movie = None;
+        self.reset();
+        Ok(())
+    }
+    //Turn the console off and on again with whatever settings the user picked
+    fn power_on(&mut self) {
+        self.fb = [[0; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT];
+        self.output.invalidate();
+        self.cpu_sys.region = self.region.resolve(self.cpu_sys.rom.info.timing);
+        self.cpu_sys.four_player.mode = self.four_player.resolve(self.cpu_sys.rom.info.expansion_device);
+        for (port, kind) in self.input_devices.iter().enumerate() {
+            let kind = kind.resolve(port, self.cpu_sys.rom.info.expansion_device);
+            self.cpu_sys.set_input_device(port, kind);
+        }
+        self.cpu.reset();
+        self.cpu_sys.reset();
+        self.ppu.reset();
+        self.rewind.clear();
+        self.replay.clear();
+        self.frame_count = 0;
+        self.desync_run_ahead();
+        //NSFs have no reset vector, the player calls INIT instead
+        match &self.cpu_sys.rom.nsf {
+            Some(nsf) => {
+                let track = nsf.starting_track();
+                self.nsf_player.start(&mut self.cpu, &mut self.cpu_sys, track);
+            }
+            None => self.cpu.interrupt(&mut self.cpu_sys, Interrupt::RESET),
+        }
+    }
+    //Draws the last frame into out for native hosts, rows pitch bytes apart so it can go straight into a locked
+    //texture or surface. get_output_width pixels across, which is wider with the NTSC filter on
+    pub fn render_into(&mut self, out: &mut [u8], pitch: usize, format: PixelFormat) -> Result<(), FramebufferError> {
+        self.output.render_into(&self.fb, &self.palette, format, out, pitch)
+    }
+    //Picks up new TV knobs, keeping the rest of the filter's settings
+    fn update_ntsc_filter(&mut self) {
+        let params = self.output.ntsc().map(|ntsc| ntsc.params);
+        if let Some(params) = params {
+            self.output.set_ntsc(Some(NtscFilter::new(params, self.picture)));
+        }
+        self.output.invalidate();
+    }
+    //One CPU instruction and everything it clocks, returns how many cycles it took and whether a frame started
+    fn step_instruction(&mut self) -> (usize, bool) {
+        let cpu_cycle = usize::from(self.cpu.step(&mut self.cpu_sys));
+        self.cpu_sys.step_apu(cpu_cycle);
+        self.cpu_sys.step_mapper(cpu_cycle);
+        let interrupt = self.ppu.step(cpu_cycle, &mut self.cpu_sys, &mut self.fb);
+        let is_frame_start = self.cpu_sys.take_input_frame();
+        if is_frame_start {
+            self.output.next_frame();
+        }
+        let is_kept = is_frame_start && !self.is_running_ahead;
+        if is_kept {
+            if let Some(input) = self.replay.pop_front() {
+                input.apply(&mut self.cpu_sys);
+            }
+        }
+        //Movies take their input as vblank starts, a reset there also swallows the NMI
+        let is_reset = is_kept && self.run_movie_frame();
+        if !is_reset {
+            if let Some(interrupt) = interrupt {
+                self.cpu.interrupt(&mut self.cpu_sys, interrupt);
+            }
+            if self.cpu_sys.read_is_irq() {
+                self.cpu.interrupt(&mut self.cpu_sys, Interrupt::IRQ);
+            }
+        }
+        if is_kept {
+            self.frame_count += 1;
+            self.rewind.record(self.frame_count, &self.cpu, &mut self.cpu_sys, &self.ppu);
+        }
+        (cpu_cycle, is_frame_start)
+    }
+    //Runs the next few frames with the input held now and shows the last of them, then goes back. Only the picture
+    //is kept, the sound from those frames is dropped
+    fn run_ahead(&mut self) {
+        let sample_count = self.cpu_sys.apu.samples.len();
+        self.is_running_ahead = true;
+        if self.is_run_ahead_second_instance {
+            self.run_ahead_second_instance();
+        } else {
+            let snapshot = Snapshot::capture_without_rom(&self.cpu, &mut self.cpu_sys, &self.ppu);
+            self.run_frames(self.run_ahead_frames);
+            snapshot.restore_without_rom(&mut self.cpu, &mut self.cpu_sys, &mut self.ppu);
+        }
+        self.is_running_ahead = false;
+        self.cpu_sys.apu.samples.truncate(sample_count);
+    }
+    //The second copy keeps running alongside this console. While the input stays the same it only has to run the
+    //frames this console just did, it gets copied again when the input changes or the console jumps somewhere else
+    fn run_ahead_second_instance(&mut self) {
+        let input = MovieFrame::capture(&self.cpu_sys, 0);
+        //Mouse movement is used up as the game reads it, so a moving mouse can't be held like a button
+        let is_mouse_moving = input
+            .ports
+            .iter()
+            .any(|port| matches!(port, PortInput::SnesMouse { dx, dy, .. } if (*dx, *dy) != (0, 0)));
+        let is_in_step =
+            !is_mouse_moving && self.run_ahead_input.as_ref() == Some(&input) && self.frame_count >= self.run_ahead_frame;
+        let (mut instance, frames) = match self.run_ahead_instance.take() {
+            Some(instance) if is_in_step => (instance, (self.frame_count - self.run_ahead_frame) as u32),
+            Some(mut instance) => {
+                instance.copy_from(&self.cpu, &mut self.cpu_sys, &self.ppu);
+                (instance, self.run_ahead_frames)
+            }
+            None => {
+                let instance = Snapshot::capture_without_rom(&self.cpu, &mut self.cpu_sys, &self.ppu);
+                (Box::new(instance), self.run_ahead_frames)
+            }
+        };
+        instance.swap_with(&mut self.cpu, &mut self.cpu_sys, &mut self.ppu);
+        self.run_frames(frames);
+        instance.swap_with(&mut self.cpu, &mut self.cpu_sys, &mut self.ppu);
+        self.run_ahead_instance = Some(instance);
+        self.run_ahead_frame = self.frame_count;
+        self.run_ahead_input = Some(input);
+    }
+    //Anything that changes the console other than by running it leaves the second copy on a different course
+    fn desync_run_ahead(&mut self) {
+        self.run_ahead_input = None;
+    }
+    //Runs until vblank starts or max_master_cycles have gone by, whichever is first. Also hands back how many master
+    //cycles it did run, which can go a little over
+    fn run_until_frame(&mut self, max_master_cycles: f64) -> (RunStatus, f64) {
+        let cpu_divider = self.cpu_sys.region.cpu_divider() as f64;
+        let mut master_cycles = 0.0;
+        while master_cycles < max_master_cycles {
+            if !self.is_at_breakpoint && !self.cpu.is_jammed && self.breakpoints.contains(&self.cpu.pc) {
+                self.is_at_breakpoint = true;
+                return (RunStatus::Breakpoint, master_cycles);
+            }
+            self.is_at_breakpoint = false;
+            let (cpu_cycle, is_frame_start) = self.step_instruction();
+            master_cycles += cpu_cycle as f64 * cpu_divider;
+            if is_frame_start {
+                let status = if self.cpu.is_jammed { RunStatus::Jam } else { RunStatus::FrameComplete };
+                return (status, master_cycles);
+            }
+        }
+        (RunStatus::TimeUp, master_cycles)
+    }
+    //Up to the start of vblank frames times
+    fn run_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            while !self.step_instruction().1 {}
+        }
+    }
+    //Records or plays back one frame of the movie, true if that reset the console
+    fn run_movie_frame(&mut self) -> bool {
+        let commands = match self.movie.as_mut() {
+            Some(session) => session.run_frame(&mut self.cpu_sys),
+            None => return false,
+        };
+        if commands & MOVIE_COMMAND_POWER != 0 {
+            self.power_on();
+            true
+        } else if commands & MOVIE_COMMAND_SOFT_RESET != 0 {
+            self.cpu.interrupt(&mut self.cpu_sys, Interrupt::RESET);
+            self.desync_run_ahead();
+            true
+        } else {
+            false
         }
     }
 }
@@ -94,66 +399,642 @@ impl WasmEmulator {
       console_log!("WasmEmulator::new()");
         WasmEmulator::default()
     }
-    //Grab the fb pointer to pass it up to the browser
-    pub fn get_fb_ptr(&self) -> *const [[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH] {
+    //Grab the fb pointer to pass it up to the browser. It's a u16 per pixel, the 9 bit palette index with emphasis,
+    //for hosts that want to do their own coloring. The picture in colors is get_output_ptr
+    pub fn get_fb_ptr(&self) -> *const [u16; VISIBLE_SCREEN_WIDTH] {
       console_log!("WasmEmulator::get_fb_ptr()");
         self.fb.as_ptr()
     }
-    //self-explanatory
+    //In pixels rather than bytes
     pub fn get_fb_size(&self) -> usize {
       console_log!("WasmEmulator::get_fb_size()");
-        NUM_OF_COLOR * VISIBLE_SCREEN_WIDTH * VISIBLE_SCREEN_HEIGHT
+        VISIBLE_SCREEN_WIDTH * VISIBLE_SCREEN_HEIGHT
+    }
+    //Load a .pal file, 64 colors or 512 with every emphasis combination
+    pub fn load_palette(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.palette = Palette::from_pal(data).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.output.invalidate();
+        Ok(())
+    }
+    //Work the palette out from the composite signal with a TV's picture controls, see Palette::generate
+    pub fn generate_palette(&mut self, hue: f32, saturation: f32, contrast: f32, brightness: f32, gamma: f32) {
+        self.picture = NtscPaletteParams {
+            hue,
+            saturation,
+            contrast,
+            brightness,
+            gamma,
+        };
+        self.palette = Palette::generate(&self.picture);
+        self.update_ntsc_filter();
+    }
+    //Back to the built in palette
+    pub fn reset_palette(&mut self) {
+        self.palette = Palette::default();
+        self.picture = NtscPaletteParams::default();
+        self.update_ntsc_filter();
+    }
+    //Decode the picture as composite video, blargg's nes_ntsc style, instead of coloring it with the palette. The
+    //output is 602 pixels wide rather than 256. artifacts, fringing and bleed go from -1.0 to 1.0 with 0.0 an
+    //ordinary TV, merging fields stops the artifacts crawling. The TV knobs are the ones from generate_palette
+    pub fn set_ntsc_filter(&mut self, is_enabled: bool, artifacts: f32, fringing: f32, bleed: f32, is_merge_fields: bool) {
+        let params = NtscFilterParams {
+            artifacts: artifacts.clamp(-1.0, 1.0),
+            fringing: fringing.clamp(-1.0, 1.0),
+            bleed: bleed.clamp(-1.0, 1.0),
+            is_merge_fields,
+        };
+        let filter = if is_enabled { Some(NtscFilter::new(params, self.picture)) } else { None };
+        self.output.set_ntsc(filter);
+    }
+    pub fn is_ntsc_filter(&self) -> bool {
+        self.output.ntsc().is_some()
+    }
+    //Pixels across the output, 256 or 602 with the NTSC filter. It's always 240 high
+    pub fn get_output_width(&self) -> usize {
+        self.output.width()
+    }
+    //Rgba8888 by default, which js can view as ImageData without copying
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.output.set_format(format);
+    }
+    pub fn get_pixel_format(&self) -> PixelFormat {
+        self.output.format
+    }
+    //The last frame in the pixel format, packed rows with no padding. Call it every draw, it fills in the picture
+    //if there's been a new frame. The pointer stays put unless the format changes, but wasm memory growing still
+    //swaps out memory.buffer under any view made from it
+    pub fn get_output_ptr(&mut self) -> *const u8 {
+        self.output.update(&self.fb, &self.palette).as_ptr()
+    }
+    pub fn get_output_size(&self) -> usize {
+        self.output.size()
     }
     //Have to be able to reset, need that button for authenticity
     pub fn reset(&mut self) {
      console_log!("WasmEmulator::reset()");
-        self.fb = [[[0; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT];
-        self.cpu.reset();
-        self.cpu_sys.reset();
-        self.ppu.reset();
-        self.cpu.interrupt(&mut self.cpu_sys, Interrupt::RESET);
+        //While recording the reset goes into the movie and happens on the next frame, same as it will on playback.
+        //Resetting during playback takes the console back from the movie
+        match self.movie.as_mut() {
+            Some(session) if session.mode == MovieMode::Recording => session.request_commands(MOVIE_COMMAND_POWER),
+            _ => {
+                self.movie = None;
+                self.power_on();
+            }
+        }
     }
     //Load a binary using a bin reader from js, surprisingly simple. This is the rom load
-    pub fn load(&mut self, binary: &[u8]) -> bool {
+    //On failure js gets an exception carrying a message it can show the user
+    //Zipped and gzipped ROMs are unpacked first, archives holding several ROMs need load_archive_entry
+    pub fn load(&mut self, binary: &[u8]) -> Result<(), JsValue> {
       console_log!("WasmEmulator::load()");
-        let success = self.cpu_sys.rom.load_bin(|addr: usize| binary[addr]);
-        if success {
-            self.reset();
+        self.load_image(binary, None, None)
+    }
+    //Same as load, but applies an IPS/UPS/BPS patch to the ROM first. The clean dump is left untouched
+    //The patch can be zipped too
+    pub fn load_with_patch(&mut self, binary: &[u8], patch: &[u8]) -> Result<(), JsValue> {
+      console_log!("WasmEmulator::load_with_patch()");
+        self.load_image(binary, None, Some(patch))
+    }
+    //The ROMs inside a .zip/.gz for the player to pick from, empty if it isn't an archive
+    pub fn list_archive_roms(&self, binary: &[u8]) -> Result<js_sys::Array, JsValue> {
+        let names = archive::list(binary, archive::ROM_EXTENSIONS).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        Ok(names.into_iter().map(JsValue::from).collect())
+    }
+    //Load one particular ROM out of an archive, by the name list_archive_roms gave
+    pub fn load_archive_entry(&mut self, binary: &[u8], entry_name: &str) -> Result<(), JsValue> {
+      console_log!("WasmEmulator::load_archive_entry()");
+        self.load_image(binary, Some(entry_name), None)
+    }
+    pub fn load_archive_entry_with_patch(
+        &mut self,
+        binary: &[u8],
+        entry_name: &str,
+        patch: &[u8],
+    ) -> Result<(), JsValue> {
+      console_log!("WasmEmulator::load_archive_entry_with_patch()");
+        self.load_image(binary, Some(entry_name), Some(patch))
+    }
+    //What the game database knows about the loaded ROM, for showing the player what they just loaded
+    //UNIF files name themselves, so fall back on that for dumps the database doesn't know
+    pub fn get_game_title(&self) -> Option<String> {
+        let rom = &self.cpu_sys.rom;
+        rom.game.and_then(|game| game.title).map(String::from).or_else(|| rom.info.title.clone())
+    }
+    pub fn get_game_publisher(&self) -> Option<String> {
+        self.cpu_sys.rom.game.and_then(|game| game.publisher).map(String::from)
+    }
+    pub fn get_game_year(&self) -> Option<u16> {
+        self.cpu_sys.rom.game.and_then(|game| game.year)
+    }
+    //Pick NTSC/PAL/Dendy, or Auto to follow the cartridge. Takes effect on the next reset
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+    //The region actually being emulated
+    pub fn get_region(&self) -> Region {
+        self.cpu_sys.region
+    }
+    //Plug in a Four Score or Hori adapter so players 3 and 4 reach games that support them. Takes effect on the next reset
+    pub fn set_four_player_mode(&mut self, mode: FourPlayerMode) {
+        self.four_player = mode;
+    }
+    pub fn get_four_player_mode(&self) -> FourPlayerMode {
+        self.cpu_sys.four_player.mode
+    }
+    //Buttons held with autofire for one player, same layout as set_buttons
+    pub fn set_turbo_buttons(&mut self, port: usize, mask: u8) {
+        if self.is_movie_playing() {
+            return;
+        }
+        //Turbo buttons in their off phase don't show in the input the second run-ahead copy is checked against
+        if self.cpu_sys.pad_mut(port).is_some_and(|pad| pad.turbo_reg != mask) {
+            self.desync_run_ahead();
+        }
+        self.cpu_sys.set_turbo_buttons(port, mask);
+    }
+    //Autofire presses for on_frames out of every period frames
+    pub fn set_turbo_rate(&mut self, period: u8, on_frames: u8) {
+        self.cpu_sys.turbo.set_rate(period, on_frames);
+        self.desync_run_ahead();
+    }
+    //Zapper, paddle and so on in port 0 or 1, or 2 for the Famicom expansion port. Takes effect on the next reset,
+    //Auto goes with the cartridge's NES 2.0 header
+    pub fn set_input_device(&mut self, port: usize, kind: InputDeviceKind) {
+        if let Some(device) = self.input_devices.get_mut(port) {
+            *device = kind;
+        }
+    }
+    //Where the gun points in screen pixels, anything off the 256x240 picture is pointing away from the TV
+    pub fn set_zapper(&mut self, x: i32, y: i32, is_trigger: bool) {
+        if self.is_movie_playing() {
+            return;
+        }
+        self.cpu_sys.set_zapper(x, y, is_trigger);
+    }
+    //Arkanoid knob from 0 turned all the way left to 255 all the way right
+    pub fn set_arkanoid_paddle(&mut self, position: u8, is_button: bool) {
+        if self.is_movie_playing() {
+            return;
+        }
+        self.cpu_sys.set_arkanoid_paddle(position, is_button);
+    }
+    //Power Pad or Family Trainer buttons, bit n for button n + 1
+    pub fn set_power_pad(&mut self, mask: u16) {
+        if self.is_movie_playing() {
+            return;
+        }
+        self.cpu_sys.set_power_pad(mask);
+    }
+    //Mouse movement since the last call, in screen pixels. The game sees it from the next frame
+    pub fn move_snes_mouse(&mut self, dx: i32, dy: i32, is_left: bool, is_right: bool) {
+        if self.is_movie_playing() {
+            return;
+        }
+        self.cpu_sys.move_snes_mouse(dx, dy, is_left, is_right);
+    }
+    //Start recording controller input. From power on resets the console first, otherwise the movie starts from a
+    //snapshot of right now and can be replayed here but not exported
+    pub fn start_movie_recording(&mut self, from_power_on: bool, rom_filename: &str) {
+      console_log!("WasmEmulator::start_movie_recording()");
+        let start = if from_power_on {
+            self.movie = None;
+            self.power_on();
+            None
+        } else {
+            Some(Box::new(Snapshot::capture(&self.cpu, &self.cpu_sys, &self.ppu)))
+        };
+        let movie = Movie::for_system(&self.cpu_sys, rom_filename);
+        self.movie = Some(MovieSession::record(movie, start));
+    }
+    //Play an FCEUX FM2 movie from power on. The movie's controllers, Four Score and TV system replace the user's
+    pub fn load_movie_fm2(&mut self, text: &str) -> Result<(), JsValue> {
+      console_log!("WasmEmulator::load_movie_fm2()");
+        let movie = Movie::parse_fm2(text).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.input_devices[0] = movie.ports[0].input_device();
+        self.input_devices[1] = movie.ports[1].input_device();
+        self.input_devices[EXPANSION_PORT] = movie.expansion.input_device();
+        self.four_player = if movie.is_four_score { FourPlayerMode::FourScore } else { FourPlayerMode::Off };
+        self.region = if movie.is_pal { Region::Pal } else { Region::Ntsc };
+        self.movie = None;
+        self.power_on();
+        self.movie = Some(MovieSession::play(movie, None));
+        Ok(())
+    }
+    //Play the current movie again from its start
+    pub fn replay_movie(&mut self) {
+        let mut session = match self.movie.take() {
+            Some(session) => session,
+            None => return,
+        };
+        match &session.start {
+            Some(start) => start.restore(&mut self.cpu, &mut self.cpu_sys, &mut self.ppu),
+            None => self.power_on(),
+        }
+        self.desync_run_ahead();
+        session.mode = MovieMode::Playing;
+        session.frame = 0;
+        self.movie = Some(session);
+    }
+    //Stop playback where it is and record over the rest of the movie
+    pub fn record_movie_from_here(&mut self) {
+        if let Some(session) = self.movie.as_mut() {
+            session.record_from_here();
+        }
+    }
+    pub fn stop_movie(&mut self) {
+        self.movie = None;
+    }
+    pub fn export_movie_fm2(&self) -> Result<String, JsValue> {
+        let session = self.movie.as_ref().ok_or_else(|| JsValue::from_str("No movie to export"))?;
+        if session.start.is_some() {
+            return Err(JsValue::from_str(&MovieError::NotFromPowerOn.to_string()));
         }
-        success
+        session.movie.to_fm2().map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+    pub fn is_movie_recording(&self) -> bool {
+        self.movie.as_ref().is_some_and(|session| session.mode == MovieMode::Recording)
+    }
+    //Playback owns the controllers, the player's input is ignored until it finishes
+    pub fn is_movie_playing(&self) -> bool {
+        self.movie.as_ref().is_some_and(|session| session.mode == MovieMode::Playing)
+    }
+    pub fn get_movie_frame(&self) -> usize {
+        self.movie.as_ref().map_or(0, |session| session.frame)
+    }
+    pub fn get_movie_length(&self) -> usize {
+        self.movie.as_ref().map_or(0, |session| session.movie.frames.len())
+    }
+    pub fn get_movie_rerecord_count(&self) -> u32 {
+        self.movie.as_ref().map_or(0, |session| session.movie.rerecord_count)
+    }
+    //Real CHR ROM can't be written to, but some homebrew and misheadered dumps depend on it anyway
+    pub fn set_chr_rom_writable(&mut self, is_writable: bool) {
+        self.cpu_sys.rom.is_chr_rom_writable = is_writable;
+        //Writable CHR ROM is kept in snapshots and otherwise left out, so older ones can't be rewound to
+        self.rewind.clear();
+        self.desync_run_ahead();
+    }
+    //RGB image of a pattern table for the debug viewer, see Ppu::draw_pattern_table
+    pub fn get_pattern_table(&mut self, table_index: u8, palette_id: u8) -> Vec<u8> {
+        self.ppu.draw_pattern_table(&mut self.cpu_sys, table_index, palette_id, &self.palette)
+    }
+    //Audio produced since the last call, mono at the rate set below
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.cpu_sys.apu.samples)
+    }
+    //Whatever the browser's AudioContext runs at
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu_sys.apu.sample_rate = sample_rate.max(1);
+    }
+    //NSF player, everything below is meaningless when a cartridge is loaded
+    pub fn is_nsf(&self) -> bool {
+        self.cpu_sys.rom.nsf.is_some()
+    }
+    pub fn get_nsf_track_count(&self) -> u8 {
+        self.cpu_sys.rom.nsf.as_ref().map_or(0, |nsf| nsf.track_count())
+    }
+    //0 based
+    pub fn get_nsf_track(&self) -> u8 {
+        self.nsf_player.track
+    }
+    //Start a track over from the beginning
+    pub fn set_nsf_track(&mut self, track: u8) {
+        if self.get_nsf_track_count() == 0 {
+            return;
+        }
+        let track = track % self.get_nsf_track_count();
+        self.cpu.reset();
+        self.cpu_sys.reset();
+        self.nsf_player.start(&mut self.cpu, &mut self.cpu_sys, track);
+    }
+    pub fn get_nsf_title(&self) -> Option<String> {
+        self.cpu_sys.rom.nsf.as_ref().map(|nsf| nsf.title.clone())
+    }
+    pub fn get_nsf_artist(&self) -> Option<String> {
+        self.cpu_sys.rom.nsf.as_ref().map(|nsf| nsf.artist.clone())
+    }
+    pub fn get_nsf_copyright(&self) -> Option<String> {
+        self.cpu_sys.rom.nsf.as_ref().map(|nsf| nsf.copyright.clone())
+    }
+    //NSFe track names and lengths, when the rip has them
+    pub fn get_nsf_track_title(&self) -> Option<String> {
+        let nsf = self.cpu_sys.rom.nsf.as_ref()?;
+        nsf.track_title(self.nsf_player.track).map(String::from)
+    }
+    pub fn get_nsf_track_length_ms(&self) -> Option<u32> {
+        let nsf = self.cpu_sys.rom.nsf.as_ref()?;
+        nsf.track_time(self.nsf_player.track)
     }
-    //Clock tick, called in a loop in js
+    //Names of the sound chips the tune was written for besides the 2A03
+    pub fn get_nsf_expansion_audio(&self) -> js_sys::Array {
+        match &self.cpu_sys.rom.nsf {
+            Some(nsf) => nsf.expansion_audio_names().into_iter().map(JsValue::from).collect(),
+            None => js_sys::Array::new(),
+        }
+    }
+    //How long the current track has been playing, in emulated time
+    pub fn get_nsf_elapsed_seconds(&self) -> f64 {
+        (self.nsf_player.elapsed_cycles as f64) / f64::from(self.cpu_sys.region.cpu_freq())
+    }
+    //The FDS BIOS (disksys.rom), needed before any disk image will load. Stays loaded until replaced
+    pub fn set_fds_bios(&mut self, bios: &[u8]) -> Result<(), JsValue> {
+      console_log!("WasmEmulator::set_fds_bios()");
+        self.cpu_sys
+            .rom
+            .set_fds_bios(bios)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+    //Disk System, everything below does nothing when a cartridge is loaded
+    pub fn is_fds(&self) -> bool {
+        self.cpu_sys.rom.fds().is_some()
+    }
+    pub fn get_fds_side_count(&self) -> u8 {
+        self.cpu_sys.rom.fds().map_or(0, |fds| fds.side_count() as u8)
+    }
+    //0 for disk 1 side A, 1 for side B and so on. Nothing while the drive is empty
+    pub fn get_fds_side(&self) -> Option<u8> {
+        self.cpu_sys.rom.fds().and_then(|fds| fds.side()).map(|side| side as u8)
+    }
+    //Flip the disk or put in another one, the old side comes out first so the game notices
+    pub fn insert_fds_side(&mut self, side: u8) {
+        if let Some(fds) = self.cpu_sys.rom.fds_mut() {
+            fds.insert(usize::from(side));
+        }
+        self.desync_run_ahead();
+    }
+    pub fn eject_fds_disk(&mut self) {
+        if let Some(fds) = self.cpu_sys.rom.fds_mut() {
+            fds.eject();
+        }
+        self.desync_run_ahead();
+    }
+    //What the game has written to the disk, as an IPS patch against the image that was loaded
+    pub fn get_fds_save(&self) -> Vec<u8> {
+        self.cpu_sys.rom.fds().map_or_else(Vec::new, |fds| fds.save())
+    }
+    //Put a save from get_fds_save back on the disk, do it straight after loading the image
+    pub fn load_fds_save(&mut self, save: &[u8]) -> Result<(), JsValue> {
+      console_log!("WasmEmulator::load_fds_save()");
+        if let Some(fds) = self.cpu_sys.rom.fds_mut() {
+            fds.load_save(save).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        }
+        self.reset();
+        Ok(())
+    }
+    //Runs up to the start of the next vblank, the point where a frame has just been drawn
+    pub fn run_frame(&mut self) -> RunStatus {
+        //NSFs have no picture, a frame is just a frame's worth of cycles
+        if self.cpu_sys.rom.nsf.is_some() {
+            self.step_line();
+            return RunStatus::FrameComplete;
+        }
+        let (status, _) = self.run_until_frame(f64::INFINITY);
+        if status != RunStatus::Breakpoint && self.run_ahead_frames > 0 {
+            self.run_ahead();
+        }
+        status
+    }
+    //Runs as much as host_elapsed_ms of real time is worth at the current speed, going by the region's master clock
+    //so PAL and NTSC run at exactly the right rate whatever the host's refresh rate. FrameComplete if at least one
+    //frame was finished, so there's something new to draw
+    pub fn run_for(&mut self, host_elapsed_ms: f64) -> RunStatus {
+        let region = self.cpu_sys.region;
+        let master_cycle_per_frame = (region.master_cycle_per_line() * usize::from(region.scanlines_per_frame())) as f64;
+        self.master_cycle_debt += host_elapsed_ms * f64::from(region.master_clock()) / 1000.0 * self.speed;
+        self.master_cycle_debt =
+            self.master_cycle_debt.min(master_cycle_per_frame * MAX_CATCH_UP_FRAMES * self.speed.max(1.0));
+        if self.cpu_sys.rom.nsf.is_some() {
+            let cpu_divider = region.cpu_divider() as f64;
+            while self.master_cycle_debt > 0.0 {
+                let cpu_cycle = self.nsf_player.step(&mut self.cpu, &mut self.cpu_sys);
+                self.cpu_sys.step_apu(cpu_cycle);
+                self.master_cycle_debt -= cpu_cycle as f64 * cpu_divider;
+            }
+            return RunStatus::TimeUp;
+        }
+        let mut status = RunStatus::TimeUp;
+        while self.master_cycle_debt > 0.0 {
+            let (frame_status, master_cycles) = self.run_until_frame(self.master_cycle_debt);
+            self.master_cycle_debt -= master_cycles;
+            match frame_status {
+                RunStatus::Breakpoint => {
+                    //Don't make up for the time spent stopped once it carries on
+                    self.master_cycle_debt = 0.0;
+                    return RunStatus::Breakpoint;
+                }
+                RunStatus::FrameComplete | RunStatus::Jam => status = frame_status,
+                RunStatus::TimeUp => {}
+            }
+        }
+        if status != RunStatus::TimeUp && self.run_ahead_frames > 0 {
+            self.run_ahead();
+        }
+        status
+    }
+    //1.0 is full speed, 2.0 fast forwards at double and 0.5 is half speed slow motion. Frame advance is run_frame
+    //while not calling run_for
+    pub fn set_speed(&mut self, speed: f64) {
+        if speed > 0.0 {
+            self.speed = speed;
+        }
+    }
+    pub fn get_speed(&self) -> f64 {
+        self.speed
+    }
+    //Stop before running the instruction at addr
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|&breakpoint| breakpoint != addr);
+    }
+    pub fn get_cpu_pc(&self) -> u16 {
+        self.cpu.pc
+    }
+    //Frames per second of the region being emulated, 60.1 for NTSC and 50 for PAL/Dendy. run_for works this out from
+    //the elapsed time by itself, this is for display
+    pub fn get_frame_rate(&self) -> f64 {
+        self.cpu_sys.region.frame_rate()
+    }
+    //A frame's worth of CPU cycles without lining up with the picture, run_frame and run_for have taken over from it
     pub fn step_line(&mut self) {
        
+        let cycle_per_frame = self.cpu_sys.region.cpu_cycle_per_frame();
         let mut total_cycle: usize = 0;
-        while total_cycle < CYCLE_PER_DRAW_FRAME {
-            let cpu_cycle = usize::from(self.cpu.step(&mut self.cpu_sys));
-            if let Some(interrupt) = self.ppu.step(cpu_cycle, &mut self.cpu_sys, &mut self.fb) {
-                self.cpu.interrupt(&mut self.cpu_sys, interrupt);
+        //No picture for NSFs, the player stands in for the PPU's NMI
+        if self.cpu_sys.rom.nsf.is_some() {
+            while total_cycle < cycle_per_frame {
+                let cpu_cycle = self.nsf_player.step(&mut self.cpu, &mut self.cpu_sys);
+                self.cpu_sys.step_apu(cpu_cycle);
+                total_cycle += cpu_cycle;
             }
-            total_cycle = total_cycle + cpu_cycle;
+            return;
+        }
+        while total_cycle < cycle_per_frame {
+            total_cycle += self.step_instruction().0;
         }
+        if self.run_ahead_frames > 0 {
+            self.run_ahead();
+        }
+    }
+    //Frames to run ahead, 0 turns it off. One or two is usually enough to take out a game's own lag, more starts
+    //to show as jitter. A second instance costs another copy of the console, but while the input stays the same it
+    //runs one frame a frame instead of frames frames
+    pub fn set_run_ahead(&mut self, frames: u32, use_second_instance: bool) {
+        self.run_ahead_frames = frames;
+        self.is_run_ahead_second_instance = use_second_instance;
+        self.run_ahead_instance = None;
+        self.desync_run_ahead();
+    }
+    //Go back up to frames frames, returns how many it actually went back. Lands on the start of vblank
+    pub fn rewind(&mut self, frames: u32) -> u32 {
+        if self.cpu_sys.rom.nsf.is_some() {
+            return 0;
+        }
+        let target = self.frame_count.saturating_sub(u64::from(frames));
+        let (snapshot, frame, inputs) = match self.rewind.seek(target) {
+            Some(seek) => seek,
+            None => return 0,
+        };
+        snapshot.restore_without_rom(&mut self.cpu, &mut self.cpu_sys, &mut self.ppu);
+        self.desync_run_ahead();
+        if let Some(session) = self.movie.as_mut() {
+            session.rewind((self.frame_count - frame) as usize);
+        }
+        let rewound = self.frame_count - frame - inputs.len() as u64;
+        self.frame_count = frame;
+        self.replay = inputs.into();
+        while !self.replay.is_empty() {
+            self.step_instruction();
+        }
+        //What was playing before the rewind
+        self.cpu_sys.apu.samples.clear();
+        rewound as u32
     }
-    //Need to hook the buttons on the keyboard up to the back end
+    //Frames of history to keep, 0 turns rewind off. It also stops short of max_bytes of memory
+    pub fn set_rewind_length(&mut self, frames: u32, max_bytes: u32) {
+        self.rewind.length = frames as usize;
+        self.rewind.max_bytes = max_bytes as usize;
+    }
+    pub fn get_rewind_available_frames(&self) -> u32 {
+        self.rewind.available_frames(self.frame_count) as u32
+    }
+    //Every button for one player as a mask, A in bit 0 through Right in bit 7. Players are numbered from 0
+    pub fn set_buttons(&mut self, port: usize, mask: u8) {
+        if self.is_movie_playing() {
+            return;
+        }
+        self.cpu_sys.set_buttons(port, mask);
+    }
+    //Need to hook the buttons on the keyboard up to player 1
     pub fn update_key(&mut self, key: KeyEvent) {
+        if self.is_movie_playing() {
+            return;
+        }
+        let pad = match self.cpu_sys.pad_mut(0) {
+            Some(pad) => pad,
+            None => return,
+        };
         match key {
-            KeyEvent::PressA => self.cpu_sys.pad1.push_button(PadButton::A),
-            KeyEvent::PressB => self.cpu_sys.pad1.push_button(PadButton::B),
-            KeyEvent::PressSelect => self.cpu_sys.pad1.push_button(PadButton::Select),
-            KeyEvent::PressStart => self.cpu_sys.pad1.push_button(PadButton::Start),
-            KeyEvent::PressUp => self.cpu_sys.pad1.push_button(PadButton::Up),
-            KeyEvent::PressDown => self.cpu_sys.pad1.push_button(PadButton::Down),
-            KeyEvent::PressLeft => self.cpu_sys.pad1.push_button(PadButton::Left),
-            KeyEvent::PressRight => self.cpu_sys.pad1.push_button(PadButton::Right),
-
-            KeyEvent::ReleaseA => self.cpu_sys.pad1.release_button(PadButton::A),
-            KeyEvent::ReleaseB => self.cpu_sys.pad1.release_button(PadButton::B),
-            KeyEvent::ReleaseSelect => self.cpu_sys.pad1.release_button(PadButton::Select),
-            KeyEvent::ReleaseStart => self.cpu_sys.pad1.release_button(PadButton::Start),
-            KeyEvent::ReleaseUp => self.cpu_sys.pad1.release_button(PadButton::Up),
-            KeyEvent::ReleaseDown => self.cpu_sys.pad1.release_button(PadButton::Down),
-            KeyEvent::ReleaseLeft => self.cpu_sys.pad1.release_button(PadButton::Left),
-            KeyEvent::ReleaseRight => self.cpu_sys.pad1.release_button(PadButton::Right),
-        }
-    }
-}
\ No newline at end of file
+            KeyEvent::PressA => pad.push_button(PadButton::A),
+            KeyEvent::PressB => pad.push_button(PadButton::B),
+            KeyEvent::PressSelect => pad.push_button(PadButton::Select),
+            KeyEvent::PressStart => pad.push_button(PadButton::Start),
+            KeyEvent::PressUp => pad.push_button(PadButton::Up),
+            KeyEvent::PressDown => pad.push_button(PadButton::Down),
+            KeyEvent::PressLeft => pad.push_button(PadButton::Left),
+            KeyEvent::PressRight => pad.push_button(PadButton::Right),
+
+            KeyEvent::ReleaseA => pad.release_button(PadButton::A),
+            KeyEvent::ReleaseB => pad.release_button(PadButton::B),
+            KeyEvent::ReleaseSelect => pad.release_button(PadButton::Select),
+            KeyEvent::ReleaseStart => pad.release_button(PadButton::Start),
+            KeyEvent::ReleaseUp => pad.release_button(PadButton::Up),
+            KeyEvent::ReleaseDown => pad.release_button(PadButton::Down),
+            KeyEvent::ReleaseLeft => pad.release_button(PadButton::Left),
+            KeyEvent::ReleaseRight => pad.release_button(PadButton::Right),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::{ines, nrom};
+
+    fn emulator() -> WasmEmulator {
+        let mut emu = WasmEmulator::default();
+        emu.cpu_sys.rom.load_bin(&nrom()).unwrap();
+        emu.power_on();
+        emu
+    }
+
+    #[test]
+    fn rewind_lands_on_the_frame_asked_for() {
+        let mut emu = emulator();
+        for _ in 0..20 {
+            emu.run_frame();
+        }
+        //Exactly one snapshot interval back lands on a snapshot's own frame
+        for frames in [4, 1, 3, 9] {
+            let target = emu.frame_count - frames;
+            assert_eq!(emu.rewind(frames as u32), frames as u32);
+            assert_eq!(emu.frame_count, target);
+            for _ in 0..frames {
+                emu.run_frame();
+            }
+        }
+    }
+
+    #[test]
+    fn rewind_restores_chr_ram() {
+        let mut emu = WasmEmulator::default();
+        emu.cpu_sys.rom.load_bin(&ines(0, 2, 0)).unwrap();
+        emu.power_on();
+        emu.cpu_sys.rom.write_video_u8(0x0123, 0x45);
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        emu.cpu_sys.rom.write_video_u8(0x0123, 0x67);
+        for _ in 0..3 {
+            emu.run_frame();
+        }
+        assert_eq!(emu.rewind(8), 8);
+        assert_eq!(emu.cpu_sys.rom.read_video_u8(0x0123), 0x45);
+    }
+
+    #[test]
+    fn rewind_past_making_chr_rom_writable() {
+        let mut emu = emulator();
+        let original = emu.cpu_sys.rom.read_video_u8(0x0123);
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        emu.set_chr_rom_writable(true);
+        emu.cpu_sys.rom.write_video_u8(0x0123, !original);
+        emu.run_frame();
+        emu.rewind(8);
+        assert_eq!(emu.cpu_sys.rom.read_video_u8(0x0123), !original);
+        emu.set_chr_rom_writable(false);
+        emu.run_frame();
+        emu.rewind(1);
+        assert_eq!(emu.cpu_sys.rom.read_video_u8(0x0123), !original);
+    }
+
+    #[test]
+    fn fast_forward_runs_past_the_catch_up_limit() {
+        let mut emu = emulator();
+        emu.set_speed(10.0);
+        let frame_ms = 1000.0 / emu.cpu_sys.region.frame_rate();
+        emu.run_for(frame_ms);
+        assert!(emu.frame_count >= 9, "{} frames", emu.frame_count);
+        //A stall at normal speed still only catches up a few frames
+        emu.set_speed(1.0);
+        let before = emu.frame_count;
+        emu.run_for(frame_ms * 100.0);
+        assert!(emu.frame_count - before <= MAX_CATCH_UP_FRAMES as u64 + 1);
+    }
+}
@@ -0,0 +1,812 @@
+/* NSF and NSFe music files */
+//https://wiki.nesdev.com/w/index.php/NSF
+//https://wiki.nesdev.com/w/index.php/NSFe
+//An NSF is a game's sound driver and music data ripped out on their own. There's no reset vector and no PPU, just an
+//INIT routine to call once per track and a PLAY routine to call at a fixed rate, usually once a frame. The code is
+//bankswitched in 4k pages through $5FF8-$5FFF, the same scheme mapper 31 carts use.
+use std::fmt;
+
+use super::cpu::Cpu;
+use super::expansion_audio::ExpansionAudio;
+use super::region::Region;
+use super::system::System;
+
+pub const NSF_HEADER_SIZE: usize = 0x80;
+pub const NSF_BANK_SIZE: usize = 0x1000;
+//The iNES mapper with the same $5FF8 bankswitching
+pub const NSF_MAPPER: u16 = 31;
+//256 4k banks
+pub const NSF_PRG_ROM_MAX_SIZE: usize = 0x10_0000;
+//$6000-$FFFF in 4k banks, the first two only switch on FDS tunes
+pub const NSF_NUM_OF_BANKS: usize = 10;
+pub const NSF_BANK_REG_BASE_ADDR: u16 = 0x5ff6;
+pub const NSF_RAM_BASE_ADDR: u16 = 0x6000;
+//Mapper 31 carts power up with the last bank at $F000 so the vectors are there
+pub const MAPPER31_INIT_BANKS: [u8; NSF_NUM_OF_BANKS] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff];
+//MMC5 tunes get its 1k of ExRAM as extra work RAM, minus the top few bytes where the bank registers are
+const NSF_MMC5_EXRAM_BASE_ADDR: u16 = 0x5c00;
+const NSF_MMC5_EXRAM_SIZE: usize = 0x3f6;
+
+//Where INIT and PLAY "return" to. Nothing ever runs from the empty space at $4100, so the player knows a routine
+//is finished when the CPU gets here
+const NSF_RETURN_ADDR: u16 = 0x4100;
+//Used when the header leaves the play rate at 0
+const NSF_DEFAULT_NTSC_PLAY_SPEED: u16 = 16639;
+const NSF_DEFAULT_PAL_PLAY_SPEED: u16 = 19997;
+//Longest the player idles in one go while waiting for PLAY, so the APU is still stepped in small pieces
+const NSF_IDLE_CYCLES: isize = 256;
+
+//The extra sound chips a tune can ask for, byte $7B
+pub const NSF_EXPANSION_VRC6: u8 = 0x01;
+pub const NSF_EXPANSION_VRC7: u8 = 0x02;
+pub const NSF_EXPANSION_FDS: u8 = 0x04;
+pub const NSF_EXPANSION_MMC5: u8 = 0x08;
+pub const NSF_EXPANSION_N163: u8 = 0x10;
+pub const NSF_EXPANSION_SUNSOFT5B: u8 = 0x20;
+const NSF_EXPANSION_NAMES: [(u8, &str); 6] = [
+    (NSF_EXPANSION_VRC6, "VRC6"),
+    (NSF_EXPANSION_VRC7, "VRC7"),
+    (NSF_EXPANSION_FDS, "FDS"),
+    (NSF_EXPANSION_MMC5, "MMC5"),
+    (NSF_EXPANSION_N163, "Namco 163"),
+    (NSF_EXPANSION_SUNSOFT5B, "Sunsoft 5B"),
+];
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum NsfError {
+    //Header or chunk runs past the end of the file
+    Truncated,
+    NoSongs,
+    //NSFe files need both INFO and DATA
+    MissingChunk(&'static str),
+    //NSFe chunks starting with a capital letter have to be understood to play the file
+    UnsupportedChunk(String),
+    //Non-bankswitched tunes have to load somewhere in ROM space
+    BadLoadAddr(u16),
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NsfError::Truncated => write!(f, "NSF file is truncated"),
+            NsfError::NoSongs => write!(f, "NSF file has no songs"),
+            NsfError::MissingChunk(chunk) => write!(f, "NSFe file has no {} chunk", chunk),
+            NsfError::UnsupportedChunk(chunk) => write!(f, "NSFe chunk {} is required but not supported", chunk),
+            NsfError::BadLoadAddr(addr) => write!(f, "NSF load address ${:04X} is outside ROM", addr),
+        }
+    }
+}
+
+//Everything about the tune other than the code itself
+#[derive(Clone, Debug, Default)]
+pub struct NsfInfo {
+    //1 or 2 for NSF, 0 for NSFe
+    pub version: u8,
+    pub total_songs: u8,
+    //0 based
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    //PLAY rate in microseconds
+    pub ntsc_play_speed: u16,
+    pub pal_play_speed: u16,
+    pub timing: Region,
+    pub expansion_audio: u8,
+    //What goes in the bank registers before INIT, $6000 first. Already worked out for non-bankswitched tunes
+    pub init_banks: [u8; NSF_NUM_OF_BANKS],
+    pub is_bankswitched: bool,
+    //NSFe and NSF2 extras, empty when the file doesn't have them
+    pub track_titles: Vec<String>,
+    //Milliseconds, None for tracks that loop forever or weren't timed
+    pub track_times: Vec<Option<u32>>,
+    pub track_fades: Vec<Option<u32>>,
+    //The order the ripper wants the tracks played in, song numbers
+    pub playlist: Vec<u8>,
+}
+
+impl NsfInfo {
+    pub fn is_fds(&self) -> bool {
+        (self.expansion_audio & NSF_EXPANSION_FDS) == NSF_EXPANSION_FDS
+    }
+    pub fn expansion_audio_names(&self) -> Vec<&'static str> {
+        NSF_EXPANSION_NAMES
+            .iter()
+            .filter(|(flag, _)| (self.expansion_audio & flag) == *flag)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+    //Tracks as the player sees them, going through the playlist when there is one
+    pub fn track_count(&self) -> u8 {
+        if self.playlist.is_empty() {
+            self.total_songs
+        } else {
+            self.playlist.len() as u8
+        }
+    }
+    pub fn song_number(&self, track: u8) -> u8 {
+        if self.playlist.is_empty() {
+            track
+        } else {
+            self.playlist[usize::from(track) % self.playlist.len()]
+        }
+    }
+    //Which track a fresh load starts on
+    pub fn starting_track(&self) -> u8 {
+        if self.playlist.is_empty() {
+            self.starting_song
+        } else {
+            0
+        }
+    }
+    pub fn track_title(&self, track: u8) -> Option<&str> {
+        self.track_titles
+            .get(usize::from(self.song_number(track)))
+            .map(|title| title.as_str())
+            .filter(|title| !title.is_empty())
+    }
+    pub fn track_time(&self, track: u8) -> Option<u32> {
+        self.track_times.get(usize::from(self.song_number(track))).copied().flatten()
+    }
+    //PLAY rate in CPU cycles
+    pub fn play_period(&self, region: Region) -> usize {
+        let speed = match region {
+            Region::Pal | Region::Dendy => match self.pal_play_speed {
+                0 => NSF_DEFAULT_PAL_PLAY_SPEED,
+                speed => speed,
+            },
+            _ => match self.ntsc_play_speed {
+                0 => NSF_DEFAULT_NTSC_PLAY_SPEED,
+                speed => speed,
+            },
+        };
+        (u64::from(speed) * u64::from(region.cpu_freq()) / 1_000_000) as usize
+    }
+}
+
+//A parsed tune, the code is laid out ready to be used as PRG ROM
+#[derive(Clone, Debug)]
+pub struct Nsf {
+    pub info: NsfInfo,
+    pub prg_rom: Vec<u8>,
+}
+
+pub fn is_nsf(binary: &[u8]) -> bool {
+    binary.starts_with(b"NESM\x1a") || binary.starts_with(b"NSFE")
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | (u16::from(data[offset + 1]) << 8)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(data, offset)) | (u32::from(read_u16(data, offset + 2)) << 16)
+}
+
+//The header's fixed size strings, and NSFe's zero terminated ones
+fn read_string(data: &[u8]) -> String {
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).trim().to_string()
+}
+
+fn read_strings(data: &[u8]) -> Vec<String> {
+    let mut strings: Vec<String> = data.split(|b| *b == 0).map(read_string).collect();
+    //Everything is terminated, so there's always an empty one left over at the end
+    if data.last() == Some(&0) {
+        strings.pop();
+    }
+    strings
+}
+
+fn read_times(data: &[u8]) -> Vec<Option<u32>> {
+    data.chunks_exact(4)
+        .map(|time| {
+            let ms = read_u32(time, 0) as i32;
+            if ms < 0 {
+                None
+            } else {
+                Some(ms as u32)
+            }
+        })
+        .collect()
+}
+
+fn timing_from_flags(flags: u8) -> Region {
+    match flags & 0x03 {
+        0 => Region::Ntsc,
+        1 => Region::Pal,
+        _ => Region::Auto,
+    }
+}
+
+impl Nsf {
+    pub fn parse(binary: &[u8]) -> Result<Nsf, NsfError> {
+        let mut info = NsfInfo::default();
+        let mut header_banks = [0; 8];
+        let data = if binary.starts_with(b"NSFE") {
+            let (data, banks) = parse_nsfe_chunks(&binary[4..], &mut info, true)?;
+            header_banks = banks;
+            data.ok_or(NsfError::MissingChunk("DATA"))?
+        } else {
+            if binary.len() < NSF_HEADER_SIZE {
+                return Err(NsfError::Truncated);
+            }
+            info.version = binary[0x05];
+            info.total_songs = binary[0x06];
+            info.starting_song = binary[0x07].saturating_sub(1);
+            info.load_addr = read_u16(binary, 0x08);
+            info.init_addr = read_u16(binary, 0x0a);
+            info.play_addr = read_u16(binary, 0x0c);
+            info.title = read_string(&binary[0x0e..0x2e]);
+            info.artist = read_string(&binary[0x2e..0x4e]);
+            info.copyright = read_string(&binary[0x4e..0x6e]);
+            info.ntsc_play_speed = read_u16(binary, 0x6e);
+            header_banks.copy_from_slice(&binary[0x70..0x78]);
+            info.pal_play_speed = read_u16(binary, 0x78);
+            info.timing = timing_from_flags(binary[0x7a]);
+            info.expansion_audio = binary[0x7b];
+            //NSF2 can say how long the program data is, and put NSFe metadata chunks after it
+            let data_len = (read_u32(binary, 0x7c) >> 8) as usize;
+            if info.version >= 2 && data_len > 0 {
+                let data_end = NSF_HEADER_SIZE + data_len;
+                let metadata = binary.get(data_end..).ok_or(NsfError::Truncated)?;
+                parse_nsfe_chunks(metadata, &mut info, false)?;
+                &binary[NSF_HEADER_SIZE..data_end]
+            } else {
+                &binary[NSF_HEADER_SIZE..]
+            }
+        };
+        if info.total_songs == 0 {
+            return Err(NsfError::NoSongs);
+        }
+        info.starting_song = info.starting_song.min(info.total_songs - 1);
+        let total_songs = info.total_songs;
+        info.playlist.retain(|song| *song < total_songs);
+
+        info.is_bankswitched = header_banks.iter().any(|bank| *bank != 0);
+        let prg_rom = if info.is_bankswitched {
+            //The data is padded so the load address lands at the right place in its bank
+            let padding = usize::from(info.load_addr) & (NSF_BANK_SIZE - 1);
+            let mut prg_rom = vec![0; padding];
+            prg_rom.extend_from_slice(data);
+            let len = prg_rom.len().div_ceil(NSF_BANK_SIZE).max(1) * NSF_BANK_SIZE;
+            prg_rom.resize(len.min(NSF_PRG_ROM_MAX_SIZE), 0);
+            info.init_banks[2..].copy_from_slice(&header_banks);
+            //FDS tunes start out with $6000-$7FFF holding the same banks as $E000-$FFFF
+            info.init_banks[0] = header_banks[6];
+            info.init_banks[1] = header_banks[7];
+            prg_rom
+        } else {
+            //Not bankswitched, lay the whole address space out as ROM and give every bank its own page
+            let base_addr = if info.is_fds() { NSF_RAM_BASE_ADDR } else { 0x8000 };
+            if info.load_addr < base_addr {
+                return Err(NsfError::BadLoadAddr(info.load_addr));
+            }
+            let mut prg_rom = vec![0; 0x10000 - usize::from(base_addr)];
+            let offset = usize::from(info.load_addr - base_addr);
+            let len = data.len().min(prg_rom.len() - offset);
+            prg_rom[offset..offset + len].copy_from_slice(&data[..len]);
+            let first_bank = if info.is_fds() { 0 } else { 2 };
+            for (i, bank) in info.init_banks.iter_mut().enumerate().skip(first_bank) {
+                *bank = (i - first_bank) as u8;
+            }
+            prg_rom
+        };
+        Ok(Nsf { info, prg_rom })
+    }
+}
+
+//NSFe is all chunks, NSF2 tacks the metadata ones onto the end. Hands back DATA and BANK when there are any
+fn parse_nsfe_chunks<'a>(
+    binary: &'a [u8],
+    info: &mut NsfInfo,
+    is_nsfe: bool,
+) -> Result<(Option<&'a [u8]>, [u8; 8]), NsfError> {
+    let mut data = None;
+    let mut banks = [0; 8];
+    let mut has_info = !is_nsfe;
+    let mut offset = 0;
+    while offset + 8 <= binary.len() {
+        let len = read_u32(binary, offset) as usize;
+        let id = &binary[offset + 4..offset + 8];
+        let chunk_start = offset + 8;
+        let chunk = binary
+            .get(chunk_start..chunk_start.saturating_add(len))
+            .ok_or(NsfError::Truncated)?;
+        offset = chunk_start + len;
+        match id {
+            b"INFO" if is_nsfe => {
+                if chunk.len() < 8 {
+                    return Err(NsfError::Truncated);
+                }
+                info.load_addr = read_u16(chunk, 0);
+                info.init_addr = read_u16(chunk, 2);
+                info.play_addr = read_u16(chunk, 4);
+                info.timing = timing_from_flags(chunk[6]);
+                info.expansion_audio = chunk[7];
+                info.total_songs = chunk.get(8).copied().unwrap_or(1);
+                info.starting_song = chunk.get(9).copied().unwrap_or(0);
+                has_info = true;
+            }
+            b"DATA" if is_nsfe => data = Some(chunk),
+            b"BANK" if is_nsfe => {
+                let len = chunk.len().min(banks.len());
+                banks[..len].copy_from_slice(&chunk[..len]);
+            }
+            b"RATE" => {
+                if chunk.len() >= 2 {
+                    info.ntsc_play_speed = read_u16(chunk, 0);
+                }
+                if chunk.len() >= 4 {
+                    info.pal_play_speed = read_u16(chunk, 2);
+                }
+            }
+            b"auth" => {
+                let mut strings = read_strings(chunk).into_iter();
+                info.title = strings.next().unwrap_or_default();
+                info.artist = strings.next().unwrap_or_default();
+                info.copyright = strings.next().unwrap_or_default();
+            }
+            b"tlbl" => info.track_titles = read_strings(chunk),
+            b"time" => info.track_times = read_times(chunk),
+            b"fade" => info.track_fades = read_times(chunk),
+            b"plst" => info.playlist = chunk.to_vec(),
+            b"NEND" => break,
+            //Lowercase chunks are optional and can be skipped, anything else we don't know means we can't play it
+            _ if !id[0].is_ascii_uppercase() => {}
+            b"INFO" | b"DATA" | b"BANK" => {}
+            _ => return Err(NsfError::UnsupportedChunk(String::from_utf8_lossy(id).into_owned())),
+        }
+    }
+    if !has_info {
+        return Err(NsfError::MissingChunk("INFO"));
+    }
+    Ok((data, banks))
+}
+
+//The bankswitching hardware, shared by NSF tunes and mapper 31 carts
+//https://wiki.nesdev.com/w/index.php/INES_Mapper_031
+#[derive(Clone, Debug)]
+pub struct NsfBanks {
+    //4k PRG bank for each of $6000-$FFFF
+    banks: [u8; NSF_NUM_OF_BANKS],
+    //FDS tunes run out of RAM, switching a bank copies it in
+    fds_ram: Vec<u8>,
+    //Whatever sound chips the tune uses, at the addresses their mappers put them
+    pub audio: ExpansionAudio,
+    //MMC5 tunes can use its ExRAM and multiplier too
+    mmc5_exram: Vec<u8>,
+    mmc5_multiplier: [u8; 2],
+}
+
+impl NsfBanks {
+    pub fn new(banks: [u8; NSF_NUM_OF_BANKS], expansion_audio: u8, prg_rom: &[u8]) -> Self {
+        let mut nsf_banks = Self {
+            banks,
+            fds_ram: Vec::new(),
+            audio: ExpansionAudio::from_nsf_flags(expansion_audio),
+            mmc5_exram: Vec::new(),
+            mmc5_multiplier: [0xff; 2],
+        };
+        if (expansion_audio & NSF_EXPANSION_FDS) == NSF_EXPANSION_FDS {
+            nsf_banks.fds_ram = vec![0; 0x10000 - usize::from(NSF_RAM_BASE_ADDR)];
+            for slot in 0..NSF_NUM_OF_BANKS {
+                nsf_banks.copy_to_fds_ram(slot, prg_rom);
+            }
+        }
+        if (expansion_audio & NSF_EXPANSION_MMC5) == NSF_EXPANSION_MMC5 {
+            nsf_banks.mmc5_exram = vec![0; NSF_MMC5_EXRAM_SIZE];
+        }
+        nsf_banks
+    }
+    //FDS RAM and ExRAM, for snapshots
+    pub fn ram_mut(&mut self) -> [&mut Vec<u8>; 2] {
+        [&mut self.fds_ram, &mut self.mmc5_exram]
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.fds_ram.capacity() + self.mmc5_exram.capacity() + self.audio.heap_bytes()
+    }
+    fn is_fds(&self) -> bool {
+        !self.fds_ram.is_empty()
+    }
+    fn prg_rom_index(&self, slot: usize, addr: u16, prg_rom: &[u8]) -> usize {
+        let num_of_banks = (prg_rom.len() / NSF_BANK_SIZE).max(1);
+        (usize::from(self.banks[slot]) % num_of_banks) * NSF_BANK_SIZE + (usize::from(addr) & (NSF_BANK_SIZE - 1))
+    }
+    fn copy_to_fds_ram(&mut self, slot: usize, prg_rom: &[u8]) {
+        let src = self.prg_rom_index(slot, 0, prg_rom);
+        let len = NSF_BANK_SIZE.min(prg_rom.len() - src);
+        let dst = slot * NSF_BANK_SIZE;
+        self.fds_ram[dst..dst + len].copy_from_slice(&prg_rom[src..src + len]);
+    }
+    //$4020-$5FFF, only the sound chips and MMC5 answer down here. The bank registers are write only
+    fn read_register(&mut self, addr: u16, is_nondestructive: bool) -> u8 {
+        let open_bus = (addr >> 8) as u8;
+        let audio = &mut self.audio;
+        match addr {
+            0x4040..=0x409f => audio.fds.as_ref().map_or(open_bus, |fds| fds.read_register(addr)),
+            0x4800..=0x4fff => audio.n163.as_mut().map_or(open_bus, |n163| n163.read_data(is_nondestructive)),
+            0x5000..=0x5015 => audio.mmc5.as_mut().and_then(|mmc5| mmc5.read_register(addr)).unwrap_or(open_bus),
+            0x5205 | 0x5206 if !self.mmc5_exram.is_empty() => {
+                let product = u16::from(self.mmc5_multiplier[0]) * u16::from(self.mmc5_multiplier[1]);
+                (product >> ((addr - 0x5205) * 8)) as u8
+            }
+            NSF_MMC5_EXRAM_BASE_ADDR..=0x5ff5 if !self.mmc5_exram.is_empty() => {
+                self.mmc5_exram[usize::from(addr - NSF_MMC5_EXRAM_BASE_ADDR)]
+            }
+            _ => open_bus,
+        }
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&mut self, addr: u16, prg_rom: &[u8], sram: &[u8], is_nondestructive: bool) -> u8 {
+        if addr < NSF_RAM_BASE_ADDR {
+            self.read_register(addr, is_nondestructive)
+        } else if self.is_fds() {
+            self.fds_ram[usize::from(addr - NSF_RAM_BASE_ADDR)]
+        } else if addr < 0x8000 {
+            sram[usize::from(addr - NSF_RAM_BASE_ADDR) % sram.len()]
+        } else {
+            let slot = usize::from((addr - NSF_RAM_BASE_ADDR) >> 12);
+            let data = prg_rom[self.prg_rom_index(slot, addr, prg_rom)];
+            //The MMC5's PCM channel can be fed straight from reads of $8000-$BFFF
+            match self.audio.mmc5 {
+                Some(ref mut mmc5) if addr < 0xc000 && !is_nondestructive => mmc5.read_pcm(data),
+                _ => {}
+            }
+            data
+        }
+    }
+    //The sound chips' registers, which can share addresses with ROM
+    fn write_audio(&mut self, addr: u16, data: u8) {
+        let audio = &mut self.audio;
+        match addr {
+            0x4040..=0x409f => {
+                if let Some(fds) = &mut audio.fds {
+                    fds.write_register(addr, data);
+                }
+            }
+            0x4800..=0x4fff => {
+                if let Some(n163) = &mut audio.n163 {
+                    n163.write_data(data);
+                }
+            }
+            0xf800..=0xffff if audio.n163.is_some() => {
+                if let Some(n163) = &mut audio.n163 {
+                    n163.write_addr(data);
+                }
+            }
+            0x5000..=0x5015 => {
+                if let Some(mmc5) = &mut audio.mmc5 {
+                    mmc5.write_register(addr, data);
+                }
+            }
+            0x9000..=0x9003 | 0xa000..=0xa002 | 0xb000..=0xb002 => {
+                if let Some(vrc6) = &mut audio.vrc6 {
+                    vrc6.write_register(addr, data);
+                }
+            }
+            0x9010 | 0x9030 => {
+                if let Some(vrc7) = &mut audio.vrc7 {
+                    vrc7.write_register(addr, data);
+                }
+            }
+            0xc000..=0xffff => {
+                if let Some(sunsoft5b) = &mut audio.sunsoft5b {
+                    sunsoft5b.write_register(addr, data);
+                }
+            }
+            _ => {}
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8, prg_rom: &[u8], sram: &mut [u8]) {
+        self.write_audio(addr, data);
+        match addr {
+            NSF_BANK_REG_BASE_ADDR..=0x5fff => {
+                let slot = usize::from(addr - NSF_BANK_REG_BASE_ADDR);
+                //$5FF6/$5FF7 only exist on FDS
+                if slot >= 2 || self.is_fds() {
+                    self.banks[slot] = data;
+                    if self.is_fds() {
+                        self.copy_to_fds_ram(slot, prg_rom);
+                    }
+                }
+            }
+            0x5205 | 0x5206 if !self.mmc5_exram.is_empty() => self.mmc5_multiplier[usize::from(addr - 0x5205)] = data,
+            NSF_MMC5_EXRAM_BASE_ADDR..=0x5ff5 if !self.mmc5_exram.is_empty() => {
+                self.mmc5_exram[usize::from(addr - NSF_MMC5_EXRAM_BASE_ADDR)] = data;
+            }
+            //$E000-$FFFF stays read only, that's where the BIOS would be
+            NSF_RAM_BASE_ADDR..=0xdfff if self.is_fds() => {
+                self.fds_ram[usize::from(addr - NSF_RAM_BASE_ADDR)] = data;
+            }
+            _ if self.is_fds() => {}
+            NSF_RAM_BASE_ADDR..=0x7fff => {
+                let len = sram.len();
+                sram[usize::from(addr - NSF_RAM_BASE_ADDR) % len] = data;
+            }
+            _ => {}
+        }
+    }
+}
+
+//Drives the CPU through INIT and then PLAY at the tune's rate, in place of the PPU's NMI
+#[derive(Clone, Debug, Default)]
+pub struct NsfPlayer {
+    //As the player sees it, see NsfInfo::song_number
+    pub track: u8,
+    play_addr: u16,
+    play_period: usize,
+    cycles_until_play: isize,
+    //In the middle of INIT or PLAY, waiting for it to return to NSF_RETURN_ADDR
+    is_in_routine: bool,
+    pub elapsed_cycles: u64,
+}
+
+impl NsfPlayer {
+    //Set the machine up the way the NSF spec says and call INIT for a track
+    pub fn start(&mut self, cpu: &mut Cpu, system: &mut System, track: u8) {
+        let (song, init_addr, play_addr, play_period, init_banks, expansion_audio) = match &system.rom.nsf {
+            Some(nsf) => (
+                nsf.song_number(track),
+                nsf.init_addr,
+                nsf.play_addr,
+                nsf.play_period(system.region),
+                nsf.init_banks,
+                nsf.expansion_audio,
+            ),
+            None => return,
+        };
+        self.track = track;
+        self.play_addr = play_addr;
+        self.play_period = play_period;
+        self.cycles_until_play = play_period as isize;
+        self.elapsed_cycles = 0;
+
+        for addr in 0x0000..0x0800 {
+            system.write_u8(addr, 0x00, false);
+        }
+        for addr in 0x6000..0x8000 {
+            system.write_u8(addr, 0x00, false);
+        }
+        //Banks go in after RAM is cleared, FDS tunes have some of their code copied into $6000-$7FFF
+        system.rom.reset_nsf_banks(init_banks, expansion_audio);
+        for addr in 0x4000..0x4014 {
+            system.write_u8(addr, 0x00, false);
+        }
+        system.write_u8(0x4015, 0x00, false);
+        system.write_u8(0x4015, 0x0f, false);
+        system.write_u8(0x4017, 0x40, false);
+
+        cpu.reset();
+        cpu.a = song;
+        cpu.x = match system.region {
+            Region::Pal | Region::Dendy => 1,
+            _ => 0,
+        };
+        self.call(cpu, system, init_addr);
+    }
+    //JSR into a routine that will RTS to NSF_RETURN_ADDR
+    fn call(&mut self, cpu: &mut Cpu, system: &mut System, addr: u16) {
+        let return_addr = NSF_RETURN_ADDR - 1;
+        cpu.stack_push(system, (return_addr >> 8) as u8);
+        cpu.stack_push(system, (return_addr & 0xff) as u8);
+        cpu.pc = addr;
+        self.is_in_routine = true;
+    }
+    //Run one instruction, or idle until PLAY is due. Returns the CPU cycles that took
+    pub fn step(&mut self, cpu: &mut Cpu, system: &mut System) -> usize {
+        let cpu_cycle = if self.is_in_routine {
+            let cpu_cycle = usize::from(cpu.step(system));
+            if cpu.pc == NSF_RETURN_ADDR {
+                self.is_in_routine = false;
+            }
+            cpu_cycle
+        } else {
+            self.cycles_until_play.clamp(1, NSF_IDLE_CYCLES) as usize
+        };
+        self.elapsed_cycles += cpu_cycle as u64;
+        self.cycles_until_play -= cpu_cycle as isize;
+        //A PLAY that runs long just delays the next one, rather than having them pile up
+        if self.cycles_until_play <= 0 && !self.is_in_routine {
+            self.cycles_until_play = self.cycles_until_play.max(0) + self.play_period as isize;
+            self.call(cpu, system, self.play_addr);
+        }
+        cpu_cycle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //An NSF header with the fields these tests care about, then the data
+    fn nsf(load_addr: u16, banks: [u8; 8], expansion_audio: u8, data: &[u8]) -> Vec<u8> {
+        let mut binary = vec![0; NSF_HEADER_SIZE];
+        binary[..5].copy_from_slice(b"NESM\x1a");
+        binary[0x05] = 1;
+        binary[0x06] = 3;
+        binary[0x07] = 2;
+        binary[0x08..0x0a].copy_from_slice(&load_addr.to_le_bytes());
+        binary[0x0a..0x0c].copy_from_slice(&0x8003u16.to_le_bytes());
+        binary[0x0c..0x0e].copy_from_slice(&0x8006u16.to_le_bytes());
+        binary[0x0e..0x13].copy_from_slice(b"Title");
+        binary[0x2e..0x34].copy_from_slice(b"Artist");
+        binary[0x6e..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        binary[0x70..0x78].copy_from_slice(&banks);
+        binary[0x7a] = 1;
+        binary[0x7b] = expansion_audio;
+        binary.extend_from_slice(data);
+        binary
+    }
+
+    fn chunk(binary: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        binary.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        binary.extend_from_slice(id);
+        binary.extend_from_slice(data);
+    }
+
+    fn nsfe(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut binary = b"NSFE".to_vec();
+        for (id, data) in chunks {
+            chunk(&mut binary, id, data);
+        }
+        binary
+    }
+
+    //Load $8000, INIT $8003, PLAY $8006, NTSC, no expansion audio, 2 songs starting on the second
+    const NSFE_INFO: &[u8] = &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x00, 0x00, 0x02, 0x01];
+
+    #[test]
+    fn nsf_header() {
+        let binary = nsf(0x8400, [0; 8], NSF_EXPANSION_VRC6 | NSF_EXPANSION_N163, &[1, 2, 3]);
+        assert!(is_nsf(&binary));
+        let info = Nsf::parse(&binary).unwrap().info;
+        assert_eq!(info.version, 1);
+        assert_eq!(info.total_songs, 3);
+        assert_eq!(info.starting_song, 1);
+        assert_eq!(info.starting_track(), 1);
+        assert_eq!((info.load_addr, info.init_addr, info.play_addr), (0x8400, 0x8003, 0x8006));
+        assert_eq!(info.title, "Title");
+        assert_eq!(info.artist, "Artist");
+        assert_eq!(info.copyright, "");
+        assert_eq!(info.timing, Region::Pal);
+        assert_eq!(info.expansion_audio_names(), vec!["VRC6", "Namco 163"]);
+        assert_eq!(info.play_period(Region::Ntsc), 29780);
+    }
+
+    #[test]
+    fn nsf_without_bankswitching_is_laid_out_at_its_load_address() {
+        let tune = Nsf::parse(&nsf(0x8400, [0; 8], 0, &[1, 2, 3])).unwrap();
+        assert!(!tune.info.is_bankswitched);
+        assert_eq!(tune.prg_rom.len(), 0x8000);
+        assert_eq!(&tune.prg_rom[0x400..0x403], &[1, 2, 3]);
+        assert_eq!(tune.info.init_banks, [0, 0, 0, 1, 2, 3, 4, 5, 6, 7]);
+        //FDS tunes can load into RAM at $6000 as well
+        let tune = Nsf::parse(&nsf(0x6000, [0; 8], NSF_EXPANSION_FDS, &[1, 2, 3])).unwrap();
+        assert_eq!(tune.prg_rom.len(), 0xa000);
+        assert_eq!(&tune.prg_rom[..3], &[1, 2, 3]);
+        assert_eq!(tune.info.init_banks, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn nsf_bankswitched_is_padded_to_its_load_address() {
+        let nsf = Nsf::parse(&nsf(0x8123, [0, 1, 2, 3, 4, 5, 6, 1], 0, &vec![0x42; 0x1000])).unwrap();
+        assert!(nsf.info.is_bankswitched);
+        assert_eq!(nsf.prg_rom.len(), 2 * NSF_BANK_SIZE);
+        assert_eq!(nsf.prg_rom[0x122], 0);
+        assert_eq!(nsf.prg_rom[0x123], 0x42);
+        assert_eq!(nsf.info.init_banks, [6, 1, 0, 1, 2, 3, 4, 5, 6, 1]);
+    }
+
+    #[test]
+    fn nsf2_metadata() {
+        let mut binary = nsf(0x8000, [0; 8], 0, &[1, 2, 3]);
+        binary[0x05] = 2;
+        binary[0x7d] = 3;
+        chunk(&mut binary, b"tlbl", b"One\0Two\0Three\0");
+        chunk(&mut binary, b"time", &[0xe8, 0x03, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        let tune = Nsf::parse(&binary).unwrap();
+        assert_eq!(tune.info.track_titles, vec!["One", "Two", "Three"]);
+        assert_eq!(tune.info.track_time(0), Some(1000));
+        assert_eq!(tune.info.track_time(1), None);
+        assert_eq!(&tune.prg_rom[..4], &[1, 2, 3, 0]);
+        //Program data longer than the file
+        binary[0x7d] = 0xff;
+        assert_eq!(Nsf::parse(&binary).err(), Some(NsfError::Truncated));
+    }
+
+    #[test]
+    fn nsf_truncated_and_bad() {
+        let binary = nsf(0x8000, [0; 8], 0, &[1, 2, 3]);
+        assert_eq!(Nsf::parse(&binary[..NSF_HEADER_SIZE - 1]).err(), Some(NsfError::Truncated));
+        let mut no_songs = binary.clone();
+        no_songs[0x06] = 0;
+        assert_eq!(Nsf::parse(&no_songs).err(), Some(NsfError::NoSongs));
+        assert_eq!(
+            Nsf::parse(&nsf(0x7000, [0; 8], 0, &[1, 2, 3])).err(),
+            Some(NsfError::BadLoadAddr(0x7000))
+        );
+    }
+
+    #[test]
+    fn nsf_oversized() {
+        //More data than the 256 banks can hold is cut off, as is data past $FFFF without bankswitching
+        let data = vec![0x42; NSF_PRG_ROM_MAX_SIZE + NSF_BANK_SIZE];
+        let tune = Nsf::parse(&nsf(0x8000, [0, 1, 2, 3, 4, 5, 6, 7], 0, &data)).unwrap();
+        assert_eq!(tune.prg_rom.len(), NSF_PRG_ROM_MAX_SIZE);
+        let tune = Nsf::parse(&nsf(0xf000, [0; 8], 0, &data[..0x2000])).unwrap();
+        assert_eq!(tune.prg_rom.len(), 0x8000);
+        assert_eq!(tune.prg_rom[0x7fff], 0x42);
+    }
+
+    #[test]
+    fn nsfe_chunks() {
+        let binary = nsfe(&[
+            (b"INFO", NSFE_INFO),
+            (b"DATA", &[1, 2, 3]),
+            (b"RATE", &[0x1a, 0x41, 0x1d, 0x4e]),
+            (b"auth", b"Title\0Artist\0Copyright\0Ripper\0"),
+            (b"plst", &[1, 0, 7]),
+            (b"xtra", &[0; 4]),
+            (b"NEND", &[]),
+            (b"JUNK", &[]),
+        ]);
+        assert!(is_nsf(&binary));
+        let tune = Nsf::parse(&binary).unwrap();
+        let info = tune.info;
+        assert_eq!(info.version, 0);
+        assert_eq!(info.total_songs, 2);
+        assert_eq!(info.starting_song, 1);
+        assert_eq!(info.timing, Region::Ntsc);
+        assert_eq!((info.ntsc_play_speed, info.pal_play_speed), (16666, 19997));
+        assert_eq!((info.title.as_str(), info.copyright.as_str()), ("Title", "Copyright"));
+        //Songs that don't exist are dropped from the playlist
+        assert_eq!(info.playlist, vec![1, 0]);
+        assert_eq!(info.starting_track(), 0);
+        assert_eq!(info.song_number(0), 1);
+        assert_eq!(&tune.prg_rom[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn nsfe_bank_chunk() {
+        let binary = nsfe(&[(b"INFO", NSFE_INFO), (b"BANK", &[0, 1, 2, 3]), (b"DATA", &[1, 2, 3])]);
+        let info = Nsf::parse(&binary).unwrap().info;
+        assert!(info.is_bankswitched);
+        assert_eq!(info.init_banks, [0, 0, 0, 1, 2, 3, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn nsfe_missing_and_unknown_chunks() {
+        assert_eq!(
+            Nsf::parse(&nsfe(&[(b"DATA", &[1, 2, 3])])).err(),
+            Some(NsfError::MissingChunk("INFO"))
+        );
+        assert_eq!(
+            Nsf::parse(&nsfe(&[(b"INFO", NSFE_INFO)])).err(),
+            Some(NsfError::MissingChunk("DATA"))
+        );
+        assert_eq!(
+            Nsf::parse(&nsfe(&[(b"INFO", NSFE_INFO), (b"DATA", &[1]), (b"VRC7", &[0])])).err(),
+            Some(NsfError::UnsupportedChunk(String::from("VRC7")))
+        );
+    }
+
+    #[test]
+    fn nsfe_truncated() {
+        let binary = nsfe(&[(b"INFO", NSFE_INFO), (b"DATA", &[1, 2, 3])]);
+        assert_eq!(Nsf::parse(&binary[..binary.len() - 1]).err(), Some(NsfError::Truncated));
+        assert_eq!(
+            Nsf::parse(&nsfe(&[(b"INFO", &NSFE_INFO[..7])])).err(),
+            Some(NsfError::Truncated)
+        );
+        //A length that goes past the end of memory, not just the file
+        let mut binary = nsfe(&[(b"INFO", NSFE_INFO)]);
+        binary.extend_from_slice(&u32::MAX.to_le_bytes());
+        binary.extend_from_slice(b"DATA");
+        assert_eq!(Nsf::parse(&binary).err(), Some(NsfError::Truncated));
+    }
+}
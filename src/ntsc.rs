@@ -0,0 +1,237 @@
+/* NTSC composite video filter */
+//https://wiki.nesdev.com/w/index.php/NTSC_video
+//http://slack.net/~ant/libs/ntsc.html
+//Along the lines of blargg's nes_ntsc. NES art was drawn for a TV fed composite video, where the color rides on the
+//same wire as the brightness, so they bleed into each other: dithering blends into solid colors, sharp edges pick up
+//colored fringes and the pattern crawls from frame to frame. Rather than look the pixels up in a palette, this makes
+//the signal the PPU would send for each row and decodes it the way a TV does, out to 602 pixels across since the
+//signal has more detail than 256 pixels can hold.
+use super::framebuffer::{FrameBuffer, FramebufferError, PixelFormat};
+use super::palette::{
+    chroma_angle, composite_signal, gamma_correct, yiq_to_rgb, NtscPaletteParams, CHROMA_GAIN, COLOR_PHASES,
+    NUM_OF_EMPHASIS_COLORS,
+};
+use super::ppu::{VISIBLE_SCREEN_HEIGHT, VISIBLE_SCREEN_WIDTH};
+
+//Same as nes_ntsc, 7 pixels out for every 3 in
+pub const NTSC_OUTPUT_WIDTH: usize = 602;
+//The PPU puts out 8 samples of the 12 phase color cycle for each pixel
+const SAMPLES_PER_PIXEL: usize = 8;
+const ROW_SAMPLES: usize = VISIBLE_SCREEN_WIDTH * SAMPLES_PER_PIXEL;
+//Black either side of the row so the filters have something to read past the edges. A whole number of color cycles,
+//so the padding doesn't move the phase
+const ROW_PADDING: usize = COLOR_PHASES * 4;
+//A line is 341 dots, 2728 samples, so each one starts 4 phases further round the color cycle than the last
+const LINE_PHASE_STEP: usize = 4;
+//Frames start 4 or 8 phases on from the one before, depending on the skipped dot, so there are only ever two
+const FRAME_PHASE_STEP: usize = 4;
+//Samples the luma filter lets through when artifacts are turned right up, short enough to keep some of the color
+const ARTIFACT_LUMA_SAMPLES: usize = 4;
+//Steps in the gamma table, plenty for 8 bit output
+const GAMMA_TABLE_SIZE: usize = 1024;
+
+//The knobs, -1.0 to 1.0 like nes_ntsc's with 0.0 being an ordinary TV and -1.0 as clean as composite gets
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NtscFilterParams {
+    //Color leaking into brightness, the dot patterns and rainbows
+    pub artifacts: f32,
+    //Brightness leaking into color, colored fringes on sharp edges
+    pub fringing: f32,
+    //How far color smears sideways
+    pub bleed: f32,
+    //Average the two frame phases, which stops the pattern crawling at the cost of blurring it
+    pub is_merge_fields: bool,
+}
+
+impl Default for NtscFilterParams {
+    fn default() -> Self {
+        Self {
+            artifacts: 0.0,
+            fringing: 0.0,
+            bleed: 0.0,
+            is_merge_fields: true,
+        }
+    }
+}
+
+pub struct NtscFilter {
+    pub params: NtscFilterParams,
+    //Hue, saturation, contrast, brightness and gamma, the same knobs as a generated palette. The tables below are
+    //worked out from them, so they're fixed once the filter is made
+    picture: NtscPaletteParams,
+    //The signal for each 9 bit index at each phase of the color cycle
+    signals: Vec<[f32; COLOR_PHASES]>,
+    //Cosine and sine the TV demodulates each phase with
+    carrier: [[f32; 2]; COLOR_PHASES],
+    gamma_table: Vec<u8>,
+    //The sample each output pixel is centered on
+    centers: Vec<usize>,
+    //Where in the color cycle this frame started
+    frame_phase: usize,
+    //Working space for one row, kept to save allocating it every row
+    samples: Vec<f32>,
+    luma: Vec<f32>,
+    sums: Vec<[f32; 3]>,
+}
+
+impl NtscFilter {
+    pub fn new(params: NtscFilterParams, picture: NtscPaletteParams) -> NtscFilter {
+        let signals = (0..NUM_OF_EMPHASIS_COLORS as u16)
+            .map(|index| {
+                let mut signal = [0.0; COLOR_PHASES];
+                for (phase, level) in signal.iter_mut().enumerate() {
+                    *level = composite_signal(index, phase);
+                }
+                signal
+            })
+            .collect();
+        let mut carrier = [[0.0; 2]; COLOR_PHASES];
+        for (phase, wave) in carrier.iter_mut().enumerate() {
+            let angle = chroma_angle(phase, picture.hue);
+            *wave = [angle.cos(), angle.sin()];
+        }
+        let gamma_table = (0..GAMMA_TABLE_SIZE)
+            .map(|step| gamma_correct(step as f32 / (GAMMA_TABLE_SIZE - 1) as f32, picture.gamma))
+            .collect();
+        let centers = (0..NTSC_OUTPUT_WIDTH)
+            .map(|x| ROW_PADDING + (x * 2 + 1) * ROW_SAMPLES / (NTSC_OUTPUT_WIDTH * 2))
+            .collect();
+        let row_len = ROW_SAMPLES + ROW_PADDING * 2;
+        NtscFilter {
+            params,
+            picture,
+            signals,
+            carrier,
+            gamma_table,
+            centers,
+            frame_phase: 0,
+            samples: vec![0.0; row_len],
+            luma: vec![0.0; row_len],
+            sums: vec![[0.0; 3]; row_len + 1],
+        }
+    }
+    //Called as each frame starts, moves the color cycle on
+    pub fn next_frame(&mut self) {
+        self.frame_phase = (self.frame_phase + FRAME_PHASE_STEP) % COLOR_PHASES;
+    }
+    //Writes the filtered picture, NTSC_OUTPUT_WIDTH wide and 240 high, into out with rows pitch bytes apart
+    pub fn render(
+        &mut self,
+        fb: &FrameBuffer,
+        format: PixelFormat,
+        out: &mut [u8],
+        pitch: usize,
+    ) -> Result<(), FramebufferError> {
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let row_bytes = bytes_per_pixel * NTSC_OUTPUT_WIDTH;
+        if pitch < row_bytes {
+            return Err(FramebufferError::PitchTooSmall(pitch, row_bytes));
+        }
+        let needed = pitch * (VISIBLE_SCREEN_HEIGHT - 1) + row_bytes;
+        if out.len() < needed {
+            return Err(FramebufferError::BufferTooSmall(out.len(), needed));
+        }
+        //Merged, every frame is both phases so it doesn't matter which this one is
+        let frame_phase = if self.params.is_merge_fields { 0 } else { self.frame_phase };
+        let mut yiq = vec![[0.0f32; 3]; NTSC_OUTPUT_WIDTH];
+        for (line, (row, out_row)) in fb.iter().zip(out.chunks_mut(pitch)).enumerate() {
+            let phase = frame_phase + line * LINE_PHASE_STEP;
+            yiq.iter_mut().for_each(|pixel| *pixel = [0.0; 3]);
+            self.decode_row(row, phase, &mut yiq);
+            let fields = if self.params.is_merge_fields {
+                self.decode_row(row, phase + FRAME_PHASE_STEP, &mut yiq);
+                2.0
+            } else {
+                1.0
+            };
+            let scale = (GAMMA_TABLE_SIZE - 1) as f32;
+            let to_channel = |value: f32| self.gamma_table[(value.clamp(0.0, 1.0) * scale) as usize];
+            for (&[y, i, q], out_pixel) in yiq.iter().zip(out_row[..row_bytes].chunks_exact_mut(bytes_per_pixel)) {
+                let [r, g, b] = yiq_to_rgb(y / fields, i / fields, q / fields);
+                format.write_pixel(out_pixel, to_channel(r), to_channel(g), to_channel(b));
+            }
+        }
+        Ok(())
+    }
+    //Makes the signal for a row starting at phase and adds what the TV makes of it onto yiq
+    fn decode_row(&mut self, row: &[u16; VISIBLE_SCREEN_WIDTH], phase: usize, yiq: &mut [[f32; 3]]) {
+        let params = self.params;
+        let picture = self.picture;
+        //The padding is black, which is 0.0. It's a whole number of color cycles, so sample n is at phase + n
+        let mut sample_phase = phase % COLOR_PHASES;
+        for (pixel, &index) in row.iter().enumerate() {
+            let signal = &self.signals[usize::from(index)];
+            let start = ROW_PADDING + pixel * SAMPLES_PER_PIXEL;
+            for sample in &mut self.samples[start..start + SAMPLES_PER_PIXEL] {
+                *sample = signal[sample_phase];
+                sample_phase = next_phase(sample_phase);
+            }
+        }
+        //Running sums make every box filter below two lookups. Brightness is the signal averaged over a whole color
+        //cycle, which cancels the color out completely
+        //They're added up a pixel at a time and then onto the total so far, which keeps the additions from all
+        //waiting on each other
+        let sample_count = self.samples.len();
+        let mut total = 0.0;
+        for (block, samples) in self.samples.chunks_exact(SAMPLES_PER_PIXEL).enumerate() {
+            let mut sum = 0.0;
+            for (offset, &sample) in samples.iter().enumerate() {
+                sum += sample;
+                self.sums[block * SAMPLES_PER_PIXEL + offset + 1][0] = total + sum;
+            }
+            total += sum;
+        }
+        //The padding is black all the way through, only the samples a cycle either side of it need working out
+        let half_cycle = COLOR_PHASES / 2;
+        for (n, luma) in self.luma.iter_mut().enumerate().skip(half_cycle).take(sample_count - COLOR_PHASES) {
+            *luma = (self.sums[n + half_cycle][0] - self.sums[n - half_cycle][0]) * (1.0 / COLOR_PHASES as f32);
+        }
+        //What's left once the brightness is taken away is color, taking away less of it lets edges through as
+        //fringes
+        let luma_removed = 0.5 - params.fringing * 0.5;
+        let (mut total_i, mut total_q) = (0.0, 0.0);
+        let mut sample_phase = phase % COLOR_PHASES;
+        let blocks = self.samples.chunks_exact(SAMPLES_PER_PIXEL).zip(self.luma.chunks_exact(SAMPLES_PER_PIXEL));
+        for (block, (samples, lumas)) in blocks.enumerate() {
+            let (mut sum_i, mut sum_q) = (0.0, 0.0);
+            for (offset, (&sample, &luma)) in samples.iter().zip(lumas).enumerate() {
+                let chroma = sample - luma * luma_removed;
+                let [cos, sin] = self.carrier[sample_phase];
+                sample_phase = next_phase(sample_phase);
+                sum_i += chroma * cos;
+                sum_q += chroma * sin;
+                let sums = &mut self.sums[block * SAMPLES_PER_PIXEL + offset + 1];
+                sums[1] = total_i + sum_i;
+                sums[2] = total_q + sum_q;
+            }
+            total_i += sum_i;
+            total_q += sum_q;
+        }
+        //Wider color filters smear it further. The padding is wide enough that none of the filters reach past it
+        let chroma_samples = (COLOR_PHASES as f32 * (2.0 + params.bleed)).round() as usize;
+        let chroma_scale = CHROMA_GAIN * picture.saturation * picture.contrast / chroma_samples as f32;
+        let artifact_amount = 0.5 + params.artifacts * 0.5;
+        let sharp_scale = 1.0 / ARTIFACT_LUMA_SAMPLES as f32;
+        for (pixel, &center) in yiq.iter_mut().zip(&self.centers) {
+            //Artifacts are the color the luma filter doesn't quite get rid of
+            let luma = self.luma[center];
+            let sharp_start = center - ARTIFACT_LUMA_SAMPLES / 2;
+            let sharp_luma =
+                (self.sums[sharp_start + ARTIFACT_LUMA_SAMPLES][0] - self.sums[sharp_start][0]) * sharp_scale;
+            let y = luma + (sharp_luma - luma) * artifact_amount;
+            let chroma_start = &self.sums[center - chroma_samples / 2];
+            let chroma_end = &self.sums[center - chroma_samples / 2 + chroma_samples];
+            pixel[0] += y * picture.contrast + picture.brightness;
+            pixel[1] += (chroma_end[1] - chroma_start[1]) * chroma_scale;
+            pixel[2] += (chroma_end[2] - chroma_start[2]) * chroma_scale;
+        }
+    }
+}
+
+fn next_phase(phase: usize) -> usize {
+    if phase + 1 == COLOR_PHASES {
+        0
+    } else {
+        phase + 1
+    }
+}
@@ -16,18 +16,29 @@ pub enum PadButton {
     Right,
 }
 
+//Controllers one System can have plugged in, two front ports and two more on the expansion port or an adapter
+pub const NUM_OF_PLAYERS: usize = 4;
+//Default autofire, 2 frames pressed then 2 released. Faster than that and some games miss the releases
+const TURBO_DEFAULT_PERIOD: u8 = 4;
+const TURBO_DEFAULT_ON_FRAMES: u8 = 2;
+
 #[derive(Clone, Debug)]
 pub struct Pad {
     pub button_reg: u8,
+    //Buttons held with turbo, they only count as pressed while the turbo clock is in its on phase
+    pub turbo_reg: u8,
     pub read_shift_index: u8,
     pub strobe_enable: bool,
+    is_turbo_phase_on: bool,
 }
 impl Pad {
     pub fn default() -> Self {
         Self {
             button_reg: 0,
+            turbo_reg: 0,
             read_shift_index: 0,
             strobe_enable: false,
+            is_turbo_phase_on: true,
         }
     }
 }
@@ -35,8 +46,50 @@ impl Pad {
 impl Pad {
     pub fn reset(&mut self) {
         self.button_reg = 0;
+        self.turbo_reg = 0;
         self.read_shift_index = 0;
         self.strobe_enable = false;
+        self.is_turbo_phase_on = true;
+    }
+}
+
+//Autofire timing, counted in emulated frames so a movie or netplay session sees exactly the same presses every time
+#[derive(Clone, Debug)]
+pub struct TurboClock {
+    //Frames in one press and release
+    period: u8,
+    //How many of those the button is down for
+    on_frames: u8,
+    frame: u8,
+}
+
+impl Default for TurboClock {
+    fn default() -> Self {
+        Self {
+            period: TURBO_DEFAULT_PERIOD,
+            on_frames: TURBO_DEFAULT_ON_FRAMES,
+            frame: 0,
+        }
+    }
+}
+
+impl TurboClock {
+    pub fn reset(&mut self) {
+        self.frame = 0;
+    }
+    //A button has to be both pressed and released each period, so the period is at least 2 frames
+    pub fn set_rate(&mut self, period: u8, on_frames: u8) {
+        self.period = period.max(2);
+        self.on_frames = on_frames.clamp(1, self.period - 1);
+        self.frame = 0;
+    }
+    //Move on a frame, returns whether turbo buttons are pressed for it
+    pub fn clock(&mut self) -> bool {
+        self.frame = (self.frame + 1) % self.period;
+        self.is_on()
+    }
+    pub fn is_on(&self) -> bool {
+        self.frame < self.on_frames
     }
 }
 
@@ -48,13 +101,31 @@ impl Pad {
         }
     }
 
+    //Everything that reads as pressed right now, held buttons plus turbo buttons in their on phase
+    pub fn buttons(&self) -> u8 {
+        if self.is_turbo_phase_on {
+            self.button_reg | self.turbo_reg
+        } else {
+            self.button_reg
+        }
+    }
     pub fn read_out(&mut self) -> u8 {
-        let data = self.button_reg.wrapping_shr(self.read_shift_index.into()) & 0x01;
+        let data = self.buttons().wrapping_shr(self.read_shift_index.into()) & 0x01;
         if !self.strobe_enable {
             self.read_shift_index = (self.read_shift_index + 1) % 8;
         }
         data
     }
+    //Every button at once, bit 0 is A through to bit 7 for Right, the same order they're read out in
+    pub fn set_buttons(&mut self, mask: u8) {
+        self.button_reg = mask;
+    }
+    pub fn set_turbo_buttons(&mut self, mask: u8) {
+        self.turbo_reg = mask;
+    }
+    pub fn set_turbo_phase(&mut self, is_on: bool) {
+        self.is_turbo_phase_on = is_on;
+    }
     pub fn push_button(&mut self, button: PadButton) {
         match button {
             PadButton::A => self.button_reg = self.button_reg | 0x01u8,
@@ -0,0 +1,197 @@
+/* Palettes */
+//https://wiki.nesdev.com/w/index.php/PPU_palettes
+//https://wiki.nesdev.com/w/index.php/NTSC_video
+//The PPU doesn't output RGB, it outputs a composite video signal, so what colors the 64 palette entries turn out to be
+//is down to the TV. Each pixel is kept as a 9 bit index, the 6 bit palette entry with PPUMASK's 3 emphasis bits above
+//it, and a palette of 512 colors turns that into RGB for the screen.
+//Palettes come from the built in table, a .pal file, or are worked out from the signal the PPU would send with the
+//knobs a TV would have.
+use super::ppu::Color;
+use std::fmt;
+
+pub const NUM_OF_PALETTE_COLORS: usize = 0x40;
+pub const NUM_OF_EMPHASIS_COLORS: usize = NUM_OF_PALETTE_COLORS * 8;
+pub const PALETTE_INDEX_MASK: u16 = 0x1ff;
+
+//Emphasis darkens the other two channels, close enough to what a TV shows when all a palette has is 64 colors
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+//Signal levels from the NTSC video page, low and high for each of the 4 brightness levels, as volts
+const SIGNAL_LOW: [f32; 4] = [0.228, 0.312, 0.552, 0.880];
+const SIGNAL_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+const SIGNAL_BLACK: f32 = 0.312;
+const SIGNAL_WHITE: f32 = 1.100;
+//How much an emphasis bit takes off the signal during its part of the color cycle
+const SIGNAL_EMPHASIS_ATTENUATION: f32 = 0.746;
+//The color cycle is 12 PPU half dots long, each palette hue is a different phase of it
+pub const COLOR_PHASES: usize = 12;
+//Lines the TV's hue reference up with the colorburst, which is what makes hue $6 red and $a green
+const COLORBURST_PHASE: f32 = 4.0;
+//How much the TV amplifies the color part of the signal, picked so the default saturation looks like the built in
+//palette
+pub const CHROMA_GAIN: f32 = 1.5;
+
+#[derive(Debug)]
+pub enum PaletteError {
+    //Length of a .pal file that isn't 64 or 512 colors
+    BadSize(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::BadSize(len) => write!(
+                f,
+                "Palette of {} bytes isn't {} or {} colors",
+                len, NUM_OF_PALETTE_COLORS, NUM_OF_EMPHASIS_COLORS
+            ),
+        }
+    }
+}
+
+//The picture controls on a TV, used to generate a palette
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NtscPaletteParams {
+    //Degrees to turn the hue
+    pub hue: f32,
+    //1.0 as is, 0.0 is black and white
+    pub saturation: f32,
+    pub contrast: f32,
+    //Added to the brightness, -1.0 to 1.0
+    pub brightness: f32,
+    //The TV's gamma, 2.2 leaves the levels as they come and higher darkens the mid tones
+    pub gamma: f32,
+}
+
+impl Default for NtscPaletteParams {
+    fn default() -> Self {
+        Self {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Palette {
+    //Indexed by the 9 bit pixel, emphasis bits on top
+    colors: Vec<Color>,
+}
+
+impl Default for Palette {
+    //The built in table, with emphasis added on
+    fn default() -> Self {
+        let colors = (0..NUM_OF_PALETTE_COLORS as u8).map(Color::from).collect::<Vec<_>>();
+        Self::with_emphasis(&colors)
+    }
+}
+
+impl Palette {
+    pub fn color(&self, index: u16) -> Color {
+        self.colors[usize::from(index & PALETTE_INDEX_MASK)]
+    }
+    //.pal files are RGB triples, either 64 colors or all 512 with emphasis in PPUMASK order
+    pub fn from_pal(data: &[u8]) -> Result<Palette, PaletteError> {
+        if !data.len().is_multiple_of(3) {
+            return Err(PaletteError::BadSize(data.len()));
+        }
+        let colors = data
+            .chunks_exact(3)
+            .map(|rgb| Color(rgb[0], rgb[1], rgb[2]))
+            .collect::<Vec<_>>();
+        match colors.len() {
+            NUM_OF_PALETTE_COLORS => Ok(Self::with_emphasis(&colors)),
+            NUM_OF_EMPHASIS_COLORS => Ok(Self { colors }),
+            _ => Err(PaletteError::BadSize(data.len())),
+        }
+    }
+    //Emphasis bit 0 is red, 1 green and 2 blue, each one darkens the other two channels
+    fn with_emphasis(colors: &[Color]) -> Palette {
+        let mut emphasized = Vec::with_capacity(NUM_OF_EMPHASIS_COLORS);
+        for emphasis in 0..8u8 {
+            //All three at once darkens everything
+            let scale = |channel: u8, bit: u8| -> f32 {
+                let others: u8 = emphasis & !(1 << bit);
+                EMPHASIS_ATTENUATION.powi(others.count_ones() as i32) * f32::from(channel)
+            };
+            emphasized.extend(colors.iter().map(|color| {
+                Color(
+                    scale(color.0, 0).round() as u8,
+                    scale(color.1, 1).round() as u8,
+                    scale(color.2, 2).round() as u8,
+                )
+            }));
+        }
+        Self { colors: emphasized }
+    }
+    //Works out what the TV would make of the signal for each of the 512 indexes. The TV averages the signal over the
+    //color cycle for brightness and demodulates it against the colorburst for the color
+    pub fn generate(params: &NtscPaletteParams) -> Palette {
+        let colors = (0..NUM_OF_EMPHASIS_COLORS as u16)
+            .map(|index| {
+                let (mut y, mut i, mut q) = (0.0f32, 0.0f32, 0.0f32);
+                for phase in 0..COLOR_PHASES {
+                    let signal = composite_signal(index, phase);
+                    let angle = chroma_angle(phase, params.hue);
+                    y += signal;
+                    i += signal * angle.cos();
+                    q += signal * angle.sin();
+                }
+                let phases = COLOR_PHASES as f32;
+                let y = y / phases * params.contrast + params.brightness;
+                let i = i / phases * CHROMA_GAIN * params.saturation * params.contrast;
+                let q = q / phases * CHROMA_GAIN * params.saturation * params.contrast;
+                yiq_to_color(y, i, q, params.gamma)
+            })
+            .collect();
+        Self { colors }
+    }
+}
+
+//The PPU's output for a 9 bit index at one phase of the color cycle, scaled so black is 0.0 and white 1.0. The signal
+//is a square wave, high for the 6 phases starting at the hue, and emphasis attenuates the third of the cycle
+//belonging to each of its colors
+pub fn composite_signal(index: u16, phase: usize) -> f32 {
+    let hue = usize::from(index & 0x0f);
+    //Hues $e and $f are black, whatever the level
+    let level = if hue > 0x0d { 1 } else { usize::from(index >> 4) & 0x03 };
+    let emphasis = index >> 6;
+    let low = if hue == 0 { SIGNAL_HIGH[level] } else { SIGNAL_LOW[level] };
+    let high = if hue > 0x0c { SIGNAL_LOW[level] } else { SIGNAL_HIGH[level] };
+    let is_in_phase = |hue: usize| (hue + phase) % COLOR_PHASES < COLOR_PHASES / 2;
+    let mut signal = if is_in_phase(hue) { high } else { low };
+    let is_attenuated = (emphasis & 0x01 != 0 && is_in_phase(0))
+        || (emphasis & 0x02 != 0 && is_in_phase(4))
+        || (emphasis & 0x04 != 0 && is_in_phase(8));
+    if is_attenuated {
+        signal *= SIGNAL_EMPHASIS_ATTENUATION;
+    }
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+//Angle the TV demodulates a phase of the color cycle at, hue is the knob in degrees
+pub fn chroma_angle(phase: usize, hue: f32) -> f32 {
+    std::f32::consts::PI * (phase as f32 + COLORBURST_PHASE) / 6.0 + hue.to_radians()
+}
+
+//https://en.wikipedia.org/wiki/YIQ
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32) -> [f32; 3] {
+    [
+        y + 0.956 * i + 0.621 * q,
+        y - 0.272 * i - 0.647 * q,
+        y - 1.106 * i + 1.703 * q,
+    ]
+}
+
+//Red, green or blue from 0.0 to 1.0 as a byte, corrected from the TV's gamma
+pub fn gamma_correct(value: f32, gamma: f32) -> u8 {
+    (value.clamp(0.0, 1.0).powf(gamma / 2.2) * 255.0).round() as u8
+}
+
+fn yiq_to_color(y: f32, i: f32, q: f32, gamma: f32) -> Color {
+    let [r, g, b] = yiq_to_rgb(y, i, q);
+    Color(gamma_correct(r, gamma), gamma_correct(g, gamma), gamma_correct(b, gamma))
+}
@@ -0,0 +1,603 @@
+/* Soft patching, applying IPS/UPS/BPS patches to a ROM image before it gets loaded */
+//Translations and romhacks are distributed as patches against a clean dump. Rather than making people keep patched
+//copies around we apply them in memory. The format is picked from the magic at the start of the patch.
+//https://zerosoft.zophar.net/ips.php
+//https://www.romhacking.net/documents/392/ (UPS)
+//https://www.romhacking.net/documents/746/ (BPS)
+use super::rom::ROM_FILE_MAX_SIZE;
+use std::fmt;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+//"EOF" read as an offset
+const IPS_EOF_OFFSET: usize = 0x454f46;
+//Record sizes are 16 bits, and 0 means an RLE record
+const IPS_MAX_RECORD_SIZE: usize = 0xffff;
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+//UPS and BPS both end in source, target and patch CRC32s
+const CHECKSUM_FOOTER_SIZE: usize = 12;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum PatchError {
+    //Didn't start with any magic we know
+    UnknownFormat,
+    //Ran out of patch in the middle of a record
+    Truncated(PatchFormat),
+    //The patch file itself is corrupt
+    PatchChecksum { expected: u32, actual: u32 },
+    //The patch was made for a different dump than the one we were given
+    SourceChecksum { expected: u32, actual: u32 },
+    SourceSize { expected: usize, actual: usize },
+    //Applied cleanly but the result isn't what the patch author got, usually a bug in the patch
+    TargetChecksum { expected: u32, actual: u32 },
+    //A record points outside of the source or target
+    OutOfBounds(PatchFormat),
+    //Target size in the header, bigger than any ROM we'd load
+    TargetTooLarge(usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Not an IPS, UPS or BPS patch"),
+            PatchError::TargetTooLarge(size) => write!(
+                f,
+                "Patch makes a {} KiB ROM, more than the {} KiB limit",
+                size / 1024,
+                ROM_FILE_MAX_SIZE / 1024
+            ),
+            PatchError::Truncated(format) => write!(f, "{:?} patch is truncated", format),
+            PatchError::PatchChecksum { expected, actual } => write!(
+                f,
+                "Patch is corrupt, its CRC32 is {:08X} but should be {:08X}",
+                actual, expected
+            ),
+            PatchError::SourceChecksum { expected, actual } => write!(
+                f,
+                "Patch is for a different ROM, this ROM's CRC32 is {:08X} but the patch expects {:08X}",
+                actual, expected
+            ),
+            PatchError::SourceSize { expected, actual } => write!(
+                f,
+                "Patch is for a different ROM, this ROM is {} bytes but the patch expects {}",
+                actual, expected
+            ),
+            PatchError::TargetChecksum { expected, actual } => write!(
+                f,
+                "Patched ROM's CRC32 is {:08X} but the patch says it should be {:08X}",
+                actual, expected
+            ),
+            PatchError::OutOfBounds(format) => {
+                write!(f, "{:?} patch writes outside of the ROM", format)
+            }
+        }
+    }
+}
+
+//Walks through the patch, every read can run off the end
+struct PatchReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    format: PatchFormat,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], offset: usize, format: PatchFormat) -> Self {
+        Self { data, offset, format }
+    }
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.offset.checked_add(len).filter(|end| *end <= self.data.len());
+        match end {
+            Some(end) => {
+                let bytes = &self.data[self.offset..end];
+                self.offset = end;
+                Ok(bytes)
+            }
+            None => Err(PatchError::Truncated(self.format)),
+        }
+    }
+    fn read_u8(&mut self) -> Result<u8, PatchError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+    //Big endian, IPS only
+    fn read_be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .read_bytes(len)?
+            .iter()
+            .fold(0, |value, byte| (value << 8) | usize::from(*byte)))
+    }
+    //UPS/BPS variable length numbers, 7 bits at a time with the top bit marking the last byte. Each continuation
+    //also adds one so that there is exactly one encoding for every number
+    fn read_varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let data = self.read_u8()?;
+            value = usize::from(data & 0x7f)
+                .checked_mul(shift)
+                .and_then(|add| value.checked_add(add))
+                .ok_or(PatchError::OutOfBounds(self.format))?;
+            if (data & 0x80) == 0x80 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds(self.format))?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds(self.format))?;
+        }
+    }
+    fn remaining(&self) -> usize {
+        self.data.len() - self.offset
+    }
+}
+
+fn read_le_u32(data: &[u8]) -> u32 {
+    u32::from(data[0]) | (u32::from(data[1]) << 8) | (u32::from(data[2]) << 16) | (u32::from(data[3]) << 24)
+}
+
+pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+    if patch.starts_with(IPS_MAGIC) {
+        Some(PatchFormat::Ips)
+    } else if patch.starts_with(UPS_MAGIC) {
+        Some(PatchFormat::Ups)
+    } else if patch.starts_with(BPS_MAGIC) {
+        Some(PatchFormat::Bps)
+    } else {
+        None
+    }
+}
+
+//Apply whichever kind of patch this is and hand back the patched image
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Ups) => apply_ups(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+//IPS is a list of (3 byte offset, 2 byte length, data) records. A length of 0 is an RLE record instead, a 2 byte count
+//and the byte to repeat. After the EOF marker there can be a 3 byte size to truncate the file to (a Lunar IPS addition)
+//There are no checksums so there's nothing to verify
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len(), PatchFormat::Ips);
+    let mut target = rom.to_vec();
+    loop {
+        let record_offset = reader.read_bytes(3)?;
+        if record_offset == IPS_EOF {
+            break;
+        }
+        let offset = record_offset
+            .iter()
+            .fold(0, |value, byte| (value << 8) | usize::from(*byte));
+        let size = reader.read_be(2)?;
+        let (size, fill) = if size == 0 {
+            let run_size = reader.read_be(2)?;
+            (run_size, Some(reader.read_u8()?))
+        } else {
+            (size, None)
+        };
+        //Records past the end grow the file, up to what the biggest ROM could need
+        if offset + size > ROM_FILE_MAX_SIZE {
+            return Err(PatchError::TargetTooLarge(offset + size));
+        }
+        if target.len() < offset + size {
+            target.resize(offset + size, 0);
+        }
+        match fill {
+            Some(value) => {
+                for byte in target[offset..offset + size].iter_mut() {
+                    *byte = value;
+                }
+            }
+            None => {
+                let bytes = reader.read_bytes(size)?;
+                target[offset..offset + size].copy_from_slice(bytes);
+            }
+        }
+    }
+    if reader.remaining() >= 3 {
+        let truncate_size = reader.read_be(3)?;
+        target.truncate(truncate_size);
+    }
+    Ok(target)
+}
+
+//The other direction, an IPS patch that turns source into target. Used for FDS saves, so target is never shorter
+pub fn create_ips(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = IPS_MAGIC.to_vec();
+    let is_changed = |offset: usize| source.get(offset) != Some(&target[offset]);
+    let mut offset = 0;
+    while offset < target.len() {
+        if !is_changed(offset) {
+            offset += 1;
+            continue;
+        }
+        //A record at 0x454F46 would read as the EOF marker, start it a byte early instead
+        let start = if offset == IPS_EOF_OFFSET { offset - 1 } else { offset };
+        let mut end = offset;
+        while end < target.len() && end - start < IPS_MAX_RECORD_SIZE && is_changed(end) {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        offset = end;
+    }
+    patch.extend_from_slice(IPS_EOF);
+    patch
+}
+
+//UPS and BPS share the checksum footer, check the patch itself and the ROM it's being applied to
+fn verify_footer(rom: &[u8], patch: &[u8], format: PatchFormat) -> Result<u32, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + CHECKSUM_FOOTER_SIZE {
+        return Err(PatchError::Truncated(format));
+    }
+    let footer = &patch[patch.len() - CHECKSUM_FOOTER_SIZE..];
+    let source_crc = read_le_u32(&footer[0..4]);
+    let target_crc = read_le_u32(&footer[4..8]);
+    let patch_crc = read_le_u32(&footer[8..12]);
+
+    let actual_patch_crc = crc32fast::hash(&patch[..patch.len() - 4]);
+    if actual_patch_crc != patch_crc {
+        return Err(PatchError::PatchChecksum {
+            expected: patch_crc,
+            actual: actual_patch_crc,
+        });
+    }
+    let actual_source_crc = crc32fast::hash(rom);
+    if actual_source_crc != source_crc {
+        return Err(PatchError::SourceChecksum {
+            expected: source_crc,
+            actual: actual_source_crc,
+        });
+    }
+    Ok(target_crc)
+}
+
+fn verify_target(target: &[u8], target_crc: u32) -> Result<(), PatchError> {
+    let actual_target_crc = crc32fast::hash(target);
+    if actual_target_crc != target_crc {
+        return Err(PatchError::TargetChecksum {
+            expected: target_crc,
+            actual: actual_target_crc,
+        });
+    }
+    Ok(())
+}
+
+//UPS records skip forward some number of bytes then XOR the source with patch bytes until (and including) a zero
+pub fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = verify_footer(rom, patch, PatchFormat::Ups)?;
+    let body = &patch[..patch.len() - CHECKSUM_FOOTER_SIZE];
+    let mut reader = PatchReader::new(body, UPS_MAGIC.len(), PatchFormat::Ups);
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSize {
+            expected: source_size,
+            actual: rom.len(),
+        });
+    }
+    //Checked before the target is allocated, the size comes straight from the patch
+    if target_size > ROM_FILE_MAX_SIZE {
+        return Err(PatchError::TargetTooLarge(target_size));
+    }
+
+    let source_at = |offset: usize| rom.get(offset).copied().unwrap_or(0);
+    let mut target: Vec<u8> = (0..target_size).map(source_at).collect();
+    let mut offset: usize = 0;
+    while reader.remaining() > 0 {
+        offset = offset
+            .checked_add(reader.read_varint()?)
+            .ok_or(PatchError::OutOfBounds(PatchFormat::Ups))?;
+        loop {
+            let data = reader.read_u8()?;
+            //The terminating zero can land one past the end of the target, the reference implementation drops it
+            if let Some(byte) = target.get_mut(offset) {
+                *byte = source_at(offset) ^ data;
+            }
+            offset += 1;
+            if data == 0 {
+                break;
+            }
+        }
+    }
+    verify_target(&target, target_crc)?;
+    Ok(target)
+}
+
+//BPS builds the target from four kinds of copy: from the same place in the source, literal bytes from the patch, and
+//from anywhere in the source or the target built so far (for runs and repeated data)
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let target_crc = verify_footer(rom, patch, PatchFormat::Bps)?;
+    let body = &patch[..patch.len() - CHECKSUM_FOOTER_SIZE];
+    let mut reader = PatchReader::new(body, BPS_MAGIC.len(), PatchFormat::Bps);
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    let metadata_size = reader.read_varint()?;
+    reader.read_bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSize {
+            expected: source_size,
+            actual: rom.len(),
+        });
+    }
+    if target_size > ROM_FILE_MAX_SIZE {
+        return Err(PatchError::TargetTooLarge(target_size));
+    }
+
+    let out_of_bounds = PatchError::OutOfBounds(PatchFormat::Bps);
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_relative: usize = 0;
+    let mut target_relative: usize = 0;
+    //Copy offsets are signed, the lowest bit is the sign
+    let move_relative = |relative: usize, data: usize| -> Result<usize, PatchError> {
+        let distance = data >> 1;
+        if (data & 0x01) == 0x01 {
+            relative.checked_sub(distance).ok_or(out_of_bounds)
+        } else {
+            relative.checked_add(distance).ok_or(out_of_bounds)
+        }
+    };
+    while reader.remaining() > 0 {
+        let data = reader.read_varint()?;
+        let length = (data >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err(out_of_bounds);
+        }
+        match data & 0x03 {
+            //SourceRead
+            0 => {
+                let start = target.len();
+                let bytes = rom.get(start..start + length).ok_or(out_of_bounds)?;
+                target.extend_from_slice(bytes);
+            }
+            //TargetRead
+            1 => {
+                target.extend_from_slice(reader.read_bytes(length)?);
+            }
+            //SourceCopy
+            2 => {
+                source_relative = move_relative(source_relative, reader.read_varint()?)?;
+                let source_end = source_relative.checked_add(length).ok_or(out_of_bounds)?;
+                let bytes = rom.get(source_relative..source_end).ok_or(out_of_bounds)?;
+                target.extend_from_slice(bytes);
+                source_relative = source_end;
+            }
+            //TargetCopy, byte by byte since the source and destination can overlap to make runs
+            _ => {
+                target_relative = move_relative(target_relative, reader.read_varint()?)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_relative).ok_or(out_of_bounds)?;
+                    target.push(byte);
+                    target_relative += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Truncated(PatchFormat::Bps));
+    }
+    verify_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> Vec<u8> {
+        (0..=255).collect()
+    }
+
+    fn target() -> Vec<u8> {
+        let mut target = source();
+        target[0x10..0x14].copy_from_slice(b"NES!");
+        target[0x80] = 0xff;
+        target.extend_from_slice(&[0xaa; 8]);
+        target
+    }
+
+    //The reverse of read_varint
+    fn varint(patch: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let data = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                patch.push(data | 0x80);
+                return;
+            }
+            patch.push(data);
+            value -= 1;
+        }
+    }
+
+    fn footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let patch_crc = crc32fast::hash(patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+    }
+
+    //XOR records for every run of changed bytes
+    fn ups(source: &[u8], target: &[u8], target_size: usize) -> Vec<u8> {
+        let mut patch = UPS_MAGIC.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target_size);
+        let at = |data: &[u8], offset: usize| data.get(offset).copied().unwrap_or(0);
+        let mut last = 0;
+        let mut offset = 0;
+        while offset < target.len() {
+            if at(source, offset) == target[offset] {
+                offset += 1;
+                continue;
+            }
+            varint(&mut patch, offset - last);
+            while offset < target.len() && at(source, offset) != target[offset] {
+                patch.push(at(source, offset) ^ target[offset]);
+                offset += 1;
+            }
+            patch.push(0);
+            offset += 1;
+            last = offset;
+        }
+        footer(&mut patch, source, target);
+        patch
+    }
+
+    //The source up to the change, "NES!" from the patch, a SourceCopy back over the rest of the source and a
+    //TargetCopy run for the padding on the end
+    fn bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(&mut patch, source.len());
+        varint(&mut patch, target.len());
+        varint(&mut patch, 0);
+        let action = |patch: &mut Vec<u8>, kind: usize, length: usize| varint(patch, ((length - 1) << 2) | kind);
+        action(&mut patch, 0, 0x10);
+        action(&mut patch, 1, 4);
+        patch.extend_from_slice(b"NES!");
+        action(&mut patch, 2, 0x6c);
+        varint(&mut patch, 0x14 << 1);
+        action(&mut patch, 1, 1);
+        patch.push(0xff);
+        action(&mut patch, 2, 0x7f);
+        varint(&mut patch, 1 << 1);
+        action(&mut patch, 1, 1);
+        patch.push(0xaa);
+        action(&mut patch, 3, 7);
+        varint(&mut patch, 0x100 << 1);
+        footer(&mut patch, source, target);
+        patch
+    }
+
+    #[test]
+    fn ips_round_trip() {
+        let patch = create_ips(&source(), &target());
+        assert_eq!(detect(&patch), Some(PatchFormat::Ips));
+        assert_eq!(apply(&source(), &patch), Ok(target()));
+    }
+
+    #[test]
+    fn ips_rle_and_truncate() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0x77]);
+        patch.extend_from_slice(IPS_EOF);
+        patch.extend_from_slice(&[0x00, 0x00, 0x08]);
+        assert_eq!(apply(&source(), &patch), Ok(vec![0, 1, 2, 3, 0x77, 0x77, 0x77, 7]));
+    }
+
+    #[test]
+    fn ips_truncated() {
+        let patch = create_ips(&source(), &target());
+        let patch = &patch[..patch.len() - IPS_EOF.len() - 1];
+        assert_eq!(apply(&source(), patch), Err(PatchError::Truncated(PatchFormat::Ips)));
+    }
+
+    #[test]
+    fn ips_oversized_target() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0xff, 0xff, 0xfe, 0x00, 0x00, 0xff, 0xff, 0x00]);
+        patch.extend_from_slice(IPS_EOF);
+        assert!(matches!(apply(&source(), &patch), Err(PatchError::TargetTooLarge(_))));
+    }
+
+    #[test]
+    fn ups_round_trip() {
+        let patch = ups(&source(), &target(), target().len());
+        assert_eq!(detect(&patch), Some(PatchFormat::Ups));
+        assert_eq!(apply(&source(), &patch), Ok(target()));
+    }
+
+    #[test]
+    fn ups_bad_checksum() {
+        let mut patch = ups(&source(), &target(), target().len());
+        patch[8] ^= 0x01;
+        assert!(matches!(apply(&source(), &patch), Err(PatchError::PatchChecksum { .. })));
+        let patch = ups(&source(), &target(), target().len());
+        let mut other = source();
+        other[0] = 0xff;
+        assert!(matches!(apply(&other, &patch), Err(PatchError::SourceChecksum { .. })));
+    }
+
+    #[test]
+    fn ups_truncated() {
+        let patch = ups(&source(), &target(), target().len());
+        assert_eq!(
+            apply(&source(), &patch[..UPS_MAGIC.len() + 4]),
+            Err(PatchError::Truncated(PatchFormat::Ups))
+        );
+        //Cut off in the middle of a record, with the footer redone so it passes the patch checksum
+        let mut patch = patch[..patch.len() - CHECKSUM_FOOTER_SIZE - 3].to_vec();
+        footer(&mut patch, &source(), &target());
+        assert_eq!(apply(&source(), &patch), Err(PatchError::Truncated(PatchFormat::Ups)));
+    }
+
+    #[test]
+    fn ups_oversized_target() {
+        let patch = ups(&source(), &target(), ROM_FILE_MAX_SIZE + 1);
+        assert_eq!(
+            apply(&source(), &patch),
+            Err(PatchError::TargetTooLarge(ROM_FILE_MAX_SIZE + 1))
+        );
+    }
+
+    #[test]
+    fn bps_round_trip() {
+        let patch = bps(&source(), &target());
+        assert_eq!(detect(&patch), Some(PatchFormat::Bps));
+        assert_eq!(apply(&source(), &patch), Ok(target()));
+    }
+
+    #[test]
+    fn bps_bad_checksum() {
+        let mut patch = bps(&source(), &target());
+        patch[8] ^= 0x01;
+        assert!(matches!(apply(&source(), &patch), Err(PatchError::PatchChecksum { .. })));
+        let mut patch = bps(&source(), &target());
+        let footer_start = patch.len() - CHECKSUM_FOOTER_SIZE;
+        patch.truncate(footer_start);
+        footer(&mut patch, &source(), &source());
+        assert!(matches!(apply(&source(), &patch), Err(PatchError::TargetChecksum { .. })));
+    }
+
+    #[test]
+    fn bps_truncated() {
+        let mut patch = bps(&source(), &target());
+        patch.truncate(patch.len() - CHECKSUM_FOOTER_SIZE - 2);
+        footer(&mut patch, &source(), &target());
+        assert_eq!(apply(&source(), &patch), Err(PatchError::Truncated(PatchFormat::Bps)));
+    }
+
+    #[test]
+    fn bps_oversized_target() {
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(&mut patch, source().len());
+        varint(&mut patch, ROM_FILE_MAX_SIZE + 1);
+        varint(&mut patch, 0);
+        footer(&mut patch, &source(), &target());
+        assert_eq!(
+            apply(&source(), &patch),
+            Err(PatchError::TargetTooLarge(ROM_FILE_MAX_SIZE + 1))
+        );
+    }
+
+    #[test]
+    fn bps_source_copy_past_the_end() {
+        let mut patch = BPS_MAGIC.to_vec();
+        varint(&mut patch, source().len());
+        varint(&mut patch, 4);
+        varint(&mut patch, 0);
+        varint(&mut patch, (3 << 2) | 2);
+        varint(&mut patch, (usize::MAX >> 1) << 1);
+        footer(&mut patch, &source(), &target());
+        assert_eq!(apply(&source(), &patch), Err(PatchError::OutOfBounds(PatchFormat::Bps)));
+    }
+}
@@ -0,0 +1,74 @@
+/* Power Pad and Family Trainer mat */
+//https://wiki.nesdev.com/w/index.php/Power_Pad
+//https://wiki.nesdev.com/w/index.php/Family_Trainer_Mat
+//A floor mat with 12 buttons in a 4x3 grid. The NES Power Pad shifts them out serially on two data lines at once,
+//the Famicom's Family Trainer sits on the expansion port and is read as a matrix instead, one row at a time.
+
+//Which buttons come out of D3 and D4 on the Power Pad, in read order
+const POWER_PAD_LOW_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const POWER_PAD_HIGH_ORDER: [u8; 4] = [4, 3, 12, 8];
+//The Family Trainer's rows, selected by pulling one of OUT0-OUT2 low. Each reads back on D1-D4
+const FAMILY_TRAINER_ROWS: [[u8; 4]; 3] = [[12, 11, 10, 9], [8, 7, 6, 5], [4, 3, 2, 1]];
+
+#[derive(Clone, Debug, Default)]
+pub struct PowerPad {
+    //Bit n is button n + 1
+    buttons: u16,
+    low_shift_reg: u8,
+    high_shift_reg: u8,
+    strobe_enable: bool,
+    //Last value written to $4016, the Family Trainer's row select
+    out: u8,
+}
+
+impl PowerPad {
+    pub fn reset(&mut self) {
+        self.low_shift_reg = 0;
+        self.high_shift_reg = 0;
+        self.strobe_enable = false;
+        self.out = 0;
+    }
+    pub fn set_buttons(&mut self, mask: u16) {
+        self.buttons = mask;
+    }
+    pub fn buttons(&self) -> u16 {
+        self.buttons
+    }
+    fn is_pressed(&self, button: u8) -> bool {
+        (self.buttons >> (button - 1)) & 0x01 == 0x01
+    }
+    //Packs the buttons in read order, first read in bit 0. Anything past the end reads back as 1
+    fn latch(&self, order: &[u8]) -> u8 {
+        order
+            .iter()
+            .enumerate()
+            .fold((0xff_u16 << order.len()) as u8, |reg, (i, &button)| reg | (u8::from(self.is_pressed(button)) << i))
+    }
+    pub fn write_strobe(&mut self, data: u8) {
+        self.out = data & 0x07;
+        self.strobe_enable = (data & 0x01) == 0x01;
+        if self.strobe_enable {
+            self.low_shift_reg = self.latch(&POWER_PAD_LOW_ORDER);
+            self.high_shift_reg = self.latch(&POWER_PAD_HIGH_ORDER);
+        }
+    }
+    //Power Pad, D3 and D4
+    pub fn read_out(&mut self) -> u8 {
+        let data = ((self.low_shift_reg & 0x01) << 3) | ((self.high_shift_reg & 0x01) << 4);
+        if !self.strobe_enable {
+            self.low_shift_reg = (self.low_shift_reg >> 1) | 0x80;
+            self.high_shift_reg = (self.high_shift_reg >> 1) | 0x80;
+        }
+        data
+    }
+    //Family Trainer, D1-D4 of $4017. Pressed buttons read as 0
+    pub fn read_matrix(&self) -> u8 {
+        let pressed = FAMILY_TRAINER_ROWS
+            .iter()
+            .enumerate()
+            .filter(|(row, _)| (self.out >> row) & 0x01 == 0x00)
+            .flat_map(|(_, buttons)| buttons.iter().enumerate())
+            .fold(0, |pressed, (i, &button)| pressed | (u8::from(self.is_pressed(button)) << (i + 1)));
+        !pressed & 0x1e
+    }
+}
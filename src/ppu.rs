@@ -4,8 +4,10 @@
 use super::cpu::*;
 use super::system::*;
 use super::video::*;
-
-pub const CPU_CYCLE_PER_LINE: usize = 341 / 3; 
+use super::region::*;
+use super::rom::VideoFetch;
+use super::framebuffer::FrameBuffer;
+use super::palette::Palette;
 
 pub const NUM_OF_COLOR: usize = 3;
 
@@ -15,8 +17,6 @@ pub const VISIBLE_SCREEN_HEIGHT: usize = 240;
 
 pub const RENDER_SCREEN_WIDTH: u16 = VISIBLE_SCREEN_WIDTH as u16;
 
-pub const RENDER_SCREEN_HEIGHT: u16 = 262; // 0 ~ 261
-
 pub const PIXEL_PER_TILE: u16 = 8; // 1tile=8*8
 
 pub const SCREEN_TILE_WIDTH: u16 = (VISIBLE_SCREEN_WIDTH as u16) / PIXEL_PER_TILE; // 256/8=32
@@ -36,6 +36,10 @@ pub const OAM_DMA_COPY_SIZE_PER_PPU_STEP: u8 = 0xaa;
 
 pub const PATTERN_TABLE_ENTRY_BYTE: u16 = 16;
 
+//Debug view of a pattern table, 16x16 tiles of 8x8
+pub const PATTERN_TABLE_VIEW_SIZE: usize = 128;
+pub const PATTERN_TABLE_VIEW_TILES: u16 = 16;
+
 
 pub const SPRITE_TEMP_SIZE: usize = 8;
 
@@ -47,15 +51,13 @@ pub const SPRITE_WIDTH: usize = 8;
 pub const SPRITE_NORMAL_HEIGHT: usize = 8;
 pub const SPRITE_LARGE_HEIGHT: usize = 16;
 
-pub const CYCLE_PER_DRAW_FRAME: usize = CPU_CYCLE_PER_LINE * ((RENDER_SCREEN_HEIGHT + 1) as usize);
-
 #[derive(Copy, Clone)]
 pub struct Position(pub u8, pub u8);
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Color(pub u8, pub u8, pub u8);
 impl Color {
-    //Build color from palette table
+    //Build color from the built in palette table, the picture itself is colored by palette::Palette
     pub fn from(src: u8) -> Color {
         let index = src & 0x3f;
         //https://wiki.nesdev.com/w/index.php/PPU_palettes
@@ -157,6 +159,7 @@ impl Sprite {
 //The PPU has 4 ways of looking at lines, as it scans down the screen. This amounts to 4 rendering phases at the hardware level
 //The line names are fairly self-explanatory except vblank, which is the period an old TV took to scan back to the top, blank the screen, and
 //begin drawing again
+//The line numbers are NTSC, PAL stretches vblank out to line 310 and Dendy idles in post-render until 290
 #[derive(Copy, Clone)]
 enum LineStatus {
     Visible,                // 0~239
@@ -166,14 +169,16 @@ enum LineStatus {
 }
 //This just tells us which status the line is in
 impl LineStatus {
-    fn from(line: u16) -> LineStatus {
+    fn from(line: u16, region: Region) -> LineStatus {
+        let vblank_start_line = region.vblank_start_line();
+        let pre_render_line = region.pre_render_line();
         if line < 240 {
             LineStatus::Visible
-        } else if line == 240 {
+        } else if line < vblank_start_line {
             LineStatus::PostRender
-        } else if line < 261 {
-            LineStatus::VerticalBlanking(line == 241)
-        } else if line == 261 {
+        } else if line < pre_render_line {
+            LineStatus::VerticalBlanking(line == vblank_start_line)
+        } else if line == pre_render_line {
             LineStatus::PreRender
         } else {
             panic!("invalid line status");
@@ -185,15 +190,17 @@ impl LineStatus {
 pub struct Ppu {
     //Object attribute memory, this is where we get the list of sprites put in the structure above
     //each sprite is 4 bytes, and it can contain 64 of them
-    pub oam: [u8; OAM_SIZE],
+    pub oam: Vec<u8>,
     //This is another OAM that holds a max of 8 sprites for the current scanline
     //This means there are limits to the amount of sprites you can have along a single line
     pub sprite_temps: [Option<Sprite>; SPRITE_TEMP_SIZE],
 
-    //Basically the PPUs way of syncing
-    pub cumulative_cpu_cyc: usize,
+    //Basically the PPUs way of syncing, counted in master clocks so PAL's 3.2 dots per CPU cycle adds up exactly
+    pub cumulative_master_cyc: usize,
     //Line variable, kind of a hack around tv scanlines determining this
     pub current_line: u16,
+    //Level of the NMI line last time we looked, so we only interrupt on the rising edge
+    pub is_nmi_output: bool,
 
     //fine scroll position
     pub fetch_scroll_x: u8,
@@ -212,11 +219,12 @@ pub struct Ppu {
 impl Default for Ppu {
     fn default() -> Self {
         Self {
-            oam: [0; OAM_SIZE],
+            oam: vec![0; OAM_SIZE],
             sprite_temps: [None; SPRITE_TEMP_SIZE],
 
-            cumulative_cpu_cyc: 0,
+            cumulative_master_cyc: 0,
             current_line: 241,
+            is_nmi_output: false,
 
             fetch_scroll_x: 0,
             fetch_scroll_y: 0,
@@ -232,11 +240,12 @@ impl Default for Ppu {
 
 impl Ppu {
    pub fn reset(&mut self) {
-        self.oam = [0; OAM_SIZE];
+        self.oam = vec![0; OAM_SIZE];
         self.sprite_temps = [None; SPRITE_TEMP_SIZE];
 
         self.current_line = 241;
-        self.cumulative_cpu_cyc = 0;
+        self.is_nmi_output = false;
+        self.cumulative_master_cyc = 0;
 
         self.fetch_scroll_x = 0;
         self.fetch_scroll_y = 0;
@@ -285,11 +294,12 @@ impl Ppu {
         //Return to pre-transfer
         self.is_dma_running = is_pre_transfer;
     }
-    //Put a line on the fb (frame buffer). Fun fact: this frame buffer is directly used way up in the browser to draw on the canvas
+    //Put a line on the fb (frame buffer). It holds palette indexes rather than colors, the palette and whatever the
+    //host asked for turn it into a picture way up in the browser
     fn draw_line(
         &mut self,
         system: &mut System,
-        fb: &mut [[[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
+        fb: &mut FrameBuffer,
     ) {
         //This is where the very clever part (read: difficult) part of the PPU starts
         //https://wiki.nesdev.com/w/index.php/PPU_nametables
@@ -303,11 +313,13 @@ impl Ppu {
         let is_write_bg = system.read_ppu_is_write_bg();
         //self-explanatory
         let is_monochrome = system.read_is_monochrome();
+        //Emphasis tints the whole line, it goes above the 6 bit palette index
+        let emphasis = u16::from(system.read_ppu_emphasis()) << 6;
         //We find the "master color" for the palette
-        let master_bg_color = Color::from(system.video.read_u8(
+        let master_bg_color = system.video.read_u8(
             &mut system.rom,
             PALETTE_TABLE_BASE_ADDR + PALETTE_BG_OFFSET,
-        ));
+        );
         //Fairly standard x/y math coordinate math, but you know, old
         let raw_y = self.current_line + u16::from(self.current_scroll_y);
         let offset_y = raw_y & 0x07; 
@@ -323,6 +335,7 @@ impl Ppu {
         //We need to go across for every scanline
         for pixel_x in 0..VISIBLE_SCREEN_WIDTH {
             
+            system.rom.set_video_fetch(VideoFetch::Sprite);
             let (sprite_palette_data_back, sprite_palette_data_front) =
                 self.get_sprite_draw_data(system, pixel_x, pixel_y);
 
@@ -338,6 +351,14 @@ impl Ppu {
             let target_nametable_base_addr = nametable_base_addr +
                 (if is_nametable_position_left { 0x0000 } else { 0x0400 }) + 
                 (if is_nametable_position_top  { 0x0000 } else { 0x0800 }); 
+            //Nametable as mentioned above, this is how we map into it given a tile
+            let nametable_addr = target_nametable_base_addr + (tile_local_y << 5) + tile_local_x;
+            //Let the mapper know which tile this is, some swap in their own nametable or attributes per tile
+            let fetch_tile_x = (pixel_x as u16 + u16::from(self.current_scroll_x & 0x07)) >> 3;
+            system.rom.set_video_fetch(VideoFetch::Background {
+                tile_x: fetch_tile_x as u8,
+                name_table_addr: nametable_addr,
+            });
             //https://wiki.nesdev.com/w/index.php/PPU_attribute_tables
             //https://wiki.nesdev.com/w/index.php/PPU_scrolling#Tile_and_attribute_fetching
             //The attribute table is a 64-byte array at the end of each nametable that controls which palette is assigned to each part of the background. 
@@ -356,8 +377,6 @@ impl Ppu {
                 (false, false) => (raw_attribute >> 6) & 0x03, // bottom right
             };
 
-            //Nametable as mentioned above, this is how we map into it given a tile
-            let nametable_addr = target_nametable_base_addr + (tile_local_y << 5) + tile_local_x;
             //We get the background tile from the nametable address, as mentioned, the nametable defines the background
             let bg_tile_id = u16::from(system.video.read_u8(&mut system.rom, nametable_addr));
             //The background is built from the lower and upper bytes of the background pattern table
@@ -402,27 +421,19 @@ impl Ppu {
             ] {
                
                 if let Some(color_index) = palette_data {
-                    let c = Color::from(*color_index);
-                    draw_color = c;
+                    draw_color = *color_index;
                     break 'select_color;
                 }
             }
-            //Load up the frame buffer to be shipped back up to the browser
-            fb[pixel_y][pixel_x][0] = draw_color.0;
-            fb[pixel_y][pixel_x][1] = draw_color.1;
-            fb[pixel_y][pixel_x][2] = draw_color.2;
-
-           
+            let mut color_index = draw_color & 0x3f;
+            //Greyscale keeps only the brightness, which leaves the grey column of the palette
             if is_monochrome {
-                let data = ((u16::from(fb[pixel_y][pixel_x][0])
-                    + u16::from(fb[pixel_y][pixel_x][1])
-                    + u16::from(fb[pixel_y][pixel_x][2]))
-                    / 3) as u8;
-                fb[pixel_y][pixel_x][0] = data;
-                fb[pixel_y][pixel_x][1] = data;
-                fb[pixel_y][pixel_x][2] = data;
+                color_index &= 0x30;
             }
+            //Load up the frame buffer to be shipped back up to the browser
+            fb[pixel_y][pixel_x] = emphasis | u16::from(color_index);
         }
+        system.rom.set_video_fetch(VideoFetch::Cpu);
     }
     //Does what it says on the tin
     fn get_sprite_draw_data(
@@ -585,7 +596,7 @@ impl Ppu {
     fn update_line(
         &mut self,
         system: &mut System,
-        fb: &mut [[[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
+        fb: &mut FrameBuffer,
     ) -> Option<Interrupt> {
       
         self.current_scroll_x = self.fetch_scroll_x;
@@ -607,38 +618,49 @@ impl Ppu {
         system.write_ppu_is_hit_sprite0(false);
         system.write_ppu_is_sprite_overflow(false);
 
+        system.rom.start_scanline(self.current_line);
+        system.sense_light(self.current_line, fb);
+
         //Get the line status, act accordingly
-        match LineStatus::from(self.current_line) {
+        let region = system.region;
+        let scanlines_per_frame = region.scanlines_per_frame();
+        match LineStatus::from(self.current_line, region) {
             LineStatus::Visible => {
               
                 self.fetch_sprite(system);
               
                 self.draw_line(system, fb);
                 
-                self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
+                self.current_line = (self.current_line + 1) % scanlines_per_frame;
 
                 None
             }
             LineStatus::PostRender => {
-                self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
+                self.current_line = (self.current_line + 1) % scanlines_per_frame;
                 None
             }
             LineStatus::VerticalBlanking(is_first) => {
-                self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
+                self.current_line = (self.current_line + 1) % scanlines_per_frame;
                 if is_first {
                     system.write_ppu_is_vblank(true);
+                    system.start_input_frame();
                 }
-                
-                if system.read_ppu_nmi_enable() && system.read_ppu_is_vblank() {
+                //NMI is edge triggered, it fires once when vblank starts (or NMI gets enabled during vblank)
+                //rather than on every vblank line, otherwise PAL's 70 line vblank would hammer the CPU with NMIs
+                let is_nmi_output = system.read_ppu_nmi_enable() && system.read_ppu_is_vblank();
+                let is_nmi_edge = is_nmi_output && !self.is_nmi_output;
+                self.is_nmi_output = is_nmi_output;
+                if is_nmi_edge {
                     Some(Interrupt::NMI)
                 } else {
                     None
                 }
             }
             LineStatus::PreRender => {
-                self.current_line = (self.current_line + 1) % RENDER_SCREEN_HEIGHT;
+                self.current_line = (self.current_line + 1) % scanlines_per_frame;
                
                 system.write_ppu_is_vblank(false);
+                self.is_nmi_output = false;
 
                 None
             }
@@ -649,7 +671,7 @@ impl Ppu {
         &mut self,
         cpu_cyc: usize,
         system: &mut System,
-        fb: &mut [[[u8; NUM_OF_COLOR]; VISIBLE_SCREEN_WIDTH]; VISIBLE_SCREEN_HEIGHT],
+        fb: &mut FrameBuffer,
     ) -> Option<Interrupt> {
         //Do the scrolling
         let (_, scroll_x, scroll_y) = system.read_ppu_scroll();
@@ -685,13 +707,52 @@ impl Ppu {
         }
 
         //Sync back up to the CPU, update lines until in sync
-        let total_cyc = self.cumulative_cpu_cyc + cpu_cyc;
-        if total_cyc >= CPU_CYCLE_PER_LINE {
-            self.cumulative_cpu_cyc = total_cyc - CPU_CYCLE_PER_LINE;
+        let master_cycle_per_line = system.region.master_cycle_per_line();
+        let total_cyc = self.cumulative_master_cyc + cpu_cyc * system.region.cpu_divider();
+        if total_cyc >= master_cycle_per_line {
+            self.cumulative_master_cyc = total_cyc - master_cycle_per_line;
             self.update_line(system, fb)
         } else {
-            self.cumulative_cpu_cyc = total_cyc;
+            self.cumulative_master_cyc = total_cyc;
             None
         }
     }
-}
\ No newline at end of file
+}
+
+//Debugging helpers, these read video memory without touching any PPU state
+impl Ppu {
+    //Draw pattern table 0 ($0000) or 1 ($1000) as an RGB image, reading through the cartridge so CHR-RAM shows up too
+    //palette_id 0-3 are the background palettes, 4-7 the sprite palettes
+    pub fn draw_pattern_table(&self, system: &mut System, table_index: u8, palette_id: u8, palette: &Palette) -> Vec<u8> {
+        let table_base_addr: u16 = if (table_index & 0x01) == 0x01 { 0x1000 } else { 0x0000 };
+        let palette_base_addr =
+            PALETTE_TABLE_BASE_ADDR + u16::from(palette_id & 0x07) * PALETTE_ENTRY_SIZE;
+        let mut view = vec![0; PATTERN_TABLE_VIEW_SIZE * PATTERN_TABLE_VIEW_SIZE * NUM_OF_COLOR];
+        for tile_id in 0..(PATTERN_TABLE_VIEW_TILES * PATTERN_TABLE_VIEW_TILES) {
+            let tile_x = usize::from(tile_id % PATTERN_TABLE_VIEW_TILES) * SPRITE_WIDTH;
+            let tile_y = usize::from(tile_id / PATTERN_TABLE_VIEW_TILES) * SPRITE_NORMAL_HEIGHT;
+            let tile_addr = table_base_addr + tile_id * PATTERN_TABLE_ENTRY_BYTE;
+            for offset_y in 0..PIXEL_PER_TILE {
+                let data_lower = system.video.read_u8(&mut system.rom, tile_addr + offset_y);
+                let data_upper = system.video.read_u8(&mut system.rom, tile_addr + offset_y + 8);
+                for offset_x in 0..PIXEL_PER_TILE {
+                    let palette_offset = (((data_upper >> (7 - offset_x)) & 0x01) << 1)
+                        | ((data_lower >> (7 - offset_x)) & 0x01);
+                    let color = palette.color(u16::from(
+                        system
+                            .video
+                            .read_u8(&mut system.rom, palette_base_addr + u16::from(palette_offset))
+                            & 0x3f,
+                    ));
+                    let pixel_y = tile_y + usize::from(offset_y);
+                    let pixel_x = tile_x + usize::from(offset_x);
+                    let index = (pixel_y * PATTERN_TABLE_VIEW_SIZE + pixel_x) * NUM_OF_COLOR;
+                    view[index] = color.0;
+                    view[index + 1] = color.1;
+                    view[index + 2] = color.2;
+                }
+            }
+        }
+        view
+    }
+}
@@ -0,0 +1,118 @@
+/* TV system timing, NTSC/PAL/Dendy */
+//https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+//https://wiki.nesdev.com/w/index.php/Clock_rate
+//Everything in the console is driven off one master crystal, the CPU and PPU just divide it down by different amounts.
+//The three regions differ in the crystal, the dividers, and how many scanlines the PPU spends in each phase of a frame.
+use wasm_bindgen::prelude::*;
+
+pub const NTSC_MASTER_CLOCK: u32 = 21_477_272;
+pub const PAL_MASTER_CLOCK: u32 = 26_601_712;
+
+pub const PPU_DOT_PER_LINE: usize = 341;
+
+#[wasm_bindgen]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum Region {
+    //Pick whatever the cartridge header says, falls back to NTSC
+    #[default]
+    Auto,
+    Ntsc,
+    Pal,
+    //The famiclone used in the former USSR, PAL crystal but NTSC-like CPU speed and a long post-render period
+    Dendy,
+}
+
+impl Region {
+    //Auto defers to the region the cartridge asked for
+    pub fn resolve(self, cartridge: Region) -> Region {
+        match (self, cartridge) {
+            (Region::Auto, Region::Auto) => Region::Ntsc,
+            (Region::Auto, r) => r,
+            (r, _) => r,
+        }
+    }
+    pub fn master_clock(self) -> u32 {
+        match self {
+            Region::Pal | Region::Dendy => PAL_MASTER_CLOCK,
+            _ => NTSC_MASTER_CLOCK,
+        }
+    }
+    //Master clocks per CPU cycle
+    pub fn cpu_divider(self) -> usize {
+        match self {
+            Region::Pal => 16,
+            Region::Dendy => 15,
+            _ => 12,
+        }
+    }
+    //Master clocks per PPU dot
+    pub fn ppu_divider(self) -> usize {
+        match self {
+            Region::Pal | Region::Dendy => 5,
+            _ => 4,
+        }
+    }
+    pub fn cpu_freq(self) -> u32 {
+        self.master_clock() / (self.cpu_divider() as u32)
+    }
+    //A scanline in master clocks, this is what lets PAL run 3.2 dots per CPU cycle without drifting
+    pub fn master_cycle_per_line(self) -> usize {
+        PPU_DOT_PER_LINE * self.ppu_divider()
+    }
+    pub fn scanlines_per_frame(self) -> u16 {
+        match self {
+            Region::Pal | Region::Dendy => 312,
+            _ => 262,
+        }
+    }
+    //The line the vblank flag gets set and the NMI fires on. Dendy keeps NTSC's 20 line vblank by idling
+    //for 50 extra lines after rendering instead
+    pub fn vblank_start_line(self) -> u16 {
+        match self {
+            Region::Dendy => 291,
+            _ => 241,
+        }
+    }
+    pub fn pre_render_line(self) -> u16 {
+        self.scanlines_per_frame() - 1
+    }
+    pub fn cpu_cycle_per_frame(self) -> usize {
+        self.master_cycle_per_line() * usize::from(self.scanlines_per_frame()) / self.cpu_divider()
+    }
+    pub fn frame_rate(self) -> f64 {
+        let master_per_frame = self.master_cycle_per_line() * usize::from(self.scanlines_per_frame());
+        f64::from(self.master_clock()) / (master_per_frame as f64)
+    }
+    //APU noise channel periods, in CPU cycles
+    //https://wiki.nesdev.com/w/index.php/APU_Noise
+    pub fn noise_period_table(self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &[
+                4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+            ],
+            _ => &[
+                4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+            ],
+        }
+    }
+    //APU DMC sample rates, in CPU cycles
+    //https://wiki.nesdev.com/w/index.php/APU_DMC
+    pub fn dmc_rate_table(self) -> &'static [u16; 16] {
+        match self {
+            Region::Pal => &[
+                398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+            ],
+            _ => &[
+                428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+            ],
+        }
+    }
+    //The frame counter steps, in CPU cycles, 4 step mode then the extra step of the 5 step mode
+    //https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    pub fn frame_counter_steps(self) -> [usize; 5] {
+        match self {
+            Region::Pal => [8313, 16627, 24939, 33253, 41565],
+            _ => [7457, 14913, 22371, 29829, 37281],
+        }
+    }
+}
@@ -0,0 +1,201 @@
+/* Rewind */
+//Recent history, kept as a snapshot every few frames plus the controller input for every frame. Rewinding restores
+//the snapshot before the frame asked for and replays the input up to it, so it lands on exactly that frame.
+//Snapshots leave the ROM out, and their RAM, disk sides included, is kept XORed against the next newer snapshot and run length encoded.
+//Most RAM is the same a few frames later, so that's mostly runs of zeros. Only the newest snapshot keeps its RAM
+//as is, older ones are worked out by undoing the deltas one at a time going back. The oldest snapshots are dropped
+//once there's more history than asked for, or it's taking up too much memory.
+use super::cpu::Cpu;
+use super::movie::MovieFrame;
+use super::ppu::Ppu;
+use super::snapshot::Snapshot;
+use super::system::System;
+use std::collections::VecDeque;
+
+//Ten seconds at 60fps
+pub const REWIND_DEFAULT_FRAMES: usize = 600;
+pub const REWIND_DEFAULT_MAX_BYTES: usize = 32 * 1024 * 1024;
+//Frames between snapshots. Rewinding replays at most this many frames
+const REWIND_SNAPSHOT_INTERVAL: u64 = 4;
+
+struct RewindEntry {
+    frame: u64,
+    //Everything but the ROM and RAM
+    state: Box<Snapshot>,
+    //Delta against the next entry's RAM, or the RAM itself for the newest entry
+    ram: Vec<u8>,
+    ram_sizes: Vec<usize>,
+    //Controller input for this frame and each one after it up to the next entry
+    inputs: Vec<MovieFrame>,
+}
+
+impl RewindEntry {
+    fn bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.state.bytes()
+            + self.ram.capacity()
+            + self.ram_sizes.capacity() * std::mem::size_of::<usize>()
+            + self.inputs.capacity() * std::mem::size_of::<MovieFrame>()
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        out.push((length as u8) | 0x80);
+        length >>= 7;
+    }
+    out.push(length as u8);
+}
+
+fn read_length(data: &[u8], index: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*index];
+        *index += 1;
+        length |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
+
+//Pairs of a run of unchanged bytes and a run of changed ones, the lengths as 7 bit varints then the changed bytes
+//XORed
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < old.len() {
+        let same_start = i;
+        while i < old.len() && old[i] == new[i] {
+            i += 1;
+        }
+        let changed_start = i;
+        while i < old.len() && old[i] != new[i] {
+            i += 1;
+        }
+        write_length(&mut out, changed_start - same_start);
+        write_length(&mut out, i - changed_start);
+        out.extend(old[changed_start..i].iter().zip(&new[changed_start..i]).map(|(a, b)| a ^ b));
+    }
+    out
+}
+
+//Turns the newer RAM back into the older one
+fn apply_delta(delta: &[u8], data: &mut [u8]) {
+    let mut index = 0;
+    let mut offset = 0;
+    while index < delta.len() {
+        offset += read_length(delta, &mut index);
+        let changed = read_length(delta, &mut index);
+        for (byte, xor) in data[offset..offset + changed].iter_mut().zip(&delta[index..index + changed]) {
+            *byte ^= xor;
+        }
+        offset += changed;
+        index += changed;
+    }
+}
+
+pub struct Rewind {
+    entries: VecDeque<RewindEntry>,
+    //Frames of history to keep, 0 turns rewind off
+    pub length: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            length: REWIND_DEFAULT_FRAMES,
+            max_bytes: REWIND_DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl Rewind {
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+    //How far back it can go from frame
+    pub fn available_frames(&self, frame: u64) -> u64 {
+        self.entries.front().map_or(0, |entry| frame - entry.frame)
+    }
+    pub fn bytes(&self) -> usize {
+        self.entries.iter().map(RewindEntry::bytes).sum()
+    }
+    //Called on each frame boundary once the input for the frame is in
+    pub fn record(&mut self, frame: u64, cpu: &Cpu, system: &mut System, ppu: &Ppu) {
+        if self.length == 0 {
+            self.entries.clear();
+            return;
+        }
+        let is_due = self
+            .entries
+            .back()
+            .is_none_or(|entry| frame >= entry.frame + REWIND_SNAPSHOT_INTERVAL);
+        if is_due {
+            self.push(frame, Snapshot::capture_without_rom(cpu, system, ppu));
+        }
+        let input = MovieFrame::capture(system, 0);
+        if let Some(entry) = self.entries.back_mut() {
+            entry.inputs.push(input);
+        }
+        self.trim(frame);
+    }
+    fn push(&mut self, frame: u64, mut state: Snapshot) {
+        let (ram, ram_sizes) = state.take_ram();
+        match self.entries.back_mut() {
+            Some(newest) if newest.ram_sizes == ram_sizes => {
+                newest.ram = encode_delta(&newest.ram, &ram);
+                newest.ram.shrink_to_fit();
+                newest.inputs.shrink_to_fit();
+            }
+            //A different cartridge, none of the history goes with it
+            Some(_) => self.entries.clear(),
+            None => {}
+        }
+        self.entries.push_back(RewindEntry {
+            frame,
+            state: Box::new(state),
+            ram,
+            ram_sizes,
+            inputs: Vec::new(),
+        });
+    }
+    fn trim(&mut self, frame: u64) {
+        let length = self.length as u64;
+        while self.entries.len() > 1 {
+            //The oldest entry isn't needed once the one after it goes back far enough
+            let is_too_old = self.entries[1].frame + length <= frame;
+            if !is_too_old && self.bytes() <= self.max_bytes {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+    //Throws away the history after frame, or after the oldest snapshot if it doesn't go back that far. Hands back the
+    //snapshot to restore, the frame it's from, and the input to replay from there to get to frame
+    pub fn seek(&mut self, frame: u64) -> Option<(Snapshot, u64, Vec<MovieFrame>)> {
+        //Start at least one frame back when possible so the replay draws the picture
+        let index = self.entries.iter().rposition(|entry| entry.frame < frame).unwrap_or(0);
+        let mut ram = self.entries.back()?.ram.clone();
+        for entry in self.entries.iter().skip(index).rev().skip(1) {
+            apply_delta(&entry.ram, &mut ram);
+        }
+        //The input for the frame the dropped entry was taken on is the first of its own, and it's still needed when
+        //frame is exactly that one
+        let carried_input = self.entries.get(index + 1).and_then(|next| next.inputs.first().cloned());
+        self.entries.truncate(index + 1);
+        let entry = self.entries.back_mut()?;
+        entry.inputs.extend(carried_input);
+        entry.ram.clone_from(&ram);
+        let replay_frames = frame.saturating_sub(entry.frame) as usize;
+        let mut inputs = entry.inputs.split_off(1.min(entry.inputs.len()));
+        inputs.truncate(replay_frames);
+        let mut state = (*entry.state).clone();
+        state.put_ram(&ram, &entry.ram_sizes);
+        Some((state, entry.frame, inputs))
+    }
+}
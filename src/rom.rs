@@ -1,15 +1,36 @@
 /* Binary loading and handling */
+use std::fmt;
 use wasm_bindgen::prelude::*;
+use super::header::*;
+use super::gamedb::{self, GameDbEntry};
+use super::unif::{self, UnifCartridge, UnifError};
+use super::nsf::{self, Nsf, NsfBanks, NsfError, NsfInfo, MAPPER31_INIT_BANKS, NSF_MAPPER, NSF_PRG_ROM_MAX_SIZE};
+use super::fds::{self, Fds, FdsError, FdsImage, FDS_BIOS_SIZE, FDS_CHR_RAM_SIZE, FDS_MAPPER, FDS_PRG_RAM_SIZE};
+use super::mmc5::{Mmc5, MMC5_CHR_ROM_MAX_SIZE, MMC5_MAPPER, MMC5_PRG_ROM_MAX_SIZE};
+use super::vrc::{self, Vrc, VRC_CHR_ROM_MAX_SIZE, VRC_PRG_ROM_MAX_SIZE};
+use super::vrc6::{Vrc6, VRC6A_MAPPER, VRC6B_MAPPER, VRC6_CHR_ROM_MAX_SIZE, VRC6_PRG_ROM_MAX_SIZE};
+use super::vrc7::{Vrc7, VRC7_CHR_ROM_MAX_SIZE, VRC7_MAPPER, VRC7_PRG_ROM_MAX_SIZE};
+use super::n163::{N163, N163_CHR_ROM_MAX_SIZE, N163_MAPPER, N163_PRG_ROM_MAX_SIZE};
+use super::fme7::{Fme7, FME7_CHR_ROM_MAX_SIZE, FME7_MAPPER, FME7_PRG_ROM_MAX_SIZE};
+use super::region::Region;
 
 
 pub const PRG_ROM_MAX_SIZE: usize = 0x8000;
 pub const CHR_ROM_MAX_SIZE: usize = 0x2000;
+//Carts without CHR ROM get this much CHR-RAM unless NES 2.0 says otherwise
+pub const CHR_RAM_DEFAULT_SIZE: usize = 0x2000;
 pub const BATTERY_PACKED_RAM_MAX_SIZE: usize = 0x2000;
+//The biggest file we load, an MMC5 cart with all the PRG and CHR ROM it can take. Patches and archives are held to
+//it as well, so a bad size field can't ask for more memory than any ROM would need
+pub const ROM_FILE_MAX_SIZE: usize =
+    INES_HEADER_SIZE + INES_TRAINER_SIZE + MMC5_PRG_ROM_MAX_SIZE + MMC5_CHR_ROM_MAX_SIZE;
 
 pub const PRG_ROM_SYSTEM_BASE_ADDR: u16 = 0x8000;
 pub const BATTERY_PACKED_RAM_BASE_ADDR: u16 = 0x6000;
 
 pub const INES_TRAINER_DATA_SIZE: usize = 0x0200;
+//$7000 relative to the start of cartridge RAM
+pub const INES_TRAINER_BASE_OFFSET: usize = 0x1000;
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
@@ -46,10 +67,26 @@ macro_rules! arr_write {
         $arr[$index] = $data
     };
 }
+//The boards we can emulate, along with whatever state their registers hold
 #[derive(Debug, Clone)]
 pub enum Mapper{
     Unknown,
     Nrom,
+    //Mapper 31, also what NSF tunes run on
+    Nsf(Box<NsfBanks>),
+    //The Famicom Disk System's RAM adapter, boxed since it carries the whole disk around
+    Fds(Box<Fds>),
+    Mmc5(Box<Mmc5>),
+    //Konami VRC2 and VRC4, mappers 21, 22, 23 and 25
+    Vrc(Box<Vrc>),
+    //Konami VRC6, mappers 24 and 26
+    Vrc6(Box<Vrc6>),
+    //Konami VRC7, mapper 85
+    Vrc7(Box<Vrc7>),
+    //Namco 163, mapper 19
+    N163(Box<N163>),
+    //Sunsoft FME-7 and 5B, mapper 69
+    Fme7(Box<Fme7>),
 }
 //Defines the nametable mirroring pattern.
 //http://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
@@ -58,10 +95,126 @@ pub enum MirrorTable{
     Unknown,
     Horizontal,
     Vertical,
-    SingleScreen,
+    //Every nametable is the first page of VRAM
+    SingleScreenA,
+    //Every nametable is the second page
+    SingleScreenB,
     FourScreen,
 }
 
+//Which 1k of memory a nametable address reads from
+#[derive(Copy, Clone, Debug)]
+pub enum NameTableSource {
+    //The usual, the console's VRAM laid out by the mirroring
+    Mirror(MirrorTable),
+    //A particular page of the console's VRAM
+    Vram(usize),
+    //Memory or logic on the cartridge, see Rom::read_name_table_u8
+    Cartridge,
+}
+
+//What the PPU is reading video memory for, mappers like the MMC5 bank differently for each
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VideoFetch {
+    //$2007 accesses, or anything else outside of rendering
+    Cpu,
+    //A background tile, tile_x counts tiles fetched from the left edge of the line
+    Background { tile_x: u8, name_table_addr: u16 },
+    Sprite,
+}
+
+//Everything that can go wrong loading a ROM, the Display impl is what ends up in front of the user
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum RomError {
+    //Doesn't start with N E S and the line break, probably not a ROM at all
+    BadMagic,
+    //The header promises more data than the file has
+    Truncated { expected: usize, actual: usize },
+    //Bigger than ROM_FILE_MAX_SIZE
+    TooLarge(usize),
+    UnsupportedMapper(u16),
+    //A UNIF board we know the mapper for, but don't implement
+    UnsupportedBoard { board: String, mapper: u16 },
+    //The header parsed but the memory sizes don't fit the board
+    SizeMismatch { name: &'static str, bytes: usize, max_bytes: usize },
+    //Vs. System, PlayChoice-10 and the various famiclones need hardware we don't emulate
+    UnsupportedConsoleType(ConsoleType),
+    //Anything else wrong with the header
+    InvalidHeader(HeaderError),
+    //Anything wrong with a UNIF file, including boards we've never heard of
+    InvalidUnif(UnifError),
+    InvalidNsf(NsfError),
+    InvalidFds(FdsError),
+}
+
+impl From<HeaderError> for RomError {
+    fn from(err: HeaderError) -> Self {
+        match err {
+            HeaderError::BadMagic => RomError::BadMagic,
+            HeaderError::TruncatedHeader(actual) => RomError::Truncated {
+                expected: INES_HEADER_SIZE,
+                actual,
+            },
+            _ => RomError::InvalidHeader(err),
+        }
+    }
+}
+
+impl From<UnifError> for RomError {
+    fn from(err: UnifError) -> Self {
+        RomError::InvalidUnif(err)
+    }
+}
+
+impl From<NsfError> for RomError {
+    fn from(err: NsfError) -> Self {
+        RomError::InvalidNsf(err)
+    }
+}
+
+impl From<FdsError> for RomError {
+    fn from(err: FdsError) -> Self {
+        RomError::InvalidFds(err)
+    }
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::BadMagic => write!(f, "Not a NES ROM (missing the NES<EOF> header)"),
+            RomError::Truncated { expected, actual } => write!(
+                f,
+                "ROM file is truncated, expected {} bytes but got {}",
+                expected, actual
+            ),
+            RomError::TooLarge(bytes) => write!(
+                f,
+                "ROM file is {} KiB, more than the {} KiB the biggest cartridge needs",
+                bytes / 1024,
+                ROM_FILE_MAX_SIZE / 1024
+            ),
+            RomError::UnsupportedMapper(mapper) => write!(f, "Mapper {} is not supported", mapper),
+            RomError::UnsupportedBoard { board, mapper } => {
+                write!(f, "UNIF board {} (mapper {}) is not supported", board, mapper)
+            }
+            RomError::SizeMismatch { name, bytes, max_bytes } => write!(
+                f,
+                "{} is {} KiB but this board only supports up to {} KiB",
+                name,
+                bytes / 1024,
+                max_bytes / 1024
+            ),
+            RomError::UnsupportedConsoleType(console_type) => {
+                write!(f, "Console type {:?} is not supported", console_type)
+            }
+            RomError::InvalidHeader(err) => write!(f, "Invalid ROM header: {}", err),
+            RomError::InvalidUnif(err) => write!(f, "Invalid UNIF file: {}", err),
+            RomError::InvalidNsf(err) => write!(f, "Invalid NSF file: {}", err),
+            RomError::InvalidFds(err) => write!(f, "Invalid FDS image: {}", err),
+        }
+    }
+}
+
 //This is the "game cartdridge" structure, in INES format
 //http://wiki.nesdev.com/w/index.php/INES
 #[derive(Clone, Debug)]
@@ -72,16 +225,29 @@ pub struct Rom{
     pub mirror_table: MirrorTable,
     //The SRAM
     pub sram : bool,
+    //Everything the header told us, see header.rs
+    pub info: CartridgeInfo,
+    //What the game database knows about this dump, if anything
+    pub game: Option<&'static GameDbEntry>,
+    //Set when an NSF was loaded rather than a cartridge, see nsf.rs
+    pub nsf: Option<NsfInfo>,
     //Program  memory size
     pub p_rom_bytes : usize,
     //Character memory size, these are the graphics
     pub c_rom_bytes : usize,
     //Actual program on the rom
-    pub p_rom:[u8; PRG_ROM_MAX_SIZE],
-    //Actual graphics
-    pub c_rom:[u8; CHR_ROM_MAX_SIZE],
+    pub p_rom: Vec<u8>,
+    //Actual graphics, always at least the 8k the PPU can see
+    pub c_rom: Vec<u8>,
+    //Graphics RAM, for carts that have the game upload its tiles instead of shipping them in ROM. Empty when the cart has CHR ROM
+    pub c_ram: Vec<u8>,
+    //Real CHR ROM ignores writes, but some homebrew and bad dumps with the wrong header expect to be able to write to it.
+    //The user's setting, kept across loads. Carts that write to it anyway say so in info, see is_chr_writable
+    pub is_chr_rom_writable: bool,
     //The ram we can modify on the ROM (I know, I know)
-    pub srambytes:[u8;BATTERY_PACKED_RAM_MAX_SIZE],
+    pub srambytes: Vec<u8>,
+    //disksys.rom, supplied by the user and kept across loads. FDS images get it as their PRG ROM
+    pub fds_bios: Vec<u8>,
 }
 
 impl Rom{
@@ -90,88 +256,280 @@ impl Rom{
             mapper : Mapper::Unknown,
             mirror_table: MirrorTable::Unknown,
             sram : false,
+            info: CartridgeInfo::default(),
+            game: None,
+            nsf: None,
             p_rom_bytes: 0,
             c_rom_bytes : 0,
-            p_rom: [0; PRG_ROM_MAX_SIZE],
-            c_rom: [0; CHR_ROM_MAX_SIZE],
-            srambytes: [0; BATTERY_PACKED_RAM_MAX_SIZE],
-        }
-    }
-    pub fn load_bin(&mut self, read_f:impl Fn(usize) -> u8) -> bool{
-
-        //Get the N E S bits and the line break
-        if read_f(0) != 0x4e {
-            return false;
-        }
-        if read_f(1) != 0x45 {
-            return false;
-        }
-        if read_f(2) != 0x53 {
-            return false;
-        }
-        if read_f(3) != 0x1a {
-            return false;
-        }
-        //4th and 5th bytes are the sizes of prg and chr rom in 16k increments
-        //I.E if p_rom_sz is 2 then there are 32k bytes in PRG rom
-        let p_rom_sz = usize::from(read_f(4));
-        //Same here except its 8k increments
-        let c_rom_sz = usize::from(read_f(5));
-        //Mapper flag 1, we only support 1 mapper and rom type so this is the only flag we actually use
-        let flags6  = read_f(6); // Mapper 1
-        let _flags7  = read_f(7); // Mapper 2
-        let _flags8  = read_f(8); // Ram Size
-        let _flags9  = read_f(9); // tv system 1
-        let _flags10 = read_f(10); // tv system 2
-        debug_assert!(p_rom_sz > 0);
-        //Are we mirroring vertically?
-        let is_vert_m = (flags6 & 0x01) == 0x01;
-        if is_vert_m{
-            self.mirror_table = MirrorTable::Vertical;
-        }else{
-            self.mirror_table = MirrorTable::Horizontal;
-        }
-        self.sram = (flags6 & 0x02) == 0x02;
-        let trainer_exists = (flags6 & 0x04) == 0x04;
-        let header_bytes = 16;
-        //I am not sure what a trainer is in this context. But its in the INES spec so we have to account for the bytes
-        let trainer_bytes = if trainer_exists { 512 } else { 0 };
-        let prg_rom_bytes = p_rom_sz * 0x4000; 
-        let chr_rom_bytes = c_rom_sz * 0x2000; 
-        let trainer_baseaddr = header_bytes;
-        let prg_rom_baseaddr = header_bytes + trainer_bytes;
-        let chr_rom_baseaddr = header_bytes + trainer_bytes + prg_rom_bytes;
-
-        self.mapper = Mapper::Nrom;
-        debug_assert!(prg_rom_bytes <= PRG_ROM_MAX_SIZE);
-        debug_assert!(chr_rom_bytes <= CHR_ROM_MAX_SIZE);
-        //Load everything in
-        if trainer_exists {
-            for i in 0..INES_TRAINER_DATA_SIZE {
-                let ines_binary_addr = trainer_baseaddr + i;
-                self.p_rom[i] = read_f(ines_binary_addr);
-            }
+            p_rom: vec![0; PRG_ROM_MAX_SIZE],
+            c_rom: vec![0; CHR_ROM_MAX_SIZE],
+            c_ram: Vec::new(),
+            is_chr_rom_writable: false,
+            srambytes: vec![0; BATTERY_PACKED_RAM_MAX_SIZE],
+            fds_bios: Vec::new(),
+        }
+    }
+    //Parse the header and copy the cartridge contents in. The parsed header is kept around on the Rom and also
+    //handed back so the caller can see what it just loaded
+    //Everything is checked before anything gets copied, so a bad file leaves the previous cartridge alone
+    //Takes INES/NES 2.0, UNIF, NSF/NSFe and FDS files
+    pub fn load_bin(&mut self, binary: &[u8]) -> Result<CartridgeInfo, RomError> {
+        if binary.len() > ROM_FILE_MAX_SIZE {
+            return Err(RomError::TooLarge(binary.len()));
+        }
+        if unif::is_unif(binary) {
+            let UnifCartridge {
+                info,
+                board,
+                prg_rom,
+                chr_rom,
+            } = UnifCartridge::parse(binary)?;
+            //The board name means more to whoever dumped it than the mapper number we gave it
+            return self
+                .load_cartridge(info, None, &prg_rom, &chr_rom)
+                .map_err(|err| match err {
+                    RomError::UnsupportedMapper(mapper) => RomError::UnsupportedBoard { board, mapper },
+                    _ => err,
+                });
         }
-        for i in 0..prg_rom_bytes {
-            let bin_addr = prg_rom_baseaddr + i;
-            self.p_rom[i] = read_f(bin_addr);
-           
+        if nsf::is_nsf(binary) {
+            return self.load_nsf(Nsf::parse(binary)?);
         }
-            
-        for i in 0..chr_rom_bytes{
-            let bin_addr = chr_rom_baseaddr + i;
-            self.c_rom[i] = read_f(bin_addr);
-            
+        if fds::is_fds(binary) {
+            return self.load_fds(FdsImage::parse(binary)?);
         }
+        let info = CartridgeInfo::parse(binary)?;
+        if binary.len() < info.image_size() {
+            return Err(RomError::Truncated {
+                expected: info.image_size(),
+                actual: binary.len(),
+            });
+        }
+        let trainer = if info.has_trainer {
+            Some(&binary[info.trainer_offset()..info.prg_rom_offset()])
+        } else {
+            None
+        };
+        let prg_rom = &binary[info.prg_rom_offset()..info.chr_rom_offset()];
+        let chr_rom = &binary[info.chr_rom_offset()..info.image_size()];
+        self.load_cartridge(info, trainer, prg_rom, chr_rom)
+    }
+    //NSF tunes are loaded as a mapper 31 cart with nothing but CHR-RAM, the player in nsf.rs does the rest
+    fn load_nsf(&mut self, nsf: Nsf) -> Result<CartridgeInfo, RomError> {
+        let info = CartridgeInfo {
+            format: HeaderFormat::Nsf,
+            mapper: NSF_MAPPER,
+            prg_rom_bytes: nsf.prg_rom.len(),
+            prg_ram_bytes: INES_PRG_RAM_BANK_SIZE,
+            chr_ram_bytes: INES_DEFAULT_CHR_RAM_SIZE,
+            timing: nsf.info.timing,
+            title: Some(nsf.info.title.clone()).filter(|title| !title.is_empty()),
+            ..CartridgeInfo::default()
+        };
+        let info = self.load_cartridge(info, None, &nsf.prg_rom, &[])?;
+        self.reset_nsf_banks(nsf.info.init_banks, nsf.info.expansion_audio);
+        self.nsf = Some(nsf.info);
+        Ok(info)
+    }
+    //The BIOS stands in for PRG ROM, everything else the RAM adapter and the disk take care of
+    fn load_fds(&mut self, image: FdsImage) -> Result<CartridgeInfo, RomError> {
+        if self.fds_bios.is_empty() {
+            return Err(FdsError::MissingBios.into());
+        }
+        let info = CartridgeInfo {
+            format: HeaderFormat::Fds,
+            mapper: FDS_MAPPER,
+            mirror_table: MirrorTable::Horizontal,
+            prg_rom_bytes: FDS_BIOS_SIZE,
+            prg_ram_bytes: FDS_PRG_RAM_SIZE,
+            chr_ram_bytes: FDS_CHR_RAM_SIZE,
+            //Never sold outside of Japan
+            timing: Region::Ntsc,
+            ..CartridgeInfo::default()
+        };
+        let bios = self.fds_bios.clone();
+        let info = self.load_cartridge(info, None, &bios, &[])?;
+        self.mapper = Mapper::Fds(Box::new(Fds::new(image)));
+        Ok(info)
+    }
+    //Has to be done before loading any disk images
+    pub fn set_fds_bios(&mut self, bios: &[u8]) -> Result<(), FdsError> {
+        if bios.len() != FDS_BIOS_SIZE {
+            return Err(FdsError::BadBiosSize(bios.len()));
+        }
+        self.fds_bios = bios.to_vec();
+        Ok(())
+    }
+    //The disk drive, when there is one
+    pub fn fds(&self) -> Option<&Fds> {
+        match &self.mapper {
+            Mapper::Fds(fds) => Some(fds),
+            _ => None,
+        }
+    }
+    pub fn fds_mut(&mut self) -> Option<&mut Fds> {
+        match &mut self.mapper {
+            Mapper::Fds(fds) => Some(fds),
+            _ => None,
+        }
+    }
+    //Everything on the cartridge a game can write to: PRG RAM, CHR RAM, CHR ROM when it's writable and whatever the
+    //mapper carries
+    pub fn ram_mut(&mut self) -> Vec<&mut Vec<u8>> {
+        let is_chr_writable = self.is_chr_writable();
+        let mut ram = vec![&mut self.srambytes, &mut self.c_ram];
+        if is_chr_writable {
+            ram.push(&mut self.c_rom);
+        }
+        match &mut self.mapper {
+            Mapper::Unknown | Mapper::Nrom => {}
+            Mapper::Nsf(banks) => ram.extend(banks.ram_mut()),
+            Mapper::Fds(fds) => ram.extend(fds.ram_mut()),
+            Mapper::Mmc5(mmc5) => ram.extend(mmc5.ram_mut()),
+            Mapper::Vrc(vrc) => ram.extend(vrc.ram_mut()),
+            Mapper::Vrc6(vrc6) => ram.extend(vrc6.ram_mut()),
+            Mapper::Vrc7(vrc7) => ram.extend(vrc7.ram_mut()),
+            Mapper::N163(n163) => ram.extend(n163.ram_mut()),
+            Mapper::Fme7(fme7) => ram.extend(fme7.ram_mut()),
+        }
+        ram
+    }
+    //Memory the cartridge has on the heap, ROM, RAM and mapper alike
+    pub fn heap_bytes(&self) -> usize {
+        let buffers = [&self.p_rom, &self.c_rom, &self.c_ram, &self.srambytes, &self.fds_bios];
+        let mapper = match &self.mapper {
+            Mapper::Unknown | Mapper::Nrom => 0,
+            Mapper::Nsf(banks) => banks.heap_bytes(),
+            Mapper::Fds(fds) => fds.heap_bytes(),
+            Mapper::Mmc5(mmc5) => mmc5.heap_bytes(),
+            Mapper::Vrc(vrc) => vrc.heap_bytes(),
+            Mapper::Vrc6(vrc6) => vrc6.heap_bytes(),
+            Mapper::Vrc7(vrc7) => vrc7.heap_bytes(),
+            Mapper::N163(n163) => n163.heap_bytes(),
+            Mapper::Fme7(fme7) => fme7.heap_bytes(),
+        };
+        buffers.iter().map(|buffer| buffer.capacity()).sum::<usize>() + mapper
+    }
+    //Put the bank registers back how the tune wants them, done before every INIT
+    pub fn reset_nsf_banks(&mut self, init_banks: [u8; nsf::NSF_NUM_OF_BANKS], expansion_audio: u8) {
+        self.mapper = Mapper::Nsf(Box::new(NsfBanks::new(init_banks, expansion_audio, &self.p_rom)));
+    }
+    //The part of loading that doesn't care what file format the cartridge came in
+    fn load_cartridge(
+        &mut self,
+        mut info: CartridgeInfo,
+        trainer: Option<&[u8]>,
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+    ) -> Result<CartridgeInfo, RomError> {
+        //Known dumps get their header fixed up from the game database before we decide whether we can run them
+        let game = gamedb::lookup(prg_rom, chr_rom);
+        if let Some(entry) = game {
+            entry.apply(&mut info);
+        }
+        if info.console_type != ConsoleType::Nes {
+            return Err(RomError::UnsupportedConsoleType(info.console_type));
+        }
+        let prg_rom_max_bytes = match info.mapper {
+            0 => PRG_ROM_MAX_SIZE,
+            NSF_MAPPER => NSF_PRG_ROM_MAX_SIZE,
+            FDS_MAPPER if info.format == HeaderFormat::Fds => FDS_BIOS_SIZE,
+            MMC5_MAPPER => MMC5_PRG_ROM_MAX_SIZE,
+            mapper if vrc::is_vrc(mapper) => VRC_PRG_ROM_MAX_SIZE,
+            VRC6A_MAPPER | VRC6B_MAPPER => VRC6_PRG_ROM_MAX_SIZE,
+            VRC7_MAPPER => VRC7_PRG_ROM_MAX_SIZE,
+            N163_MAPPER => N163_PRG_ROM_MAX_SIZE,
+            FME7_MAPPER => FME7_PRG_ROM_MAX_SIZE,
+            _ => return Err(RomError::UnsupportedMapper(info.mapper)),
+        };
+        let chr_rom_max_bytes = match info.mapper {
+            MMC5_MAPPER => MMC5_CHR_ROM_MAX_SIZE,
+            mapper if vrc::is_vrc(mapper) => VRC_CHR_ROM_MAX_SIZE,
+            VRC6A_MAPPER | VRC6B_MAPPER => VRC6_CHR_ROM_MAX_SIZE,
+            VRC7_MAPPER => VRC7_CHR_ROM_MAX_SIZE,
+            N163_MAPPER => N163_CHR_ROM_MAX_SIZE,
+            FME7_MAPPER => FME7_CHR_ROM_MAX_SIZE,
+            _ => CHR_ROM_MAX_SIZE,
+        };
+        if info.prg_rom_bytes > prg_rom_max_bytes {
+            return Err(RomError::SizeMismatch {
+                name: "PRG ROM",
+                bytes: info.prg_rom_bytes,
+                max_bytes: prg_rom_max_bytes,
+            });
+        }
+        if info.chr_rom_bytes > chr_rom_max_bytes {
+            return Err(RomError::SizeMismatch {
+                name: "CHR ROM",
+                bytes: info.chr_rom_bytes,
+                max_bytes: chr_rom_max_bytes,
+            });
+        }
+
+        self.mirror_table = info.mirror_table;
+        self.sram = info.has_battery;
+        let prg_rom_bytes = info.prg_rom_bytes;
+        let chr_rom_bytes = info.chr_rom_bytes;
+
+        self.mapper = match info.mapper {
+            NSF_MAPPER => Mapper::Nsf(Box::new(NsfBanks::new(MAPPER31_INIT_BANKS, 0, prg_rom))),
+            MMC5_MAPPER => Mapper::Mmc5(Box::default()),
+            mapper if vrc::is_vrc(mapper) => {
+                let has_prg_ram = info.prg_ram_bytes + info.prg_nvram_bytes > 0;
+                Mapper::Vrc(Box::new(Vrc::new(mapper, info.submapper, has_prg_ram)))
+            }
+            mapper @ (VRC6A_MAPPER | VRC6B_MAPPER) => Mapper::Vrc6(Box::new(Vrc6::new(mapper))),
+            VRC7_MAPPER => Mapper::Vrc7(Box::new(Vrc7::new(info.submapper))),
+            N163_MAPPER => Mapper::N163(Box::default()),
+            FME7_MAPPER => Mapper::Fme7(Box::default()),
+            _ => Mapper::Nrom,
+        };
+        self.nsf = None;
+        //Load everything in
+        //The trainer is 512 bytes of code some copiers needed, it lives at $7000 in the cartridge RAM
+        if let Some(trainer) = trainer {
+            self.srambytes[INES_TRAINER_BASE_OFFSET..INES_TRAINER_BASE_OFFSET + INES_TRAINER_DATA_SIZE]
+                .copy_from_slice(trainer);
+        }
+        self.p_rom = prg_rom.to_vec();
+        self.c_rom = chr_rom.to_vec();
+        self.c_rom.resize(chr_rom_bytes.max(CHR_ROM_MAX_SIZE), 0);
+
+        //No CHR ROM means the graphics live in RAM the game fills in itself, a fresh cart starts out blank
+        let chr_ram_bytes = if chr_rom_bytes == 0 {
+            match info.chr_ram_bytes + info.chr_nvram_bytes {
+                0 => CHR_RAM_DEFAULT_SIZE,
+                bytes => bytes,
+            }
+        } else {
+            0
+        };
+        self.c_ram = vec![0; chr_ram_bytes];
 
         self.p_rom_bytes= prg_rom_bytes;
         self.c_rom_bytes = chr_rom_bytes;
+        self.info = info.clone();
+        self.game = game;
 
-        true
+        Ok(info)
     }
     //Read 8 bytes from ROM, mapped out appropriately
-   pub fn read_u8(&mut self, addr: u16, _is_nondestructive: bool) -> u8 {
-        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
+   pub fn read_u8(&mut self, addr: u16, is_nondestructive: bool) -> u8 {
+        match self.mapper {
+            Mapper::Nsf(ref mut banks) => return banks.read_u8(addr, &self.p_rom, &self.srambytes, is_nondestructive),
+            Mapper::Fds(ref mut fds) => return fds.read_u8(addr, &self.p_rom, is_nondestructive),
+            Mapper::Mmc5(ref mut mmc5) => return mmc5.read_u8(addr, &self.p_rom, is_nondestructive),
+            Mapper::Vrc(ref vrc) => return vrc.read_u8(addr, &self.p_rom),
+            Mapper::Vrc6(ref vrc6) => return vrc6.read_u8(addr, &self.p_rom),
+            Mapper::Vrc7(ref vrc7) => return vrc7.read_u8(addr, &self.p_rom),
+            Mapper::N163(ref mut n163) => return n163.read_u8(addr, &self.p_rom, is_nondestructive),
+            Mapper::Fme7(ref fme7) => return fme7.read_u8(addr, &self.p_rom),
+            _ => {}
+        }
+        if addr < BATTERY_PACKED_RAM_BASE_ADDR {
+            //Nothing on an NROM cart answers at $4020-$5FFF, so this is whatever was left on the bus
+            (addr >> 8) as u8
+        } else if addr < PRG_ROM_SYSTEM_BASE_ADDR {
             debug_assert!(addr >= BATTERY_PACKED_RAM_BASE_ADDR);
 
             let index = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR);
@@ -181,54 +539,313 @@ impl Rom{
 
             let index = usize::from(addr - PRG_ROM_SYSTEM_BASE_ADDR);
         
-            if index < self.p_rom_bytes {
-                arr_read!(self.p_rom, index)
-            } else {
-                arr_read!(self.p_rom, index - self.p_rom_bytes)
-            }
+            //16k carts show up twice
+            arr_read!(self.p_rom, index % self.p_rom.len())
         }
     }
     //Same as above for write
     pub fn write_u8(&mut self, addr: u16, data: u8, _is_nondestructive: bool) {
-        if addr < PRG_ROM_SYSTEM_BASE_ADDR {
-            debug_assert!(addr >= BATTERY_PACKED_RAM_BASE_ADDR);
-
+        match self.mapper {
+            Mapper::Nsf(ref mut banks) => {
+                banks.write_u8(addr, data, &self.p_rom, &mut self.srambytes);
+                return;
+            }
+            //The RAM adapter switches mirroring itself
+            Mapper::Fds(ref mut fds) => {
+                fds.write_u8(addr, data);
+                self.mirror_table = fds.mirror_table;
+                return;
+            }
+            Mapper::Mmc5(ref mut mmc5) => {
+                mmc5.write_u8(addr, data);
+                return;
+            }
+            Mapper::Vrc(ref mut vrc) => {
+                vrc.write_u8(addr, data);
+                return;
+            }
+            Mapper::Vrc6(ref mut vrc6) => {
+                vrc6.write_u8(addr, data);
+                return;
+            }
+            Mapper::Vrc7(ref mut vrc7) => {
+                vrc7.write_u8(addr, data);
+                return;
+            }
+            Mapper::N163(ref mut n163) => {
+                n163.write_u8(addr, data);
+                return;
+            }
+            Mapper::Fme7(ref mut fme7) => {
+                fme7.write_u8(addr, data);
+                return;
+            }
+            _ => {}
+        }
+        if (BATTERY_PACKED_RAM_BASE_ADDR..PRG_ROM_SYSTEM_BASE_ADDR).contains(&addr) {
             let index = usize::from(addr - BATTERY_PACKED_RAM_BASE_ADDR);
             arr_write!(self.srambytes, index, data)
-        } else {
-            debug_assert!(addr >= PRG_ROM_SYSTEM_BASE_ADDR);
-
-            let index = usize::from(addr - PRG_ROM_SYSTEM_BASE_ADDR);
-
-            if index < self.p_rom_bytes {
-                arr_write!(self.p_rom, index, data);
-            } else {
-                arr_write!(self.p_rom, index - self.p_rom_bytes, data);
-            }
+        }
+        //NROM has no registers, writes to ROM go nowhere
+    }
+    //Mapper timers and the like, run alongside the CPU
+    pub fn step(&mut self, cpu_cyc: usize) {
+        match self.mapper {
+            Mapper::Fds(ref mut fds) => fds.step(cpu_cyc),
+            Mapper::Vrc(ref mut vrc) => vrc.step(cpu_cyc),
+            Mapper::Vrc6(ref mut vrc6) => vrc6.step(cpu_cyc),
+            Mapper::Vrc7(ref mut vrc7) => vrc7.step(cpu_cyc),
+            Mapper::N163(ref mut n163) => n163.step(cpu_cyc),
+            Mapper::Fme7(ref mut fme7) => fme7.step(cpu_cyc),
+            _ => {}
+        }
+    }
+    //The mapper's side of the IRQ line
+    pub fn is_irq(&self) -> bool {
+        match &self.mapper {
+            Mapper::Fds(fds) => fds.is_irq(),
+            Mapper::Mmc5(mmc5) => mmc5.is_irq(),
+            Mapper::Vrc(vrc) => vrc.is_irq(),
+            Mapper::Vrc6(vrc6) => vrc6.is_irq(),
+            Mapper::Vrc7(vrc7) => vrc7.is_irq(),
+            Mapper::N163(n163) => n163.is_irq(),
+            Mapper::Fme7(fme7) => fme7.is_irq(),
+            _ => false,
+        }
+    }
+    //Mappers that keep an eye on the PPU. The CPU writing PPUCTRL/PPUMASK, index is the register
+    pub fn snoop_ppu_write(&mut self, index: usize, data: u8) {
+        if let Mapper::Mmc5(ref mut mmc5) = self.mapper {
+            mmc5.snoop_ppu_write(index, data);
+        }
+    }
+    //The PPU starting on a line
+    pub fn start_scanline(&mut self, line: u16) {
+        if let Mapper::Mmc5(ref mut mmc5) = self.mapper {
+            mmc5.start_scanline(line);
+        }
+    }
+    pub fn set_video_fetch(&mut self, fetch: VideoFetch) {
+        if let Mapper::Mmc5(ref mut mmc5) = self.mapper {
+            mmc5.set_video_fetch(fetch);
+        }
+    }
+    //Which memory a nametable address goes to, most boards just pick a mirroring
+    pub fn name_table_source(&self, addr: u16) -> NameTableSource {
+        match &self.mapper {
+            Mapper::Mmc5(mmc5) => mmc5.name_table_source(addr),
+            Mapper::Vrc(vrc) => vrc.name_table_source(),
+            Mapper::Vrc6(vrc6) => vrc6.name_table_source(),
+            Mapper::Vrc7(vrc7) => vrc7.name_table_source(),
+            Mapper::N163(n163) => n163.name_table_source(addr),
+            Mapper::Fme7(fme7) => fme7.name_table_source(),
+            _ => NameTableSource::Mirror(self.mirror_table),
+        }
+    }
+    pub fn read_name_table_u8(&self, addr: u16) -> u8 {
+        match &self.mapper {
+            Mapper::Mmc5(mmc5) => mmc5.read_name_table_u8(addr),
+            Mapper::N163(n163) => n163.read_name_table_u8(addr, &self.c_rom),
+            _ => 0,
+        }
+    }
+    pub fn write_name_table_u8(&mut self, addr: u16, data: u8) {
+        if let Mapper::Mmc5(ref mut mmc5) = self.mapper {
+            mmc5.write_name_table_u8(addr, data);
+        }
+    }
+    //Sound chips on the cartridge, clocked every CPU cycle by the APU and mixed in with its output
+    pub fn clock_expansion_audio(&mut self) {
+        match self.mapper {
+            Mapper::Nsf(ref mut banks) => banks.audio.clock(),
+            Mapper::Fds(ref mut fds) => fds.audio.clock(),
+            Mapper::Mmc5(ref mut mmc5) => mmc5.audio.clock(),
+            Mapper::Vrc6(ref mut vrc6) => vrc6.audio.clock(),
+            Mapper::Vrc7(ref mut vrc7) => vrc7.clock_audio(),
+            Mapper::N163(ref mut n163) => n163.clock_audio(),
+            Mapper::Fme7(ref mut fme7) => fme7.audio.clock(),
+            _ => {}
+        }
+    }
+    pub fn expansion_audio_output(&self) -> f32 {
+        match &self.mapper {
+            Mapper::Nsf(banks) => banks.audio.output(),
+            Mapper::Fds(fds) => fds.audio.output(),
+            Mapper::Mmc5(mmc5) => mmc5.audio.output(),
+            Mapper::Vrc6(vrc6) => vrc6.audio.output(),
+            Mapper::Vrc7(vrc7) => vrc7.audio_output(),
+            Mapper::N163(n163) => n163.audio_output(),
+            Mapper::Fme7(fme7) => fme7.audio.output(),
+            _ => 0.0,
         }
     }
     //Reads and writes to graphics memory
     pub fn read_video_u8(&mut self, addr: u16) -> u8 {
-            let index = usize::from(addr);
-            debug_assert!(index < CHR_ROM_MAX_SIZE);
-            arr_read!(self.c_rom, index)
+            let index = self.chr_index(addr);
+            if self.c_ram.is_empty() {
+                arr_read!(self.c_rom, index % self.c_rom.len())
+            } else {
+                arr_read!(self.c_ram, index % self.c_ram.len())
+            }
         }
        
     pub fn write_video_u8(&mut self, addr: u16, data: u8) {
-            let index = usize::from(addr);
-            debug_assert!(index < CHR_ROM_MAX_SIZE);
-            arr_write!(self.c_rom, index, data);
+            let index = self.chr_index(addr);
+            if !self.c_ram.is_empty() {
+                let ram_index = index % self.c_ram.len();
+                arr_write!(self.c_ram, ram_index, data);
+            } else if self.is_chr_writable() {
+                let rom_index = index % self.c_rom.len();
+                arr_write!(self.c_rom, rom_index, data);
+            }
+        }
+    //Whether writes to CHR ROM stick, either because the user asked or the cart is VROR
+    pub fn is_chr_writable(&self) -> bool {
+        self.is_chr_rom_writable || self.info.is_chr_rom_writable
+    }
+    //Where a PPU address in $0000-$1FFF lands in CHR ROM/RAM once the mapper has banked it
+    fn chr_index(&self, addr: u16) -> usize {
+        match &self.mapper {
+            Mapper::Mmc5(mmc5) => mmc5.chr_index(addr),
+            Mapper::Vrc(vrc) => vrc.chr_index(addr),
+            Mapper::Vrc6(vrc6) => vrc6.chr_index(addr),
+            Mapper::Vrc7(vrc7) => vrc7.chr_index(addr),
+            Mapper::N163(n163) => n163.chr_index(addr),
+            Mapper::Fme7(fme7) => fme7.chr_index(addr),
+            _ => {
+                debug_assert!(usize::from(addr) < CHR_ROM_MAX_SIZE);
+                usize::from(addr)
+            }
         }
+    }
       
     pub fn reset(&mut self) {
             self.mapper = Mapper::Unknown;
             self.mirror_table = MirrorTable::Unknown;
             self.sram = false;
+            self.info = CartridgeInfo::default();
+            self.game = None;
+            self.nsf = None;
             self.p_rom_bytes = 0;
             self.c_rom_bytes = 0;
-            self.p_rom = [0; PRG_ROM_MAX_SIZE];
-            self.c_rom = [0; CHR_ROM_MAX_SIZE];
-            self.srambytes = [0; BATTERY_PACKED_RAM_MAX_SIZE];
+            self.p_rom = vec![0; PRG_ROM_MAX_SIZE];
+            self.c_rom = vec![0; CHR_ROM_MAX_SIZE];
+            self.c_ram = Vec::new();
+            self.srambytes = vec![0; BATTERY_PACKED_RAM_MAX_SIZE];
+        }
+
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    //32 KiB NROM with 8 KiB of CHR ROM. Turns NMIs on and spins, the NMI handler counts frames in $10-$11
+    pub fn nrom() -> Vec<u8> {
+        let mut binary = vec![0x4e, 0x45, 0x53, 0x1a, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xea; 0x8000];
+        //LDA #$80, STA $2000, JMP $8005
+        prg[..8].copy_from_slice(&[0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80]);
+        //INC $10, BNE +2, INC $11, RTI
+        prg[0x100..0x107].copy_from_slice(&[0xe6, 0x10, 0xd0, 0x02, 0xe6, 0x11, 0x40]);
+        prg[0x7ffa..].copy_from_slice(&[0x00, 0x81, 0x00, 0x80, 0x00, 0x80]);
+        binary.extend(prg);
+        binary.extend(vec![0; 0x2000]);
+        binary
+    }
+
+    //An iNES file for any mapper. Every byte of each 8k PRG bank and 1k CHR bank is that bank's number, so a read
+    //says which bank is mapped in
+    pub fn ines(mapper: u16, prg_16k_banks: u8, chr_8k_banks: u8) -> Vec<u8> {
+        let flags6 = (mapper as u8) << 4;
+        let flags7 = (mapper as u8) & 0xf0;
+        let mut binary = vec![0x4e, 0x45, 0x53, 0x1a, prg_16k_banks, chr_8k_banks, flags6, flags7, 0, 0, 0, 0, 0, 0, 0, 0];
+        for bank in 0..usize::from(prg_16k_banks) * 2 {
+            binary.extend(vec![bank as u8; 0x2000]);
         }
+        for bank in 0..usize::from(chr_8k_banks) * 8 {
+            binary.extend(vec![bank as u8; 0x0400]);
+        }
+        binary
+    }
+
+    #[test]
+    fn loads_nrom() {
+        let mut rom = Rom::default();
+        let info = rom.load_bin(&ines(0, 2, 1)).unwrap();
+        assert_eq!(info.mapper, 0);
+        assert_eq!(rom.read_u8(0x8000, true), 0);
+        assert_eq!(rom.read_u8(0xe000, true), 3);
+        assert_eq!(rom.read_video_u8(0x1c00), 7);
+        //No CHR ROM gets the default CHR-RAM
+        rom.load_bin(&ines(0, 1, 0)).unwrap();
+        assert_eq!(rom.c_ram.len(), CHR_RAM_DEFAULT_SIZE);
+        rom.write_video_u8(0x0123, 0x45);
+        assert_eq!(rom.read_video_u8(0x0123), 0x45);
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_rom() {
+        let mut rom = Rom::default();
+        let mut binary = ines(0, 2, 1);
+        binary[0] = b'M';
+        assert_eq!(rom.load_bin(&binary).err(), Some(RomError::BadMagic));
+        assert_eq!(
+            rom.load_bin(&binary[..8]).err(),
+            Some(RomError::Truncated {
+                expected: INES_HEADER_SIZE,
+                actual: 8
+            })
+        );
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn rejects_truncated() {
+        let binary = ines(0, 2, 1);
+        assert_eq!(
+            Rom::default().load_bin(&binary[..binary.len() - 1]).err(),
+            Some(RomError::Truncated {
+                expected: binary.len(),
+                actual: binary.len() - 1
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_oversized() {
+        let mut binary = ines(0, 2, 1);
+        binary.resize(ROM_FILE_MAX_SIZE + 1, 0);
+        assert_eq!(
+            Rom::default().load_bin(&binary).err(),
+            Some(RomError::TooLarge(ROM_FILE_MAX_SIZE + 1))
+        );
+        assert_eq!(
+            Rom::default().load_bin(&ines(0, 4, 1)).err(),
+            Some(RomError::SizeMismatch {
+                name: "PRG ROM",
+                bytes: 0x10000,
+                max_bytes: PRG_ROM_MAX_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_hardware_we_dont_have() {
+        assert_eq!(Rom::default().load_bin(&ines(4, 2, 1)).err(), Some(RomError::UnsupportedMapper(4)));
+        let mut binary = ines(0, 2, 1);
+        binary[7] = 0x01;
+        assert_eq!(
+            Rom::default().load_bin(&binary).err(),
+            Some(RomError::UnsupportedConsoleType(ConsoleType::VsSystem))
+        );
+    }
+
+    #[test]
+    fn failed_load_keeps_the_cartridge() {
+        let mut rom = Rom::default();
+        rom.load_bin(&ines(0, 2, 1)).unwrap();
+        assert!(rom.load_bin(&ines(4, 2, 1)).is_err());
+        assert_eq!(rom.info.mapper, 0);
+        assert_eq!(rom.read_u8(0xe000, true), 3);
+    }
+}
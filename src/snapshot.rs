@@ -0,0 +1,121 @@
+/* Savestates */
+//Everything the emulation needs to carry on from a moment in time. Each part of the console is Clone, so a snapshot
+//is a copy of the CPU, the bus with everything hanging off it, and the PPU.
+use super::cpu::Cpu;
+use super::fds::FdsImage;
+use super::ppu::Ppu;
+use super::system::System;
+
+#[derive(Clone)]
+pub struct Snapshot {
+    cpu: Cpu,
+    system: System,
+    ppu: Ppu,
+}
+
+//The parts of the cartridge that never change while a game runs
+struct RomData {
+    p_rom: Vec<u8>,
+    //None when CHR ROM is writable, then it's part of the state
+    c_rom: Option<Vec<u8>>,
+    fds_bios: Vec<u8>,
+    fds_image: Option<FdsImage>,
+}
+
+impl Snapshot {
+    pub fn capture(cpu: &Cpu, system: &System, ppu: &Ppu) -> Self {
+        Self {
+            cpu: cpu.clone(),
+            system: system.clone(),
+            ppu: ppu.clone(),
+        }
+    }
+    pub fn restore(&self, cpu: &mut Cpu, system: &mut System, ppu: &mut Ppu) {
+        cpu.clone_from(&self.cpu);
+        system.clone_from(&self.system);
+        ppu.clone_from(&self.ppu);
+    }
+    //ROM never changes while a game runs, so snapshots kept in bulk leave it out and borrow it back from the console
+    //when they're restored. CHR ROM that's been made writable stays in, and so does an FDS disk's contents. It's only
+    //the image as it was loaded that's left out
+    pub fn capture_without_rom(cpu: &Cpu, system: &mut System, ppu: &Ppu) -> Self {
+        let rom = Self::take_rom(system);
+        let snapshot = Self::capture(cpu, system, ppu);
+        Self::put_rom(system, rom);
+        snapshot
+    }
+    pub fn restore_without_rom(&self, cpu: &mut Cpu, system: &mut System, ppu: &mut Ppu) {
+        let rom = Self::take_rom(system);
+        self.restore(cpu, system, ppu);
+        Self::put_rom(system, rom);
+    }
+    //Makes this a copy of the console as it is now, leaving the ROM out
+    pub fn copy_from(&mut self, cpu: &Cpu, system: &mut System, ppu: &Ppu) {
+        let rom = Self::take_rom(system);
+        self.cpu.clone_from(cpu);
+        self.system.clone_from(system);
+        self.ppu.clone_from(ppu);
+        Self::put_rom(system, rom);
+    }
+    //Trades places with the console, so a copy can be run and the original put back without copying it again. The
+    //ROM moves across to whichever one is in the console
+    pub fn swap_with(&mut self, cpu: &mut Cpu, system: &mut System, ppu: &mut Ppu) {
+        let rom = Self::take_rom(system);
+        std::mem::swap(cpu, &mut self.cpu);
+        std::mem::swap(system, &mut self.system);
+        std::mem::swap(ppu, &mut self.ppu);
+        Self::put_rom(system, rom);
+    }
+    fn take_rom(system: &mut System) -> RomData {
+        let rom = &mut system.rom;
+        RomData {
+            p_rom: std::mem::take(&mut rom.p_rom),
+            c_rom: if rom.is_chr_writable() { None } else { Some(std::mem::take(&mut rom.c_rom)) },
+            fds_bios: std::mem::take(&mut rom.fds_bios),
+            fds_image: rom.fds_mut().map(|fds| fds.take_image()),
+        }
+    }
+    fn put_rom(system: &mut System, rom: RomData) {
+        system.rom.p_rom = rom.p_rom;
+        if let Some(c_rom) = rom.c_rom {
+            system.rom.c_rom = c_rom;
+        }
+        system.rom.fds_bios = rom.fds_bios;
+        if let (Some(fds), Some(image)) = (system.rom.fds_mut(), rom.fds_image) {
+            fds.put_image(image);
+        }
+    }
+    //Memory the snapshot takes up, what's on the heap included
+    pub fn bytes(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.system.wram.capacity()
+            + self.system.video.nametables.capacity()
+            + self.ppu.oam.capacity()
+            + self.system.rom.heap_bytes()
+            + self.system.apu.heap_bytes()
+    }
+    //Work RAM, nametables, OAM and everything the cartridge can write to. Most of a snapshot by size, and mostly
+    //the same from one frame to the next
+    fn ram_mut(&mut self) -> Vec<&mut Vec<u8>> {
+        let mut ram = vec![&mut self.system.wram, &mut self.system.video.nametables, &mut self.ppu.oam];
+        ram.extend(self.system.rom.ram_mut());
+        ram
+    }
+    //Moves all the RAM out back to back, along with how long each piece was
+    pub fn take_ram(&mut self) -> (Vec<u8>, Vec<usize>) {
+        let mut data = Vec::new();
+        let mut sizes = Vec::new();
+        for ram in self.ram_mut() {
+            sizes.push(ram.len());
+            data.extend_from_slice(&std::mem::take(ram));
+        }
+        (data, sizes)
+    }
+    pub fn put_ram(&mut self, data: &[u8], sizes: &[usize]) {
+        let mut offset = 0;
+        for (ram, &size) in self.ram_mut().into_iter().zip(sizes) {
+            *ram = data[offset..offset + size].to_vec();
+            offset += size;
+        }
+    }
+}
@@ -0,0 +1,96 @@
+/* SNES mouse on a NES port */
+//https://wiki.nesdev.com/w/index.php/Super_NES_Mouse
+//Homebrew can use a Super NES mouse through a port adapter. Strobing latches a 32 bit report that shifts out on D0
+//MSB first: a byte of zeros, the buttons, sensitivity and a signature, then Y and X movement since the last strobe.
+//Movement is sign and magnitude, the sign bit set for up and left.
+
+const SNES_MOUSE_REPORT_BITS: u8 = 32;
+const SNES_MOUSE_SIGNATURE: u32 = 0x01;
+const SNES_MOUSE_MAX_MOTION: i32 = 0x7f;
+//Low, medium and high, it steps through them when clocked with the strobe held
+const SNES_MOUSE_NUM_OF_SENSITIVITIES: u8 = 3;
+
+#[derive(Clone, Debug, Default)]
+pub struct SnesMouse {
+    dx: i32,
+    dy: i32,
+    //Movement that's waiting for the next frame to start, and what the last one started with
+    pending_motion: (i32, i32),
+    frame_motion: (i32, i32),
+    is_left: bool,
+    is_right: bool,
+    sensitivity: u8,
+    report: u32,
+    read_index: u8,
+    strobe_enable: bool,
+}
+
+impl SnesMouse {
+    pub fn reset(&mut self) {
+        self.dx = 0;
+        self.dy = 0;
+        self.pending_motion = (0, 0);
+        self.frame_motion = (0, 0);
+        self.sensitivity = 0;
+        self.report = 0;
+        self.read_index = 0;
+        self.strobe_enable = false;
+    }
+    //Movement is held back until the next frame starts, so a movie can record it a frame at a time, then adds up
+    //until the game next strobes
+    pub fn move_by(&mut self, dx: i32, dy: i32) {
+        self.pending_motion.0 = self.pending_motion.0.saturating_add(dx);
+        self.pending_motion.1 = self.pending_motion.1.saturating_add(dy);
+    }
+    pub fn start_frame(&mut self) {
+        self.frame_motion = std::mem::take(&mut self.pending_motion);
+        self.dx = self.dx.saturating_add(self.frame_motion.0);
+        self.dy = self.dy.saturating_add(self.frame_motion.1);
+    }
+    pub fn frame_motion(&self) -> (i32, i32) {
+        self.frame_motion
+    }
+    //Swaps the movement this frame started with for a recorded one
+    pub fn set_frame_motion(&mut self, dx: i32, dy: i32) {
+        self.dx = self.dx.saturating_add(dx.saturating_sub(self.frame_motion.0));
+        self.dy = self.dy.saturating_add(dy.saturating_sub(self.frame_motion.1));
+        self.frame_motion = (dx, dy);
+    }
+    pub fn set_buttons(&mut self, is_left: bool, is_right: bool) {
+        self.is_left = is_left;
+        self.is_right = is_right;
+    }
+    pub fn buttons(&self) -> (bool, bool) {
+        (self.is_left, self.is_right)
+    }
+    fn motion_byte(delta: i32) -> u32 {
+        let magnitude = delta.unsigned_abs().min(SNES_MOUSE_MAX_MOTION as u32);
+        let sign = if delta < 0 { 0x80 } else { 0x00 };
+        sign | magnitude
+    }
+    pub fn write_strobe(&mut self, is_enable: bool) {
+        self.strobe_enable = is_enable;
+        if is_enable {
+            let status = (u32::from(self.is_right) << 7)
+                | (u32::from(self.is_left) << 6)
+                | (u32::from(self.sensitivity) << 4)
+                | SNES_MOUSE_SIGNATURE;
+            self.report = (status << 16) | (Self::motion_byte(self.dy) << 8) | Self::motion_byte(self.dx);
+            self.read_index = 0;
+            self.dx = 0;
+            self.dy = 0;
+        }
+    }
+    pub fn read_out(&mut self) -> u8 {
+        if self.strobe_enable {
+            self.sensitivity = (self.sensitivity + 1) % SNES_MOUSE_NUM_OF_SENSITIVITIES;
+            return 0;
+        }
+        if self.read_index >= SNES_MOUSE_REPORT_BITS {
+            return 1;
+        }
+        let data = (self.report >> (SNES_MOUSE_REPORT_BITS - 1 - self.read_index)) as u8 & 0x01;
+        self.read_index += 1;
+        data
+    }
+}
@@ -64,22 +64,38 @@ pub const PPU_DATA_OFFSET: usize = 0x07;
 pub const APU_IO_OAM_DMA_OFFSET: usize = 0x14;
 
 use crate::video::VideoSystem;
+use crate::region::Region;
+use crate::apu::*;
 
 use super::rom::*;
 use super::pad::*;
+use super::four_player::{FourPlayerAdapter, FourPlayerMode};
+use super::input::{ExpansionDevice, InputDevice, InputDeviceKind, EXPANSION_PORT};
+use super::framebuffer::FrameBuffer;
 
 //This is how we're doing our bus, a big struct that holds all relevant info
 #[derive(Clone, Debug)]
 pub struct System {
     //Memory for each component
-    pub wram : [u8; WRAM_SIZE],
+    pub wram : Vec<u8>,
     pub ppu_reg: [u8; PPU_REG_SIZE],
     pub io_reg: [u8; APU_IO_REG_SIZE],
     pub rom : Rom,
     pub video: VideoSystem,
-    //Pads
-    pub pad1: Pad,
-    pub pad2: Pad,
+    pub apu: Apu,
+    //The TV system we're running as, never Auto once the emulator has been reset
+    pub region: Region,
+    //Whatever is plugged into the two front ports, controllers unless a game wants something else
+    pub ports: [InputDevice; 2],
+    //The Famicom expansion port, players 3 and 4 by default. They answer on bit 1 of $4016/$4017
+    //https://wiki.nesdev.com/w/index.php/Standard_controller#Famicom_expansion_port
+    pub expansion: ExpansionDevice,
+    //Four Score or Hori adapter, when one is plugged in it reads out all four pads instead
+    pub four_player: FourPlayerAdapter,
+    //Autofire rate shared by every controller
+    pub turbo: TurboClock,
+    //Set as vblank starts, the emulator takes it to record or play back a movie frame
+    pub is_input_frame_started: bool,
     //Read/Write flags for each component
     
     pub write_oam_data: bool,
@@ -100,13 +116,18 @@ pub struct System {
 impl System{
    pub fn default() -> Self {
         Self{
-            wram:[0; WRAM_SIZE],
+            wram: vec![0; WRAM_SIZE],
             ppu_reg:[0;PPU_REG_SIZE],
             io_reg:[0;APU_IO_REG_SIZE],
             rom: Rom::default(),
-            pad1: Pad::default(),
-            pad2: Pad::default(),
+            ports: [InputDevice::new(InputDeviceKind::Pad), InputDevice::new(InputDeviceKind::Pad)],
+            expansion: ExpansionDevice::new(InputDeviceKind::Pad),
+            four_player: FourPlayerAdapter::default(),
+            turbo: TurboClock::default(),
+            is_input_frame_started: false,
             video: VideoSystem::default(),
+            apu: Apu::default(),
+            region: Region::Ntsc,
             write_oam_data: false,
             write_ppu_scroll:false,
             write_ppu_addr:false,
@@ -126,9 +147,15 @@ impl System {
 
     pub fn reset(&mut self){
         self.video.reset();
-        self.pad1.reset();
-        self.pad2.reset();
-        self.wram = [0; WRAM_SIZE];
+        self.apu.reset();
+        for device in self.ports.iter_mut() {
+            device.reset();
+        }
+        self.expansion.reset();
+        self.four_player.reset();
+        self.turbo.reset();
+        self.is_input_frame_started = false;
+        self.wram = vec![0; WRAM_SIZE];
         self.ppu_reg = [0; PPU_REG_SIZE];
         self.io_reg = [0; APU_IO_REG_SIZE];
 
@@ -194,9 +221,8 @@ impl System {
             let index = usize::from(addr - APU_IO_REG_BASE_ADDR);
             if !is_nondestructive {
                 match index {
-                    
-                    0x16 => self.pad1.read_out(), // pad1
-                    0x17 => self.pad2.read_out(), // pad2
+                    APU_STATUS_OFFSET => self.apu.read_status(),
+                    0x16 | 0x17 => self.read_controller_port(index - 0x16),
                     _ => arr_read!(self.io_reg, index),
                 }
             } else {
@@ -268,15 +294,25 @@ impl System {
                     arr_write!(self.ppu_reg, index, data);
                 }
             };
+            //Some mappers keep track of the PPU's settings
+            if !is_nondestructive {
+                self.rom.snoop_ppu_write(index, data);
+            }
         } else if addr < ROM_BASE_ADDR {
             let index = usize::from(addr - APU_IO_REG_BASE_ADDR);
             if !is_nondestructive {
                 match index {
                 
                     0x14 => self.write_oam_dma = true, 
-                    0x16 => self.pad1.write_strobe((data & 0x01) == 0x01), 
-                    0x17 => self.pad2.write_strobe((data & 0x01) == 0x01), 
-                    _ => {}
+                    //OUT0 goes to every controller port, $4017 writes are only for the APU's frame counter
+                    0x16 => {
+                        for device in self.ports.iter_mut() {
+                            device.write_strobe(data);
+                        }
+                        self.expansion.write_strobe(data);
+                        self.four_player.write_strobe((data & 0x01) == 0x01);
+                    }
+                    _ => self.apu.write_register(index, data),
                 }
             }
             arr_write!(self.io_reg, index, data);
@@ -286,6 +322,168 @@ impl System {
     }
 }
 
+//APU
+impl System {
+    //The APU runs alongside the CPU, the DMC reaches into the cartridge for its samples
+    pub fn step_apu(&mut self, cpu_cyc: usize) {
+        self.apu.step(cpu_cyc, self.region, &mut self.rom);
+    }
+    //Frame counter or DMC interrupt, held until the game acknowledges it
+    pub fn read_apu_is_irq(&self) -> bool {
+        self.apu.is_irq()
+    }
+}
+
+//Input
+impl System {
+    //Plug a device into a port, 0 and 1 are the front ports and EXPANSION_PORT the Famicom's. Auto should be resolved first
+    pub fn set_input_device(&mut self, port: usize, kind: InputDeviceKind) {
+        match port {
+            0 | 1 => self.ports[port] = InputDevice::new(kind),
+            EXPANSION_PORT => self.expansion = ExpansionDevice::new(kind),
+            _ => {}
+        }
+    }
+    //$4016/$4017, D0 is the front port and D1 the Famicom expansion port
+    fn read_controller_port(&mut self, port: usize) -> u8 {
+        //An adapter with nothing in a socket reads as a controller with no buttons held
+        let empty = Pad::default();
+        let (expansion3, expansion4) = self.expansion.pads().unwrap_or((&empty, &empty));
+        let expansion = if port == 0 { expansion3 } else { expansion4 };
+        match self.four_player.mode {
+            //The Four Score takes over both front ports
+            FourPlayerMode::FourScore => {
+                let front = self.ports[port].pad().unwrap_or(&empty);
+                self.four_player.read_out(port, front, expansion)
+            }
+            //The Famicom's own controllers are still there on D0
+            FourPlayerMode::Hori => {
+                let front = self.ports[port].pad().unwrap_or(&empty).clone();
+                let adapter = self.four_player.read_out(port, &front, expansion);
+                self.ports[port].read_out() | (adapter << 1)
+            }
+            _ => self.ports[port].read_out() | self.expansion.read_out(port),
+        }
+    }
+    //Player 1 and 2's controllers in the front ports, 3 and 4 on the Famicom expansion port or a four player adapter
+    pub fn pad_mut(&mut self, player: usize) -> Option<&mut Pad> {
+        match (player, &mut self.ports, &mut self.expansion) {
+            (0, [InputDevice::Pad(pad), _], _) => Some(pad),
+            (1, [_, InputDevice::Pad(pad)], _) => Some(pad),
+            (2, _, ExpansionDevice::Pads(pad3, _)) => Some(pad3),
+            (3, _, ExpansionDevice::Pads(_, pad4)) => Some(pad4),
+            _ => None,
+        }
+    }
+    //Every button for one player at once, nothing happens if they don't have a controller plugged in
+    pub fn set_buttons(&mut self, player: usize, mask: u8) {
+        if let Some(pad) = self.pad_mut(player) {
+            pad.set_buttons(mask);
+        }
+    }
+    //Same again for the buttons being held with turbo
+    pub fn set_turbo_buttons(&mut self, player: usize, mask: u8) {
+        if let Some(pad) = self.pad_mut(player) {
+            pad.set_turbo_buttons(mask);
+        }
+    }
+    //Called once a frame as vblank starts, turbo buttons flip between pressed and released and mouse movement
+    //comes in on frame boundaries
+    pub fn start_input_frame(&mut self) {
+        let is_on = self.turbo.clock();
+        for player in 0..NUM_OF_PLAYERS {
+            if let Some(pad) = self.pad_mut(player) {
+                pad.set_turbo_phase(is_on);
+            }
+        }
+        for device in self.ports.iter_mut() {
+            if let InputDevice::SnesMouse(mouse) = device {
+                mouse.start_frame();
+            }
+        }
+        self.is_input_frame_started = true;
+    }
+    pub fn take_input_frame(&mut self) -> bool {
+        std::mem::take(&mut self.is_input_frame_started)
+    }
+    //Light guns look at each row of the picture once the PPU has drawn it
+    pub fn sense_light(&mut self, line: u16, fb: &FrameBuffer) {
+        for device in self.ports.iter_mut() {
+            if let InputDevice::Zapper(zapper) = device {
+                zapper.start_scanline(line, fb);
+            }
+        }
+        if let ExpansionDevice::Zapper(zapper) = &mut self.expansion {
+            zapper.start_scanline(line, fb);
+        }
+    }
+    pub fn set_zapper(&mut self, x: i32, y: i32, is_trigger: bool) {
+        let expansion = match &mut self.expansion {
+            ExpansionDevice::Zapper(zapper) => Some(zapper),
+            _ => None,
+        };
+        let ports = self.ports.iter_mut().filter_map(|device| match device {
+            InputDevice::Zapper(zapper) => Some(zapper),
+            _ => None,
+        });
+        for zapper in ports.chain(expansion) {
+            zapper.set_aim(x, y);
+            zapper.set_trigger(is_trigger);
+        }
+    }
+    //Knob position from 0 to 255
+    pub fn set_arkanoid_paddle(&mut self, position: u8, is_button: bool) {
+        let expansion = match &mut self.expansion {
+            ExpansionDevice::ArkanoidPaddle(paddle) => Some(paddle),
+            _ => None,
+        };
+        let ports = self.ports.iter_mut().filter_map(|device| match device {
+            InputDevice::ArkanoidPaddle(paddle) => Some(paddle),
+            _ => None,
+        });
+        for paddle in ports.chain(expansion) {
+            paddle.set_position(position);
+            paddle.set_button(is_button);
+        }
+    }
+    //Bit n is button n + 1 on the mat
+    pub fn set_power_pad(&mut self, mask: u16) {
+        let expansion = match &mut self.expansion {
+            ExpansionDevice::FamilyTrainer(mat) => Some(mat),
+            _ => None,
+        };
+        let ports = self.ports.iter_mut().filter_map(|device| match device {
+            InputDevice::PowerPad(mat) => Some(mat),
+            _ => None,
+        });
+        for mat in ports.chain(expansion) {
+            mat.set_buttons(mask);
+        }
+    }
+    pub fn move_snes_mouse(&mut self, dx: i32, dy: i32, is_left: bool, is_right: bool) {
+        for device in self.ports.iter_mut() {
+            if let InputDevice::SnesMouse(mouse) = device {
+                mouse.move_by(dx, dy);
+                mouse.set_buttons(is_left, is_right);
+            }
+        }
+    }
+}
+
+//Mapper
+impl System {
+    pub fn step_mapper(&mut self, cpu_cyc: usize) {
+        self.rom.step(cpu_cyc);
+    }
+    pub fn read_mapper_is_irq(&self) -> bool {
+        self.rom.is_irq()
+    }
+    //Everything that can pull the CPU's IRQ line low, it stays low until every source is acknowledged
+    pub fn read_is_irq(&self) -> bool {
+        self.read_apu_is_irq() || self.read_mapper_is_irq()
+    }
+}
+
 //PPU registers
 //This is all explained in the ppu.r file. It's mostly a bunch of internally consistent opaque rules which
 //Are largely uninteresting beyond how they are described in that source file.
@@ -356,6 +554,15 @@ impl System {
     pub fn read_is_monochrome(&self) -> bool {
         (self.ppu_reg[PPU_MASK_OFFSET] & 0x01u8) == 0x01u8
     }
+    //Red, green and blue emphasis as bits 0-2. PAL and Dendy PPUs have the red and green bits the other way round,
+    //they're put back in NTSC order here so the palettes don't have to care
+    pub fn read_ppu_emphasis(&self) -> u8 {
+        let emphasis = self.ppu_reg[PPU_MASK_OFFSET] >> 5;
+        match self.region {
+            Region::Pal | Region::Dendy => (emphasis & 0x04) | ((emphasis & 0x01) << 1) | ((emphasis & 0x02) >> 1),
+            _ => emphasis,
+        }
+    }
 
     pub fn read_ppu_is_vblank(&self) -> bool {
         (self.ppu_reg[PPU_STATUS_OFFSET] & 0x80u8) == 0x80u8
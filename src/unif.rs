@@ -0,0 +1,394 @@
+/* UNIF cartridge parsing */
+//https://wiki.nesdev.com/w/index.php/UNIF
+//UNIF was the alternative to INES from before NES 2.0 existed. Instead of a mapper number it names the actual board,
+//and everything else comes in tagged chunks. A lot of unlicensed carts and multicarts were only ever dumped this way.
+//We turn it into the same CartridgeInfo the INES header gives, plus the ROM contents glued back together.
+use std::fmt;
+
+use super::header::*;
+use super::region::Region;
+use super::rom::MirrorTable;
+
+pub const UNIF_HEADER_SIZE: usize = 32;
+const UNIF_CHUNK_HEADER_SIZE: usize = 8;
+//PRG0-PRGF and CHR0-CHRF
+const UNIF_NUM_OF_ROM_CHUNKS: usize = 16;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum UnifError {
+    //Chunk length runs off the end of the file
+    Truncated(String),
+    //No MAPR chunk, so no way of telling what the board is
+    NoBoard,
+    UnknownBoard(String),
+    NoPrgRom,
+    //PCKn/CCKn didn't match the ROM chunk it covers
+    ChecksumMismatch(String),
+}
+
+impl fmt::Display for UnifError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnifError::Truncated(chunk) => write!(f, "UNIF chunk {} runs past the end of the file", chunk),
+            UnifError::NoBoard => write!(f, "UNIF file has no MAPR chunk naming the board"),
+            UnifError::UnknownBoard(board) => write!(f, "UNIF board {} is not known", board),
+            UnifError::NoPrgRom => write!(f, "UNIF file has no PRG ROM"),
+            UnifError::ChecksumMismatch(chunk) => write!(f, "UNIF chunk {} failed its CRC check", chunk),
+        }
+    }
+}
+
+//Board names and the mapper that implements them, NES 2.0 numbering for everything past 255
+//https://wiki.nesdev.com/w/index.php/UNIF_to_NES_2.0_Mapper_Cross_Reference
+//Names are listed without the NES-/HVC-/UNL-/BMC-/BTL- prefix, see board_name
+const UNIF_BOARDS: &[(&str, u16, u8)] = &[
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("RROM", 0, 0),
+    ("RROM-128", 0, 0),
+    ("SAROM", 1, 0),
+    ("SBROM", 1, 0),
+    ("SCROM", 1, 0),
+    ("SEROM", 1, 5),
+    ("SGROM", 1, 0),
+    ("SKROM", 1, 0),
+    ("SL1ROM", 1, 0),
+    ("SLROM", 1, 0),
+    ("SNROM", 1, 0),
+    ("SOROM", 1, 0),
+    ("SUROM", 1, 0),
+    ("SXROM", 1, 0),
+    ("UNROM", 2, 0),
+    ("UOROM", 2, 0),
+    ("CNROM", 3, 0),
+    ("TBROM", 4, 0),
+    ("TEROM", 4, 0),
+    ("TFROM", 4, 0),
+    ("TGROM", 4, 0),
+    ("TKROM", 4, 0),
+    ("TLROM", 4, 0),
+    ("TR1ROM", 4, 0),
+    ("TSROM", 4, 0),
+    ("TVROM", 4, 0),
+    ("B4", 4, 0),
+    ("EKROM", 5, 0),
+    ("ELROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("AMROM", 7, 0),
+    ("ANROM", 7, 0),
+    ("AOROM", 7, 0),
+    ("PNROM", 9, 0),
+    ("FJROM", 10, 0),
+    ("FKROM", 10, 0),
+    ("CPROM", 13, 0),
+    ("MHROM", 66, 0),
+    ("GNROM", 66, 0),
+    ("BTR", 69, 0),
+    ("JLROM", 69, 0),
+    ("JSROM", 69, 0),
+    ("TLSROM", 118, 0),
+    ("TKSROM", 118, 0),
+    ("TQROM", 119, 0),
+    ("Sachen-8259A", 141, 0),
+    ("Sachen-8259B", 138, 0),
+    ("Sachen-8259C", 139, 0),
+    ("Sachen-8259D", 137, 0),
+    ("SA-72007", 145, 0),
+    ("SA-016-1M", 146, 0),
+    ("TC-U01-1.5M", 147, 0),
+    ("SA-0037", 148, 0),
+    ("SA-0036", 149, 0),
+    ("Sachen-74LS374N", 150, 0),
+    ("Super24in1SC03", 176, 0),
+    ("H2288", 123, 0),
+    ("42in1ResetSwitch", 233, 0),
+    ("D1038", 59, 0),
+    ("Supervision16in1", 53, 0),
+    ("MARIO1-MALEE2", 55, 0),
+    ("TEK90", 90, 0),
+    ("CC-21", 27, 0),
+    ("KOF97", 263, 0),
+    ("64in1NoRepeat", 314, 0),
+    ("70in1", 236, 0),
+    ("70in1B", 236, 0),
+    ("GS-2004", 283, 0),
+    ("GS-2013", 283, 0),
+    ("T-262", 265, 0),
+    ("8157", 301, 0),
+    ("810544-C-A1", 261, 0),
+    ("NTD-03", 290, 0),
+    ("AX5705", 530, 0),
+    ("SMB2J", 304, 0),
+    ("UNROM-512-8", 30, 0),
+    ("UNROM-512-16", 30, 0),
+    ("UNROM-512-32", 30, 0),
+];
+
+//Dumpers weren't consistent about prefixes, "NES-NROM-256" and "NROM-256" are the same board
+fn board_name(mapr: &str) -> &str {
+    ["NES-", "HVC-", "UNL-", "BMC-", "BTL-", "IREM-", "KONAMI-", "TENGEN-"]
+        .iter()
+        .find_map(|prefix| mapr.strip_prefix(prefix))
+        .unwrap_or(mapr)
+}
+
+fn lookup_board(mapr: &str) -> Option<(u16, u8)> {
+    let name = board_name(mapr);
+    UNIF_BOARDS
+        .iter()
+        .find(|(board, _, _)| board.eq_ignore_ascii_case(name))
+        .map(|(_, mapper, submapper)| (*mapper, *submapper))
+}
+
+//Everything we need out of a UNIF file, the ROM chunks concatenated in PRG0, PRG1... order
+#[derive(Clone, Debug)]
+pub struct UnifCartridge {
+    pub info: CartridgeInfo,
+    pub board: String,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+}
+
+pub fn is_unif(binary: &[u8]) -> bool {
+    binary.starts_with(b"UNIF")
+}
+
+//Strings in UNIF chunks are zero terminated, but not always
+fn chunk_string(data: &[u8]) -> String {
+    let len = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).trim().to_string()
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from(data[offset])
+        | (u32::from(data[offset + 1]) << 8)
+        | (u32::from(data[offset + 2]) << 16)
+        | (u32::from(data[offset + 3]) << 24)
+}
+
+impl UnifCartridge {
+    pub fn parse(binary: &[u8]) -> Result<UnifCartridge, UnifError> {
+        if binary.len() < UNIF_HEADER_SIZE {
+            return Err(UnifError::Truncated(String::from("header")));
+        }
+        //Without a MIRR chunk, or with it left to the mapper, go with horizontal like an INES header of all zeroes.
+        //Boards that control mirroring set it themselves, the PPU mustn't ever see Unknown
+        let mut info = CartridgeInfo {
+            format: HeaderFormat::Unif,
+            mirror_table: MirrorTable::Horizontal,
+            ..CartridgeInfo::default()
+        };
+        let mut board = None;
+        let mut prg_chunks: [Option<&[u8]>; UNIF_NUM_OF_ROM_CHUNKS] = [None; UNIF_NUM_OF_ROM_CHUNKS];
+        let mut chr_chunks: [Option<&[u8]>; UNIF_NUM_OF_ROM_CHUNKS] = [None; UNIF_NUM_OF_ROM_CHUNKS];
+        let mut prg_checksums: [Option<u32>; UNIF_NUM_OF_ROM_CHUNKS] = [None; UNIF_NUM_OF_ROM_CHUNKS];
+        let mut chr_checksums: [Option<u32>; UNIF_NUM_OF_ROM_CHUNKS] = [None; UNIF_NUM_OF_ROM_CHUNKS];
+
+        let mut offset = UNIF_HEADER_SIZE;
+        while offset + UNIF_CHUNK_HEADER_SIZE <= binary.len() {
+            let id = &binary[offset..offset + 4];
+            let id_name = || String::from_utf8_lossy(id).into_owned();
+            let len = read_u32(binary, offset + 4) as usize;
+            let data_start = offset + UNIF_CHUNK_HEADER_SIZE;
+            let data = binary
+                .get(data_start..data_start.saturating_add(len))
+                .ok_or_else(|| UnifError::Truncated(id_name()))?;
+            offset = data_start + len;
+
+            //The last character of ROM and checksum chunk IDs is a hex digit saying which one it is
+            let chunk_index = char::from(id[3]).to_digit(16).map(|i| i as usize);
+            match (&id[..3], chunk_index) {
+                (b"PRG", Some(i)) => prg_chunks[i] = Some(data),
+                (b"CHR", Some(i)) => chr_chunks[i] = Some(data),
+                (b"PCK", Some(i)) if data.len() >= 4 => prg_checksums[i] = Some(read_u32(data, 0)),
+                (b"CCK", Some(i)) if data.len() >= 4 => chr_checksums[i] = Some(read_u32(data, 0)),
+                _ => match id {
+                    b"MAPR" => board = Some(chunk_string(data)),
+                    b"NAME" => info.title = Some(chunk_string(data)).filter(|name| !name.is_empty()),
+                    //Presence alone means battery, the contents don't matter
+                    b"BATR" => info.has_battery = true,
+                    b"VROR" => info.is_chr_rom_writable = true,
+                    b"MIRR" => {
+                        info.mirror_table = match data.first() {
+                            Some(1) => MirrorTable::Vertical,
+                            Some(2) => MirrorTable::SingleScreenA,
+                            Some(3) => MirrorTable::SingleScreenB,
+                            Some(4) => MirrorTable::FourScreen,
+                            //5 is mapper controlled
+                            _ => MirrorTable::Horizontal,
+                        }
+                    }
+                    b"TVCI" => {
+                        info.timing = match data.first() {
+                            Some(0) => Region::Ntsc,
+                            Some(1) => Region::Pal,
+                            _ => Region::Auto,
+                        }
+                    }
+                    //READ, DINF, CTRL and anything newer are of no use to us
+                    _ => {}
+                },
+            }
+        }
+
+        let board = board.ok_or(UnifError::NoBoard)?;
+        let (mapper, submapper) = lookup_board(&board).ok_or_else(|| UnifError::UnknownBoard(board.clone()))?;
+        info.mapper = mapper;
+        info.submapper = submapper;
+
+        let join = |chunks: &[Option<&[u8]>], checksums: &[Option<u32>], kind: &str| {
+            let mut rom = Vec::new();
+            for (i, chunk) in chunks.iter().enumerate() {
+                if let Some(chunk) = chunk {
+                    if let Some(crc32) = checksums[i] {
+                        if crc32 != crc32fast::hash(chunk) {
+                            return Err(UnifError::ChecksumMismatch(format!("{}{:X}", kind, i)));
+                        }
+                    }
+                    rom.extend_from_slice(chunk);
+                }
+            }
+            Ok(rom)
+        };
+        let prg_rom = join(&prg_chunks, &prg_checksums, "PRG")?;
+        let chr_rom = join(&chr_chunks, &chr_checksums, "CHR")?;
+        if prg_rom.is_empty() {
+            return Err(UnifError::NoPrgRom);
+        }
+
+        info.prg_rom_bytes = prg_rom.len();
+        info.chr_rom_bytes = chr_rom.len();
+        //UNIF doesn't record RAM sizes, assume the usual 8k of each like INES does
+        if info.has_battery {
+            info.prg_nvram_bytes = INES_PRG_RAM_BANK_SIZE;
+        } else {
+            info.prg_ram_bytes = INES_PRG_RAM_BANK_SIZE;
+        }
+        if chr_rom.is_empty() {
+            info.chr_ram_bytes = INES_DEFAULT_CHR_RAM_SIZE;
+        }
+
+        Ok(UnifCartridge {
+            info,
+            board,
+            prg_rom,
+            chr_rom,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::{Rom, RomError, ROM_FILE_MAX_SIZE};
+
+    fn chunk(unif: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        unif.extend_from_slice(id);
+        unif.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        unif.extend_from_slice(data);
+    }
+
+    //Revision 7 header then whatever chunks the test wants
+    fn unif(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut unif = b"UNIF".to_vec();
+        unif.extend_from_slice(&7u32.to_le_bytes());
+        unif.resize(UNIF_HEADER_SIZE, 0);
+        for (id, data) in chunks {
+            chunk(&mut unif, id, data);
+        }
+        unif
+    }
+
+    #[test]
+    fn parses_chunks() {
+        let prg0 = vec![0x11; 0x4000];
+        let prg1 = vec![0x22; 0x4000];
+        let chr0 = vec![0x33; 0x2000];
+        let binary = unif(&[
+            (b"MAPR", b"NES-NROM-256\0"),
+            (b"NAME", b"Test\0"),
+            (b"PRG1", &prg1),
+            (b"PRG0", &prg0),
+            (b"PCK0", &crc32fast::hash(&prg0).to_le_bytes()),
+            (b"CHR0", &chr0),
+            (b"MIRR", &[1]),
+            (b"TVCI", &[1]),
+            (b"BATR", &[0]),
+        ]);
+        assert!(is_unif(&binary));
+        let cartridge = UnifCartridge::parse(&binary).unwrap();
+        assert_eq!(cartridge.board, "NES-NROM-256");
+        assert_eq!(cartridge.info.mapper, 0);
+        assert_eq!(cartridge.info.title.as_deref(), Some("Test"));
+        assert!(matches!(cartridge.info.mirror_table, MirrorTable::Vertical));
+        assert_eq!(cartridge.info.timing, Region::Pal);
+        assert!(cartridge.info.has_battery);
+        assert_eq!(cartridge.info.prg_nvram_bytes, INES_PRG_RAM_BANK_SIZE);
+        assert_eq!(cartridge.prg_rom, [prg0, prg1].concat());
+        assert_eq!(cartridge.chr_rom, chr0);
+        assert_eq!(cartridge.info.chr_ram_bytes, 0);
+    }
+
+    #[test]
+    fn single_screen_mirroring_keeps_its_page() {
+        let prg = [0; 0x4000];
+        let mirroring = |mirr: u8| {
+            let binary = unif(&[(b"MAPR", b"NROM"), (b"PRG0", &prg), (b"MIRR", &[mirr])]);
+            UnifCartridge::parse(&binary).unwrap().info.mirror_table
+        };
+        assert!(matches!(mirroring(2), MirrorTable::SingleScreenA));
+        assert!(matches!(mirroring(3), MirrorTable::SingleScreenB));
+        assert!(matches!(mirroring(5), MirrorTable::Horizontal));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(
+            UnifCartridge::parse(b"UNIF").err(),
+            Some(UnifError::Truncated(String::from("header")))
+        );
+        let mut binary = unif(&[(b"MAPR", b"NROM"), (b"PRG0", &[0; 0x4000])]);
+        binary.truncate(binary.len() - 1);
+        assert_eq!(UnifCartridge::parse(&binary).err(), Some(UnifError::Truncated(String::from("PRG0"))));
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let mut binary = unif(&[(b"MAPR", b"NROM")]);
+        binary.extend_from_slice(b"PRG0");
+        binary.extend_from_slice(&u32::MAX.to_le_bytes());
+        binary.extend_from_slice(&[0; 0x10]);
+        assert_eq!(UnifCartridge::parse(&binary).err(), Some(UnifError::Truncated(String::from("PRG0"))));
+        let binary = unif(&[(b"MAPR", b"NROM"), (b"PRG0", &vec![0; ROM_FILE_MAX_SIZE])]);
+        assert!(matches!(Rom::default().load_bin(&binary), Err(RomError::TooLarge(_))));
+    }
+
+    #[test]
+    fn rejects_bad_chunks() {
+        let prg = [0; 0x4000];
+        assert_eq!(UnifCartridge::parse(&unif(&[(b"PRG0", &prg)])).err(), Some(UnifError::NoBoard));
+        assert_eq!(
+            UnifCartridge::parse(&unif(&[(b"MAPR", b"NOT-A-BOARD"), (b"PRG0", &prg)])).err(),
+            Some(UnifError::UnknownBoard(String::from("NOT-A-BOARD")))
+        );
+        assert_eq!(UnifCartridge::parse(&unif(&[(b"MAPR", b"NROM")])).err(), Some(UnifError::NoPrgRom));
+        assert_eq!(
+            UnifCartridge::parse(&unif(&[(b"MAPR", b"NROM"), (b"PRG0", &prg), (b"PCK0", &[0; 4])])).err(),
+            Some(UnifError::ChecksumMismatch(String::from("PRG0")))
+        );
+    }
+
+    #[test]
+    fn names_the_board_it_cant_run() {
+        let binary = unif(&[(b"MAPR", b"NES-UNROM"), (b"PRG0", &[0; 0x20000])]);
+        assert_eq!(
+            Rom::default().load_bin(&binary).err(),
+            Some(RomError::UnsupportedBoard {
+                board: String::from("NES-UNROM"),
+                mapper: 2
+            })
+        );
+    }
+}
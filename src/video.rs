@@ -11,7 +11,8 @@ pub const PALETTE_TABLE_BASE_ADDR: u16 = 0x3f00;
 pub const VIDEO_ADDRESS_SIZE: u16 = 0x4000;
 
 pub const NAME_TABLE_SIZE: usize = 0x0400;
-pub const NUM_OF_NAME_TABLE: usize = 2;
+//Only 2 are inside the console, four screen cartridges bring the other 2 along themselves
+pub const NUM_OF_NAME_TABLE: usize = 4;
 pub const ATTRIBUTE_TABLE_SIZE: u16 = 0x0040;
 pub const ATTRIBUTE_TABLE_OFFSET: u16 = 0x03c0; 
 
@@ -53,7 +54,8 @@ macro_rules! arr_write {
 #[derive(Clone, Debug)]
 pub struct VideoSystem {
 
-    pub nametables: [[u8; NAME_TABLE_SIZE]; NUM_OF_NAME_TABLE],
+    //The four 1k tables back to back, the console only has two of them unless the cartridge adds more
+    pub nametables: Vec<u8>,
 
 
     pub palette: [u8; PALETTE_SIZE],
@@ -62,7 +64,7 @@ pub struct VideoSystem {
 impl Default for VideoSystem {
     fn default() -> Self {
         Self {
-            nametables: [[0; NAME_TABLE_SIZE]; NUM_OF_NAME_TABLE],
+            nametables: vec![0; NAME_TABLE_SIZE * NUM_OF_NAME_TABLE],
             palette: [0; PALETTE_SIZE],
         }
     }
@@ -70,18 +72,24 @@ impl Default for VideoSystem {
 
 impl VideoSystem {
     pub fn reset(&mut self) {
-        self.nametables = [[0; NAME_TABLE_SIZE]; NUM_OF_NAME_TABLE];
+        self.nametables = vec![0; NAME_TABLE_SIZE * NUM_OF_NAME_TABLE];
         self.palette = [0; PALETTE_SIZE];
     }
 }
 
 impl VideoSystem {
 
-    fn convert_name_table_addr(&self, mirror_mode: MirrorTable, addr: u16) -> (usize, usize) {
+    //The mapper gets a say in where each nametable lives, None means the cartridge is handling it
+    fn convert_name_table_addr(&self, rom: &Rom, addr: u16) -> Option<(usize, usize)> {
         debug_assert!(addr >= NAME_TABLE_BASE_ADDR);
         debug_assert!(addr < NAME_TABLE_MIRROR_BASE_ADDR);
 
         let offset = usize::from(addr - NAME_TABLE_BASE_ADDR) % NAME_TABLE_SIZE;
+        let mirror_mode = match rom.name_table_source(addr) {
+            NameTableSource::Mirror(mirror_mode) => mirror_mode,
+            NameTableSource::Vram(index) => return Some((index, offset)),
+            NameTableSource::Cartridge => return None,
+        };
         let table_index = match mirror_mode {
             MirrorTable::Horizontal => {
                 // [A, A]
@@ -102,34 +110,39 @@ impl VideoSystem {
                     1
                 }
             }
-            MirrorTable::SingleScreen => {
+            MirrorTable::SingleScreenA => {
                 // [A, A]
                 // [A, A]
                 0
             }
+            MirrorTable::SingleScreenB => {
+                // [B, B]
+                // [B, B]
+                1
+            }
             MirrorTable::FourScreen => {
                 // [A, B]
                 // [C, D]
-                usize::from((addr - 0x2000) / 4)
+                usize::from((addr - NAME_TABLE_BASE_ADDR) / (NAME_TABLE_SIZE as u16))
             }
             _ => {
                 unimplemented!();
             }
         };
-        (table_index, offset)
+        Some((table_index, offset))
     }
     pub fn read_u8(&self, rom: &mut Rom, addr: u16) -> u8 {
         debug_assert!(addr < VIDEO_ADDRESS_SIZE);
 
         if addr < NAME_TABLE_BASE_ADDR {
             rom.read_video_u8(addr)
-        } else if addr < NAME_TABLE_MIRROR_BASE_ADDR {
-            let (index, offset) = self.convert_name_table_addr(rom.mirror_table, addr);
-            self.nametables[index][offset]
         } else if addr < PALETTE_TABLE_BASE_ADDR {
-            let (index, offset) =
-                self.convert_name_table_addr(rom.mirror_table, addr - 0x1000);
-            self.nametables[index][offset]
+            //$3000-$3EFF mirrors the nametables
+            let addr = if addr < NAME_TABLE_MIRROR_BASE_ADDR { addr } else { addr - 0x1000 };
+            match self.convert_name_table_addr(rom, addr) {
+                Some((index, offset)) => self.nametables[index * NAME_TABLE_SIZE + offset],
+                None => rom.read_name_table_u8(addr),
+            }
         } else {
             let index = usize::from(addr - PALETTE_TABLE_BASE_ADDR) % PALETTE_SIZE;
             match index {
@@ -146,14 +159,12 @@ impl VideoSystem {
 
         if addr < NAME_TABLE_BASE_ADDR {
             rom.write_video_u8(addr, data);
-        } else if addr < NAME_TABLE_MIRROR_BASE_ADDR {
-            let (index, offset) = self.convert_name_table_addr(rom.mirror_table, addr);
-            self.nametables[index][offset] = data;
         } else if addr < PALETTE_TABLE_BASE_ADDR {
-           
-            let (index, offset) =
-                self.convert_name_table_addr(rom.mirror_table, addr - 0x1000);
-            self.nametables[index][offset] = data;
+            let addr = if addr < NAME_TABLE_MIRROR_BASE_ADDR { addr } else { addr - 0x1000 };
+            match self.convert_name_table_addr(rom, addr) {
+                Some((index, offset)) => self.nametables[index * NAME_TABLE_SIZE + offset] = data,
+                None => rom.write_name_table_u8(addr, data),
+            }
         } else {
             let index = usize::from(addr - PALETTE_TABLE_BASE_ADDR) % PALETTE_SIZE;
           
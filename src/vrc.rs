@@ -0,0 +1,259 @@
+/* Konami VRC2 and VRC4 */
+//https://wiki.nesdev.com/w/index.php/VRC2_and_VRC4
+//Two 8k PRG banks, eight 1k CHR banks and, on the VRC4, an IRQ counter that can count CPU cycles or scanlines.
+//Boards wired the chip's two register select pins to whichever CPU address lines they liked, which is why the same
+//chip turns up as mappers 21, 22, 23 and 25. NES 2.0 submappers say which wiring a board has; without one every
+//candidate line is listened to at once, which works for nearly everything.
+use super::rom::{MirrorTable, NameTableSource};
+
+pub const VRC_PRG_ROM_MAX_SIZE: usize = 0x4_0000;
+pub const VRC_CHR_ROM_MAX_SIZE: usize = 0x8_0000;
+const VRC_PRG_BANK_SIZE: usize = 0x2000;
+const VRC_CHR_BANK_SIZE: usize = 0x0400;
+const VRC_PRG_RAM_SIZE: usize = 0x2000;
+const VRC_NUM_OF_CHR_BANKS: usize = 8;
+
+//The IRQ prescaler counts down in thirds of a CPU cycle, 341 of them make a scanline. The VRC6 and VRC7 have the
+//same counter
+const VRC_IRQ_PRESCALER_PERIOD: i16 = 341;
+const VRC_IRQ_PRESCALER_STEP: i16 = 3;
+
+//A mapper number and submapper, and which chip and address lines that means
+//https://wiki.nesdev.com/w/index.php/NES_2.0_submappers#021.2C_023.2C_025:_Konami_VRC2.2FVRC4
+#[derive(Clone, Copy, Debug)]
+struct VrcWiring {
+    is_vrc2: bool,
+    //Address lines that drive the chip's A0 and A1 pins
+    a0: u16,
+    a1: u16,
+}
+
+fn lookup_wiring(mapper: u16, submapper: u8) -> Option<VrcWiring> {
+    let (is_vrc2, a0, a1) = match (mapper, submapper) {
+        //VRC4a, VRC4c
+        (21, 1) => (false, 0x02, 0x04),
+        (21, 2) => (false, 0x40, 0x80),
+        (21, _) => (false, 0x42, 0x84),
+        //VRC2a
+        (22, _) => (true, 0x02, 0x01),
+        //VRC4f, VRC4e, VRC2b
+        (23, 1) => (false, 0x01, 0x02),
+        (23, 2) => (false, 0x04, 0x08),
+        (23, 3) => (true, 0x01, 0x02),
+        (23, _) => (false, 0x05, 0x0a),
+        //VRC4b, VRC4d, VRC2c
+        (25, 1) => (false, 0x02, 0x01),
+        (25, 2) => (false, 0x08, 0x04),
+        (25, 3) => (true, 0x02, 0x01),
+        (25, _) => (false, 0x0a, 0x05),
+        _ => return None,
+    };
+    Some(VrcWiring { is_vrc2, a0, a1 })
+}
+
+pub fn is_vrc(mapper: u16) -> bool {
+    lookup_wiring(mapper, 0).is_some()
+}
+
+#[derive(Clone, Debug)]
+pub struct Vrc {
+    wiring: VrcWiring,
+    //VRC2a only has 7 CHR bank bits wired, from the second bit up
+    is_chr_shifted: bool,
+    prg_banks: [u8; 2],
+    //VRC4 $9002 bit 1, $8000 and $C000 trade places
+    is_prg_swapped: bool,
+    is_prg_ram_enabled: bool,
+    prg_ram: Vec<u8>,
+    //VRC2 boards without RAM have a single bit latch at $6000 instead
+    latch: u8,
+    chr_banks: [u16; VRC_NUM_OF_CHR_BANKS],
+    //$9000: vertical, horizontal, one screen A, one screen B
+    mirroring: u8,
+    irq: VrcIrq,
+}
+
+impl Vrc {
+    pub fn new(mapper: u16, submapper: u8, has_prg_ram: bool) -> Self {
+        let wiring = lookup_wiring(mapper, submapper).expect("not a VRC2/VRC4 mapper");
+        Self {
+            wiring,
+            is_chr_shifted: mapper == 22,
+            prg_banks: [0; 2],
+            is_prg_swapped: false,
+            //The VRC2 has no enable bit, its RAM is always there
+            is_prg_ram_enabled: wiring.is_vrc2,
+            prg_ram: if has_prg_ram { vec![0; VRC_PRG_RAM_SIZE] } else { Vec::new() },
+            latch: 0,
+            chr_banks: [0; VRC_NUM_OF_CHR_BANKS],
+            mirroring: 0,
+            irq: VrcIrq::default(),
+        }
+    }
+    //PRG RAM, for snapshots
+    pub fn ram_mut(&mut self) -> [&mut Vec<u8>; 1] {
+        [&mut self.prg_ram]
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.prg_ram.capacity()
+    }
+    //$8000-$FFFF down to $x000-$x003 the way the chip sees it
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = u16::from(addr & self.wiring.a0 != 0);
+        let a1 = u16::from(addr & self.wiring.a1 != 0);
+        (addr & 0xf000) | (a1 << 1) | a0
+    }
+    fn prg_index(&self, addr: u16, prg_rom: &[u8]) -> usize {
+        let num_of_banks = prg_rom.len() / VRC_PRG_BANK_SIZE;
+        let second_last = num_of_banks.saturating_sub(2);
+        let bank = match (addr >> 13) & 0x03 {
+            0 if self.is_prg_swapped => second_last,
+            0 => usize::from(self.prg_banks[0]),
+            1 => usize::from(self.prg_banks[1]),
+            2 if self.is_prg_swapped => usize::from(self.prg_banks[0]),
+            2 => second_last,
+            _ => num_of_banks.saturating_sub(1),
+        };
+        (bank * VRC_PRG_BANK_SIZE + usize::from(addr & 0x1fff)) % prg_rom.len()
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&self, addr: u16, prg_rom: &[u8]) -> u8 {
+        let open_bus = (addr >> 8) as u8;
+        match addr {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() && self.is_prg_ram_enabled => {
+                self.prg_ram[usize::from(addr & 0x1fff)]
+            }
+            0x6000..=0x6fff if self.prg_ram.is_empty() && self.wiring.is_vrc2 => (open_bus & 0xfe) | self.latch,
+            0x8000..=0xffff => prg_rom[self.prg_index(addr, prg_rom)],
+            _ => open_bus,
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if !self.prg_ram.is_empty() && self.is_prg_ram_enabled => {
+                self.prg_ram[usize::from(addr & 0x1fff)] = data;
+            }
+            0x6000..=0x6fff if self.prg_ram.is_empty() && self.wiring.is_vrc2 => self.latch = data & 0x01,
+            0x8000..=0xffff => self.write_register(self.register(addr), data),
+            _ => {}
+        }
+    }
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1f,
+            0x9000..=0x9001 if self.wiring.is_vrc2 => self.mirroring = data & 0x01,
+            0x9000 => self.mirroring = data & 0x03,
+            0x9002 if !self.wiring.is_vrc2 => {
+                self.is_prg_ram_enabled = (data & 0x01) == 0x01;
+                self.is_prg_swapped = (data & 0x02) == 0x02;
+            }
+            0xa000..=0xa003 => self.prg_banks[1] = data & 0x1f,
+            //Two registers per CHR bank, low 4 bits then the high ones
+            0xb000..=0xefff => {
+                let bank = usize::from(((register >> 12) - 0xb) * 2 + ((register >> 1) & 0x01));
+                let is_high = (register & 0x01) == 0x01;
+                let current = self.chr_banks[bank];
+                self.chr_banks[bank] = if is_high {
+                    let high_mask = if self.wiring.is_vrc2 { 0x0f } else { 0x1f };
+                    (current & 0x0f) | (u16::from(data & high_mask) << 4)
+                } else {
+                    (current & 0x1f0) | u16::from(data & 0x0f)
+                };
+            }
+            //The latch goes in a nibble at a time
+            0xf000 if !self.wiring.is_vrc2 => self.irq.latch = (self.irq.latch & 0xf0) | (data & 0x0f),
+            0xf001 if !self.wiring.is_vrc2 => self.irq.latch = (self.irq.latch & 0x0f) | (data << 4),
+            0xf002 if !self.wiring.is_vrc2 => self.irq.write_control(data),
+            0xf003 if !self.wiring.is_vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    pub fn chr_index(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[usize::from(addr >> 10) & 0x07];
+        let bank = if self.is_chr_shifted { bank >> 1 } else { bank };
+        usize::from(bank) * VRC_CHR_BANK_SIZE + usize::from(addr & 0x03ff)
+    }
+    pub fn name_table_source(&self) -> NameTableSource {
+        match self.mirroring {
+            0 => NameTableSource::Mirror(MirrorTable::Vertical),
+            1 => NameTableSource::Mirror(MirrorTable::Horizontal),
+            page => NameTableSource::Vram(usize::from(page - 2)),
+        }
+    }
+    pub fn step(&mut self, cpu_cyc: usize) {
+        self.irq.step(cpu_cyc);
+    }
+    pub fn is_irq(&self) -> bool {
+        self.irq.is_pending
+    }
+}
+
+//The IRQ counter every VRC from the VRC4 on has. Counts up from the latch to $FF, in CPU cycles or scanlines
+#[derive(Clone, Debug)]
+pub struct VrcIrq {
+    pub latch: u8,
+    counter: u8,
+    prescaler: i16,
+    is_enabled: bool,
+    //Enabled again on acknowledge
+    is_enabled_after_ack: bool,
+    is_cycle_mode: bool,
+    pub is_pending: bool,
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        Self {
+            latch: 0,
+            counter: 0,
+            prescaler: VRC_IRQ_PRESCALER_PERIOD,
+            is_enabled: false,
+            is_enabled_after_ack: false,
+            is_cycle_mode: false,
+            is_pending: false,
+        }
+    }
+}
+
+impl VrcIrq {
+    pub fn write_control(&mut self, data: u8) {
+        self.is_enabled_after_ack = (data & 0x01) == 0x01;
+        self.is_enabled = (data & 0x02) == 0x02;
+        self.is_cycle_mode = (data & 0x04) == 0x04;
+        self.is_pending = false;
+        if self.is_enabled {
+            self.counter = self.latch;
+            self.prescaler = VRC_IRQ_PRESCALER_PERIOD;
+        }
+    }
+    pub fn acknowledge(&mut self) {
+        self.is_pending = false;
+        self.is_enabled = self.is_enabled_after_ack;
+    }
+    fn clock_counter(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.is_pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+    //Runs off M2 rather than the PPU, so scanline mode is just a divider that happens to match the PPU's lines
+    pub fn step(&mut self, cpu_cyc: usize) {
+        if !self.is_enabled {
+            return;
+        }
+        for _ in 0..cpu_cyc {
+            if self.is_cycle_mode {
+                self.clock_counter();
+            } else {
+                self.prescaler -= VRC_IRQ_PRESCALER_STEP;
+                if self.prescaler <= 0 {
+                    self.prescaler += VRC_IRQ_PRESCALER_PERIOD;
+                    self.clock_counter();
+                }
+            }
+        }
+    }
+}
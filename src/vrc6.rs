@@ -0,0 +1,185 @@
+/* Konami VRC6 */
+//https://wiki.nesdev.com/w/index.php/VRC6
+//A 16k and an 8k PRG bank, eight 1k CHR banks, the VRC4's IRQ counter and three channels of sound (see Vrc6Audio).
+//Mapper 24 is VRC6a, mapper 26 is VRC6b, which is the same chip with the A0 and A1 pins swapped over on the board.
+use super::expansion_audio::Vrc6Audio;
+use super::rom::{MirrorTable, NameTableSource};
+use super::vrc::VrcIrq;
+
+pub const VRC6A_MAPPER: u16 = 24;
+pub const VRC6B_MAPPER: u16 = 26;
+pub const VRC6_PRG_ROM_MAX_SIZE: usize = 0x4_0000;
+pub const VRC6_CHR_ROM_MAX_SIZE: usize = 0x4_0000;
+const VRC6_PRG_BANK_SIZE: usize = 0x2000;
+const VRC6_CHR_BANK_SIZE: usize = 0x0400;
+const VRC6_PRG_RAM_SIZE: usize = 0x2000;
+const VRC6_NUM_OF_CHR_BANKS: usize = 8;
+
+#[derive(Clone, Debug)]
+pub struct Vrc6 {
+    is_vrc6b: bool,
+    //In 16k banks
+    prg_bank_8000: u8,
+    prg_bank_c000: u8,
+    chr_banks: [u8; VRC6_NUM_OF_CHR_BANKS],
+    //$B003 bits 2-3: vertical, horizontal, one screen A, one screen B
+    mirroring: u8,
+    is_prg_ram_enabled: bool,
+    prg_ram: Vec<u8>,
+    irq: VrcIrq,
+    pub audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+    pub fn new(mapper: u16) -> Self {
+        Self {
+            is_vrc6b: mapper == VRC6B_MAPPER,
+            prg_bank_8000: 0,
+            prg_bank_c000: 0,
+            chr_banks: [0; VRC6_NUM_OF_CHR_BANKS],
+            mirroring: 0,
+            is_prg_ram_enabled: false,
+            prg_ram: vec![0; VRC6_PRG_RAM_SIZE],
+            irq: VrcIrq::default(),
+            audio: Vrc6Audio::default(),
+        }
+    }
+    //PRG RAM, for snapshots
+    pub fn ram_mut(&mut self) -> [&mut Vec<u8>; 1] {
+        [&mut self.prg_ram]
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.prg_ram.capacity()
+    }
+    //$8000-$FFFF down to $x000-$x003 as VRC6a has them
+    fn register(&self, addr: u16) -> u16 {
+        if self.is_vrc6b {
+            (addr & 0xf000) | ((addr & 0x01) << 1) | ((addr & 0x02) >> 1)
+        } else {
+            addr & 0xf003
+        }
+    }
+    fn prg_index(&self, addr: u16, prg_rom: &[u8]) -> usize {
+        let num_of_banks = prg_rom.len() / VRC6_PRG_BANK_SIZE;
+        let bank = match addr {
+            0x8000..=0xbfff => usize::from(self.prg_bank_8000) * 2 + usize::from((addr >> 13) & 0x01),
+            0xc000..=0xdfff => usize::from(self.prg_bank_c000),
+            _ => num_of_banks.saturating_sub(1),
+        };
+        (bank * VRC6_PRG_BANK_SIZE + usize::from(addr & 0x1fff)) % prg_rom.len()
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&self, addr: u16, prg_rom: &[u8]) -> u8 {
+        match addr {
+            0x6000..=0x7fff if self.is_prg_ram_enabled => self.prg_ram[usize::from(addr & 0x1fff)],
+            0x8000..=0xffff => prg_rom[self.prg_index(addr, prg_rom)],
+            _ => (addr >> 8) as u8,
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.is_prg_ram_enabled => self.prg_ram[usize::from(addr & 0x1fff)] = data,
+            0x8000..=0xffff => self.write_register(self.register(addr), data),
+            _ => {}
+        }
+    }
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0x8000..=0x8003 => self.prg_bank_8000 = data & 0x0f,
+            //Only the standard PPU banking mode, which is all the games use
+            0xb003 => {
+                self.is_prg_ram_enabled = (data & 0x80) == 0x80;
+                self.mirroring = (data >> 2) & 0x03;
+            }
+            0x9000..=0xb002 => self.audio.write_register(register, data),
+            0xc000..=0xc003 => self.prg_bank_c000 = data & 0x1f,
+            0xd000..=0xefff => {
+                let bank = usize::from(((register >> 12) - 0xd) * 4 + (register & 0x03));
+                self.chr_banks[bank] = data;
+            }
+            0xf000 => self.irq.latch = data,
+            0xf001 => self.irq.write_control(data),
+            0xf002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    pub fn chr_index(&self, addr: u16) -> usize {
+        usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]) * VRC6_CHR_BANK_SIZE + usize::from(addr & 0x03ff)
+    }
+    pub fn name_table_source(&self) -> NameTableSource {
+        match self.mirroring {
+            0 => NameTableSource::Mirror(MirrorTable::Vertical),
+            1 => NameTableSource::Mirror(MirrorTable::Horizontal),
+            page => NameTableSource::Vram(usize::from(page - 2)),
+        }
+    }
+    pub fn step(&mut self, cpu_cyc: usize) {
+        self.irq.step(cpu_cyc);
+    }
+    pub fn is_irq(&self) -> bool {
+        self.irq.is_pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+    use crate::rom::Rom;
+
+    fn load(mapper: u16) -> Rom {
+        let mut rom = Rom::default();
+        rom.load_bin(&ines(mapper, 8, 8)).unwrap();
+        rom
+    }
+
+    #[test]
+    fn banks_prg_and_chr() {
+        let mut rom = load(VRC6A_MAPPER);
+        rom.write_u8(0x8000, 3, false);
+        rom.write_u8(0xc000, 9, false);
+        rom.write_u8(0xe002, 42, false);
+        assert_eq!(rom.read_u8(0x8000, true), 6);
+        assert_eq!(rom.read_u8(0xa000, true), 7);
+        assert_eq!(rom.read_u8(0xc000, true), 9);
+        assert_eq!(rom.read_u8(0xe000, true), 15);
+        assert_eq!(rom.read_video_u8(0x1800), 42);
+    }
+
+    #[test]
+    fn vrc6b_swaps_a0_and_a1() {
+        let mut rom = load(VRC6B_MAPPER);
+        rom.write_u8(0xd002, 5, false);
+        assert_eq!(rom.read_video_u8(0x0400), 5);
+    }
+
+    #[test]
+    fn irq_counts_cpu_cycles() {
+        let mut rom = load(VRC6A_MAPPER);
+        rom.write_u8(0xf000, 0xf0, false);
+        rom.write_u8(0xf001, 0x06, false);
+        rom.step(15);
+        assert!(!rom.is_irq());
+        rom.step(1);
+        assert!(rom.is_irq());
+        rom.write_u8(0xf002, 0, false);
+        assert!(!rom.is_irq());
+    }
+
+    #[test]
+    fn cartridge_plays_its_sound() {
+        let mut rom = load(VRC6A_MAPPER);
+        //Sawtooth at its fastest rate
+        rom.write_u8(0xb000, 0x3f, false);
+        rom.write_u8(0xb001, 0x10, false);
+        rom.write_u8(0xb002, 0x80, false);
+        let loudest = (0..1000)
+            .map(|_| {
+                rom.clock_expansion_audio();
+                rom.expansion_audio_output()
+            })
+            .fold(0.0, f32::max);
+        assert!(loudest > 0.0);
+    }
+}
@@ -0,0 +1,554 @@
+/* Konami VRC7 and its FM synthesis */
+//https://wiki.nesdev.com/w/index.php/VRC7_audio
+//A cut down YM2413 (OPLL): six 2 operator FM channels, 15 instruments baked into the chip and one custom one. Each
+//channel is a modulator sine wave bending the phase of a carrier sine wave. Attenuation is all worked in dB here,
+//the chip does the same with log and exp tables.
+//The mapper side is at the bottom: three 8k PRG banks, eight 1k CHR banks and the VRC4's IRQ counter.
+use std::f32::consts::PI;
+
+use super::region::NTSC_MASTER_CLOCK;
+use super::rom::{MirrorTable, NameTableSource};
+use super::vrc::VrcIrq;
+
+pub const VRC7_MAPPER: u16 = 85;
+pub const VRC7_PRG_ROM_MAX_SIZE: usize = 0x8_0000;
+pub const VRC7_CHR_ROM_MAX_SIZE: usize = 0x4_0000;
+const VRC7_PRG_BANK_SIZE: usize = 0x2000;
+const VRC7_CHR_BANK_SIZE: usize = 0x0400;
+const VRC7_PRG_RAM_SIZE: usize = 0x2000;
+const VRC7_NUM_OF_CHR_BANKS: usize = 8;
+
+const VRC7_NUM_OF_CHANNELS: usize = 6;
+//The chip makes one sample every 36 CPU cycles, about 49.7kHz
+const VRC7_CYCLES_PER_SAMPLE: u8 = 36;
+//The games are all Japanese, so at an NTSC console's CPU clock
+const VRC7_SAMPLE_RATE: f32 = NTSC_MASTER_CLOCK as f32 / 12.0 / VRC7_CYCLES_PER_SAMPLE as f32;
+//A single channel at full volume
+const VRC7_AUDIO_LEVEL: f32 = 0.2;
+
+//Phase is 20 bits, one trip round the sine
+const PHASE_BITS: u32 = 20;
+const PHASE_MASK: u32 = (1 << PHASE_BITS) - 1;
+//Quiet enough that the envelope counts as finished
+const ENVELOPE_MAX_DB: f32 = 48.0;
+//How long a full 48dB decay and a full attack take at rate 1 with no key scaling
+const DECAY_SECONDS: f32 = 19.64;
+const ATTACK_SECONDS: f32 = 2.826;
+//Release rates used when the patch doesn't supply one
+const RELEASE_SUSTAIN_RATE: u8 = 5;
+const RELEASE_PERCUSSIVE_RATE: u8 = 7;
+
+//Tremolo, 4.8dB at 3.7Hz, and vibrato, 14 cents either way at 6.4Hz
+const AM_DEPTH_DB: f32 = 4.8;
+const AM_FREQ: f32 = 3.7;
+const PM_DEPTH: f32 = 0.008_12;
+const PM_FREQ: f32 = 6.4;
+
+//Double the frequency multiplier, so the 0.5 for MULT 0 fits
+const MULT2: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+//Key scale level in dB for the top 4 bits of F-Number, at block 7
+const KSL_TABLE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+
+//The built in instruments, 1-15. 0 is the custom one in $00-$07
+//https://wiki.nesdev.com/w/index.php/VRC7_audio#Internal_patch_set
+const VRC7_PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+    [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+    [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+    [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+    [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06],
+];
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+//The settings for one operator, pulled out of a patch
+#[derive(Clone, Copy, Debug, Default)]
+struct OperatorPatch {
+    is_am: bool,
+    is_pm: bool,
+    //Holds at the sustain level while the key is down, rather than carrying on decaying
+    is_sustained: bool,
+    is_ksr: bool,
+    mult: u8,
+    ksl: u8,
+    //Negative half of the sine is cut off
+    is_half_sine: bool,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+}
+
+impl OperatorPatch {
+    //index 0 is the modulator, 1 the carrier
+    fn from_patch(patch: &[u8; 8], index: usize) -> Self {
+        Self {
+            is_am: (patch[index] & 0x80) == 0x80,
+            is_pm: (patch[index] & 0x40) == 0x40,
+            is_sustained: (patch[index] & 0x20) == 0x20,
+            is_ksr: (patch[index] & 0x10) == 0x10,
+            mult: patch[index] & 0x0f,
+            ksl: patch[2 + index] >> 6,
+            is_half_sine: (patch[3] >> (3 + index)) & 0x01 == 0x01,
+            attack_rate: patch[4 + index] >> 4,
+            decay_rate: patch[4 + index] & 0x0f,
+            sustain_level: patch[6 + index] >> 4,
+            release_rate: patch[6 + index] & 0x0f,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Operator {
+    phase: u32,
+    state: EnvelopeState,
+    //Envelope attenuation in dB
+    envelope: f32,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Self {
+            phase: 0,
+            state: EnvelopeState::Off,
+            envelope: ENVELOPE_MAX_DB,
+        }
+    }
+}
+
+//(4 + low bits) << high bits, relative to rate 1 with no key scaling
+fn rate_factor(rate: u8, rks: u8) -> f32 {
+    let rate = (rate * 4 + rks).min(63);
+    (((4 + (rate & 0x03)) as u32) << (rate >> 2)) as f32 / 8.0
+}
+
+impl Operator {
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.state = EnvelopeState::Attack;
+    }
+    fn key_off(&mut self) {
+        if self.state != EnvelopeState::Off {
+            self.state = EnvelopeState::Release;
+        }
+    }
+    fn decay(&mut self, rate: u8, rks: u8) {
+        if rate != 0 {
+            self.envelope += ENVELOPE_MAX_DB / (DECAY_SECONDS * VRC7_SAMPLE_RATE) * rate_factor(rate, rks);
+        }
+    }
+    fn clock_envelope(&mut self, patch: &OperatorPatch, rks: u8, is_channel_sustain: bool) {
+        let sustain_db = f32::from(patch.sustain_level) * 3.0;
+        match self.state {
+            EnvelopeState::Attack => {
+                if patch.attack_rate == 15 {
+                    self.envelope = 0.0;
+                } else if patch.attack_rate != 0 {
+                    let c = 49f32.ln() / (ATTACK_SECONDS * VRC7_SAMPLE_RATE) * rate_factor(patch.attack_rate, rks);
+                    self.envelope -= (self.envelope + 1.0) * c.min(1.0);
+                }
+                if self.envelope <= 0.0 {
+                    self.envelope = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.decay(patch.decay_rate, rks);
+                if self.envelope >= sustain_db {
+                    self.envelope = sustain_db;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            //Percussive patches keep going down at the release rate even with the key held
+            EnvelopeState::Sustain if !patch.is_sustained => self.decay(patch.release_rate, rks),
+            EnvelopeState::Sustain => {}
+            EnvelopeState::Release => {
+                let rate = if is_channel_sustain {
+                    RELEASE_SUSTAIN_RATE
+                } else if patch.is_sustained {
+                    patch.release_rate
+                } else {
+                    RELEASE_PERCUSSIVE_RATE
+                };
+                self.decay(rate, rks);
+            }
+            EnvelopeState::Off => {}
+        }
+        if self.envelope >= ENVELOPE_MAX_DB {
+            self.envelope = ENVELOPE_MAX_DB;
+            if self.state != EnvelopeState::Attack {
+                self.state = EnvelopeState::Off;
+            }
+        }
+    }
+    //The wave at the current phase plus the modulation, in radians, taking attenuation in dB
+    fn output(&self, modulation: f32, attenuation: f32, is_half_sine: bool) -> f32 {
+        if self.state == EnvelopeState::Off || self.envelope >= ENVELOPE_MAX_DB {
+            return 0.0;
+        }
+        let wave = (2.0 * PI * (self.phase as f32) / ((1 << PHASE_BITS) as f32) + modulation).sin();
+        if is_half_sine && wave < 0.0 {
+            0.0
+        } else {
+            wave * 10f32.powf(-attenuation / 20.0)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Vrc7Channel {
+    //9 bits
+    fnum: u16,
+    block: u8,
+    is_key_on: bool,
+    //Release slowly after key off
+    is_sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    //The modulator's last two outputs, averaged for feedback
+    feedback: [f32; 2],
+}
+
+impl Vrc7Channel {
+    //Key scale rate, higher notes move through their envelopes faster
+    fn rks(&self, patch: &OperatorPatch) -> u8 {
+        let rks = (self.block << 1) | (self.fnum >> 8) as u8;
+        if patch.is_ksr {
+            rks
+        } else {
+            rks >> 2
+        }
+    }
+    //Key scale level, higher notes get quieter
+    fn ksl(&self, patch: &OperatorPatch) -> f32 {
+        if patch.ksl == 0 {
+            return 0.0;
+        }
+        let db = (KSL_TABLE[usize::from(self.fnum >> 5)] - 6.0 * f32::from(7 - self.block)).max(0.0);
+        db / f32::from(1u8 << (3 - patch.ksl))
+    }
+    fn phase_increment(&self, patch: &OperatorPatch, pm: f32) -> u32 {
+        let increment = ((u32::from(self.fnum) << self.block) * MULT2[usize::from(patch.mult)]) / 2;
+        if patch.is_pm {
+            ((increment as f32) * (1.0 + pm)) as u32
+        } else {
+            increment
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Vrc7Audio {
+    //$9010
+    addr: u8,
+    custom_patch: [u8; 8],
+    channels: [Vrc7Channel; VRC7_NUM_OF_CHANNELS],
+    cycle: u8,
+    //LFO phases, 0-1
+    am_phase: f32,
+    pm_phase: f32,
+    sample: f32,
+}
+
+impl Vrc7Audio {
+    //$9010 latches a register, $9030 writes it
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr & 0xf030 {
+            0x9010 => self.addr = data & 0x3f,
+            0x9030 => self.write_data(data),
+            _ => {}
+        }
+    }
+    fn write_data(&mut self, data: u8) {
+        let reg = usize::from(self.addr);
+        let index = reg & 0x0f;
+        match reg {
+            0x00..=0x07 => self.custom_patch[reg] = data,
+            0x10..=0x35 if index < VRC7_NUM_OF_CHANNELS => {
+                let channel = &mut self.channels[index];
+                match reg >> 4 {
+                    1 => channel.fnum = (channel.fnum & 0x100) | u16::from(data),
+                    2 => {
+                        channel.fnum = (channel.fnum & 0x0ff) | (u16::from(data & 0x01) << 8);
+                        channel.block = (data >> 1) & 0x07;
+                        channel.is_sustain = (data & 0x20) == 0x20;
+                        let is_key_on = (data & 0x10) == 0x10;
+                        if is_key_on && !channel.is_key_on {
+                            channel.modulator.key_on();
+                            channel.carrier.key_on();
+                        } else if !is_key_on && channel.is_key_on {
+                            channel.modulator.key_off();
+                            channel.carrier.key_off();
+                        }
+                        channel.is_key_on = is_key_on;
+                    }
+                    _ => {
+                        channel.instrument = data >> 4;
+                        channel.volume = data & 0x0f;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        match instrument {
+            0 => self.custom_patch,
+            _ => VRC7_PATCHES[usize::from(instrument) - 1],
+        }
+    }
+    fn update_sample(&mut self) {
+        self.am_phase = (self.am_phase + AM_FREQ / VRC7_SAMPLE_RATE).fract();
+        self.pm_phase = (self.pm_phase + PM_FREQ / VRC7_SAMPLE_RATE).fract();
+        let am = AM_DEPTH_DB * (1.0 - (2.0 * PI * self.am_phase).cos()) / 2.0;
+        let pm = PM_DEPTH * (2.0 * PI * self.pm_phase).sin();
+
+        let mut sample = 0.0;
+        for i in 0..VRC7_NUM_OF_CHANNELS {
+            let patch = self.patch(self.channels[i].instrument);
+            let modulator_patch = OperatorPatch::from_patch(&patch, 0);
+            let carrier_patch = OperatorPatch::from_patch(&patch, 1);
+            let channel = &mut self.channels[i];
+
+            let rks = channel.rks(&modulator_patch);
+            channel.modulator.clock_envelope(&modulator_patch, rks, channel.is_sustain);
+            let rks = channel.rks(&carrier_patch);
+            channel.carrier.clock_envelope(&carrier_patch, rks, channel.is_sustain);
+
+            //Modulator, with its total level in 0.75dB steps and feeding back into itself
+            let feedback_level = patch[3] & 0x07;
+            let feedback = if feedback_level == 0 {
+                0.0
+            } else {
+                (channel.feedback[0] + channel.feedback[1]) / 2.0 * PI / 32.0 * f32::from(1u8 << feedback_level)
+            };
+            let attenuation = channel.modulator.envelope
+                + f32::from(patch[2] & 0x3f) * 0.75
+                + channel.ksl(&modulator_patch)
+                + if modulator_patch.is_am { am } else { 0.0 };
+            let modulation = channel.modulator.output(feedback, attenuation, modulator_patch.is_half_sine);
+            channel.feedback = [channel.feedback[1], modulation];
+
+            //Carrier, with the channel volume in 3dB steps
+            let attenuation = channel.carrier.envelope
+                + f32::from(channel.volume) * 3.0
+                + channel.ksl(&carrier_patch)
+                + if carrier_patch.is_am { am } else { 0.0 };
+            sample += channel.carrier.output(modulation * 4.0 * PI, attenuation, carrier_patch.is_half_sine);
+
+            channel.modulator.phase =
+                (channel.modulator.phase + channel.phase_increment(&modulator_patch, pm)) & PHASE_MASK;
+            channel.carrier.phase = (channel.carrier.phase + channel.phase_increment(&carrier_patch, pm)) & PHASE_MASK;
+        }
+        self.sample = sample;
+    }
+    pub fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle == VRC7_CYCLES_PER_SAMPLE {
+            self.cycle = 0;
+            self.update_sample();
+        }
+    }
+    pub fn output(&self) -> f32 {
+        self.sample * VRC7_AUDIO_LEVEL
+    }
+}
+
+//https://wiki.nesdev.com/w/index.php/VRC7
+//Every register comes in pairs, the second of each on A4 for VRC7a (Lagrange Point) or A3 for VRC7b (Tiny Toon
+//Adventures 2). Without a submapper both are listened to, nothing writes to the other one
+#[derive(Clone, Debug)]
+pub struct Vrc7 {
+    //A4 or A3, whichever the board wired up
+    high_line: u16,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; VRC7_NUM_OF_CHR_BANKS],
+    //$E000 bits 0-1: vertical, horizontal, one screen A, one screen B
+    mirroring: u8,
+    is_audio_silenced: bool,
+    is_prg_ram_enabled: bool,
+    prg_ram: Vec<u8>,
+    irq: VrcIrq,
+    //Boxed, the FM synth carries a lot of state
+    pub audio: Box<Vrc7Audio>,
+}
+
+impl Vrc7 {
+    pub fn new(submapper: u8) -> Self {
+        Self {
+            high_line: match submapper {
+                1 => 0x08,
+                2 => 0x10,
+                _ => 0x18,
+            },
+            prg_banks: [0; 3],
+            chr_banks: [0; VRC7_NUM_OF_CHR_BANKS],
+            mirroring: 0,
+            is_audio_silenced: false,
+            is_prg_ram_enabled: false,
+            prg_ram: vec![0; VRC7_PRG_RAM_SIZE],
+            irq: VrcIrq::default(),
+            audio: Box::default(),
+        }
+    }
+    //PRG RAM, for snapshots
+    pub fn ram_mut(&mut self) -> [&mut Vec<u8>; 1] {
+        [&mut self.prg_ram]
+    }
+    //Memory this takes up, counting the Box it lives in
+    pub fn heap_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + std::mem::size_of::<Vrc7Audio>() + self.prg_ram.capacity()
+    }
+    fn prg_index(&self, addr: u16, prg_rom: &[u8]) -> usize {
+        let bank = match addr {
+            0x8000..=0xdfff => usize::from(self.prg_banks[usize::from((addr - 0x8000) >> 13)]),
+            _ => (prg_rom.len() / VRC7_PRG_BANK_SIZE).saturating_sub(1),
+        };
+        (bank * VRC7_PRG_BANK_SIZE + usize::from(addr & 0x1fff)) % prg_rom.len()
+    }
+    //$4020-$FFFF
+    pub fn read_u8(&self, addr: u16, prg_rom: &[u8]) -> u8 {
+        match addr {
+            0x6000..=0x7fff if self.is_prg_ram_enabled => self.prg_ram[usize::from(addr & 0x1fff)],
+            0x8000..=0xffff => prg_rom[self.prg_index(addr, prg_rom)],
+            _ => (addr >> 8) as u8,
+        }
+    }
+    pub fn write_u8(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.is_prg_ram_enabled => self.prg_ram[usize::from(addr & 0x1fff)] = data,
+            //The sound chip decodes its own two addresses
+            0x9010 | 0x9030 => self.audio.write_register(addr, data),
+            0x8000..=0xffff => {
+                let register = (addr & 0xf000) | if (addr & self.high_line) != 0 { 0x10 } else { 0 };
+                self.write_register(register, data);
+            }
+            _ => {}
+        }
+    }
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0x8000 => self.prg_banks[0] = data & 0x3f,
+            0x8010 => self.prg_banks[1] = data & 0x3f,
+            0x9000 => self.prg_banks[2] = data & 0x3f,
+            0xa000..=0xd010 => {
+                let bank = usize::from(((register >> 12) - 0xa) * 2 + ((register >> 4) & 0x01));
+                self.chr_banks[bank] = data;
+            }
+            0xe000 => {
+                self.mirroring = data & 0x03;
+                self.is_audio_silenced = (data & 0x40) == 0x40;
+                self.is_prg_ram_enabled = (data & 0x80) == 0x80;
+            }
+            0xe010 => self.irq.latch = data,
+            0xf000 => self.irq.write_control(data),
+            0xf010 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    pub fn chr_index(&self, addr: u16) -> usize {
+        usize::from(self.chr_banks[usize::from(addr >> 10) & 0x07]) * VRC7_CHR_BANK_SIZE + usize::from(addr & 0x03ff)
+    }
+    pub fn name_table_source(&self) -> NameTableSource {
+        match self.mirroring {
+            0 => NameTableSource::Mirror(MirrorTable::Vertical),
+            1 => NameTableSource::Mirror(MirrorTable::Horizontal),
+            page => NameTableSource::Vram(usize::from(page - 2)),
+        }
+    }
+    pub fn step(&mut self, cpu_cyc: usize) {
+        self.irq.step(cpu_cyc);
+    }
+    pub fn is_irq(&self) -> bool {
+        self.irq.is_pending
+    }
+    pub fn clock_audio(&mut self) {
+        if !self.is_audio_silenced {
+            self.audio.clock();
+        }
+    }
+    pub fn audio_output(&self) -> f32 {
+        if self.is_audio_silenced {
+            0.0
+        } else {
+            self.audio.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+    use crate::rom::Rom;
+
+    fn load() -> Rom {
+        let mut rom = Rom::default();
+        rom.load_bin(&ines(VRC7_MAPPER, 8, 8)).unwrap();
+        rom
+    }
+
+    #[test]
+    fn listens_on_a3_and_a4() {
+        let mut rom = load();
+        rom.write_u8(0x8010, 4, false);
+        rom.write_u8(0x9000, 5, false);
+        rom.write_u8(0xb008, 7, false);
+        assert_eq!(rom.read_u8(0xa000, true), 4);
+        assert_eq!(rom.read_u8(0xc000, true), 5);
+        assert_eq!(rom.read_u8(0xe000, true), 15);
+        assert_eq!(rom.read_video_u8(0x0c00), 7);
+    }
+
+    #[test]
+    fn prg_ram_needs_enabling() {
+        let mut rom = load();
+        rom.write_u8(0x6000, 0x55, false);
+        assert_ne!(rom.read_u8(0x6000, true), 0x55);
+        rom.write_u8(0xe000, 0x80, false);
+        rom.write_u8(0x6000, 0x55, false);
+        assert_eq!(rom.read_u8(0x6000, true), 0x55);
+    }
+
+    #[test]
+    fn cartridge_plays_its_sound() {
+        let mut rom = load();
+        //Channel 0 on instrument 3 at full volume, key on
+        for (register, data) in [(0x30, 0x30), (0x10, 0x80), (0x20, 0x1c)] {
+            rom.write_u8(0x9010, register, false);
+            rom.write_u8(0x9030, data, false);
+        }
+        let loudest = (0..20_000)
+            .map(|_| {
+                rom.clock_expansion_audio();
+                rom.expansion_audio_output().abs()
+            })
+            .fold(0.0, f32::max);
+        assert!(loudest > 0.0);
+        //Silenced
+        rom.write_u8(0xe000, 0x40, false);
+        assert_eq!(rom.expansion_audio_output(), 0.0);
+    }
+}
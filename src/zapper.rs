@@ -0,0 +1,76 @@
+/* Zapper light gun */
+//https://wiki.nesdev.com/w/index.php/Zapper
+//The gun has a trigger and a photodiode behind a lens. Games flash a white target for a frame and check whether the
+//diode saw light while the beam went past the spot the gun points at. The diode stays lit for a number of scanlines
+//after the beam passes, so we sample the finished framebuffer row by row as the PPU draws it.
+use super::framebuffer::FrameBuffer;
+use super::ppu::{Color, VISIBLE_SCREEN_HEIGHT, VISIBLE_SCREEN_WIDTH};
+
+//How far from the aim point the lens picks up light, in pixels
+const ZAPPER_SENSE_RADIUS: usize = 2;
+//How long the diode stays lit after seeing something bright
+const ZAPPER_LIGHT_LINES: u8 = 20;
+//Luma out of 255 that counts as bright, the palette's white and light colors are above it and the rest below
+const ZAPPER_BRIGHTNESS_THRESHOLD: u32 = 0xa0;
+
+#[derive(Clone, Debug, Default)]
+pub struct Zapper {
+    //Screen pixel the gun points at, None when it's pointed off screen
+    aim: Option<(usize, usize)>,
+    is_trigger: bool,
+    light_lines_left: u8,
+}
+
+impl Zapper {
+    pub fn reset(&mut self) {
+        self.light_lines_left = 0;
+    }
+    //Anything outside the 256x240 picture counts as pointing away from the TV
+    pub fn set_aim(&mut self, x: i32, y: i32) {
+        self.aim = if (0..VISIBLE_SCREEN_WIDTH as i32).contains(&x) && (0..VISIBLE_SCREEN_HEIGHT as i32).contains(&y) {
+            Some((x as usize, y as usize))
+        } else {
+            None
+        };
+    }
+    pub fn set_trigger(&mut self, is_pulled: bool) {
+        self.is_trigger = is_pulled;
+    }
+    pub fn aim(&self) -> Option<(usize, usize)> {
+        self.aim
+    }
+    pub fn is_trigger(&self) -> bool {
+        self.is_trigger
+    }
+    //Called as the PPU starts a line, by then the row above is finished
+    pub fn start_scanline(&mut self, line: u16, fb: &FrameBuffer) {
+        self.light_lines_left = self.light_lines_left.saturating_sub(1);
+        let drawn_line = match usize::from(line).checked_sub(1) {
+            Some(drawn_line) if drawn_line < VISIBLE_SCREEN_HEIGHT => drawn_line,
+            _ => return,
+        };
+        let (aim_x, aim_y) = match self.aim {
+            Some(aim) => aim,
+            None => return,
+        };
+        if drawn_line + ZAPPER_SENSE_RADIUS < aim_y || drawn_line > aim_y + ZAPPER_SENSE_RADIUS {
+            return;
+        }
+        let left = aim_x.saturating_sub(ZAPPER_SENSE_RADIUS);
+        let right = (aim_x + ZAPPER_SENSE_RADIUS).min(VISIBLE_SCREEN_WIDTH - 1);
+        //Judged on the built in palette rather than the one showing, so the gun behaves the same whichever is picked
+        let is_bright = fb[drawn_line][left..=right].iter().any(|&index| {
+            let Color(r, g, b) = Color::from(index as u8);
+            (u32::from(r) * 299 + u32::from(g) * 587 + u32::from(b) * 114) / 1000 >= ZAPPER_BRIGHTNESS_THRESHOLD
+        });
+        if is_bright {
+            self.light_lines_left = ZAPPER_LIGHT_LINES;
+        }
+    }
+    //D3 is low while light is seen, D4 is high while the trigger is held
+    pub fn read_out(&self) -> u8 {
+        let light = if self.light_lines_left > 0 { 0x00 } else { 0x08 };
+        let trigger = if self.is_trigger { 0x10 } else { 0x00 };
+        light | trigger
+    }
+}